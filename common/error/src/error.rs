@@ -44,6 +44,9 @@ pub enum HetuError {
     External(GenericError),
     /// Error returned data fusion error message
     DataFusionError(DataFusionError),
+    /// Error returned when a query exceeds the resources it is allowed to use,
+    /// like its memory limit
+    ResourcesExhausted(String),
 }
 
 impl From<io::Error> for HetuError {
@@ -81,6 +84,9 @@ impl Display for HetuError {
             HetuError::DataFusionError(ref desc) => {
                 write!(f, "Data fusion error: {}", desc)
             }
+            HetuError::ResourcesExhausted(ref desc) => {
+                write!(f, "Resources exhausted: {}", desc)
+            }
         }
     }
 }
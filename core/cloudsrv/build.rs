@@ -14,7 +14,8 @@
 
 fn main() -> Result<(), String> {
     println!("cargo:rerun-if-changed=proto/keda.proto");
+    println!("cargo:rerun-if-changed=proto/raft.proto");
     tonic_build::configure()
-        .compile(&["proto/keda.proto"], &["proto"])
+        .compile(&["proto/keda.proto", "proto/raft.proto"], &["proto"])
         .map_err(|e| format!("protobuf compilation failed: {}", e))
 }
@@ -11,14 +11,22 @@
 // limitations under the License.
 
 use crate::scheduler_server::SchedulerServer;
+use datafusion::physical_plan::displayable;
 use datafusion_proto::logical_plan::AsLogicalPlan;
+use hetu_core::serde::protobuf::{
+    job_status, task_status, JobStatus, QueryHistoryEntry, StageProfile,
+};
 use hetu_core::serde::AsExecutionPlan;
 use hetu_core::BALLISTA_VERSION;
+use warp::http::StatusCode;
+use warp::reply::{Json, WithStatus};
 use warp::Rejection;
 
 #[derive(Debug, serde::Serialize)]
 struct StateResponse {
     executors: Vec<ExecutorMetaResponse>,
+    queued_jobs: usize,
+    running_jobs: usize,
     started: u128,
     version: &'static str,
 }
@@ -29,6 +37,15 @@ pub struct ExecutorMetaResponse {
     pub host: String,
     pub port: u16,
     pub last_seen: u128,
+    pub draining: bool,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct LockHolderResponse {
+    pub key: String,
+    pub owner: String,
+    pub acquired_at: u64,
+    pub expires_at: u64,
 }
 
 pub(crate) async fn scheduler_state<T: AsLogicalPlan, U: AsExecutionPlan>(
@@ -46,12 +63,471 @@ pub(crate) async fn scheduler_state<T: AsLogicalPlan, U: AsExecutionPlan>(
             host: metadata.host,
             port: metadata.port,
             last_seen: duration.as_millis(),
+            draining: data_server.state.executor_manager.is_draining(&metadata.id),
         })
         .collect();
     let response = StateResponse {
         executors,
+        queued_jobs: data_server.state.job_queue.queued_jobs(),
+        running_jobs: data_server.state.job_queue.running_jobs(),
         started: data_server.start_time,
         version: BALLISTA_VERSION,
     };
     Ok(warp::reply::json(&response))
 }
+
+/// List who holds the state backend locks, to find out what a blocked scheduler waits for
+pub(crate) async fn lock_holders<T: AsLogicalPlan, U: AsExecutionPlan>(
+    data_server: SchedulerServer<T, U>,
+) -> Result<impl warp::Reply, Rejection> {
+    match data_server.state.get_lock_holders().await {
+        Ok(holders) => {
+            let response: Vec<LockHolderResponse> = holders
+                .into_iter()
+                .map(|holder| LockHolderResponse {
+                    key: holder.key,
+                    owner: holder.owner,
+                    acquired_at: holder.acquired_at,
+                    expires_at: holder.expires_at,
+                })
+                .collect();
+            Ok(with_status(&response, StatusCode::OK))
+        }
+        Err(e) => Ok(with_status(
+            &e.to_string(),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+/// Scheduler metrics in the Prometheus text format
+pub(crate) async fn metrics<T: AsLogicalPlan, U: AsExecutionPlan>(
+    data_server: SchedulerServer<T, U>,
+) -> Result<impl warp::Reply, Rejection> {
+    let buffer = crate::metrics::gather(&data_server.state).await;
+    Ok(warp::reply::with_header(
+        buffer,
+        "content-type",
+        prometheus::TEXT_FORMAT,
+    ))
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct JobResponse {
+    pub job_id: String,
+    pub status: &'static str,
+    pub error: Option<String>,
+    /// Epoch milliseconds, unknown for the jobs submitted to another scheduler
+    pub queued_at: Option<u64>,
+    pub started_at: Option<u64>,
+    pub finished_at: Option<u64>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct StageResponse {
+    pub stage_id: u32,
+    pub status: &'static str,
+    pub partitions: usize,
+    /// Stages whose shuffle output this stage reads
+    pub input_stages: Vec<u32>,
+    /// Stages which read the shuffle output of this stage
+    pub output_stages: Vec<u32>,
+    pub pending_tasks: usize,
+    pub running_tasks: usize,
+    pub completed_tasks: usize,
+    pub failed_tasks: usize,
+    pub plan: String,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct JobStagesResponse {
+    pub job: JobResponse,
+    pub final_stage_id: Option<u32>,
+    pub stages: Vec<StageResponse>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct TaskResponse {
+    pub partition_id: u32,
+    pub status: &'static str,
+    pub executor_id: Option<String>,
+    pub error: Option<String>,
+    pub shuffle_partitions: Vec<PartitionStatsResponse>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct PartitionStatsResponse {
+    pub partition_id: u64,
+    pub path: String,
+    pub num_rows: u64,
+    pub num_batches: u64,
+    pub num_bytes: u64,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct OperatorMetricResponse {
+    pub name: String,
+    pub value: u64,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct OperatorProfileResponse {
+    pub operator: String,
+    pub metrics: Vec<OperatorMetricResponse>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct StageProfileResponse {
+    pub stage_id: u32,
+    pub partitions: u32,
+    pub completed_tasks: u32,
+    pub failed_tasks: u32,
+    pub elapsed_ms: u64,
+    /// Operators of the stage plan in pre-order, summed over the completed tasks
+    pub operators: Vec<OperatorProfileResponse>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct JobProfileResponse {
+    pub job: JobResponse,
+    pub stages: Vec<StageProfileResponse>,
+}
+
+fn job_status_name(status: &JobStatus) -> (&'static str, Option<String>) {
+    match &status.status {
+        Some(job_status::Status::Queued(_)) => ("queued", None),
+        Some(job_status::Status::Running(_)) => ("running", None),
+        Some(job_status::Status::Failed(failed)) => {
+            ("failed", Some(failed.error.clone()))
+        }
+        Some(job_status::Status::Completed(_)) => ("completed", None),
+        None => ("unknown", None),
+    }
+}
+
+fn job_response<T: AsLogicalPlan, U: AsExecutionPlan>(
+    data_server: &SchedulerServer<T, U>,
+    job_id: String,
+    status: &JobStatus,
+) -> JobResponse {
+    let (status, error) = job_status_name(status);
+    let timing = data_server.state.get_job_timing(&job_id);
+    JobResponse {
+        job_id,
+        status,
+        error,
+        queued_at: timing.queued_at,
+        started_at: timing.started_at,
+        finished_at: timing.finished_at,
+    }
+}
+
+fn history_job_response(entry: &QueryHistoryEntry) -> JobResponse {
+    let (status, error) = entry
+        .status
+        .as_ref()
+        .map(job_status_name)
+        .unwrap_or(("unknown", None));
+    let known = |millis: u64| Some(millis).filter(|millis| *millis > 0);
+    JobResponse {
+        job_id: entry.job_id.clone(),
+        status,
+        error,
+        queued_at: known(entry.queued_at),
+        started_at: known(entry.started_at),
+        finished_at: known(entry.finished_at),
+    }
+}
+
+fn stage_profile_response(stage: StageProfile) -> StageProfileResponse {
+    StageProfileResponse {
+        stage_id: stage.stage_id,
+        partitions: stage.partitions,
+        completed_tasks: stage.completed_tasks,
+        failed_tasks: stage.failed_tasks,
+        elapsed_ms: stage.elapsed_ms,
+        operators: stage
+            .operators
+            .into_iter()
+            .map(|operator| OperatorProfileResponse {
+                operator: operator.operator,
+                metrics: operator
+                    .metrics
+                    .into_iter()
+                    .map(|metric| OperatorMetricResponse {
+                        name: metric.name,
+                        value: metric.value,
+                    })
+                    .collect(),
+            })
+            .collect(),
+    }
+}
+
+fn with_status<R: serde::Serialize>(
+    response: &R,
+    status: StatusCode,
+) -> WithStatus<Json> {
+    warp::reply::with_status(warp::reply::json(response), status)
+}
+
+fn not_found(message: String) -> WithStatus<Json> {
+    with_status(&message, StatusCode::NOT_FOUND)
+}
+
+/// List the jobs known to the scheduler, the latest submitted first
+pub(crate) async fn list_jobs<T: AsLogicalPlan, U: AsExecutionPlan>(
+    data_server: SchedulerServer<T, U>,
+) -> Result<impl warp::Reply, Rejection> {
+    let mut jobs: Vec<JobResponse> = data_server
+        .state
+        .get_jobs_metadata()
+        .into_iter()
+        .map(|(job_id, status)| job_response(&data_server, job_id, &status))
+        .collect();
+    jobs.sort_by(|a, b| b.queued_at.cmp(&a.queued_at));
+    Ok(warp::reply::json(&jobs))
+}
+
+/// The recently finished jobs kept in the query history, the latest finished first
+pub(crate) async fn query_history<T: AsLogicalPlan, U: AsExecutionPlan>(
+    data_server: SchedulerServer<T, U>,
+) -> Result<impl warp::Reply, Rejection> {
+    let history: Vec<JobResponse> = data_server
+        .state
+        .get_query_history()
+        .iter()
+        .map(history_job_response)
+        .collect();
+    Ok(warp::reply::json(&history))
+}
+
+/// The operator metrics of a job, also available after the job expired as long as it is in
+/// the query history
+pub(crate) async fn job_profile<T: AsLogicalPlan, U: AsExecutionPlan>(
+    job_id: String,
+    data_server: SchedulerServer<T, U>,
+) -> Result<impl warp::Reply, Rejection> {
+    let job = if let Some(status) = data_server.state.get_job_metadata(&job_id) {
+        job_response(&data_server, job_id.clone(), &status)
+    } else if let Some(entry) = data_server.state.get_query_history_entry(&job_id) {
+        history_job_response(&entry)
+    } else {
+        return Ok(not_found(format!("Job {} not found", job_id)));
+    };
+    let stages = data_server
+        .state
+        .get_job_profile(&job_id)
+        .map(|profile| {
+            profile
+                .stages
+                .into_iter()
+                .map(stage_profile_response)
+                .collect()
+        })
+        .unwrap_or_default();
+    Ok(with_status(
+        &JobProfileResponse { job, stages },
+        StatusCode::OK,
+    ))
+}
+
+/// The stage DAG of a job
+pub(crate) async fn job_stages<T: AsLogicalPlan, U: AsExecutionPlan>(
+    job_id: String,
+    data_server: SchedulerServer<T, U>,
+) -> Result<impl warp::Reply, Rejection> {
+    let status = match data_server.state.get_job_metadata(&job_id) {
+        Some(status) => status,
+        None => return Ok(not_found(format!("Job {} not found", job_id))),
+    };
+    let stage_manager = &data_server.state.stage_manager;
+    let stage_ids = data_server.state.get_stage_ids(&job_id);
+    let mut final_stage_id = None;
+    let stages = stage_ids
+        .iter()
+        .map(|stage_id| {
+            let stage_id = *stage_id;
+            if stage_manager.is_final_stage(&job_id, stage_id) {
+                final_stage_id = Some(stage_id);
+            }
+            let status = if stage_manager.is_completed_stage(&job_id, stage_id) {
+                "completed"
+            } else if stage_manager.is_running_stage(&job_id, stage_id) {
+                "running"
+            } else if stage_manager.is_pending_stage(&job_id, stage_id) {
+                "pending"
+            } else {
+                "unscheduled"
+            };
+            let mut output_stages: Vec<u32> = stage_manager
+                .get_parent_stages(&job_id, stage_id)
+                .map(|stages| stages.into_iter().collect())
+                .unwrap_or_default();
+            output_stages.sort_unstable();
+            let input_stages: Vec<u32> = stage_ids
+                .iter()
+                .filter(|input_stage_id| {
+                    stage_manager
+                        .get_parent_stages(&job_id, **input_stage_id)
+                        .map(|stages| stages.contains(&stage_id))
+                        .unwrap_or(false)
+                })
+                .cloned()
+                .collect();
+            let tasks = stage_manager
+                .get_stage_tasks(&job_id, stage_id)
+                .unwrap_or_default();
+            let count = |f: fn(&Option<task_status::Status>) -> bool| {
+                tasks.iter().filter(|task| f(&task.status)).count()
+            };
+            let plan = data_server.state.get_stage_plan(&job_id, stage_id as usize);
+            StageResponse {
+                stage_id,
+                status,
+                partitions: plan
+                    .as_ref()
+                    .map(|plan| plan.output_partitioning().partition_count())
+                    .unwrap_or_default(),
+                input_stages,
+                output_stages,
+                pending_tasks: count(|status| status.is_none()),
+                running_tasks: count(|status| {
+                    matches!(status, Some(task_status::Status::Running(_)))
+                }),
+                completed_tasks: count(|status| {
+                    matches!(status, Some(task_status::Status::Completed(_)))
+                }),
+                failed_tasks: count(|status| {
+                    matches!(status, Some(task_status::Status::Failed(_)))
+                }),
+                plan: plan
+                    .map(|plan| displayable(plan.as_ref()).indent().to_string())
+                    .unwrap_or_default(),
+            }
+        })
+        .collect();
+    let response = JobStagesResponse {
+        job: job_response(&data_server, job_id, &status),
+        final_stage_id,
+        stages,
+    };
+    Ok(with_status(&response, StatusCode::OK))
+}
+
+/// The tasks of a stage which is running or completed
+pub(crate) async fn stage_tasks<T: AsLogicalPlan, U: AsExecutionPlan>(
+    job_id: String,
+    stage_id: u32,
+    data_server: SchedulerServer<T, U>,
+) -> Result<impl warp::Reply, Rejection> {
+    let tasks = match data_server
+        .state
+        .stage_manager
+        .get_stage_tasks(&job_id, stage_id)
+    {
+        Some(tasks) => tasks,
+        None => {
+            return Ok(not_found(format!(
+                "Stage {}/{} is not scheduled",
+                job_id, stage_id
+            )))
+        }
+    };
+    let response: Vec<TaskResponse> = tasks
+        .iter()
+        .enumerate()
+        .map(|(partition_id, task)| {
+            let partition_id = partition_id as u32;
+            match &task.status {
+                None => TaskResponse {
+                    partition_id,
+                    status: "pending",
+                    executor_id: None,
+                    error: None,
+                    shuffle_partitions: vec![],
+                },
+                Some(task_status::Status::Running(running)) => TaskResponse {
+                    partition_id,
+                    status: "running",
+                    executor_id: Some(running.executor_id.clone()),
+                    error: None,
+                    shuffle_partitions: vec![],
+                },
+                Some(task_status::Status::Failed(failed)) => TaskResponse {
+                    partition_id,
+                    status: "failed",
+                    executor_id: None,
+                    error: Some(failed.error.clone()),
+                    shuffle_partitions: vec![],
+                },
+                Some(task_status::Status::Completed(completed)) => TaskResponse {
+                    partition_id,
+                    status: "completed",
+                    executor_id: Some(completed.executor_id.clone()),
+                    error: None,
+                    shuffle_partitions: completed
+                        .partitions
+                        .iter()
+                        .map(|partition| PartitionStatsResponse {
+                            partition_id: partition.partition_id,
+                            path: partition.path.clone(),
+                            num_rows: partition.num_rows,
+                            num_batches: partition.num_batches,
+                            num_bytes: partition.num_bytes,
+                        })
+                        .collect(),
+                },
+            }
+        })
+        .collect();
+    Ok(with_status(&response, StatusCode::OK))
+}
+
+pub(crate) async fn cancel_job<T: AsLogicalPlan, U: AsExecutionPlan>(
+    job_id: String,
+    data_server: SchedulerServer<T, U>,
+) -> Result<impl warp::Reply, Rejection> {
+    match data_server.cancel_job(&job_id).await {
+        Ok(true) => Ok(with_status(
+            &format!("Job {} cancelled", job_id),
+            StatusCode::OK,
+        )),
+        Ok(false) => Ok(not_found(format!("No running job {}", job_id))),
+        Err(e) => Ok(with_status(
+            &e.to_string(),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+pub(crate) async fn drain_executor<T: AsLogicalPlan, U: AsExecutionPlan>(
+    executor_id: String,
+    data_server: SchedulerServer<T, U>,
+) -> Result<impl warp::Reply, Rejection> {
+    if data_server.drain_executor(&executor_id) {
+        Ok(with_status(
+            &format!("Executor {} is draining", executor_id),
+            StatusCode::OK,
+        ))
+    } else {
+        Ok(not_found(format!("Executor {} not found", executor_id)))
+    }
+}
+
+pub(crate) async fn decommission_executor<T: AsLogicalPlan, U: AsExecutionPlan>(
+    executor_id: String,
+    data_server: SchedulerServer<T, U>,
+) -> Result<impl warp::Reply, Rejection> {
+    if data_server.decommission_executor(&executor_id) {
+        Ok(with_status(
+            &format!(
+                "Executor {} will be decommissioned once its tasks finish",
+                executor_id
+            ),
+            StatusCode::OK,
+        ))
+    } else {
+        Ok(not_found(format!("Executor {} not found", executor_id)))
+    }
+}
@@ -12,16 +12,20 @@
 
 mod handlers;
 
+use crate::config::RestTlsConfig;
 use crate::scheduler_server::SchedulerServer;
 use anyhow::Result;
 use datafusion_proto::logical_plan::AsLogicalPlan;
 use hetu_core::serde::AsExecutionPlan;
 use std::{
+    net::IpAddr,
     pin::Pin,
     task::{Context as TaskContext, Poll},
 };
 use warp::filters::BoxedFilter;
-use warp::{Buf, Filter, Reply};
+use warp::http::StatusCode;
+use warp::reply::{Json, WithStatus};
+use warp::{Buf, Filter, Rejection, Reply};
 
 pub enum EitherBody<A, B> {
     Left(A),
@@ -82,11 +86,344 @@ fn with_data_server<T: AsLogicalPlan + Clone, U: 'static + AsExecutionPlan>(
     warp::any().map(move || db.clone())
 }
 
+/// A request rejected with a status, see [handle_rejection]
+#[derive(Debug)]
+struct ApiRejection {
+    status: StatusCode,
+    message: String,
+}
+
+impl warp::reject::Reject for ApiRejection {}
+
+/// The state of a standby scheduler is stale, its clients should ask the leader
+async fn leader_only<T: AsLogicalPlan + Clone, U: 'static + AsExecutionPlan>(
+    data_server: SchedulerServer<T, U>,
+) -> Result<SchedulerServer<T, U>, Rejection> {
+    match data_server.check_leader() {
+        Ok(()) => Ok(data_server),
+        Err(status) => Err(warp::reject::custom(ApiRejection {
+            status: StatusCode::SERVICE_UNAVAILABLE,
+            message: status.message().to_owned(),
+        })),
+    }
+}
+
+fn with_leader<T: AsLogicalPlan + Clone, U: 'static + AsExecutionPlan>(
+    db: SchedulerServer<T, U>,
+) -> impl Filter<Extract = (SchedulerServer<T, U>,), Error = Rejection> + Clone {
+    with_data_server(db).and_then(leader_only)
+}
+
+/// Only the admins change the cluster, they present the admin token as a bearer token or
+/// a client certificate verified by mTLS
+async fn admin_only<T: AsLogicalPlan + Clone, U: 'static + AsExecutionPlan>(
+    data_server: SchedulerServer<T, U>,
+    authorization: Option<String>,
+    client_authenticated: bool,
+) -> Result<SchedulerServer<T, U>, Rejection> {
+    if client_authenticated || data_server.is_admin(authorization.as_deref()) {
+        Ok(data_server)
+    } else {
+        Err(warp::reject::custom(ApiRejection {
+            status: StatusCode::UNAUTHORIZED,
+            message: "The admin token is required".to_owned(),
+        }))
+    }
+}
+
+fn with_admin<T: AsLogicalPlan + Clone, U: 'static + AsExecutionPlan>(
+    db: SchedulerServer<T, U>,
+    client_authenticated: bool,
+) -> impl Filter<Extract = (SchedulerServer<T, U>,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and(with_data_server(db))
+        .and_then(move |authorization, db| {
+            admin_only(db, authorization, client_authenticated)
+        })
+        .and_then(leader_only)
+}
+
+async fn handle_rejection(rejection: Rejection) -> Result<WithStatus<Json>, Rejection> {
+    match rejection.find::<ApiRejection>() {
+        Some(rejection) => Ok(warp::reply::with_status(
+            warp::reply::json(&rejection.message),
+            rejection.status,
+        )),
+        None => Err(rejection),
+    }
+}
+
+/// The routes of the REST API. The routes reading the state of the scheduler are only
+/// served by the leader, and the ones changing the cluster by the leader to the admins.
 pub fn get_routes<T: AsLogicalPlan + Clone, U: 'static + AsExecutionPlan>(
     scheduler_server: SchedulerServer<T, U>,
 ) -> BoxedFilter<(impl Reply,)> {
-    let routes = warp::path("state")
-        .and(with_data_server(scheduler_server))
+    routes(scheduler_server, false)
+}
+
+fn routes<T: AsLogicalPlan + Clone, U: 'static + AsExecutionPlan>(
+    scheduler_server: SchedulerServer<T, U>,
+    client_authenticated: bool,
+) -> BoxedFilter<(impl Reply,)> {
+    let route_state = warp::path("state")
+        .and(with_leader(scheduler_server.clone()))
         .and_then(handlers::scheduler_state);
-    routes.boxed()
+    let route_locks = warp::path("locks")
+        .and(with_data_server(scheduler_server.clone()))
+        .and_then(handlers::lock_holders);
+    let route_metrics = warp::path("metrics")
+        .and(with_data_server(scheduler_server.clone()))
+        .and_then(handlers::metrics);
+    let route_jobs = warp::path!("jobs")
+        .and(warp::get())
+        .and(with_leader(scheduler_server.clone()))
+        .and_then(handlers::list_jobs);
+    let route_job_profile = warp::path!("jobs" / String / "profile")
+        .and(warp::get())
+        .and(with_leader(scheduler_server.clone()))
+        .and_then(handlers::job_profile);
+    let route_history = warp::path!("history")
+        .and(warp::get())
+        .and(with_leader(scheduler_server.clone()))
+        .and_then(handlers::query_history);
+    let route_job_stages = warp::path!("jobs" / String / "stages")
+        .and(warp::get())
+        .and(with_leader(scheduler_server.clone()))
+        .and_then(handlers::job_stages);
+    let route_stage_tasks = warp::path!("jobs" / String / "stages" / u32 / "tasks")
+        .and(warp::get())
+        .and(with_leader(scheduler_server.clone()))
+        .and_then(handlers::stage_tasks);
+    let route_cancel_job = warp::path!("jobs" / String / "cancel")
+        .and(warp::post())
+        .and(with_admin(scheduler_server.clone(), client_authenticated))
+        .and_then(handlers::cancel_job);
+    let route_drain_executor = warp::path!("executors" / String / "drain")
+        .and(warp::post())
+        .and(with_admin(scheduler_server.clone(), client_authenticated))
+        .and_then(handlers::drain_executor);
+    let route_decommission_executor = warp::path!("executors" / String / "decommission")
+        .and(warp::post())
+        .and(with_admin(scheduler_server, client_authenticated))
+        .and_then(handlers::decommission_executor);
+    let routes = route_state
+        .or(route_locks)
+        .or(route_metrics)
+        .or(route_jobs)
+        .or(route_job_profile)
+        .or(route_history)
+        .or(route_job_stages)
+        .or(route_stage_tasks)
+        .or(route_cancel_job)
+        .or(route_drain_executor)
+        .or(route_decommission_executor);
+    routes.recover(handle_rejection).boxed()
+}
+
+/// Serve the REST API over TLS on its own port, it cannot share the port of the gRPC
+/// services once they use TLS
+pub async fn serve_tls<T: AsLogicalPlan + Clone, U: 'static + AsExecutionPlan>(
+    scheduler_server: SchedulerServer<T, U>,
+    host: IpAddr,
+    tls_config: RestTlsConfig,
+) {
+    // the clients are authenticated with mTLS when the client CA is set
+    let client_authenticated = !tls_config.client_ca_file.is_empty();
+    let server = warp::serve(routes(scheduler_server, client_authenticated))
+        .tls()
+        .cert_path(&tls_config.cert_file)
+        .key_path(&tls_config.key_file);
+    let server = if tls_config.client_ca_file.is_empty() {
+        server
+    } else {
+        server.client_auth_required_path(&tls_config.client_ca_file)
+    };
+    server.run((host, tls_config.port)).await
+}
+
+#[cfg(all(test, feature = "sled"))]
+mod test {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use datafusion::execution::context::default_session_builder;
+    use datafusion_proto::protobuf::LogicalPlanNode;
+    use hetu_core::config::TaskSchedulingPolicy;
+    use hetu_core::error::Result;
+    use hetu_core::serde::protobuf::{
+        job_status, CompletedJob, JobStatus, PhysicalPlanNode, QueuedJob,
+    };
+    use hetu_core::serde::BallistaCodec;
+    use warp::http::StatusCode;
+
+    use super::{get_routes, routes};
+    use crate::config::SchedulerConfig;
+    use crate::scheduler_server::SchedulerServer;
+    use crate::state::backend::standalone::StandaloneClient;
+
+    async fn test_scheduler() -> Result<SchedulerServer<LogicalPlanNode, PhysicalPlanNode>>
+    {
+        let mut scheduler: SchedulerServer<LogicalPlanNode, PhysicalPlanNode> =
+            SchedulerServer::new_with_config(
+                Arc::new(StandaloneClient::try_new_temporary()?),
+                "default".to_owned(),
+                TaskSchedulingPolicy::PullStaged,
+                BallistaCodec::default(),
+                default_session_builder,
+                SchedulerConfig::default().with_admin_token("secret"),
+            );
+        scheduler.init().await?;
+        scheduler
+            .state
+            .save_job_metadata(
+                "queued_job",
+                &JobStatus {
+                    status: Some(job_status::Status::Queued(QueuedJob {})),
+                },
+            )
+            .await?;
+        scheduler
+            .state
+            .save_job_metadata(
+                "completed_job",
+                &JobStatus {
+                    status: Some(job_status::Status::Completed(CompletedJob {
+                        partition_location: vec![],
+                    })),
+                },
+            )
+            .await?;
+        Ok(scheduler)
+    }
+
+    #[tokio::test]
+    async fn test_list_jobs() -> Result<()> {
+        let routes = get_routes(test_scheduler().await?);
+
+        let response = warp::test::request()
+            .method("GET")
+            .path("/jobs")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = String::from_utf8(response.body().to_vec()).unwrap();
+        assert!(body.contains(r#""job_id":"queued_job","status":"queued""#));
+        assert!(body.contains(r#""job_id":"completed_job","status":"completed""#));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cancel_job() -> Result<()> {
+        let scheduler = test_scheduler().await?;
+        let routes = get_routes(scheduler.clone());
+        let cancel = |job_id: &str| {
+            warp::test::request()
+                .method("POST")
+                .path(&format!("/jobs/{}/cancel", job_id))
+                .header("authorization", "Bearer secret")
+                .reply(&routes)
+        };
+
+        assert_eq!(cancel("queued_job").await.status(), StatusCode::OK);
+        assert_eq!(
+            cancel("completed_job").await.status(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(cancel("unknown_job").await.status(), StatusCode::NOT_FOUND);
+
+        // the job fails once the query stage scheduler handles the cancellation
+        let mut cancelled = false;
+        for _ in 0..100 {
+            if let Some(JobStatus {
+                status: Some(job_status::Status::Failed(failed)),
+            }) = scheduler.state.get_job_metadata("queued_job")
+            {
+                assert_eq!(failed.error, "Job queued_job cancelled");
+                cancelled = true;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(cancelled);
+        assert_eq!(cancel("queued_job").await.status(), StatusCode::NOT_FOUND);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_admin_token() -> Result<()> {
+        let scheduler = test_scheduler().await?;
+        let api = get_routes(scheduler.clone());
+        let drain = |authorization: Option<&str>| {
+            let mut request = warp::test::request()
+                .method("POST")
+                .path("/executors/abc/drain");
+            if let Some(authorization) = authorization {
+                request = request.header("authorization", authorization);
+            }
+            request.reply(&api)
+        };
+
+        for authorization in [None, Some("Bearer secreT"), Some("secret")] {
+            assert_eq!(
+                drain(authorization).await.status(),
+                StatusCode::UNAUTHORIZED
+            );
+        }
+        assert_eq!(
+            drain(Some("Bearer secret")).await.status(),
+            StatusCode::NOT_FOUND
+        );
+
+        // The clients verified by mTLS are admins
+        let response = warp::test::request()
+            .method("POST")
+            .path("/executors/abc/drain")
+            .reply(&routes(scheduler, true))
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_standby_scheduler() -> Result<()> {
+        // The leader election is not started, the scheduler stays a standby
+        let scheduler: SchedulerServer<LogicalPlanNode, PhysicalPlanNode> =
+            SchedulerServer::new_with_config(
+                Arc::new(StandaloneClient::try_new_temporary()?),
+                "default".to_owned(),
+                TaskSchedulingPolicy::PullStaged,
+                BallistaCodec::default(),
+                default_session_builder,
+                SchedulerConfig::default()
+                    .with_ha_enabled(true)
+                    .with_admin_token("secret"),
+            );
+        let routes = get_routes(scheduler);
+
+        let response = warp::test::request()
+            .method("GET")
+            .path("/jobs")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body = String::from_utf8(response.body().to_vec()).unwrap();
+        assert!(body.contains("is not the leader"), "{}", body);
+        let response = warp::test::request()
+            .method("POST")
+            .path("/jobs/queued_job/cancel")
+            .header("authorization", "Bearer secret")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        // The metrics of the process are served by every scheduler
+        let response = warp::test::request()
+            .method("GET")
+            .path("/metrics")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        Ok(())
+    }
 }
@@ -12,14 +12,22 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::collections::HashMap;
 use std::env;
+use std::fs;
 
 use clap::Parser;
 use serde::Deserialize;
 use serde::Serialize;
 use serfig::collectors::{from_env, from_file, from_self};
 use serfig::parsers::Toml;
+use tonic::transport::{Certificate, ClientTlsConfig, Identity, ServerTlsConfig};
 
+use hetu_core::object_store::{
+    object_store_settings, OBJECT_STORE_HDFS_NAMENODE, OBJECT_STORE_HDFS_USER,
+    OBJECT_STORE_S3_ACCESS_KEY_ID, OBJECT_STORE_S3_ENDPOINT, OBJECT_STORE_S3_REGION,
+    OBJECT_STORE_S3_SECRET_ACCESS_KEY,
+};
 use hetu_error::{HetuError, Result};
 
 #[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize, Parser)]
@@ -30,7 +38,7 @@ pub struct Config {
     #[clap(long, short = 'c', default_value_t)]
     pub config_file: String,
 
-    /// The configuration backend for the scheduler, see StateBackend::variants() for options. Default: Standalone
+    /// The configuration backend for the scheduler, one of Standalone, Etcd or Raft. Default: Standalone
     #[clap(long, default_value = "Standalone")]
     pub config_backend: String,
 
@@ -42,6 +50,30 @@ pub struct Config {
     #[clap(long, default_value = "localhost:2379")]
     pub etcd_urls: String,
 
+    /// Id of this node in the raft group when the config backend is `Raft`. Default: 1
+    #[clap(long, default_value = "1")]
+    pub raft_node_id: u64,
+
+    /// Raft nodes of the group as `id=host:port` separated by commas, empty for a single node. Default: ""
+    #[clap(long, default_value = "")]
+    pub raft_peers: String,
+
+    /// Port of the raft service of this node. Default: 50060
+    #[clap(long, default_value = "50060")]
+    pub raft_bind_port: u16,
+
+    /// Shared secret the raft nodes present to each other, required when there are raft_peers. Only read from the config file or the RAFT_AUTH_TOKEN environment variable. Default: ""
+    #[clap(skip)]
+    pub raft_auth_token: String,
+
+    /// PEM CA certificate verifying the raft nodes, the raft service is served over TLS with tls_cert_file and tls_key_file and the peers are reached over TLS when it is set. Default: ""
+    #[clap(long, default_value = "")]
+    pub raft_tls_ca_file: String,
+
+    /// Directory of the raft log and snapshots, required when the config backend is `Raft`. Default: ""
+    #[clap(long, default_value = "")]
+    pub raft_data_dir: String,
+
     /// Local host name or IP address to bind to. Default: 0.0.0.0
     #[clap(long, default_value = "0.0.0.0")]
     pub bind_host: String,
@@ -53,9 +85,219 @@ pub struct Config {
     /// The scheduing policy for the scheduler, see TaskSchedulingPolicy::variants() for options. Default: PullStaged
     #[clap(long, default_value = "PullStaged")]
     pub scheduler_policy: String,
+
+    /// Max number of jobs running at the same time in the cluster, 0 means unlimited. Default: 0
+    #[clap(long, default_value = "0")]
+    pub max_running_jobs: usize,
+
+    /// Max number of jobs running at the same time for one tenant, 0 means unlimited. Default: 0
+    #[clap(long, default_value = "0")]
+    pub max_running_jobs_per_tenant: usize,
+
+    /// Max number of jobs running at the same time for one user, the jobs of the sessions setting no ballista.job.user are not limited per user, 0 means unlimited. Default: 0
+    #[clap(long, default_value = "0")]
+    pub max_running_jobs_per_user: usize,
+
+    /// Seconds a job may wait in the admission queue before it fails, 0 means no timeout. Default: 0
+    #[clap(long, default_value = "0")]
+    pub job_queue_timeout_seconds: u64,
+
+    /// Run several schedulers on the same state backend, only the elected leader serves requests. Default: false
+    #[clap(long, parse(try_from_str = true_or_false), default_value_t)]
+    pub scheduler_ha_enabled: bool,
+
+    /// Unique id of this scheduler for the leader election, a random one is used when empty. Default: ""
+    #[clap(long, default_value = "")]
+    pub scheduler_id: String,
+
+    /// Host name or IP address the executors use to reach this scheduler. Default: localhost
+    #[clap(long, default_value = "localhost")]
+    pub external_host: String,
+
+    /// Seconds after which the leadership expires if the leader does not renew it. Default: 10
+    #[clap(long, default_value = "10")]
+    pub leader_lease_seconds: u64,
+
+    /// Seconds a finished job is kept before it is removed from the state backend, 0 means forever. Default: 3600
+    #[clap(long, default_value = "3600")]
+    pub finished_job_retention_seconds: u64,
+
+    /// Max number of the finished jobs whose status and profile are kept in the query history, 0 disables the history. Default: 100
+    #[clap(long, default_value = "100")]
+    pub query_history_size: usize,
+
+    /// Percentage of the memory in use above which an executor gets no new tasks, 0 disables the check. Default: 90
+    #[clap(long, default_value = "90")]
+    pub executor_max_memory_percent: u32,
+
+    /// Percentage of the work dir disk in use above which an executor gets no new tasks, 0 disables the check. Default: 95
+    #[clap(long, default_value = "95")]
+    pub executor_max_disk_percent: u32,
+
+    /// Number of tasks the KEDA external scaler targets for each executor, 0 uses the task slots of the registered executors. Default: 0
+    #[clap(long, default_value = "0")]
+    pub scaler_target_tasks_per_executor: u32,
+
+    /// Seconds the KEDA external scaler keeps the executors after the last task or queued job, before scaling them to zero. Default: 300
+    #[clap(long, default_value = "300")]
+    pub scaler_scale_to_zero_delay_seconds: u64,
+
+    /// Shared secret the executors must present to register, empty accepts any executor. Default: ""
+    #[clap(long, default_value = "")]
+    pub executor_auth_token: String,
+
+    /// Shared secret the clients of the REST API present as a bearer token to cancel jobs and to drain or decommission executors, these requests are rejected when it is empty unless the REST API is served with mTLS. Only read from the config file or the ADMIN_TOKEN environment variable. Default: ""
+    #[clap(skip)]
+    pub admin_token: String,
+
+    /// PEM certificate of the scheduler gRPC service, TLS is enabled when it is set with tls_key_file. Default: ""
+    #[clap(long, default_value = "")]
+    pub tls_cert_file: String,
+
+    /// PEM private key of the scheduler gRPC service. Default: ""
+    #[clap(long, default_value = "")]
+    pub tls_key_file: String,
+
+    /// PEM CA certificate verifying the client certificates, the clients must present one signed by it when it is set (mTLS). Default: ""
+    #[clap(long, default_value = "")]
+    pub tls_client_ca_file: String,
+
+    /// Port of the REST API when TLS is enabled, it is served over TLS with the same certificates since it cannot share the port of the gRPC services then. Default: 50054
+    #[clap(long, default_value = "50054")]
+    pub rest_api_tls_port: u16,
+
+    /// PEM CA certificate verifying the executors, their gRPC services are reached over TLS when it is set. Default: ""
+    #[clap(long, default_value = "")]
+    pub executor_tls_ca_file: String,
+
+    /// Endpoint of the S3 compatible service reading s3:// urls, like http://minio:9000, AWS S3 when empty. Default: ""
+    #[clap(long, default_value = "")]
+    pub s3_endpoint: String,
+
+    /// Region of the S3 buckets, us-east-1 when empty. Default: ""
+    #[clap(long, default_value = "")]
+    pub s3_region: String,
+
+    /// Access key id of the S3 requests. Default: ""
+    #[clap(long, default_value = "")]
+    pub s3_access_key_id: String,
+
+    /// Secret access key of the S3 requests. Only read from the config file or the S3_SECRET_ACCESS_KEY environment variable. Default: ""
+    #[clap(skip)]
+    pub s3_secret_access_key: String,
+
+    /// WebHDFS url of the namenode reading hdfs:// urls, like http://namenode:9870. Default: ""
+    #[clap(long, default_value = "")]
+    pub hdfs_namenode_url: String,
+
+    /// User of the WebHDFS requests. Default: ""
+    #[clap(long, default_value = "")]
+    pub hdfs_user: String,
+}
+
+fn true_or_false(s: &str) -> Result<bool> {
+    match s {
+        "true" => Ok(true),
+        _ => Ok(false),
+    }
+}
+
+/// The PEM files and the port the REST API is served over TLS with
+#[derive(Clone, Debug, PartialEq)]
+pub struct RestTlsConfig {
+    pub port: u16,
+    pub cert_file: String,
+    pub key_file: String,
+    /// The clients must present a certificate signed by it when it is not empty (mTLS)
+    pub client_ca_file: String,
 }
 
 impl Config {
+    /// The TLS config of the gRPC services, None if TLS is not enabled
+    pub fn server_tls_config(&self) -> Result<Option<ServerTlsConfig>> {
+        let identity = match self.tls_identity()? {
+            Some(identity) => identity,
+            None => return Ok(None),
+        };
+        let mut tls_config = ServerTlsConfig::new().identity(identity);
+        if !self.tls_client_ca_file.is_empty() {
+            let client_ca = fs::read(&self.tls_client_ca_file)?;
+            tls_config = tls_config.client_ca_root(Certificate::from_pem(client_ca));
+        }
+        Ok(Some(tls_config))
+    }
+
+    /// The REST API served over TLS on its own port, None if TLS is not enabled
+    pub fn rest_tls_config(&self) -> Option<RestTlsConfig> {
+        if self.tls_cert_file.is_empty() || self.tls_key_file.is_empty() {
+            return None;
+        }
+        Some(RestTlsConfig {
+            port: self.rest_api_tls_port,
+            cert_file: self.tls_cert_file.clone(),
+            key_file: self.tls_key_file.clone(),
+            client_ca_file: self.tls_client_ca_file.clone(),
+        })
+    }
+
+    /// The TLS config of the connections to the executors, None if they are reached over
+    /// plain http
+    pub fn executor_tls_config(&self) -> Result<Option<ClientTlsConfig>> {
+        self.client_tls_config(&self.executor_tls_ca_file)
+    }
+
+    /// The TLS config of the connections to the other raft nodes, None if they are reached
+    /// over plain http
+    pub fn raft_client_tls_config(&self) -> Result<Option<ClientTlsConfig>> {
+        self.client_tls_config(&self.raft_tls_ca_file)
+    }
+
+    fn client_tls_config(&self, ca_file: &str) -> Result<Option<ClientTlsConfig>> {
+        if ca_file.is_empty() {
+            return Ok(None);
+        }
+        let ca = fs::read(ca_file)?;
+        let mut tls_config =
+            ClientTlsConfig::new().ca_certificate(Certificate::from_pem(ca));
+        if let Some(identity) = self.tls_identity()? {
+            tls_config = tls_config.identity(identity);
+        }
+        Ok(Some(tls_config))
+    }
+
+    fn tls_identity(&self) -> Result<Option<Identity>> {
+        if self.tls_cert_file.is_empty() || self.tls_key_file.is_empty() {
+            return Ok(None);
+        }
+        let cert = fs::read(&self.tls_cert_file)?;
+        let key = fs::read(&self.tls_key_file)?;
+        Ok(Some(Identity::from_pem(cert, key)))
+    }
+
+    /// The object store settings of the cluster, the sessions may override them
+    pub fn object_store_settings(&self) -> HashMap<String, String> {
+        object_store_settings(&HashMap::from([
+            (
+                OBJECT_STORE_S3_ENDPOINT.to_owned(),
+                self.s3_endpoint.clone(),
+            ),
+            (OBJECT_STORE_S3_REGION.to_owned(), self.s3_region.clone()),
+            (
+                OBJECT_STORE_S3_ACCESS_KEY_ID.to_owned(),
+                self.s3_access_key_id.clone(),
+            ),
+            (
+                OBJECT_STORE_S3_SECRET_ACCESS_KEY.to_owned(),
+                self.s3_secret_access_key.clone(),
+            ),
+            (
+                OBJECT_STORE_HDFS_NAMENODE.to_owned(),
+                self.hdfs_namenode_url.clone(),
+            ),
+            (OBJECT_STORE_HDFS_USER.to_owned(), self.hdfs_user.clone()),
+        ]))
+    }
+
     pub fn load() -> Result<Self> {
         let args: Self = Config::parse();
 
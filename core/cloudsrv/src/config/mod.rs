@@ -13,5 +13,7 @@
 // limitations under the License.
 
 mod config;
+mod scheduler_config;
 
-pub use config::Config;
+pub use config::{Config, RestTlsConfig};
+pub use scheduler_config::SchedulerConfig;
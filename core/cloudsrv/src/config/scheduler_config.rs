@@ -0,0 +1,214 @@
+// Copyright 2021 HetuDB.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashMap;
+
+use uuid::Uuid;
+
+use crate::config::Config;
+
+/// Options of the scheduler server which are not part of the state backend
+#[derive(Clone, Debug)]
+pub struct SchedulerConfig {
+    /// Max number of jobs running at the same time, 0 means unlimited
+    pub max_running_jobs: usize,
+    /// Max number of jobs running at the same time for one tenant, 0 means unlimited
+    pub max_running_jobs_per_tenant: usize,
+    /// Max number of jobs running at the same time for one user, 0 means unlimited
+    pub max_running_jobs_per_user: usize,
+    /// Seconds a job may wait in the admission queue, 0 means no timeout
+    pub job_queue_timeout_seconds: u64,
+    /// Elect a leader among the schedulers sharing the state backend
+    pub ha_enabled: bool,
+    /// Unique id of this scheduler in the leader election
+    pub scheduler_id: String,
+    /// Address the executors use to reach this scheduler
+    pub scheduler_address: String,
+    /// Seconds after which the leadership expires if it is not renewed
+    pub leader_lease_seconds: u64,
+    /// Seconds a finished job is kept in the state backend, 0 means forever
+    pub finished_job_retention_seconds: u64,
+    /// Max number of the finished jobs kept in the query history, 0 disables the history
+    pub query_history_size: usize,
+    /// Percentage of the memory in use above which an executor gets no new tasks, 0
+    /// disables the check
+    pub executor_max_memory_percent: u32,
+    /// Percentage of the work dir disk in use above which an executor gets no new tasks,
+    /// 0 disables the check
+    pub executor_max_disk_percent: u32,
+    /// Tasks the external scaler targets for each executor, 0 uses the task slots of the
+    /// executors
+    pub scaler_target_tasks_per_executor: u32,
+    /// Seconds the external scaler stays active once there is no work left
+    pub scaler_scale_to_zero_delay_seconds: u64,
+    /// Shared secret the executors present to register, empty accepts any executor
+    pub executor_auth_token: String,
+    /// Shared secret the admins present to change the cluster through the REST API, empty
+    /// rejects these requests
+    pub admin_token: String,
+    /// The `ballista.object_store.*` settings of the cluster, the sessions may override
+    /// them
+    pub object_store_settings: HashMap<String, String>,
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        Self {
+            max_running_jobs: 0,
+            max_running_jobs_per_tenant: 0,
+            max_running_jobs_per_user: 0,
+            job_queue_timeout_seconds: 0,
+            ha_enabled: false,
+            scheduler_id: Uuid::new_v4().to_string(),
+            scheduler_address: "localhost:50050".to_owned(),
+            leader_lease_seconds: 10,
+            finished_job_retention_seconds: 3600,
+            query_history_size: 100,
+            executor_max_memory_percent: 90,
+            executor_max_disk_percent: 95,
+            scaler_target_tasks_per_executor: 0,
+            scaler_scale_to_zero_delay_seconds: 300,
+            executor_auth_token: "".to_owned(),
+            admin_token: "".to_owned(),
+            object_store_settings: HashMap::new(),
+        }
+    }
+}
+
+impl SchedulerConfig {
+    pub fn with_max_running_jobs(mut self, max_running_jobs: usize) -> Self {
+        self.max_running_jobs = max_running_jobs;
+        self
+    }
+
+    pub fn with_max_running_jobs_per_tenant(
+        mut self,
+        max_running_jobs_per_tenant: usize,
+    ) -> Self {
+        self.max_running_jobs_per_tenant = max_running_jobs_per_tenant;
+        self
+    }
+
+    pub fn with_max_running_jobs_per_user(
+        mut self,
+        max_running_jobs_per_user: usize,
+    ) -> Self {
+        self.max_running_jobs_per_user = max_running_jobs_per_user;
+        self
+    }
+
+    pub fn with_job_queue_timeout_seconds(mut self, timeout_seconds: u64) -> Self {
+        self.job_queue_timeout_seconds = timeout_seconds;
+        self
+    }
+
+    pub fn with_ha_enabled(mut self, ha_enabled: bool) -> Self {
+        self.ha_enabled = ha_enabled;
+        self
+    }
+
+    pub fn with_scheduler_id(mut self, scheduler_id: impl Into<String>) -> Self {
+        self.scheduler_id = scheduler_id.into();
+        self
+    }
+
+    pub fn with_scheduler_address(
+        mut self,
+        scheduler_address: impl Into<String>,
+    ) -> Self {
+        self.scheduler_address = scheduler_address.into();
+        self
+    }
+
+    pub fn with_leader_lease_seconds(mut self, lease_seconds: u64) -> Self {
+        self.leader_lease_seconds = lease_seconds;
+        self
+    }
+
+    pub fn with_finished_job_retention_seconds(mut self, retention_seconds: u64) -> Self {
+        self.finished_job_retention_seconds = retention_seconds;
+        self
+    }
+
+    pub fn with_query_history_size(mut self, query_history_size: usize) -> Self {
+        self.query_history_size = query_history_size;
+        self
+    }
+
+    pub fn with_executor_max_memory_percent(mut self, max_memory_percent: u32) -> Self {
+        self.executor_max_memory_percent = max_memory_percent;
+        self
+    }
+
+    pub fn with_executor_max_disk_percent(mut self, max_disk_percent: u32) -> Self {
+        self.executor_max_disk_percent = max_disk_percent;
+        self
+    }
+
+    pub fn with_scaler_target_tasks_per_executor(mut self, target_tasks: u32) -> Self {
+        self.scaler_target_tasks_per_executor = target_tasks;
+        self
+    }
+
+    pub fn with_scaler_scale_to_zero_delay_seconds(mut self, delay_seconds: u64) -> Self {
+        self.scaler_scale_to_zero_delay_seconds = delay_seconds;
+        self
+    }
+
+    pub fn with_executor_auth_token(mut self, auth_token: impl Into<String>) -> Self {
+        self.executor_auth_token = auth_token.into();
+        self
+    }
+
+    pub fn with_admin_token(mut self, admin_token: impl Into<String>) -> Self {
+        self.admin_token = admin_token.into();
+        self
+    }
+
+    pub fn with_object_store_settings(
+        mut self,
+        object_store_settings: HashMap<String, String>,
+    ) -> Self {
+        self.object_store_settings = object_store_settings;
+        self
+    }
+}
+
+impl From<&Config> for SchedulerConfig {
+    fn from(conf: &Config) -> Self {
+        let mut scheduler_config = Self::default()
+            .with_max_running_jobs(conf.max_running_jobs)
+            .with_max_running_jobs_per_tenant(conf.max_running_jobs_per_tenant)
+            .with_max_running_jobs_per_user(conf.max_running_jobs_per_user)
+            .with_job_queue_timeout_seconds(conf.job_queue_timeout_seconds)
+            .with_ha_enabled(conf.scheduler_ha_enabled)
+            .with_scheduler_address(format!("{}:{}", conf.external_host, conf.bind_port))
+            .with_leader_lease_seconds(conf.leader_lease_seconds)
+            .with_finished_job_retention_seconds(conf.finished_job_retention_seconds)
+            .with_query_history_size(conf.query_history_size)
+            .with_executor_max_memory_percent(conf.executor_max_memory_percent)
+            .with_executor_max_disk_percent(conf.executor_max_disk_percent)
+            .with_scaler_target_tasks_per_executor(conf.scaler_target_tasks_per_executor)
+            .with_scaler_scale_to_zero_delay_seconds(
+                conf.scaler_scale_to_zero_delay_seconds,
+            )
+            .with_executor_auth_token(&conf.executor_auth_token)
+            .with_admin_token(&conf.admin_token)
+            .with_object_store_settings(conf.object_store_settings());
+        if !conf.scheduler_id.is_empty() {
+            scheduler_config = scheduler_config.with_scheduler_id(&conf.scheduler_id);
+        }
+        scheduler_config
+    }
+}
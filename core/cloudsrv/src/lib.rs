@@ -21,6 +21,7 @@ pub const CLOUD_SERVICE_VERSION: &str = env!("CARGO_PKG_VERSION");
 
 pub mod config;
 pub mod meta;
+pub mod metrics;
 pub mod scheduler;
 
 pub mod api;
@@ -21,13 +21,15 @@ use hyper::{server::conn::AddrStream, service::make_service_fn, Server};
 use std::convert::Infallible;
 use std::{net::SocketAddr, sync::Arc};
 use tonic::transport::server::Connected;
-use tonic::transport::Server as TonicServer;
+use tonic::transport::{Server as TonicServer, ServerTlsConfig};
 use tower::Service;
 
 use datafusion_proto::protobuf::LogicalPlanNode;
-use hetu_cloudsrv::api::{get_routes, EitherBody, Error};
+use hetu_cloudsrv::api::{get_routes, serve_tls, EitherBody, Error};
 #[cfg(feature = "etcd")]
 use hetu_cloudsrv::state::backend::etcd::EtcdClient;
+#[cfg(feature = "raft")]
+use hetu_cloudsrv::state::backend::raft::{RaftClient, RaftConfig};
 #[cfg(feature = "sled")]
 use hetu_cloudsrv::state::backend::standalone::StandaloneClient;
 use hetu_core::{
@@ -38,12 +40,13 @@ use hetu_core::{
 use hetu_cloudsrv::scheduler_server::SchedulerServer;
 use hetu_cloudsrv::state::backend::{StateBackend, StateBackendClient};
 
+use hetu_core::client::set_executor_tls_config;
 use hetu_core::config::TaskSchedulingPolicy;
 use hetu_core::serde::BallistaCodec;
 use log::info;
 
 use datafusion::execution::context::default_session_builder;
-use hetu_cloudsrv::config::Config;
+use hetu_cloudsrv::config::{Config, RestTlsConfig, SchedulerConfig};
 use hetu_cloudsrv::CLOUD_SERVICE_VERSION;
 
 async fn start_server(
@@ -51,6 +54,9 @@ async fn start_server(
     namespace: String,
     addr: SocketAddr,
     policy: TaskSchedulingPolicy,
+    scheduler_config: SchedulerConfig,
+    tls_config: Option<ServerTlsConfig>,
+    rest_tls_config: Option<RestTlsConfig>,
 ) -> Result<()> {
     info!(
         "Hetu Cloud Service v{} Scheduler listening on {:?}",
@@ -62,23 +68,40 @@ async fn start_server(
         policy
     );
     let mut scheduler_server: SchedulerServer<LogicalPlanNode, PhysicalPlanNode> =
-        match policy {
-            TaskSchedulingPolicy::PushStaged => SchedulerServer::new_with_policy(
-                config_backend.clone(),
-                namespace.clone(),
-                policy,
-                BallistaCodec::default(),
-                default_session_builder,
-            ),
-            _ => SchedulerServer::new(
-                config_backend.clone(),
-                namespace.clone(),
-                BallistaCodec::default(),
-            ),
-        };
+        SchedulerServer::new_with_config(
+            config_backend.clone(),
+            namespace.clone(),
+            policy,
+            BallistaCodec::default(),
+            default_session_builder,
+            scheduler_config,
+        );
 
     scheduler_server.init().await?;
 
+    if let Some(tls_config) = tls_config {
+        // The REST API only shares the port with the gRPC services without TLS
+        if let Some(rest_tls_config) = rest_tls_config {
+            info!(
+                "Serving the gRPC services with TLS, the REST API listens on port {}",
+                rest_tls_config.port
+            );
+            tokio::spawn(serve_tls(
+                scheduler_server.clone(),
+                addr.ip(),
+                rest_tls_config,
+            ));
+        }
+        return TonicServer::builder()
+            .tls_config(tls_config)
+            .context("Invalid TLS config")?
+            .add_service(SchedulerGrpcServer::new(scheduler_server.clone()))
+            .add_service(ExternalScalerServer::new(scheduler_server))
+            .serve(addr)
+            .await
+            .context("Could not start grpc server");
+    }
+
     Server::bind(&addr)
         .serve(make_service_fn(move |request: &AddrStream| {
             let scheduler_grpc_server =
@@ -126,14 +149,25 @@ async fn main() -> Result<()> {
     env_logger::init();
 
     let conf: Config = Config::load()?;
+    let scheduler_config = SchedulerConfig::from(&conf);
+    let tls_config = conf
+        .server_tls_config()
+        .context("Could not load the TLS certificates")?;
+    let rest_tls_config = conf.rest_tls_config();
+    if let Some(executor_tls_config) = conf
+        .executor_tls_config()
+        .context("Could not load the executor TLS certificates")?
+    {
+        set_executor_tls_config(executor_tls_config)?;
+    }
 
     if false {
         print_version();
         std::process::exit(0);
     }
 
-    let namespace = conf.namespace;
-    let bind_host = conf.bind_host;
+    let namespace = conf.namespace.clone();
+    let bind_host = conf.bind_host.clone();
     let port = conf.bind_port;
 
     let addr = format!("{}:{}", bind_host, port);
@@ -141,13 +175,14 @@ async fn main() -> Result<()> {
 
     let config_backend: StateBackend = match conf.config_backend.as_str() {
         "Etcd" => StateBackend::Etcd,
+        "Raft" => StateBackend::Raft,
         _ => StateBackend::Standalone,
     };
 
     let client: Arc<dyn StateBackendClient> = match config_backend {
-        #[cfg(not(any(feature = "sled", feature = "etcd")))]
+        #[cfg(not(any(feature = "sled", feature = "etcd", feature = "raft")))]
         _ => std::compile_error!(
-            "To build the scheduler enable at least one config backend feature (`etcd` or `sled`)"
+            "To build the scheduler enable at least one config backend feature (`etcd`, `sled` or `raft`)"
         ),
         #[cfg(feature = "etcd")]
         StateBackend::Etcd => {
@@ -176,6 +211,24 @@ async fn main() -> Result<()> {
                 "build the scheduler with the `sled` feature to use the standalone config backend"
             )
         }
+        #[cfg(feature = "raft")]
+        StateBackend::Raft => {
+            let raft_config = RaftConfig::try_from(&conf)
+                .context("Invalid raft config backend configuration")?;
+            let raft_addr = format!("{}:{}", bind_host, conf.raft_bind_port).parse()?;
+            // The raft service runs on its own port, since the scheduler only starts to
+            // serve after its state is loaded from the backend
+            Arc::new(
+                RaftClient::serve(raft_config, raft_addr)
+                    .context("Could not start raft config backend")?,
+            )
+        }
+        #[cfg(not(feature = "raft"))]
+        StateBackend::Raft => {
+            unimplemented!(
+                "build the scheduler with the `raft` feature to use the raft config backend"
+            )
+        }
     };
 
     let policy: TaskSchedulingPolicy = match conf.scheduler_policy.as_str() {
@@ -183,6 +236,15 @@ async fn main() -> Result<()> {
         _ => hetu_core::config::TaskSchedulingPolicy::PullStaged,
     };
 
-    start_server(client, namespace, addr, policy).await?;
+    start_server(
+        client,
+        namespace,
+        addr,
+        policy,
+        scheduler_config,
+        tls_config,
+        rest_tls_config,
+    )
+    .await?;
     Ok(())
 }
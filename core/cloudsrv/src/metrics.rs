@@ -0,0 +1,230 @@
+// Copyright 2021 HetuDB.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Prometheus metrics of the scheduler, served in the text format on `/metrics`.
+
+use std::time::Duration;
+
+use datafusion_proto::logical_plan::AsLogicalPlan;
+use hetu_core::serde::AsExecutionPlan;
+use log::error;
+use once_cell::sync::Lazy;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, Opts, Registry, TextEncoder,
+};
+
+use crate::state::SchedulerState;
+
+/// Executors which have not sent a heartbeat for longer are not counted
+pub(crate) const EXECUTOR_ALIVE_THRESHOLD: Duration = Duration::from_secs(60);
+
+static METRICS: Lazy<SchedulerMetrics> = Lazy::new(SchedulerMetrics::new);
+
+struct SchedulerMetrics {
+    registry: Registry,
+    jobs: IntCounterVec,
+    job_duration: HistogramVec,
+    stages: IntCounter,
+    stage_duration: Histogram,
+    tasks: IntCounterVec,
+    task_duration: Histogram,
+    shuffle_written_bytes: IntCounter,
+    queued_jobs: IntGauge,
+    running_jobs: IntGauge,
+    alive_executors: IntGauge,
+    executor_task_slots: IntGaugeVec,
+}
+
+fn duration_buckets() -> Vec<f64> {
+    prometheus::exponential_buckets(0.01, 2.0, 20).unwrap()
+}
+
+impl SchedulerMetrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("hetu_scheduler".to_owned()), None)
+            .expect("Invalid metrics prefix");
+        let jobs = IntCounterVec::new(
+            Opts::new("jobs_total", "Number of the submitted and finished jobs"),
+            &["status"],
+        )
+        .unwrap();
+        let job_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "job_duration_seconds",
+                "Time from submitting to finishing the jobs",
+            )
+            .buckets(duration_buckets()),
+            &["status"],
+        )
+        .unwrap();
+        let stages =
+            IntCounter::new("stages_completed_total", "Number of the completed stages")
+                .unwrap();
+        let stage_duration = Histogram::with_opts(
+            HistogramOpts::new(
+                "stage_duration_seconds",
+                "Time from scheduling to completing the stages",
+            )
+            .buckets(duration_buckets()),
+        )
+        .unwrap();
+        let tasks = IntCounterVec::new(
+            Opts::new("tasks_total", "Number of the finished tasks"),
+            &["status"],
+        )
+        .unwrap();
+        let task_duration = Histogram::with_opts(
+            HistogramOpts::new(
+                "task_duration_seconds",
+                "Time from assigning to finishing the tasks",
+            )
+            .buckets(duration_buckets()),
+        )
+        .unwrap();
+        let shuffle_written_bytes = IntCounter::new(
+            "shuffle_written_bytes_total",
+            "Number of the shuffle bytes written by the completed tasks",
+        )
+        .unwrap();
+        let queued_jobs =
+            IntGauge::new("queued_jobs", "Number of the jobs waiting for admission")
+                .unwrap();
+        let running_jobs =
+            IntGauge::new("running_jobs", "Number of the admitted jobs").unwrap();
+        let alive_executors =
+            IntGauge::new("alive_executors", "Number of the alive executors").unwrap();
+        let executor_task_slots = IntGaugeVec::new(
+            Opts::new("executor_task_slots", "Task slots of the alive executors"),
+            &["state"],
+        )
+        .unwrap();
+
+        registry.register(Box::new(jobs.clone())).unwrap();
+        registry.register(Box::new(job_duration.clone())).unwrap();
+        registry.register(Box::new(stages.clone())).unwrap();
+        registry.register(Box::new(stage_duration.clone())).unwrap();
+        registry.register(Box::new(tasks.clone())).unwrap();
+        registry.register(Box::new(task_duration.clone())).unwrap();
+        registry
+            .register(Box::new(shuffle_written_bytes.clone()))
+            .unwrap();
+        registry.register(Box::new(queued_jobs.clone())).unwrap();
+        registry.register(Box::new(running_jobs.clone())).unwrap();
+        registry
+            .register(Box::new(alive_executors.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(executor_task_slots.clone()))
+            .unwrap();
+
+        Self {
+            registry,
+            jobs,
+            job_duration,
+            stages,
+            stage_duration,
+            tasks,
+            task_duration,
+            shuffle_written_bytes,
+            queued_jobs,
+            running_jobs,
+            alive_executors,
+            executor_task_slots,
+        }
+    }
+}
+
+pub(crate) fn record_job_submitted() {
+    METRICS.jobs.with_label_values(&["submitted"]).inc();
+}
+
+/// Status is either completed or failed, the duration is unknown for the jobs submitted to
+/// another scheduler
+pub(crate) fn record_job_finished(status: &str, duration: Option<Duration>) {
+    METRICS.jobs.with_label_values(&[status]).inc();
+    if let Some(duration) = duration {
+        METRICS
+            .job_duration
+            .with_label_values(&[status])
+            .observe(duration.as_secs_f64());
+    }
+}
+
+pub(crate) fn record_stage_completed(duration: Duration) {
+    METRICS.stages.inc();
+    METRICS.stage_duration.observe(duration.as_secs_f64());
+}
+
+pub(crate) fn record_task_finished(
+    succeeded: bool,
+    duration: Duration,
+    shuffle_written_bytes: u64,
+) {
+    let status = if succeeded { "completed" } else { "failed" };
+    METRICS.tasks.with_label_values(&[status]).inc();
+    METRICS.task_duration.observe(duration.as_secs_f64());
+    METRICS.shuffle_written_bytes.inc_by(shuffle_written_bytes);
+}
+
+/// Refresh the gauges from the scheduler state and encode all the metrics in the
+/// Prometheus text format
+pub(crate) async fn gather<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan>(
+    state: &SchedulerState<T, U>,
+) -> Vec<u8> {
+    METRICS
+        .queued_jobs
+        .set(state.job_queue.queued_jobs() as i64);
+    METRICS
+        .running_jobs
+        .set(state.job_queue.running_jobs() as i64);
+    match state.get_executors_metadata().await {
+        Ok(executors) => {
+            let mut alive_executors = 0;
+            let mut total_slots = 0;
+            let mut used_slots = 0;
+            for (metadata, last_seen) in executors {
+                if last_seen > EXECUTOR_ALIVE_THRESHOLD {
+                    continue;
+                }
+                alive_executors += 1;
+                let slots = metadata.specification.task_slots as i64;
+                total_slots += slots;
+                used_slots += std::cmp::min(
+                    state
+                        .stage_manager
+                        .get_running_tasks_of_executor(&metadata.id)
+                        as i64,
+                    slots,
+                );
+            }
+            METRICS.alive_executors.set(alive_executors);
+            METRICS
+                .executor_task_slots
+                .with_label_values(&["used"])
+                .set(used_slots);
+            METRICS
+                .executor_task_slots
+                .with_label_values(&["free"])
+                .set(total_slots - used_slots);
+        }
+        Err(e) => error!("Fail to get the executors for the metrics: {}", e),
+    }
+
+    let mut buffer = vec![];
+    if let Err(e) = TextEncoder::new().encode(&METRICS.registry.gather(), &mut buffer) {
+        error!("Fail to encode the metrics: {}", e);
+    }
+    buffer
+}
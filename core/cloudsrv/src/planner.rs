@@ -254,6 +254,41 @@ pub fn remove_unresolved_shuffles(
     Ok(with_new_children_if_necessary(stage, new_children)?)
 }
 
+/// Point the shuffle readers of an input stage to the new locations of its partitions,
+/// once the stage ran again to recover the partitions lost with an executor
+pub fn update_shuffle_readers(
+    stage: Arc<dyn ExecutionPlan>,
+    input_stage_id: usize,
+    partition_locations: &HashMap<usize, Vec<PartitionLocation>>,
+) -> Result<Arc<dyn ExecutionPlan>> {
+    let mut new_children: Vec<Arc<dyn ExecutionPlan>> = vec![];
+    for child in stage.children() {
+        match child.as_any().downcast_ref::<ShuffleReaderExec>() {
+            Some(reader)
+                if reader
+                    .partition_locations()
+                    .iter()
+                    .flatten()
+                    .any(|l| l.partition_id.stage_id == input_stage_id) =>
+            {
+                let locations = (0..reader.partition_locations().len())
+                    .map(|i| partition_locations.get(&i).cloned().unwrap_or_default())
+                    .collect();
+                new_children.push(Arc::new(ShuffleReaderExec::try_new(
+                    locations,
+                    reader.schema(),
+                )?))
+            }
+            _ => new_children.push(update_shuffle_readers(
+                child,
+                input_stage_id,
+                partition_locations,
+            )?),
+        }
+    }
+    Ok(with_new_children_if_necessary(stage, new_children)?)
+}
+
 fn create_shuffle_writer(
     job_id: &str,
     stage_id: usize,
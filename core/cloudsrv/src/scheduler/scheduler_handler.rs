@@ -21,12 +21,14 @@ use hyper::{server::conn::AddrStream, service::make_service_fn, Server};
 use std::convert::Infallible;
 use std::{net::SocketAddr, sync::Arc};
 use tonic::transport::server::Connected;
-use tonic::transport::Server as TonicServer;
+use tonic::transport::{Server as TonicServer, ServerTlsConfig};
 use tower::Service;
 
-use crate::api::{get_routes, EitherBody, Error};
+use crate::api::{get_routes, serve_tls, EitherBody, Error};
 #[cfg(feature = "etcd")]
 use crate::state::backend::etcd::EtcdClient;
+#[cfg(feature = "raft")]
+use crate::state::backend::raft::{RaftClient, RaftConfig};
 #[cfg(feature = "sled")]
 use crate::state::backend::standalone::StandaloneClient;
 use datafusion_proto::protobuf::LogicalPlanNode;
@@ -38,13 +40,14 @@ use hetu_core::{
 use crate::scheduler_server::SchedulerServer;
 use crate::state::backend::{StateBackend, StateBackendClient};
 
+use hetu_core::client::set_executor_tls_config;
 use hetu_core::config::TaskSchedulingPolicy;
 use hetu_core::serde::BallistaCodec;
 use log::info;
 
 use datafusion::execution::context::default_session_builder;
 
-use crate::config::Config;
+use crate::config::{Config, RestTlsConfig, SchedulerConfig};
 use crate::CLOUD_SERVICE_VERSION;
 
 pub struct SchedulerHandler {
@@ -58,6 +61,17 @@ impl SchedulerHandler {
 
     pub async fn start(&mut self) -> Result<SocketAddr> {
         let conf = self.conf.clone();
+        let scheduler_config = SchedulerConfig::from(&conf);
+        let tls_config = conf
+            .server_tls_config()
+            .context("Could not load the TLS certificates")?;
+        let rest_tls_config = conf.rest_tls_config();
+        if let Some(executor_tls_config) = conf
+            .executor_tls_config()
+            .context("Could not load the executor TLS certificates")?
+        {
+            set_executor_tls_config(executor_tls_config)?;
+        }
         // start scheduler
         env_logger::init();
 
@@ -66,8 +80,8 @@ impl SchedulerHandler {
             std::process::exit(0);
         }
 
-        let namespace = conf.namespace;
-        let bind_host = conf.bind_host;
+        let namespace = conf.namespace.clone();
+        let bind_host = conf.bind_host.clone();
         let port = conf.bind_port;
 
         let addr = format!("{}:{}", bind_host, port);
@@ -75,13 +89,14 @@ impl SchedulerHandler {
 
         let config_backend: StateBackend = match conf.config_backend.as_str() {
             "Etcd" => StateBackend::Etcd,
+            "Raft" => StateBackend::Raft,
             _ => StateBackend::Standalone,
         };
 
         let client: Arc<dyn StateBackendClient> = match config_backend {
-            #[cfg(not(any(feature = "sled", feature = "etcd")))]
+            #[cfg(not(any(feature = "sled", feature = "etcd", feature = "raft")))]
             _ => std::compile_error!(
-            "To build the scheduler enable at least one config backend feature (`etcd` or `sled`)"
+            "To build the scheduler enable at least one config backend feature (`etcd`, `sled` or `raft`)"
         ),
             #[cfg(feature = "etcd")]
             StateBackend::Etcd => {
@@ -110,6 +125,24 @@ impl SchedulerHandler {
                     "build the scheduler with the `sled` feature to use the standalone config backend"
                 )
             }
+            #[cfg(feature = "raft")]
+            StateBackend::Raft => {
+                let raft_config = RaftConfig::try_from(&conf)
+                    .context("Invalid raft config backend configuration")?;
+                let raft_addr = format!("{}:{}", bind_host, conf.raft_bind_port).parse()?;
+                // The raft service runs on its own port, since the scheduler only starts to
+                // serve after its state is loaded from the backend
+                Arc::new(
+                    RaftClient::serve(raft_config, raft_addr)
+                        .context("Could not start raft config backend")?,
+                )
+            }
+            #[cfg(not(feature = "raft"))]
+            StateBackend::Raft => {
+                unimplemented!(
+                    "build the scheduler with the `raft` feature to use the raft config backend"
+                )
+            }
         };
 
         let policy: TaskSchedulingPolicy = match conf.scheduler_policy.as_str() {
@@ -117,7 +150,16 @@ impl SchedulerHandler {
             _ => hetu_core::config::TaskSchedulingPolicy::PullStaged,
         };
 
-        start_server(client, namespace, addr, policy).await?;
+        start_server(
+            client,
+            namespace,
+            addr,
+            policy,
+            scheduler_config,
+            tls_config,
+            rest_tls_config,
+        )
+        .await?;
         Ok(addr)
     }
 }
@@ -127,6 +169,9 @@ async fn start_server(
     namespace: String,
     addr: SocketAddr,
     policy: TaskSchedulingPolicy,
+    scheduler_config: SchedulerConfig,
+    tls_config: Option<ServerTlsConfig>,
+    rest_tls_config: Option<RestTlsConfig>,
 ) -> Result<()> {
     info!(
         "Hetu Cloud Service v{} Scheduler listening on {:?}",
@@ -138,23 +183,40 @@ async fn start_server(
         policy
     );
     let mut scheduler_server: SchedulerServer<LogicalPlanNode, PhysicalPlanNode> =
-        match policy {
-            TaskSchedulingPolicy::PushStaged => SchedulerServer::new_with_policy(
-                config_backend.clone(),
-                namespace.clone(),
-                policy,
-                BallistaCodec::default(),
-                default_session_builder,
-            ),
-            _ => SchedulerServer::new(
-                config_backend.clone(),
-                namespace.clone(),
-                BallistaCodec::default(),
-            ),
-        };
+        SchedulerServer::new_with_config(
+            config_backend.clone(),
+            namespace.clone(),
+            policy,
+            BallistaCodec::default(),
+            default_session_builder,
+            scheduler_config,
+        );
 
     scheduler_server.init().await?;
 
+    if let Some(tls_config) = tls_config {
+        // The REST API only shares the port with the gRPC services without TLS
+        if let Some(rest_tls_config) = rest_tls_config {
+            info!(
+                "Serving the gRPC services with TLS, the REST API listens on port {}",
+                rest_tls_config.port
+            );
+            tokio::spawn(serve_tls(
+                scheduler_server.clone(),
+                addr.ip(),
+                rest_tls_config,
+            ));
+        }
+        return TonicServer::builder()
+            .tls_config(tls_config)
+            .context("Invalid TLS config")?
+            .add_service(SchedulerGrpcServer::new(scheduler_server.clone()))
+            .add_service(ExternalScalerServer::new(scheduler_server))
+            .serve_with_shutdown(addr, shutdown_signal())
+            .await
+            .context("Could not start grpc server");
+    }
+
     Server::bind(&addr)
         .serve(make_service_fn(move |request: &AddrStream| {
             let scheduler_grpc_server =
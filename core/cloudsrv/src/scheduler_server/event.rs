@@ -18,16 +18,30 @@
 use datafusion::physical_plan::ExecutionPlan;
 use std::sync::Arc;
 
+use crate::state::job_queue::JobOwner;
+
 #[derive(Clone)]
 pub(crate) enum SchedulerServerEvent {
     // number of offer rounds
     ReviveOffers(u32),
+    // stop the running tasks of a cancelled job on the executors
+    CancelTasks(String, Vec<String>),
 }
 
 #[derive(Clone)]
 pub enum QueryStageSchedulerEvent {
+    // job id, owner and plan of a job waiting for admission
+    JobQueued(String, JobOwner, Arc<dyn ExecutionPlan>),
+    // fail the jobs which waited too long for admission
+    ExpireQueuedJobs,
     JobSubmitted(String, Arc<dyn ExecutionPlan>),
+    // job id and owner of a running job whose saved stages run again after a failover
+    JobResumed(String, JobOwner),
     StageFinished(String, u32),
+    // job id and id of a completed stage running again to recover its lost shuffle output
+    StageResubmitted(String, u32),
     JobFinished(String),
     JobFailed(String, u32, String),
+    // stop scheduling the tasks of a job on behalf of the user
+    JobCancelled(String),
 }
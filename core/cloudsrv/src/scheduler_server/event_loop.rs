@@ -28,7 +28,7 @@ use crate::state::SchedulerState;
 use datafusion_proto::logical_plan::AsLogicalPlan;
 use hetu_core::error::{BallistaError, Result};
 use hetu_core::event_loop::EventAction;
-use hetu_core::serde::protobuf::{LaunchTaskParams, TaskDefinition};
+use hetu_core::serde::protobuf::{CancelTasksParams, LaunchTaskParams, TaskDefinition};
 use hetu_core::serde::scheduler::ExecutorDataChange;
 use hetu_core::serde::AsExecutionPlan;
 
@@ -143,6 +143,30 @@ impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan>
 
         Ok(())
     }
+
+    async fn cancel_tasks(&self, job_id: String, executors: Vec<String>) {
+        for executor_id in executors {
+            let client = self
+                .executors_client
+                .read()
+                .await
+                .get(&executor_id)
+                .cloned();
+            if let Some(mut client) = client {
+                if let Err(e) = client
+                    .cancel_tasks(CancelTasksParams {
+                        job_id: vec![job_id.clone()],
+                    })
+                    .await
+                {
+                    warn!(
+                        "Fail to cancel the tasks of job {} on executor {}: {}",
+                        job_id, executor_id, e
+                    );
+                }
+            }
+        }
+    }
 }
 
 #[async_trait]
@@ -161,6 +185,10 @@ impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan>
     ) -> Result<Option<SchedulerServerEvent>> {
         match event {
             SchedulerServerEvent::ReviveOffers(n) => self.offer_resources(n).await,
+            SchedulerServerEvent::CancelTasks(job_id, executors) => {
+                self.cancel_tasks(job_id, executors).await;
+                Ok(None)
+            }
         }
     }
 
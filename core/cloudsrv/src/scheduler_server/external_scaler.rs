@@ -15,6 +15,8 @@
 // specific language governing permissions and limitations
 // under the License.
 
+use crate::config::SchedulerConfig;
+use crate::metrics::EXECUTOR_ALIVE_THRESHOLD;
 use crate::scheduler_server::externalscaler::{
     external_scaler_server::ExternalScaler, GetMetricSpecResponse, GetMetricsRequest,
     GetMetricsResponse, IsActiveResponse, MetricSpec, MetricValue, ScaledObjectRef,
@@ -23,10 +25,90 @@ use crate::scheduler_server::SchedulerServer;
 use datafusion_proto::logical_plan::AsLogicalPlan;
 use hetu_core::serde::AsExecutionPlan;
 use log::debug;
+use parking_lot::Mutex;
+use std::time::{Duration, Instant};
 use tonic::{Request, Response};
 
 const INFLIGHT_TASKS_METRIC_NAME: &str = "inflight_tasks";
 
+/// Settings and activity of the KEDA external scaler
+pub(crate) struct ExternalScalerState {
+    // 0 uses the average task slots of the executors
+    target_tasks_per_executor: u32,
+    scale_to_zero_delay: Duration,
+    // When there was work for the executors for the last time
+    last_active_at: Mutex<Instant>,
+}
+
+impl ExternalScalerState {
+    pub(crate) fn new(config: &SchedulerConfig) -> Self {
+        Self {
+            target_tasks_per_executor: config.scaler_target_tasks_per_executor,
+            scale_to_zero_delay: Duration::from_secs(
+                config.scaler_scale_to_zero_delay_seconds,
+            ),
+            last_active_at: Mutex::new(Instant::now()),
+        }
+    }
+}
+
+/// The work of the cluster which the executors are scaled for
+#[derive(Debug, Default)]
+struct ClusterLoad {
+    pending_tasks: usize,
+    running_tasks: usize,
+    queued_jobs: usize,
+    draining_executors: usize,
+    // Alive executors which may get new tasks
+    executors: usize,
+    task_slots: usize,
+}
+
+impl ClusterLoad {
+    /// Tasks the executors are needed for, a queued job needs at least one
+    fn inflight_tasks(&self) -> usize {
+        self.pending_tasks + self.running_tasks + self.queued_jobs
+    }
+
+    /// Tasks for each executor, the average task slots of the executors if not configured
+    fn target_tasks_per_executor(&self, configured: u32) -> usize {
+        if configured > 0 {
+            configured as usize
+        } else if self.executors > 0 {
+            std::cmp::max(self.task_slots / self.executors, 1)
+        } else {
+            1
+        }
+    }
+}
+
+impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan> SchedulerServer<T, U> {
+    async fn cluster_load(&self) -> Result<ClusterLoad, tonic::Status> {
+        let (pending_tasks, running_tasks) = self.state.stage_manager.get_tasks_count();
+        let draining_executors = self.state.executor_manager.get_draining_executors();
+        let mut load = ClusterLoad {
+            pending_tasks,
+            running_tasks,
+            queued_jobs: self.state.job_queue.queued_jobs(),
+            draining_executors: draining_executors.len(),
+            ..Default::default()
+        };
+        let executors = self.state.get_executors_metadata().await.map_err(|e| {
+            tonic::Status::internal(format!("Could not get executors metadata: {}", e))
+        })?;
+        for (metadata, last_seen) in executors {
+            if last_seen <= EXECUTOR_ALIVE_THRESHOLD
+                && !draining_executors.contains(&metadata.id)
+            {
+                load.executors += 1;
+                load.task_slots += metadata.specification.task_slots as usize;
+            }
+        }
+        debug!("Cluster load: {:?}", load);
+        Ok(load)
+    }
+}
+
 #[tonic::async_trait]
 impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan> ExternalScaler
     for SchedulerServer<T, U>
@@ -35,8 +117,19 @@ impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan> ExternalScaler
         &self,
         _request: Request<ScaledObjectRef>,
     ) -> Result<Response<IsActiveResponse>, tonic::Status> {
-        let result = self.state.stage_manager.has_running_tasks();
-        debug!("Are there active tasks? {}", result);
+        let load = self.cluster_load().await?;
+        // Keep the executors while the draining ones still serve shuffle files
+        let busy = load.inflight_tasks() > 0 || load.draining_executors > 0;
+        let mut last_active_at = self.external_scaler.last_active_at.lock();
+        if busy {
+            *last_active_at = Instant::now();
+        }
+        let result =
+            busy || last_active_at.elapsed() < self.external_scaler.scale_to_zero_delay;
+        debug!(
+            "Are there tasks, queued jobs or draining executors? {}",
+            result
+        );
         Ok(Response::new(IsActiveResponse { result }))
     }
 
@@ -44,10 +137,13 @@ impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan> ExternalScaler
         &self,
         _request: Request<ScaledObjectRef>,
     ) -> Result<Response<GetMetricSpecResponse>, tonic::Status> {
+        let load = self.cluster_load().await?;
+        let target_size = load
+            .target_tasks_per_executor(self.external_scaler.target_tasks_per_executor);
         Ok(Response::new(GetMetricSpecResponse {
             metric_specs: vec![MetricSpec {
                 metric_name: INFLIGHT_TASKS_METRIC_NAME.to_string(),
-                target_size: 1,
+                target_size: target_size as i64,
             }],
         }))
     }
@@ -56,11 +152,184 @@ impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan> ExternalScaler
         &self,
         _request: Request<GetMetricsRequest>,
     ) -> Result<Response<GetMetricsResponse>, tonic::Status> {
+        let load = self.cluster_load().await?;
         Ok(Response::new(GetMetricsResponse {
             metric_values: vec![MetricValue {
                 metric_name: INFLIGHT_TASKS_METRIC_NAME.to_string(),
-                metric_value: 10000000, // A very high number to saturate the HPA
+                metric_value: load.inflight_tasks() as i64,
             }],
         }))
     }
 }
+
+#[cfg(all(test, feature = "sled"))]
+mod test {
+    use crate::config::SchedulerConfig;
+    use crate::scheduler_server::externalscaler::external_scaler_client::ExternalScalerClient;
+    use crate::scheduler_server::externalscaler::external_scaler_server::ExternalScalerServer;
+    use crate::scheduler_server::externalscaler::{GetMetricsRequest, ScaledObjectRef};
+    use crate::scheduler_server::SchedulerServer;
+    use crate::state::backend::standalone::StandaloneClient;
+    use datafusion::execution::context::default_session_builder;
+    use datafusion_proto::protobuf::LogicalPlanNode;
+    use hetu_core::config::TaskSchedulingPolicy;
+    use hetu_core::error::Result;
+    use hetu_core::serde::protobuf::{ExecutorHeartbeat, PhysicalPlanNode};
+    use hetu_core::serde::scheduler::{ExecutorMetadata, ExecutorSpecification};
+    use hetu_core::serde::BallistaCodec;
+    use std::sync::Arc;
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use tokio::net::TcpListener;
+    use tokio_stream::wrappers::TcpListenerStream;
+    use tonic::transport::{Channel, Server};
+    use tonic::Request;
+
+    type ScalerClient = ExternalScalerClient<Channel>;
+
+    /// A scheduler serving the external scaler on a local port, and a client of it
+    async fn test_scheduler(
+        config: SchedulerConfig,
+    ) -> Result<(
+        SchedulerServer<LogicalPlanNode, PhysicalPlanNode>,
+        ScalerClient,
+    )> {
+        let scheduler: SchedulerServer<LogicalPlanNode, PhysicalPlanNode> =
+            SchedulerServer::new_with_config(
+                Arc::new(StandaloneClient::try_new_temporary()?),
+                "default".to_owned(),
+                TaskSchedulingPolicy::PullStaged,
+                BallistaCodec::default(),
+                default_session_builder,
+                config,
+            );
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(
+            Server::builder()
+                .add_service(ExternalScalerServer::new(scheduler.clone()))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+        let client = ExternalScalerClient::connect(format!("http://{}", addr))
+            .await
+            .expect("Could not connect to the external scaler");
+        Ok((scheduler, client))
+    }
+
+    async fn register_executor(
+        scheduler: &SchedulerServer<LogicalPlanNode, PhysicalPlanNode>,
+        executor_id: &str,
+        task_slots: u32,
+    ) -> Result<()> {
+        scheduler
+            .state
+            .save_executor_metadata(ExecutorMetadata {
+                id: executor_id.to_owned(),
+                host: "localhost".to_owned(),
+                port: 0,
+                grpc_port: 0,
+                specification: ExecutorSpecification { task_slots },
+            })
+            .await?;
+        scheduler
+            .state
+            .executor_manager
+            .save_executor_heartbeat(ExecutorHeartbeat {
+                executor_id: executor_id.to_owned(),
+                timestamp: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .expect("Time went backwards")
+                    .as_secs(),
+                state: None,
+            });
+        Ok(())
+    }
+
+    async fn is_active(client: &ScalerClient) -> bool {
+        client
+            .clone()
+            .is_active(Request::new(ScaledObjectRef::default()))
+            .await
+            .expect("Received error response")
+            .into_inner()
+            .result
+    }
+
+    async fn metric_values(client: &ScalerClient) -> (i64, i64) {
+        let target_size = client
+            .clone()
+            .get_metric_spec(Request::new(ScaledObjectRef::default()))
+            .await
+            .expect("Received error response")
+            .into_inner()
+            .metric_specs[0]
+            .target_size;
+        let metric_value = client
+            .clone()
+            .get_metrics(Request::new(GetMetricsRequest::default()))
+            .await
+            .expect("Received error response")
+            .into_inner()
+            .metric_values[0]
+            .metric_value;
+        (target_size, metric_value)
+    }
+
+    #[tokio::test]
+    async fn test_inflight_tasks_metric() -> Result<()> {
+        let (scheduler, client) = test_scheduler(
+            SchedulerConfig::default().with_scaler_scale_to_zero_delay_seconds(0),
+        )
+        .await?;
+        assert_eq!(metric_values(&client).await, (1, 0));
+        assert!(!is_active(&client).await);
+
+        // The target follows the task slots of the executors which are not draining
+        register_executor(&scheduler, "executor-1", 4).await?;
+        register_executor(&scheduler, "executor-2", 8).await?;
+        register_executor(&scheduler, "executor-3", 2).await?;
+        scheduler
+            .state
+            .executor_manager
+            .drain_executor("executor-3");
+        assert_eq!(metric_values(&client).await, (6, 0));
+        // The draining executor keeps the scaler active
+        assert!(is_active(&client).await);
+        scheduler
+            .state
+            .executor_manager
+            .decommission_executor("executor-3");
+        assert!(!is_active(&client).await);
+
+        scheduler
+            .state
+            .stage_manager
+            .add_running_stage("job", 1, 10);
+        assert_eq!(metric_values(&client).await, (6, 10));
+        assert!(is_active(&client).await);
+
+        scheduler.state.stage_manager.remove_job("job");
+        assert_eq!(metric_values(&client).await, (6, 0));
+        assert!(!is_active(&client).await);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_configured_target_and_scale_to_zero_delay() -> Result<()> {
+        let (scheduler, client) = test_scheduler(
+            SchedulerConfig::default()
+                .with_scaler_target_tasks_per_executor(3)
+                .with_scaler_scale_to_zero_delay_seconds(3600),
+        )
+        .await?;
+        register_executor(&scheduler, "executor-1", 8).await?;
+        scheduler.state.stage_manager.add_running_stage("job", 1, 7);
+        assert_eq!(metric_values(&client).await, (3, 7));
+        assert!(is_active(&client).await);
+
+        // Stays active within the delay after the work is done
+        scheduler.state.stage_manager.remove_job("job");
+        assert_eq!(metric_values(&client).await, (3, 0));
+        assert!(is_active(&client).await);
+        Ok(())
+    }
+}
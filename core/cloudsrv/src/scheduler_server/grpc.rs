@@ -19,34 +19,44 @@ use crate::scheduler_server::event::QueryStageSchedulerEvent;
 use crate::scheduler_server::{
     create_datafusion_context, update_datafusion_context, SchedulerServer,
 };
+use crate::state::job_queue::job_owner;
 use crate::state::task_scheduler::TaskScheduler;
+use crate::state::JobStatusWatch;
 use anyhow::Context;
-use datafusion::datafusion_data_access::object_store::{
-    local::LocalFileSystem, ObjectStore,
-};
+use datafusion::datasource::file_format::avro::AvroFormat;
+use datafusion::datasource::file_format::csv::CsvFormat;
+use datafusion::datasource::file_format::json::JsonFormat;
 use datafusion::datasource::file_format::parquet::ParquetFormat;
 use datafusion::datasource::file_format::FileFormat;
+use datafusion::physical_plan::{ColumnStatistics, Statistics};
+use datafusion::scalar::ScalarValue;
 use datafusion_proto::logical_plan::AsLogicalPlan;
-use futures::TryStreamExt;
+use futures::{Stream, TryStreamExt};
+use hetu_core::client::executor_endpoint;
 use hetu_core::config::{BallistaConfig, TaskSchedulingPolicy};
 use hetu_core::error::BallistaError;
+use hetu_core::object_store::object_store_for_location;
 use hetu_core::serde::protobuf::execute_query_params::{OptionalSessionId, Query};
 use hetu_core::serde::protobuf::executor_grpc_client::ExecutorGrpcClient;
 use hetu_core::serde::protobuf::executor_registration::OptionalHost;
 use hetu_core::serde::protobuf::scheduler_grpc_server::SchedulerGrpc;
 use hetu_core::serde::protobuf::{
-    job_status, ExecuteQueryParams, ExecuteQueryResult, ExecutorHeartbeat, FailedJob,
-    FileType, GetFileMetadataParams, GetFileMetadataResult, GetJobStatusParams,
-    GetJobStatusResult, HeartBeatParams, HeartBeatResult, JobStatus, PollWorkParams,
-    PollWorkResult, QueuedJob, RegisterExecutorParams, RegisterExecutorResult,
-    UpdateTaskStatusParams, UpdateTaskStatusResult,
+    job_status, CancelJobParams, CancelJobResult, DrainExecutorParams,
+    DrainExecutorResult, ExecuteQueryParams, ExecuteQueryResult, ExecutorHeartbeat,
+    FailedJob, FileStatistics, FileType, GetFileMetadataParams, GetFileMetadataResult,
+    GetJobStatusParams, GetJobStatusResult, HeartBeatParams, HeartBeatResult,
+    JobQueueStatus, JobStatus, PollWorkParams, PollWorkResult, QueuedJob,
+    RegisterExecutorParams, RegisterExecutorResult, UpdateTaskStatusParams,
+    UpdateTaskStatusResult, WatchJobStatusParams,
 };
 use hetu_core::serde::scheduler::{ExecutorData, ExecutorDataChange, ExecutorMetadata};
 use hetu_core::serde::AsExecutionPlan;
 use log::{debug, error, info, trace, warn};
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use std::cmp::Ordering;
 use std::convert::TryInto;
 use std::ops::Deref;
+use std::pin::Pin;
 use std::sync::Arc;
 use std::time::Instant;
 use std::time::{SystemTime, UNIX_EPOCH};
@@ -60,6 +70,7 @@ impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan> SchedulerGrpc
         &self,
         request: Request<PollWorkParams>,
     ) -> std::result::Result<Response<PollWorkResult>, tonic::Status> {
+        self.check_leader()?;
         if let TaskSchedulingPolicy::PushStaged = self.policy {
             error!("Poll work interface is not supported for push-based task scheduling");
             return Err(tonic::Status::failed_precondition(
@@ -68,12 +79,16 @@ impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan> SchedulerGrpc
         }
         let remote_addr = request.remote_addr();
         if let PollWorkParams {
-            metadata: Some(metadata),
+            metadata: Some(mut metadata),
             can_accept_task,
             task_status,
+            state,
         } = request.into_inner()
         {
+            let auth_token = std::mem::take(&mut metadata.auth_token);
             debug!("Received poll_work request for {:?}", metadata);
+            self.check_executor_auth_token(&metadata.id, &auth_token)?;
+            self.check_not_decommissioned(&metadata.id)?;
             let metadata = ExecutorMetadata {
                 id: metadata.id,
                 host: metadata
@@ -92,10 +107,12 @@ impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan> SchedulerGrpc
                     .duration_since(UNIX_EPOCH)
                     .expect("Time went backwards")
                     .as_secs(),
-                state: None,
+                state,
             };
             // In case that it's the first time to poll work, do registration
-            if self.state.get_executor_metadata(&metadata.id).is_none() {
+            self.check_duplicated_executor(&metadata)?;
+            if self.state.get_executor_metadata(&metadata.id).as_ref() != Some(&metadata)
+            {
                 self.state
                     .save_executor_metadata(metadata.clone())
                     .await
@@ -116,6 +133,12 @@ impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan> SchedulerGrpc
                 error!("{}", msg);
                 tonic::Status::internal(msg)
             })?;
+            let can_accept_task = can_accept_task
+                && !self.state.executor_manager.is_draining(&metadata.id)
+                && !self
+                    .state
+                    .executor_manager
+                    .is_short_of_resources(&metadata.id);
             let task: Result<Option<_>, Status> = if can_accept_task {
                 let mut executors_data = vec![ExecutorData {
                     executor_id: metadata.id.clone(),
@@ -143,7 +166,14 @@ impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan> SchedulerGrpc
             } else {
                 Ok(None)
             };
-            Ok(Response::new(PollWorkResult { task: task? }))
+            Ok(Response::new(PollWorkResult {
+                task: task?,
+                drain: self.state.executor_manager.is_decommissioning(&metadata.id),
+                cancelled_jobs: self
+                    .state
+                    .executor_manager
+                    .take_cancelled_jobs(&metadata.id),
+            }))
         } else {
             warn!("Received invalid executor poll_work request");
             Err(tonic::Status::invalid_argument(
@@ -156,12 +186,16 @@ impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan> SchedulerGrpc
         &self,
         request: Request<RegisterExecutorParams>,
     ) -> Result<Response<RegisterExecutorResult>, Status> {
+        self.check_leader()?;
         let remote_addr = request.remote_addr();
         if let RegisterExecutorParams {
-            metadata: Some(metadata),
+            metadata: Some(mut metadata),
         } = request.into_inner()
         {
+            let auth_token = std::mem::take(&mut metadata.auth_token);
             info!("Received register executor request for {:?}", metadata);
+            self.check_executor_auth_token(&metadata.id, &auth_token)?;
+            self.check_not_decommissioned(&metadata.id)?;
             let metadata = ExecutorMetadata {
                 id: metadata.id,
                 host: metadata
@@ -174,17 +208,20 @@ impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan> SchedulerGrpc
                 grpc_port: metadata.grpc_port as u16,
                 specification: metadata.specification.unwrap().into(),
             };
+            self.check_duplicated_executor(&metadata)?;
             // Check whether the executor starts the grpc service
             {
-                let executor_url =
-                    format!("http://{}:{}", metadata.host, metadata.grpc_port);
-                info!("Connect to executor {:?}", executor_url);
-                let executor_client = ExecutorGrpcClient::connect(executor_url)
+                let endpoint = executor_endpoint(&metadata.host, metadata.grpc_port)
+                    .map_err(|e| tonic::Status::invalid_argument(e.to_string()))?;
+                info!("Connect to executor {:?}", endpoint.uri());
+                let executor_client = endpoint
+                    .connect()
                     .await
+                    .map(ExecutorGrpcClient::new)
                     .context("Could not connect to executor")
                     .map_err(|e| tonic::Status::internal(format!("{:?}", e)))?;
                 let mut clients = self.executors_client.as_ref().unwrap().write().await;
-                // TODO check duplicated registration
+                // A registration from the same address or a stale one is replaced
                 clients.insert(metadata.id.clone(), executor_client);
                 info!("Size of executor clients: {:?}", clients.len());
             }
@@ -217,9 +254,16 @@ impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan> SchedulerGrpc
         &self,
         request: Request<HeartBeatParams>,
     ) -> Result<Response<HeartBeatResult>, Status> {
-        let HeartBeatParams { executor_id, state } = request.into_inner();
+        self.check_leader()?;
+        let HeartBeatParams {
+            executor_id,
+            state,
+            auth_token,
+        } = request.into_inner();
 
         debug!("Received heart beat request for {:?}", executor_id);
+        self.check_executor_auth_token(&executor_id, &auth_token)?;
+        self.check_not_decommissioned(&executor_id)?;
         trace!("Related executor state is {:?}", state);
         let executor_heartbeat = ExecutorHeartbeat {
             executor_id,
@@ -229,25 +273,35 @@ impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan> SchedulerGrpc
                 .as_secs(),
             state,
         };
+        // A new leader does not know the task slots of the executors in push mode
+        let reregister = matches!(self.policy, TaskSchedulingPolicy::PushStaged)
+            && self
+                .state
+                .executor_manager
+                .get_executor_data(&executor_heartbeat.executor_id)
+                .is_none();
         self.state
             .executor_manager
             .save_executor_heartbeat(executor_heartbeat);
-        Ok(Response::new(HeartBeatResult { reregister: false }))
+        Ok(Response::new(HeartBeatResult { reregister }))
     }
 
     async fn update_task_status(
         &self,
         request: Request<UpdateTaskStatusParams>,
     ) -> Result<Response<UpdateTaskStatusResult>, Status> {
+        self.check_leader()?;
         let UpdateTaskStatusParams {
             executor_id,
             task_status,
+            auth_token,
         } = request.into_inner();
 
         debug!(
             "Received task status update request for executor {:?}",
             executor_id
         );
+        self.check_executor_auth_token(&executor_id, &auth_token)?;
         let num_tasks = task_status.len();
         if let Some(executor_data) =
             self.state.executor_manager.get_executor_data(&executor_id)
@@ -278,11 +332,24 @@ impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan> SchedulerGrpc
         &self,
         request: Request<GetFileMetadataParams>,
     ) -> std::result::Result<Response<GetFileMetadataResult>, tonic::Status> {
-        // TODO support multiple object stores
-        let obj_store = Arc::new(LocalFileSystem {}) as Arc<dyn ObjectStore>;
+        self.check_leader()?;
         // TODO shouldn't this take a ListingOption object as input?
+        let GetFileMetadataParams {
+            path,
+            file_type,
+            has_header,
+            delimiter,
+        } = request.into_inner();
 
-        let GetFileMetadataParams { path, file_type } = request.into_inner();
+        let (obj_store, path) = object_store_for_location(
+            &path,
+            &self.object_store_settings,
+        )
+        .map_err(|e| {
+            let msg = format!("Error resolving the object store of {}: {}", path, e);
+            error!("{}", msg);
+            tonic::Status::invalid_argument(msg)
+        })?;
 
         let file_type: FileType = file_type.try_into().map_err(|e| {
             let msg = format!("Error reading request: {}", e);
@@ -291,12 +358,27 @@ impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan> SchedulerGrpc
         })?;
 
         let file_format: Arc<dyn FileFormat> = match file_type {
-            FileType::Parquet => Ok(Arc::new(ParquetFormat::default())),
-            // TODO implement for CSV
-            _ => Err(tonic::Status::unimplemented(
-                "get_file_metadata unsupported file type",
-            )),
-        }?;
+            FileType::Parquet => Arc::new(ParquetFormat::default()),
+            FileType::Csv => {
+                let delimiter = match delimiter.as_bytes() {
+                    [] => b',',
+                    [delimiter] => *delimiter,
+                    _ => {
+                        return Err(tonic::Status::invalid_argument(format!(
+                            "Invalid CSV delimiter {:?}, it must be a single byte",
+                            delimiter
+                        )))
+                    }
+                };
+                Arc::new(
+                    CsvFormat::default()
+                        .with_has_header(has_header)
+                        .with_delimiter(delimiter),
+                )
+            }
+            FileType::NdJson => Arc::new(JsonFormat::default()),
+            FileType::Avro => Arc::new(AvroFormat::default()),
+        };
 
         let file_metas: Vec<_> = obj_store
             .list_file(&path)
@@ -318,8 +400,39 @@ impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan> SchedulerGrpc
                 tonic::Status::internal(msg)
             })?;
 
+        let mut files = Vec::with_capacity(file_metas.len());
+        for file_meta in &file_metas {
+            let mut statistics = file_format
+                .infer_stats(&obj_store, schema.clone(), file_meta)
+                .await
+                .map_err(|e| {
+                    let msg = format!(
+                        "Error inferring statistics of {}: {}",
+                        file_meta.path(),
+                        e
+                    );
+                    error!("{}", msg);
+                    tonic::Status::internal(msg)
+                })?;
+            // Only Parquet keeps the statistics in the files, the others have their size
+            if statistics.total_byte_size.is_none() {
+                statistics.total_byte_size = Some(file_meta.size() as usize);
+            }
+            files.push((file_meta, statistics));
+        }
+        let statistics = merge_statistics(files.iter().map(|(_, statistics)| statistics));
+
         Ok(Response::new(GetFileMetadataResult {
             schema: Some(schema.as_ref().into()),
+            statistics: Some((&statistics).into()),
+            files: files
+                .iter()
+                .map(|(file_meta, statistics)| FileStatistics {
+                    path: file_meta.path().to_owned(),
+                    size: file_meta.size(),
+                    statistics: Some(statistics.into()),
+                })
+                .collect(),
         }))
     }
 
@@ -327,6 +440,7 @@ impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan> SchedulerGrpc
         &self,
         request: Request<ExecuteQueryParams>,
     ) -> std::result::Result<Response<ExecuteQueryResult>, tonic::Status> {
+        self.check_leader()?;
         let query_params = request.into_inner();
         if let ExecuteQueryParams {
             query: Some(query),
@@ -370,6 +484,7 @@ impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan> SchedulerGrpc
                     df_session
                 }
             };
+            self.register_session_settings(&df_session, &config).await?;
 
             let plan = match query {
                 Query::LogicalPlan(message) => T::try_decode(message.as_slice())
@@ -400,6 +515,7 @@ impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan> SchedulerGrpc
             // TODO Maybe the format will be changed in the future
             let job_id = generate_job_id();
             let session_id = df_session.session_id();
+            let owner = job_owner(&config, &session_id);
             let state = self.state.clone();
             let query_stage_event_sender =
                 self.query_stage_event_loop.get_sender().map_err(|e| {
@@ -466,8 +582,9 @@ impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan> SchedulerGrpc
                     );
 
                     query_stage_event_sender
-                        .post_event(QueryStageSchedulerEvent::JobSubmitted(
+                        .post_event(QueryStageSchedulerEvent::JobQueued(
                             job_id_spawn.clone(),
+                            owner,
                             plan,
                         ))
                         .await?;
@@ -519,6 +636,7 @@ impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan> SchedulerGrpc
                 .session_registry()
                 .register_session(df_session.clone())
                 .await;
+            self.register_session_settings(&df_session, &config).await?;
             Ok(Response::new(ExecuteQueryResult {
                 job_id: "NA".to_owned(),
                 session_id: df_session.session_id(),
@@ -528,19 +646,172 @@ impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan> SchedulerGrpc
         }
     }
 
+    async fn drain_executor(
+        &self,
+        request: Request<DrainExecutorParams>,
+    ) -> Result<Response<DrainExecutorResult>, Status> {
+        self.check_leader()?;
+        let remote_addr = request.remote_addr();
+        let DrainExecutorParams {
+            executor_id,
+            auth_token,
+        } = request.into_inner();
+        debug!("Received drain_executor request for {}", executor_id);
+        // An executor drains itself, the operators drain the others through the REST API
+        self.check_executor_auth_token(&executor_id, &auth_token)?;
+        self.check_executor_address(&executor_id, remote_addr)
+            .await?;
+        let drained = self.drain_executor_on_request(&executor_id).await;
+        Ok(Response::new(DrainExecutorResult { drained }))
+    }
+
+    async fn cancel_job(
+        &self,
+        request: Request<CancelJobParams>,
+    ) -> Result<Response<CancelJobResult>, Status> {
+        self.check_leader()?;
+        let job_id = request.into_inner().job_id;
+        debug!("Received cancel_job request for job {}", job_id);
+        let cancelled = self.cancel_job(&job_id).await.map_err(|e| {
+            let msg = format!("Could not cancel job {}: {}", job_id, e);
+            error!("{}", msg);
+            tonic::Status::internal(msg)
+        })?;
+        Ok(Response::new(CancelJobResult { cancelled }))
+    }
+
+    type WatchJobStatusStream =
+        Pin<Box<dyn Stream<Item = Result<JobStatus, Status>> + Send + 'static>>;
+
+    async fn watch_job_status(
+        &self,
+        request: Request<WatchJobStatusParams>,
+    ) -> Result<Response<Self::WatchJobStatusStream>, Status> {
+        self.check_leader()?;
+        let job_id = request.into_inner().job_id;
+        debug!("Received watch_job_status request for job {}", job_id);
+        // watch before reading the status for no change to be missed in between
+        let watch = self.state.watch_job_metadata(&job_id).await.map_err(|e| {
+            let msg = format!("Could not watch job {}: {}", job_id, e);
+            error!("{}", msg);
+            tonic::Status::internal(msg)
+        })?;
+        let status = self.job_status(&job_id)?;
+        Ok(Response::new(Box::pin(job_status_updates(status, watch))))
+    }
+
     async fn get_job_status(
         &self,
         request: Request<GetJobStatusParams>,
     ) -> std::result::Result<Response<GetJobStatusResult>, tonic::Status> {
+        self.check_leader()?;
         let job_id = request.into_inner().job_id;
         debug!("Received get_job_status request for job {}", job_id);
-        let job_meta = self.state.get_job_metadata(&job_id).unwrap();
+        let job_meta = self.job_status(&job_id)?;
+        let job_queue = &self.state.job_queue;
         Ok(Response::new(GetJobStatusResult {
             status: Some(job_meta),
+            queue_status: Some(JobQueueStatus {
+                queued_jobs: job_queue.queued_jobs() as u32,
+                running_jobs: job_queue.running_jobs() as u32,
+                queue_position: job_queue.queue_position(&job_id).unwrap_or(0) as u32,
+            }),
+            profile: self.state.get_job_profile(&job_id),
         }))
     }
 }
 
+/// The status of a job followed by its changes, ending with its final status or once the
+/// job is removed
+fn job_status_updates(
+    status: JobStatus,
+    watch: JobStatusWatch,
+) -> impl Stream<Item = Result<JobStatus, Status>> + Send + 'static {
+    let state = (Some(status), watch, None::<JobStatus>);
+    futures::stream::unfold(state, |(mut next, mut watch, last)| async move {
+        if matches!(
+            last.as_ref().and_then(|last| last.status.as_ref()),
+            Some(job_status::Status::Completed(_)) | Some(job_status::Status::Failed(_))
+        ) {
+            watch.cancel().await;
+            return None;
+        }
+        loop {
+            let status = match next.take() {
+                Some(status) => status,
+                None => watch.next().await?,
+            };
+            // a job is saved again without its status changing
+            if last.as_ref() != Some(&status) {
+                return Some((Ok(status.clone()), (None, watch, Some(status))));
+            }
+        }
+    })
+}
+
+/// Merge the statistics of the files of a table, a count or a bound is only known if it
+/// is known for every file
+fn merge_statistics<'a>(files: impl Iterator<Item = &'a Statistics>) -> Statistics {
+    let mut merged = Statistics {
+        num_rows: Some(0),
+        total_byte_size: Some(0),
+        column_statistics: None,
+        is_exact: true,
+    };
+    for (i, statistics) in files.enumerate() {
+        merged.num_rows = add_counts(merged.num_rows, statistics.num_rows);
+        merged.total_byte_size =
+            add_counts(merged.total_byte_size, statistics.total_byte_size);
+        merged.is_exact &= statistics.is_exact;
+        merged.column_statistics = match (
+            i,
+            merged.column_statistics.take(),
+            &statistics.column_statistics,
+        ) {
+            (0, _, Some(columns)) => Some(columns.clone()),
+            (_, Some(merged), Some(columns)) if merged.len() == columns.len() => Some(
+                merged
+                    .iter()
+                    .zip(columns)
+                    .map(|(merged, column)| ColumnStatistics {
+                        null_count: add_counts(merged.null_count, column.null_count),
+                        min_value: bound(
+                            &merged.min_value,
+                            &column.min_value,
+                            Ordering::Less,
+                        ),
+                        max_value: bound(
+                            &merged.max_value,
+                            &column.max_value,
+                            Ordering::Greater,
+                        ),
+                        distinct_count: None,
+                    })
+                    .collect(),
+            ),
+            _ => None,
+        };
+    }
+    merged
+}
+
+fn add_counts(a: Option<usize>, b: Option<usize>) -> Option<usize> {
+    a.zip(b).map(|(a, b)| a + b)
+}
+
+// The smaller or the greater of two bounds, depending on the ordering kept
+fn bound(
+    a: &Option<ScalarValue>,
+    b: &Option<ScalarValue>,
+    keep: Ordering,
+) -> Option<ScalarValue> {
+    let (a, b) = (a.as_ref()?, b.as_ref()?);
+    match b.partial_cmp(a)? {
+        ordering if ordering == keep => Some(b.clone()),
+        _ => Some(a.clone()),
+    }
+}
+
 fn generate_job_id() -> String {
     let mut rng = thread_rng();
     std::iter::repeat(())
@@ -552,22 +823,34 @@ fn generate_job_id() -> String {
 
 #[cfg(all(test, feature = "sled"))]
 mod test {
+    use std::convert::TryFrom;
     use std::sync::Arc;
+    use std::time::Duration;
 
-    use tonic::Request;
+    use futures::TryStreamExt;
+    use tonic::transport::server::Connected;
+    use tonic::{Code, Request};
 
+    use crate::config::SchedulerConfig;
     use crate::state::{backend::standalone::StandaloneClient, SchedulerState};
+    use datafusion::arrow::datatypes::{DataType, Schema};
     use datafusion::execution::context::default_session_builder;
+    use datafusion::physical_plan::{ColumnStatistics, Statistics};
+    use datafusion::scalar::ScalarValue;
     use datafusion_proto::protobuf::LogicalPlanNode;
+    use hetu_core::config::TaskSchedulingPolicy;
     use hetu_core::error::BallistaError;
     use hetu_core::serde::protobuf::{
-        executor_registration::OptionalHost, ExecutorRegistration, PhysicalPlanNode,
-        PollWorkParams,
+        executor_registration::OptionalHost, job_status, task_status, CompletedJob,
+        CompletedTask, DrainExecutorParams, ExecutorHeartbeat, ExecutorRegistration,
+        FileType, GetFileMetadataParams, HeartBeatParams, JobStatus, PartitionId,
+        PhysicalPlanNode, PollWorkParams, QueuedJob, RunningJob, RunningTask, TaskStatus,
+        UpdateTaskStatusParams, WatchJobStatusParams,
     };
     use hetu_core::serde::scheduler::ExecutorSpecification;
     use hetu_core::serde::BallistaCodec;
 
-    use super::{SchedulerGrpc, SchedulerServer};
+    use super::{merge_statistics, SchedulerGrpc, SchedulerServer};
 
     #[tokio::test]
     async fn test_poll_work() -> Result<(), BallistaError> {
@@ -585,11 +868,13 @@ mod test {
             port: 0,
             grpc_port: 0,
             specification: Some(ExecutorSpecification { task_slots: 2 }.into()),
+            auth_token: "".to_owned(),
         };
         let request: Request<PollWorkParams> = Request::new(PollWorkParams {
             metadata: Some(exec_meta.clone()),
             can_accept_task: false,
             task_status: vec![],
+            state: None,
         });
         let response = scheduler
             .poll_work(request)
@@ -613,6 +898,7 @@ mod test {
             metadata: Some(exec_meta.clone()),
             can_accept_task: true,
             task_status: vec![],
+            state: None,
         });
         let response = scheduler
             .poll_work(request)
@@ -633,4 +919,406 @@ mod test {
         assert_eq!(state.get_executors_metadata().await.unwrap().len(), 1);
         Ok(())
     }
+
+    fn poll_work_params(id: &str, host: &str, auth_token: &str) -> PollWorkParams {
+        PollWorkParams {
+            metadata: Some(ExecutorRegistration {
+                id: id.to_owned(),
+                optional_host: Some(OptionalHost::Host(host.to_owned())),
+                port: 0,
+                grpc_port: 0,
+                specification: Some(ExecutorSpecification { task_slots: 2 }.into()),
+                auth_token: auth_token.to_owned(),
+            }),
+            can_accept_task: false,
+            task_status: vec![],
+            state: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_executor_auth_token() -> Result<(), BallistaError> {
+        let scheduler: SchedulerServer<LogicalPlanNode, PhysicalPlanNode> =
+            SchedulerServer::new_with_config(
+                Arc::new(StandaloneClient::try_new_temporary()?),
+                "default".to_owned(),
+                TaskSchedulingPolicy::PullStaged,
+                BallistaCodec::default(),
+                default_session_builder,
+                SchedulerConfig::default().with_executor_auth_token("secret"),
+            );
+        for auth_token in ["", "secreT", "secret2"] {
+            let status = scheduler
+                .poll_work(Request::new(poll_work_params("abc", "a", auth_token)))
+                .await
+                .expect_err("Unauthenticated executor is accepted");
+            assert_eq!(status.code(), Code::Unauthenticated);
+        }
+        assert!(scheduler.state.get_executor_metadata("abc").is_none());
+
+        scheduler
+            .poll_work(Request::new(poll_work_params("abc", "a", "secret")))
+            .await
+            .expect("Received error response");
+        assert!(scheduler.state.get_executor_metadata("abc").is_some());
+
+        // Every request of the executors is authenticated
+        let heart_beat_params = |auth_token: &str| HeartBeatParams {
+            executor_id: "abc".to_owned(),
+            state: None,
+            auth_token: auth_token.to_owned(),
+        };
+        let update_task_status_params = |auth_token: &str| UpdateTaskStatusParams {
+            executor_id: "abc".to_owned(),
+            task_status: vec![],
+            auth_token: auth_token.to_owned(),
+        };
+        let status = scheduler
+            .heart_beat_from_executor(Request::new(heart_beat_params("")))
+            .await
+            .expect_err("Unauthenticated heart beat is accepted");
+        assert_eq!(status.code(), Code::Unauthenticated);
+        // The inherent update_task_status of the scheduler takes the task status only
+        let status = SchedulerGrpc::update_task_status(
+            &scheduler,
+            Request::new(update_task_status_params("secreT")),
+        )
+        .await
+        .expect_err("Unauthenticated task status is accepted");
+        assert_eq!(status.code(), Code::Unauthenticated);
+        scheduler
+            .heart_beat_from_executor(Request::new(heart_beat_params("secret")))
+            .await
+            .expect("Received error response");
+        SchedulerGrpc::update_task_status(
+            &scheduler,
+            Request::new(update_task_status_params("secret")),
+        )
+        .await
+        .expect("Received error response");
+        Ok(())
+    }
+
+    fn drain_executor_params(id: &str, auth_token: &str) -> DrainExecutorParams {
+        DrainExecutorParams {
+            executor_id: id.to_owned(),
+            auth_token: auth_token.to_owned(),
+        }
+    }
+
+    /// A request coming from a connection to localhost
+    async fn local_request<T>(message: T) -> Request<T> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let _client = tokio::net::TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut request = Request::new(message);
+        request.extensions_mut().insert(stream.connect_info());
+        request
+    }
+
+    #[tokio::test]
+    async fn test_drain_executor() -> Result<(), BallistaError> {
+        let scheduler: SchedulerServer<LogicalPlanNode, PhysicalPlanNode> =
+            SchedulerServer::new_with_config(
+                Arc::new(StandaloneClient::try_new_temporary()?),
+                "default".to_owned(),
+                TaskSchedulingPolicy::PullStaged,
+                BallistaCodec::default(),
+                default_session_builder,
+                SchedulerConfig::default().with_executor_auth_token("secret"),
+            );
+        scheduler
+            .poll_work(Request::new(poll_work_params("abc", "127.0.0.1", "secret")))
+            .await
+            .expect("Received error response");
+        let task_id = PartitionId {
+            job_id: "job".to_owned(),
+            stage_id: 1,
+            partition_id: 0,
+        };
+        scheduler.state.stage_manager.add_running_stage("job", 1, 1);
+        scheduler
+            .state
+            .stage_manager
+            .update_tasks_status(vec![TaskStatus {
+                status: Some(task_status::Status::Running(RunningTask {
+                    executor_id: "abc".to_owned(),
+                })),
+                task_id: Some(task_id.clone()),
+            }]);
+
+        let status = scheduler
+            .drain_executor(local_request(drain_executor_params("abc", "")).await)
+            .await
+            .expect_err("Unauthenticated executor is drained");
+        assert_eq!(status.code(), Code::Unauthenticated);
+        // Only the executor itself may drain it
+        let status = scheduler
+            .drain_executor(Request::new(drain_executor_params("abc", "secret")))
+            .await
+            .expect_err("Executor is drained from another host");
+        assert_eq!(status.code(), Code::PermissionDenied);
+        let status = scheduler
+            .drain_executor(local_request(drain_executor_params("def", "secret")).await)
+            .await
+            .expect_err("Unknown executor is drained");
+        assert_eq!(status.code(), Code::NotFound);
+        assert!(!scheduler.state.executor_manager.is_draining("abc"));
+
+        // The running task keeps the executor from being drained
+        let drained = scheduler
+            .drain_executor(local_request(drain_executor_params("abc", "secret")).await)
+            .await
+            .expect("Received error response")
+            .into_inner()
+            .drained;
+        assert!(!drained);
+        assert!(scheduler.state.executor_manager.is_draining("abc"));
+        assert!(!scheduler.state.executor_manager.is_decommissioned("abc"));
+
+        scheduler
+            .state
+            .stage_manager
+            .update_tasks_status(vec![TaskStatus {
+                status: Some(task_status::Status::Completed(CompletedTask {
+                    executor_id: "abc".to_owned(),
+                    partitions: vec![],
+                    metrics: vec![],
+                })),
+                task_id: Some(task_id),
+            }]);
+        for _ in 0..2 {
+            let drained = scheduler
+                .drain_executor(
+                    local_request(drain_executor_params("abc", "secret")).await,
+                )
+                .await
+                .expect("Received error response")
+                .into_inner()
+                .drained;
+            assert!(drained);
+            assert!(scheduler.state.executor_manager.is_decommissioned("abc"));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_duplicated_executor() -> Result<(), BallistaError> {
+        let scheduler: SchedulerServer<LogicalPlanNode, PhysicalPlanNode> =
+            SchedulerServer::new(
+                Arc::new(StandaloneClient::try_new_temporary()?),
+                "default".to_owned(),
+                BallistaCodec::default(),
+            );
+        scheduler
+            .poll_work(Request::new(poll_work_params("abc", "a", "")))
+            .await
+            .expect("Received error response");
+        // The same executor polls again
+        scheduler
+            .poll_work(Request::new(poll_work_params("abc", "a", "")))
+            .await
+            .expect("Received error response");
+
+        // Another executor takes the id of the alive one
+        let status = scheduler
+            .poll_work(Request::new(poll_work_params("abc", "b", "")))
+            .await
+            .expect_err("Duplicated executor is accepted");
+        assert_eq!(status.code(), Code::AlreadyExists);
+        assert_eq!(
+            scheduler.state.get_executor_metadata("abc").unwrap().host,
+            "a"
+        );
+
+        // The registration is replaced once the first executor is gone
+        scheduler
+            .state
+            .executor_manager
+            .save_executor_heartbeat(ExecutorHeartbeat {
+                executor_id: "abc".to_owned(),
+                timestamp: 0,
+                state: None,
+            });
+        scheduler
+            .poll_work(Request::new(poll_work_params("abc", "b", "")))
+            .await
+            .expect("Received error response");
+        assert_eq!(
+            scheduler.state.get_executor_metadata("abc").unwrap().host,
+            "b"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_file_metadata() -> Result<(), BallistaError> {
+        let scheduler: SchedulerServer<LogicalPlanNode, PhysicalPlanNode> =
+            SchedulerServer::new(
+                Arc::new(StandaloneClient::try_new_temporary()?),
+                "default".to_owned(),
+                BallistaCodec::default(),
+            );
+        let dir = tempfile::TempDir::new()?;
+        std::fs::write(dir.path().join("a.csv"), "a;b\n1;x\n2;y\n")?;
+        std::fs::write(dir.path().join("b.json"), "{\"a\": 1, \"b\": \"x\"}\n")?;
+
+        let result = scheduler
+            .get_file_metadata(Request::new(GetFileMetadataParams {
+                path: dir.path().join("a.csv").to_str().unwrap().to_owned(),
+                file_type: FileType::Csv as i32,
+                has_header: true,
+                delimiter: ";".to_owned(),
+            }))
+            .await
+            .expect("Received error response")
+            .into_inner();
+        let schema = Schema::try_from(result.schema.as_ref().unwrap()).unwrap();
+        assert_eq!(
+            schema.fields().iter().map(|f| f.name()).collect::<Vec<_>>(),
+            vec!["a", "b"]
+        );
+        assert_eq!(result.files.len(), 1);
+        assert_eq!(result.files[0].size, 12);
+        let statistics = result.statistics.unwrap();
+        assert_eq!(statistics.total_byte_size, 12);
+        // The CSV files are not read to count their rows
+        assert_eq!(statistics.num_rows, -1);
+
+        let result = scheduler
+            .get_file_metadata(Request::new(GetFileMetadataParams {
+                path: dir.path().join("b.json").to_str().unwrap().to_owned(),
+                file_type: FileType::NdJson as i32,
+                has_header: false,
+                delimiter: "".to_owned(),
+            }))
+            .await
+            .expect("Received error response")
+            .into_inner();
+        let schema = Schema::try_from(result.schema.as_ref().unwrap()).unwrap();
+        assert_eq!(schema.field(0).data_type(), &DataType::Int64);
+
+        let status = scheduler
+            .get_file_metadata(Request::new(GetFileMetadataParams {
+                path: dir.path().join("a.csv").to_str().unwrap().to_owned(),
+                file_type: FileType::Csv as i32,
+                has_header: true,
+                delimiter: ";;".to_owned(),
+            }))
+            .await
+            .expect_err("Invalid delimiter is accepted");
+        assert_eq!(status.code(), Code::InvalidArgument);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_watch_job_status() -> Result<(), BallistaError> {
+        let scheduler: SchedulerServer<LogicalPlanNode, PhysicalPlanNode> =
+            SchedulerServer::new(
+                Arc::new(StandaloneClient::try_new_temporary()?),
+                "default".to_owned(),
+                BallistaCodec::default(),
+            );
+        let queued = JobStatus {
+            status: Some(job_status::Status::Queued(QueuedJob {})),
+        };
+        let running = JobStatus {
+            status: Some(job_status::Status::Running(RunningJob {})),
+        };
+        let completed = JobStatus {
+            status: Some(job_status::Status::Completed(CompletedJob {
+                partition_location: vec![],
+            })),
+        };
+        let status = scheduler
+            .watch_job_status(Request::new(WatchJobStatusParams {
+                job_id: "job".to_owned(),
+            }))
+            .await
+            .expect_err("Unknown job is watched");
+        assert_eq!(status.code(), Code::NotFound);
+
+        scheduler.state.save_job_metadata("job", &queued).await?;
+        let statuses = scheduler
+            .watch_job_status(Request::new(WatchJobStatusParams {
+                job_id: "job".to_owned(),
+            }))
+            .await
+            .expect("Received error response")
+            .into_inner();
+        // The statuses saved again without changing are sent once
+        for status in [&queued, &running, &running, &queued, &completed, &completed] {
+            scheduler.state.save_job_metadata("job", status).await?;
+        }
+        // The stream ends with the final status
+        let statuses: Vec<_> =
+            tokio::time::timeout(Duration::from_secs(5), statuses.try_collect())
+                .await
+                .expect("The stream didn't end with the final status")
+                .expect("Received error status");
+        assert_eq!(
+            statuses,
+            vec![queued.clone(), running, queued, completed.clone()]
+        );
+
+        // A finished job only sends its final status
+        let statuses: Vec<_> = scheduler
+            .watch_job_status(Request::new(WatchJobStatusParams {
+                job_id: "job".to_owned(),
+            }))
+            .await
+            .expect("Received error response")
+            .into_inner()
+            .try_collect()
+            .await
+            .expect("Received error status");
+        assert_eq!(statuses, vec![completed]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_statistics() {
+        let column = |min: i32, max: i32| ColumnStatistics {
+            null_count: Some(1),
+            min_value: Some(ScalarValue::Int32(Some(min))),
+            max_value: Some(ScalarValue::Int32(Some(max))),
+            distinct_count: Some(2),
+        };
+        let files = vec![
+            Statistics {
+                num_rows: Some(10),
+                total_byte_size: Some(100),
+                column_statistics: Some(vec![column(3, 8)]),
+                is_exact: true,
+            },
+            Statistics {
+                num_rows: Some(5),
+                total_byte_size: Some(50),
+                column_statistics: Some(vec![column(1, 6)]),
+                is_exact: true,
+            },
+        ];
+        let merged = merge_statistics(files.iter());
+        assert_eq!(merged.num_rows, Some(15));
+        assert_eq!(merged.total_byte_size, Some(150));
+        assert!(merged.is_exact);
+        assert_eq!(
+            merged.column_statistics,
+            Some(vec![ColumnStatistics {
+                null_count: Some(2),
+                min_value: Some(ScalarValue::Int32(Some(1))),
+                max_value: Some(ScalarValue::Int32(Some(8))),
+                distinct_count: None,
+            }])
+        );
+
+        // The row count of a file is unknown
+        let files = vec![files[0].clone(), Statistics::default()];
+        let merged = merge_statistics(files.iter());
+        assert_eq!(merged.num_rows, None);
+        assert_eq!(merged.column_statistics, None);
+        assert!(!merged.is_exact);
+    }
 }
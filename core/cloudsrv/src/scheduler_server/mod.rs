@@ -15,23 +15,34 @@
 // specific language governing permissions and limitations
 // under the License.
 
+use crate::config::SchedulerConfig;
+use crate::metrics::EXECUTOR_ALIVE_THRESHOLD;
 use crate::scheduler_server::event::{QueryStageSchedulerEvent, SchedulerServerEvent};
 use crate::scheduler_server::event_loop::SchedulerServerEventAction;
+use crate::scheduler_server::external_scaler::ExternalScalerState;
 use crate::scheduler_server::query_stage_scheduler::QueryStageScheduler;
 use crate::state::backend::StateBackendClient;
+use crate::state::leader_election::LeaderElection;
 use crate::state::SchedulerState;
 use datafusion::execution::context::{default_session_builder, SessionState};
 use datafusion::prelude::{SessionConfig, SessionContext};
 use datafusion_proto::logical_plan::AsLogicalPlan;
+use hetu_core::client::tokens_match;
 use hetu_core::config::{BallistaConfig, TaskSchedulingPolicy};
 use hetu_core::error::Result;
 use hetu_core::event_loop::EventLoop;
+use hetu_core::object_store::{
+    merge_object_store_settings, register_object_stores, without_secrets,
+};
 use hetu_core::serde::protobuf::executor_grpc_client::ExecutorGrpcClient;
-use hetu_core::serde::protobuf::TaskStatus;
+use hetu_core::serde::protobuf::{job_status, JobStatus, StopExecutorParams, TaskStatus};
+use hetu_core::serde::scheduler::ExecutorMetadata;
 use hetu_core::serde::{AsExecutionPlan, BallistaCodec};
+use log::{debug, error, info, warn};
 use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::sync::RwLock;
 use tonic::transport::Channel;
 
@@ -61,6 +72,15 @@ pub struct SchedulerServer<T: 'static + AsLogicalPlan, U: 'static + AsExecutionP
     codec: BallistaCodec<T, U>,
     /// SessionState Builder
     session_builder: SessionBuilder,
+    leader_election: Option<Arc<LeaderElection>>,
+    finished_job_retention: Option<Duration>,
+    external_scaler: Arc<ExternalScalerState>,
+    // Shared secret the executors present to register, any executor may register if none
+    executor_auth_token: Option<String>,
+    // Shared secret the admins present to the REST API, no request is authorized if none
+    admin_token: Option<String>,
+    // Object store settings of the cluster, the ones of the sessions override them
+    object_store_settings: HashMap<String, String>,
 }
 
 impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan> SchedulerServer<T, U> {
@@ -100,11 +120,41 @@ impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan> SchedulerServer<T
         codec: BallistaCodec<T, U>,
         session_builder: SessionBuilder,
     ) -> Self {
-        let state = Arc::new(SchedulerState::new(
+        SchedulerServer::new_with_config(
+            config,
+            namespace,
+            policy,
+            codec,
+            session_builder,
+            SchedulerConfig::default(),
+        )
+    }
+
+    pub fn new_with_config(
+        config: Arc<dyn StateBackendClient>,
+        namespace: String,
+        policy: TaskSchedulingPolicy,
+        codec: BallistaCodec<T, U>,
+        session_builder: SessionBuilder,
+        scheduler_config: SchedulerConfig,
+    ) -> Self {
+        let leader_election = scheduler_config.ha_enabled.then(|| {
+            Arc::new(LeaderElection::new(
+                config.clone(),
+                &namespace,
+                &scheduler_config,
+            ))
+        });
+        let finished_job_retention = (scheduler_config.finished_job_retention_seconds
+            > 0)
+        .then(|| Duration::from_secs(scheduler_config.finished_job_retention_seconds));
+        let external_scaler = Arc::new(ExternalScalerState::new(&scheduler_config));
+        let state = Arc::new(SchedulerState::new_with_config(
             config,
             namespace,
             session_builder,
             codec.clone(),
+            &scheduler_config,
         ));
 
         let (executors_client, event_loop) =
@@ -137,15 +187,18 @@ impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan> SchedulerServer<T
             query_stage_event_loop,
             codec,
             session_builder,
+            leader_election,
+            finished_job_retention,
+            external_scaler,
+            executor_auth_token: (!scheduler_config.executor_auth_token.is_empty())
+                .then(|| scheduler_config.executor_auth_token.clone()),
+            admin_token: (!scheduler_config.admin_token.is_empty())
+                .then(|| scheduler_config.admin_token.clone()),
+            object_store_settings: scheduler_config.object_store_settings,
         }
     }
 
     pub async fn init(&mut self) -> Result<()> {
-        {
-            // initialize state
-            self.state.init().await?;
-        }
-
         {
             if let Some(event_loop) = self.event_loop.as_mut() {
                 event_loop.start()?;
@@ -165,9 +218,341 @@ impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan> SchedulerServer<T
             self.query_stage_event_loop.start()?;
         }
 
+        {
+            // recover the state, a standby scheduler does it when it takes over
+            let query_stage_event_sender = self.query_stage_event_loop.get_sender()?;
+            if let Some(leader_election) = self.leader_election.clone() {
+                tokio::spawn(
+                    leader_election.run(self.state.clone(), query_stage_event_sender),
+                );
+            } else {
+                self.state.recover(&query_stage_event_sender).await?;
+            }
+        }
+
+        if let Some(queue_timeout) = self.state.job_queue.queue_timeout() {
+            // Check the queued jobs a few times within the timeout
+            let check_interval = std::cmp::max(queue_timeout / 4, Duration::from_secs(1));
+            let query_stage_event_sender = self.query_stage_event_loop.get_sender()?;
+            tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(check_interval).await;
+                    if let Err(e) = query_stage_event_sender
+                        .post_event(QueryStageSchedulerEvent::ExpireQueuedJobs)
+                        .await
+                    {
+                        error!("Fail to check the job queue due to {}", e);
+                        break;
+                    }
+                }
+            });
+        }
+
+        if let Some(retention) = self.finished_job_retention {
+            let check_interval = std::cmp::min(
+                std::cmp::max(retention / 4, Duration::from_secs(1)),
+                Duration::from_secs(60),
+            );
+            let state = self.state.clone();
+            let leader_election = self.leader_election.clone();
+            tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(check_interval).await;
+                    // Only the leader owns the jobs in the state backend
+                    if let Some(leader_election) = leader_election.as_ref() {
+                        if !leader_election.is_leader() {
+                            continue;
+                        }
+                    }
+                    match state.remove_expired_jobs(retention).await {
+                        Ok(0) => {}
+                        Ok(n) => info!("Removed {} finished jobs after the retention", n),
+                        Err(e) => warn!("Fail to remove the finished jobs due to {}", e),
+                    }
+                }
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Whether this scheduler serves requests, which is always the case without HA
+    pub fn is_leader(&self) -> bool {
+        self.leader_election
+            .as_ref()
+            .map(|leader_election| leader_election.is_leader())
+            .unwrap_or(true)
+    }
+
+    /// Give up the leadership before shutting down so that a standby takes over at once
+    pub async fn resign(&self) -> Result<()> {
+        if let Some(leader_election) = self.leader_election.as_ref() {
+            leader_election.resign().await?;
+        }
         Ok(())
     }
 
+    /// Reject the requests to a standby scheduler, the clients should fail over to the leader
+    pub(crate) fn check_leader(&self) -> std::result::Result<(), tonic::Status> {
+        match self.leader_election.as_ref() {
+            Some(leader_election) if !leader_election.is_leader() => {
+                Err(tonic::Status::unavailable(format!(
+                    "Scheduler {} is not the leader, the current leader is {}",
+                    leader_election.scheduler_id(),
+                    leader_election
+                        .leader_address()
+                        .unwrap_or_else(|| "unknown".to_owned())
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Register the object stores of a session into its context and keep the settings the
+    /// tasks of the session are sent with, the session settings override the cluster ones
+    pub(crate) async fn register_session_settings(
+        &self,
+        session_ctx: &SessionContext,
+        config: &BallistaConfig,
+    ) -> std::result::Result<(), tonic::Status> {
+        let mut settings =
+            merge_object_store_settings(&self.object_store_settings, config);
+        register_object_stores(&session_ctx.runtime_env(), &settings).map_err(|e| {
+            tonic::Status::invalid_argument(format!(
+                "Invalid object store settings: {}",
+                e
+            ))
+        })?;
+        settings.extend(config.shuffle_settings());
+        self.state
+            .session_registry()
+            .set_task_settings(&session_ctx.session_id(), settings)
+            .await;
+        Ok(())
+    }
+
+    /// Reject an executor which does not present the shared secret
+    pub(crate) fn check_executor_auth_token(
+        &self,
+        executor_id: &str,
+        auth_token: &str,
+    ) -> std::result::Result<(), tonic::Status> {
+        match self.executor_auth_token.as_ref() {
+            Some(expected) if !tokens_match(expected, auth_token) => {
+                warn!("Rejecting executor {} with a wrong auth token", executor_id);
+                Err(tonic::Status::unauthenticated(format!(
+                    "Executor {} is not authenticated",
+                    executor_id
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether the Authorization header of a REST API request carries the admin token as
+    /// a bearer token
+    pub(crate) fn is_admin(&self, authorization: Option<&str>) -> bool {
+        let token = authorization.and_then(|header| header.strip_prefix("Bearer "));
+        match (self.admin_token.as_ref(), token) {
+            (Some(expected), Some(token)) => tokens_match(expected, token),
+            _ => false,
+        }
+    }
+
+    /// Reject a request about an executor which does not come from the host the executor is
+    /// registered at, so that an executor can only act on its own behalf
+    pub(crate) async fn check_executor_address(
+        &self,
+        executor_id: &str,
+        remote_addr: Option<SocketAddr>,
+    ) -> std::result::Result<(), tonic::Status> {
+        let host = match self.state.get_executor_metadata(executor_id) {
+            Some(metadata) => metadata.host,
+            None => {
+                return Err(tonic::Status::not_found(format!(
+                    "Executor {} is not registered",
+                    executor_id
+                )))
+            }
+        };
+        let remote_ip = remote_addr.map(|addr| addr.ip());
+        let is_executor_host = match remote_ip {
+            Some(remote_ip) => match host.parse::<IpAddr>() {
+                Ok(ip) => ip == remote_ip,
+                Err(_) => tokio::net::lookup_host((host.as_str(), 0))
+                    .await
+                    .map(|mut addrs| addrs.any(|addr| addr.ip() == remote_ip))
+                    .unwrap_or(false),
+            },
+            None => false,
+        };
+        if is_executor_host {
+            Ok(())
+        } else {
+            warn!(
+                "Rejecting a request about executor {} from {:?}, it is registered at {}",
+                executor_id, remote_ip, host
+            );
+            Err(tonic::Status::permission_denied(format!(
+                "Only executor {} may send this request",
+                executor_id
+            )))
+        }
+    }
+
+    /// Reject an executor taking the id of another alive executor. An executor registering
+    /// again from the same address, or one whose registration is stale, is replaced
+    pub(crate) fn check_duplicated_executor(
+        &self,
+        metadata: &ExecutorMetadata,
+    ) -> std::result::Result<(), tonic::Status> {
+        match self.state.get_executor_metadata(&metadata.id) {
+            Some(registered)
+                if (&registered.host, registered.port, registered.grpc_port)
+                    != (&metadata.host, metadata.port, metadata.grpc_port) =>
+            {
+                if self
+                    .state
+                    .executor_manager
+                    .is_alive(&metadata.id, EXECUTOR_ALIVE_THRESHOLD)
+                {
+                    warn!(
+                        "Rejecting executor {} at {}:{}, it is registered at {}:{}",
+                        metadata.id,
+                        metadata.host,
+                        metadata.port,
+                        registered.host,
+                        registered.port
+                    );
+                    Err(tonic::Status::already_exists(format!(
+                        "Executor {} is already registered at {}:{}",
+                        metadata.id, registered.host, registered.port
+                    )))
+                } else {
+                    info!(
+                        "Replacing the stale registration of executor {} at {}:{}",
+                        metadata.id, registered.host, registered.port
+                    );
+                    Ok(())
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Reject the requests of an executor which has been decommissioned
+    pub(crate) fn check_not_decommissioned(
+        &self,
+        executor_id: &str,
+    ) -> std::result::Result<(), tonic::Status> {
+        if self.state.executor_manager.is_decommissioned(executor_id) {
+            Err(tonic::Status::failed_precondition(format!(
+                "Executor {} has been decommissioned",
+                executor_id
+            )))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The status of a job, the status of an expired job is still in the query history
+    pub(crate) fn job_status(
+        &self,
+        job_id: &str,
+    ) -> std::result::Result<JobStatus, tonic::Status> {
+        self.state
+            .get_job_metadata(job_id)
+            .or_else(|| {
+                self.state
+                    .get_query_history_entry(job_id)
+                    .and_then(|entry| entry.status)
+            })
+            .ok_or_else(|| tonic::Status::not_found(format!("Job {} not found", job_id)))
+    }
+
+    /// Cancel a queued or running job, return false if the job is unknown or finished.
+    ///
+    /// No new tasks of the job are scheduled and its running tasks are stopped.
+    pub async fn cancel_job(&self, job_id: &str) -> Result<bool> {
+        match self.state.get_job_metadata(job_id) {
+            Some(JobStatus {
+                status:
+                    Some(job_status::Status::Queued(_)) | Some(job_status::Status::Running(_)),
+            }) => {
+                self.post_stage_event(QueryStageSchedulerEvent::JobCancelled(
+                    job_id.to_owned(),
+                ))
+                .await?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Stop assigning new tasks to an executor, return false if the executor is unknown
+    pub fn drain_executor(&self, executor_id: &str) -> bool {
+        if self.state.get_executor_metadata(executor_id).is_none()
+            || self.state.executor_manager.is_decommissioned(executor_id)
+        {
+            return false;
+        }
+        self.state.executor_manager.drain_executor(executor_id);
+        true
+    }
+
+    /// Drain an executor, ask it to exit and forget it once it is drained, return false if
+    /// the executor is unknown
+    pub fn decommission_executor(&self, executor_id: &str) -> bool {
+        if !self.drain_executor(executor_id) {
+            return false;
+        }
+        self.state.executor_manager.start_decommission(executor_id);
+        let state = self.state.clone();
+        let executors_client = self.executors_client.clone();
+        let executor_id = executor_id.to_owned();
+        tokio::spawn(async move {
+            // In push mode the executor is told to drain itself, in pull mode it learns it
+            // when polling work
+            if let Some(executors_client) = executors_client.as_ref() {
+                let client = executors_client.read().await.get(&executor_id).cloned();
+                if let Some(mut client) = client {
+                    if let Err(e) = client.stop_executor(StopExecutorParams {}).await {
+                        warn!("Fail to stop executor {}: {}", executor_id, e);
+                    }
+                }
+            }
+            // The executor may not be alive to ask for it, so it is forgotten anyway
+            while !state.is_executor_drained(&executor_id) {
+                debug!("Waiting for executor {} to be drained", executor_id);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+            state.executor_manager.decommission_executor(&executor_id);
+            if let Some(executors_client) = executors_client {
+                executors_client.write().await.remove(&executor_id);
+            }
+        });
+        true
+    }
+
+    /// Drain an executor on its own request, it is deregistered and the method returns true
+    /// once it is drained
+    pub(crate) async fn drain_executor_on_request(&self, executor_id: &str) -> bool {
+        if self.state.executor_manager.is_decommissioned(executor_id) {
+            return true;
+        }
+        self.state.executor_manager.drain_executor(executor_id);
+        if !self.state.is_executor_drained(executor_id) {
+            return false;
+        }
+        self.state
+            .executor_manager
+            .decommission_executor(executor_id);
+        if let Some(executors_client) = self.executors_client.as_ref() {
+            executors_client.write().await.remove(executor_id);
+        }
+        true
+    }
+
     pub(crate) async fn update_task_status(
         &self,
         tasks_status: Vec<TaskStatus>,
@@ -235,6 +620,9 @@ pub fn update_datafusion_context(
 pub struct SessionContextRegistry {
     /// A map from session_id to SessionContext
     pub running_sessions: RwLock<HashMap<String, Arc<SessionContext>>>,
+    /// A map from session_id to the settings its tasks are sent with, the object store
+    /// and the shuffle settings
+    task_settings: RwLock<HashMap<String, HashMap<String, String>>>,
 }
 
 impl Default for SessionContextRegistry {
@@ -249,6 +637,7 @@ impl SessionContextRegistry {
     pub fn new() -> Self {
         Self {
             running_sessions: RwLock::new(HashMap::new()),
+            task_settings: RwLock::new(HashMap::new()),
         }
     }
 
@@ -273,9 +662,26 @@ impl SessionContextRegistry {
         &self,
         session_id: &str,
     ) -> Option<Arc<SessionContext>> {
+        self.task_settings.write().await.remove(session_id);
         let mut sessions = self.running_sessions.write().await;
         sessions.remove(session_id)
     }
+
+    /// Set the settings the tasks of a session are sent with, without their secrets
+    pub async fn set_task_settings(
+        &self,
+        session_id: &str,
+        settings: HashMap<String, String>,
+    ) {
+        let mut task_settings = self.task_settings.write().await;
+        task_settings.insert(session_id.to_owned(), without_secrets(settings));
+    }
+
+    /// The settings the tasks of a session are sent with, empty if it has none
+    pub async fn task_settings(&self, session_id: &str) -> HashMap<String, String> {
+        let task_settings = self.task_settings.read().await;
+        task_settings.get(session_id).cloned().unwrap_or_default()
+    }
 }
 #[cfg(all(test, feature = "sled"))]
 mod test {
@@ -534,6 +940,7 @@ mod test {
                 status: Some(task_status::Status::Completed(CompletedTask {
                     executor_id: "localhost".to_owned(),
                     partitions: Vec::new(),
+                    metrics: vec![],
                 })),
                 task_id: Some(PartitionId {
                     job_id: job_id.to_owned(),
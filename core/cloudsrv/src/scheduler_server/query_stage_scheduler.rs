@@ -16,10 +16,12 @@
 // under the License.
 
 use crate::planner::{
-    find_unresolved_shuffles, remove_unresolved_shuffles, DistributedPlanner,
+    find_unresolved_shuffles, remove_unresolved_shuffles, update_shuffle_readers,
+    DistributedPlanner,
 };
 use crate::scheduler_server::event::{QueryStageSchedulerEvent, SchedulerServerEvent};
-use crate::state::SchedulerState;
+use crate::state::job_queue::AdmittedJob;
+use crate::state::{shuffle_service_id, SchedulerState};
 use async_recursion::async_recursion;
 use async_trait::async_trait;
 use datafusion::physical_plan::ExecutionPlan;
@@ -31,7 +33,9 @@ use hetu_core::serde::protobuf::{
     job_status, task_status, CompletedJob, CompletedTask, FailedJob, FailedTask,
     JobStatus, RunningJob, TaskStatus,
 };
-use hetu_core::serde::scheduler::{ExecutorMetadata, PartitionStats};
+use hetu_core::serde::scheduler::{
+    ExecutorMetadata, ExecutorSpecification, PartitionStats,
+};
 use hetu_core::serde::{protobuf, AsExecutionPlan};
 use log::{debug, error, info, warn};
 use std::collections::{HashMap, HashSet};
@@ -104,6 +108,122 @@ impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan> QueryStageSchedul
         Ok(())
     }
 
+    /// Rebuild the stage dependencies of a job from its saved stage plans and submit its
+    /// final stage. The stages whose inputs have been resolved run again, the other ones
+    /// wait for their inputs as usual.
+    async fn resume_stages(&self, job_id: &str) -> Result<()> {
+        let stage_ids = self.state.get_stage_ids(job_id);
+        // The final stage is planned last
+        let final_stage_id = *stage_ids.last().ok_or_else(|| {
+            BallistaError::General(format!("Job {} has no saved stages", job_id))
+        })?;
+
+        let mut stages_dependency: HashMap<u32, HashSet<u32>> = HashMap::new();
+        for stage_id in stage_ids {
+            let stage_plan = self
+                .state
+                .get_stage_plan(job_id, stage_id as usize)
+                .ok_or_else(|| {
+                    BallistaError::General(format!(
+                        "Fail to find stage plan for {}/{}",
+                        job_id, stage_id
+                    ))
+                })?;
+            for child in find_unresolved_shuffles(&stage_plan)? {
+                stages_dependency
+                    .entry(child.stage_id as u32)
+                    .or_insert_with(HashSet::new)
+                    .insert(stage_id);
+            }
+        }
+
+        self.state
+            .stage_manager
+            .add_stages_dependency(job_id, stages_dependency);
+        self.state
+            .stage_manager
+            .add_final_stage(job_id, final_stage_id);
+        self.submit_stage(job_id, final_stage_id as usize).await
+    }
+
+    /// Stop the running tasks of a cancelled job on their executors. In push mode they are
+    /// told right away, in pull mode when they poll work next.
+    async fn cancel_tasks(&self, job_id: &str, executors: HashSet<String>) -> Result<()> {
+        if executors.is_empty() {
+            return Ok(());
+        }
+        match self.event_sender.as_ref() {
+            Some(event_sender) => {
+                event_sender
+                    .post_event(SchedulerServerEvent::CancelTasks(
+                        job_id.to_owned(),
+                        executors.into_iter().collect(),
+                    ))
+                    .await
+            }
+            None => {
+                for executor_id in executors {
+                    self.state
+                        .executor_manager
+                        .cancel_tasks(&executor_id, job_id);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn is_job_finished(&self, job_id: &str) -> bool {
+        matches!(
+            self.state.get_job_metadata(job_id),
+            Some(JobStatus {
+                status: Some(job_status::Status::Completed(_))
+                    | Some(job_status::Status::Failed(_)),
+            })
+        )
+    }
+
+    /// Generate the stages of the admitted jobs. A job which fails to be planned releases its
+    /// slot immediately, so the jobs admitted in turn are submitted as well.
+    async fn submit_jobs(&self, mut jobs: Vec<AdmittedJob>) -> Result<()> {
+        while !jobs.is_empty() {
+            let (job_id, plan) = jobs.remove(0);
+            info!("Job {} submitted", job_id);
+            match self.generate_stages(&job_id, plan).await {
+                Err(e) => {
+                    let msg = format!("Job {} failed due to {}", job_id, e);
+                    warn!("{}", msg);
+                    self.state
+                        .save_job_metadata(
+                            &job_id,
+                            &JobStatus {
+                                status: Some(job_status::Status::Failed(FailedJob {
+                                    error: msg.to_string(),
+                                })),
+                            },
+                        )
+                        .await?;
+                    jobs.extend(self.state.job_queue.release(&job_id));
+                }
+                Ok(()) => {
+                    if let Err(e) = self
+                        .state
+                        .save_job_metadata(
+                            &job_id,
+                            &JobStatus {
+                                status: Some(job_status::Status::Running(RunningJob {})),
+                            },
+                        )
+                        .await
+                    {
+                        warn!("Could not update job {} status to running: {}", job_id, e);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
     async fn submit_pending_stages(&self, job_id: &str, stage_id: usize) -> Result<()> {
         if let Some(parent_stages) = self
             .state
@@ -176,6 +296,142 @@ impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan> QueryStageSchedul
         Ok(())
     }
 
+    /// The locations of the shuffle partitions written by the tasks of a completed stage,
+    /// by shuffle partition id
+    fn get_shuffle_partition_locations(
+        &self,
+        job_id: &str,
+        input_stage_id: usize,
+    ) -> Result<HashMap<usize, Vec<hetu_core::serde::scheduler::PartitionLocation>>> {
+        let mut stage_shuffle_partition_locations = HashMap::new();
+        let input_stage_tasks = self
+            .state
+            .stage_manager
+            .get_stage_tasks(job_id, input_stage_id as u32)
+            .ok_or_else(|| {
+                BallistaError::General(format!(
+                    "Fail to find completed stage for {}/{}",
+                    job_id, input_stage_id
+                ))
+            })?;
+        // each input partition can produce multiple output partitions
+        for (shuffle_input_partition_id, task_status) in
+            input_stage_tasks.iter().enumerate()
+        {
+            match &task_status.status {
+                Some(task_status::Status::Completed(CompletedTask {
+                    executor_id,
+                    partitions,
+                    ..
+                })) => {
+                    debug!(
+                        "Task for unresolved shuffle input partition {} completed and produced these shuffle partitions:\n\t{}",
+                        shuffle_input_partition_id,
+                        partitions.iter().map(|p| format!("{}={}", p.partition_id, &p.path)).collect::<Vec<_>>().join("\n\t")
+                    );
+
+                    // the empty partitions are not read
+                    for shuffle_write_partition in
+                        partitions.iter().filter(|p| p.num_rows > 0)
+                    {
+                        let temp = stage_shuffle_partition_locations
+                            .entry(shuffle_write_partition.partition_id as usize)
+                            .or_insert(Vec::new());
+                        let executor_meta =
+                            match shuffle_service_metadata(shuffle_write_partition) {
+                                Some(executor_meta) => executor_meta,
+                                None => self
+                                    .state
+                                    .get_executor_metadata(executor_id)
+                                    .ok_or_else(|| {
+                                        BallistaError::General(format!(
+                                            "Fail to find executor metadata for {}",
+                                            &executor_id
+                                        ))
+                                    })?,
+                            };
+                        let partition_location =
+                            hetu_core::serde::scheduler::PartitionLocation {
+                                partition_id: hetu_core::serde::scheduler::PartitionId {
+                                    job_id: job_id.to_owned(),
+                                    stage_id: input_stage_id,
+                                    partition_id: shuffle_write_partition.partition_id
+                                        as usize,
+                                },
+                                executor_meta,
+                                partition_stats: PartitionStats::new(
+                                    Some(shuffle_write_partition.num_rows),
+                                    Some(shuffle_write_partition.num_batches),
+                                    Some(shuffle_write_partition.num_bytes),
+                                ),
+                                path: shuffle_write_partition.path.clone(),
+                            };
+                        debug!(
+                            "Scheduler storing stage {} output partition {} path: {}",
+                            input_stage_id,
+                            partition_location.partition_id.partition_id,
+                            partition_location.path
+                        );
+                        temp.push(partition_location);
+                    }
+                }
+                _ => {
+                    debug!(
+                        "Stage {} input partition {} has not completed yet",
+                        input_stage_id, shuffle_input_partition_id
+                    );
+                    // TODO task error handling
+                }
+            }
+        }
+        Ok(stage_shuffle_partition_locations)
+    }
+
+    /// Point the stages waiting for an input stage which ran again to the recovered
+    /// shuffle partitions, and schedule their tasks again
+    async fn resume_waiting_stages(
+        &self,
+        job_id: &str,
+        input_stage_id: u32,
+    ) -> Result<()> {
+        let waiting_stages = self
+            .state
+            .stage_manager
+            .get_stages_waiting_for(job_id, input_stage_id);
+        if waiting_stages.is_empty() {
+            return Ok(());
+        }
+        let partition_locations =
+            self.get_shuffle_partition_locations(job_id, input_stage_id as usize)?;
+        for stage_id in waiting_stages {
+            let stage_plan = self
+                .state
+                .get_stage_plan(job_id, stage_id as usize)
+                .ok_or_else(|| {
+                    BallistaError::General(format!(
+                        "Fail to find stage plan for {}/{}",
+                        job_id, stage_id
+                    ))
+                })?;
+            let plan = update_shuffle_readers(
+                stage_plan,
+                input_stage_id as usize,
+                &partition_locations,
+            )?;
+            self.state
+                .save_stage_plan(job_id, stage_id as usize, plan)
+                .await?;
+            info!(
+                "Job stage {}/{} reads the recovered output of stage {}",
+                job_id, stage_id, input_stage_id
+            );
+            self.state
+                .stage_manager
+                .stop_waiting(job_id, stage_id, input_stage_id);
+        }
+        Ok(())
+    }
+
     /// Try to resolve a stage if all of the unresolved shuffles are completed.
     /// Return the unresolved shuffles which are incomplete
     async fn try_resolve_stage(
@@ -218,87 +474,10 @@ impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan> QueryStageSchedul
             > = HashMap::new();
             for unresolved_shuffle in unresolved_shuffles.iter() {
                 let input_stage_id = unresolved_shuffle.stage_id;
-                let stage_shuffle_partition_locations = partition_locations
-                    .entry(input_stage_id)
-                    .or_insert_with(HashMap::new);
-                if let Some(input_stage_tasks) = self
-                    .state
-                    .stage_manager
-                    .get_stage_tasks(job_id, input_stage_id as u32)
-                {
-                    // each input partition can produce multiple output partitions
-                    for (shuffle_input_partition_id, task_status) in
-                        input_stage_tasks.iter().enumerate()
-                    {
-                        match &task_status.status {
-                            Some(task_status::Status::Completed(CompletedTask {
-                                executor_id,
-                                partitions,
-                            })) => {
-                                debug!(
-                                    "Task for unresolved shuffle input partition {} completed and produced these shuffle partitions:\n\t{}",
-                                    shuffle_input_partition_id,
-                                    partitions.iter().map(|p| format!("{}={}", p.partition_id, &p.path)).collect::<Vec<_>>().join("\n\t")
-                                );
-
-                                for shuffle_write_partition in partitions {
-                                    let temp = stage_shuffle_partition_locations
-                                        .entry(
-                                            shuffle_write_partition.partition_id as usize,
-                                        )
-                                        .or_insert(Vec::new());
-                                    let executor_meta = self
-                                        .state
-                                        .get_executor_metadata(executor_id)
-                                        .ok_or_else(|| {
-                                            BallistaError::General(format!(
-                                                "Fail to find executor metadata for {}",
-                                                &executor_id
-                                            ))
-                                        })?;
-                                    let partition_location =
-                                        hetu_core::serde::scheduler::PartitionLocation {
-                                            partition_id:
-                                                hetu_core::serde::scheduler::PartitionId {
-                                                    job_id: job_id.to_owned(),
-                                                    stage_id: unresolved_shuffle.stage_id,
-                                                    partition_id: shuffle_write_partition
-                                                        .partition_id
-                                                        as usize,
-                                                },
-                                            executor_meta,
-                                            partition_stats: PartitionStats::new(
-                                                Some(shuffle_write_partition.num_rows),
-                                                Some(shuffle_write_partition.num_batches),
-                                                Some(shuffle_write_partition.num_bytes),
-                                            ),
-                                            path: shuffle_write_partition.path.clone(),
-                                        };
-                                    debug!(
-                                            "Scheduler storing stage {} output partition {} path: {}",
-                                            unresolved_shuffle.stage_id,
-                                            partition_location.partition_id.partition_id,
-                                            partition_location.path
-                                        );
-                                    temp.push(partition_location);
-                                }
-                            }
-                            _ => {
-                                debug!(
-                                    "Stage {} input partition {} has not completed yet",
-                                    unresolved_shuffle.stage_id,
-                                    shuffle_input_partition_id
-                                );
-                                // TODO task error handling
-                            }
-                        }
-                    }
-                } else {
-                    return Err(BallistaError::General(format!(
-                        "Fail to find completed stage for {}/{}",
-                        job_id, stage_id
-                    )));
-                }
+                partition_locations.insert(
+                    input_stage_id,
+                    self.get_shuffle_partition_locations(job_id, input_stage_id)?,
+                );
             }
 
             let plan = remove_unresolved_shuffles(stage_plan, &partition_locations)?;
@@ -324,49 +503,82 @@ impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan>
         event: QueryStageSchedulerEvent,
     ) -> Result<Option<QueryStageSchedulerEvent>> {
         match event {
-            QueryStageSchedulerEvent::JobSubmitted(job_id, plan) => {
-                info!("Job {} submitted", job_id);
-                match self.generate_stages(&job_id, plan).await {
-                    Err(e) => {
-                        let msg = format!("Job {} failed due to {}", job_id, e);
-                        warn!("{}", msg);
+            QueryStageSchedulerEvent::JobQueued(job_id, owner, plan) => {
+                if self.is_job_finished(&job_id) {
+                    // The job has been cancelled before it was queued
+                    return Ok(None);
+                }
+                info!("Job {} of {} queued", job_id, owner);
+                // The job is queued again with its plan if the scheduler fails over
+                if let Err(e) = self.state.save_job_plan(&job_id, plan.clone()).await {
+                    warn!("Could not save the plan of job {}: {}", job_id, e);
+                }
+                let admitted = self.state.job_queue.enqueue(&job_id, &owner, plan);
+                self.submit_jobs(admitted).await?;
+            }
+            QueryStageSchedulerEvent::ExpireQueuedJobs => {
+                for job_id in self.state.job_queue.expire() {
+                    let msg = format!(
+                        "Job {} failed due to waiting in the queue for more than {} seconds",
+                        job_id,
                         self.state
-                            .save_job_metadata(
-                                &job_id,
-                                &JobStatus {
-                                    status: Some(job_status::Status::Failed(FailedJob {
-                                        error: msg.to_string(),
-                                    })),
-                                },
-                            )
-                            .await?;
-                    }
-                    Ok(()) => {
-                        if let Err(e) = self
-                            .state
-                            .save_job_metadata(
-                                &job_id,
-                                &JobStatus {
-                                    status: Some(job_status::Status::Running(
-                                        RunningJob {},
-                                    )),
-                                },
-                            )
-                            .await
-                        {
-                            warn!(
-                                "Could not update job {} status to running: {}",
-                                job_id, e
-                            );
-                        }
-                    }
+                            .job_queue
+                            .queue_timeout()
+                            .map(|timeout| timeout.as_secs())
+                            .unwrap_or_default()
+                    );
+                    warn!("{}", msg);
+                    self.state
+                        .save_job_metadata(
+                            &job_id,
+                            &JobStatus {
+                                status: Some(job_status::Status::Failed(FailedJob {
+                                    error: msg,
+                                })),
+                            },
+                        )
+                        .await?;
+                }
+            }
+            QueryStageSchedulerEvent::JobSubmitted(job_id, plan) => {
+                self.submit_jobs(vec![(job_id, plan)]).await?;
+            }
+            QueryStageSchedulerEvent::JobResumed(job_id, owner) => {
+                info!("Job {} of {} resumed", job_id, owner);
+                self.state.job_queue.resume(&job_id, &owner);
+                if let Err(e) = self.resume_stages(&job_id).await {
+                    let msg = format!("Job {} failed due to {}", job_id, e);
+                    warn!("{}", msg);
+                    self.state
+                        .save_job_metadata(
+                            &job_id,
+                            &JobStatus {
+                                status: Some(job_status::Status::Failed(FailedJob {
+                                    error: msg,
+                                })),
+                            },
+                        )
+                        .await?;
+                    self.submit_jobs(self.state.job_queue.release(&job_id))
+                        .await?;
                 }
             }
             QueryStageSchedulerEvent::StageFinished(job_id, stage_id) => {
                 info!("Job stage {}/{} finished", job_id, stage_id);
+                if self.is_job_finished(&job_id) {
+                    debug!("Job {} has been cancelled", job_id);
+                    return Ok(None);
+                }
+                self.resume_waiting_stages(&job_id, stage_id).await?;
                 self.submit_pending_stages(&job_id, stage_id as usize)
                     .await?;
             }
+            QueryStageSchedulerEvent::StageResubmitted(job_id, stage_id) => {
+                warn!(
+                    "Job stage {}/{} runs again to recover its lost shuffle output",
+                    job_id, stage_id
+                );
+            }
             QueryStageSchedulerEvent::JobFinished(job_id) => {
                 info!("Job {} finished", job_id);
                 let tasks_for_complete_final_stage = self
@@ -385,6 +597,8 @@ impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan>
                     &executors,
                 );
                 self.state.save_job_metadata(&job_id, &job_status).await?;
+                self.submit_jobs(self.state.job_queue.release(&job_id))
+                    .await?;
             }
             QueryStageSchedulerEvent::JobFailed(job_id, stage_id, fail_message) => {
                 error!(
@@ -397,6 +611,25 @@ impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan>
                     })),
                 };
                 self.state.save_job_metadata(&job_id, &job_status).await?;
+                self.submit_jobs(self.state.job_queue.release(&job_id))
+                    .await?;
+            }
+            QueryStageSchedulerEvent::JobCancelled(job_id) => {
+                if self.is_job_finished(&job_id) {
+                    debug!("Job {} has finished before being cancelled", job_id);
+                    return Ok(None);
+                }
+                info!("Job {} cancelled", job_id);
+                let executors = self.state.stage_manager.cancel_job(&job_id);
+                self.cancel_tasks(&job_id, executors).await?;
+                let job_status = JobStatus {
+                    status: Some(job_status::Status::Failed(FailedJob {
+                        error: format!("Job {} cancelled", job_id),
+                    })),
+                };
+                self.state.save_job_metadata(&job_id, &job_status).await?;
+                self.submit_jobs(self.state.job_queue.release(&job_id))
+                    .await?;
             }
         }
 
@@ -413,6 +646,23 @@ impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan>
     fn on_error(&self, _error: BallistaError) {}
 }
 
+/// The metadata of the shuffle service holding a shuffle partition, which the readers
+/// fetch the partition from like from an executor. None if the executor holds it
+fn shuffle_service_metadata(
+    partition: &protobuf::ShuffleWritePartition,
+) -> Option<ExecutorMetadata> {
+    if partition.remote_host.is_empty() {
+        return None;
+    }
+    Some(ExecutorMetadata {
+        id: shuffle_service_id(&partition.remote_host, partition.remote_port),
+        host: partition.remote_host.clone(),
+        port: partition.remote_port as u16,
+        grpc_port: 0,
+        specification: ExecutorSpecification { task_slots: 0 },
+    })
+}
+
 fn get_job_status_from_tasks(
     tasks: &[Arc<TaskStatus>],
     executors: &HashMap<String, ExecutorMetadata>,
@@ -423,6 +673,7 @@ fn get_job_status_from_tasks(
             Some(task_status::Status::Completed(CompletedTask {
                 executor_id,
                 partitions,
+                ..
             })) => Ok((task, executor_id, partitions)),
             _ => Err(BallistaError::General("Task not completed".to_string())),
         })
@@ -433,15 +684,22 @@ fn get_job_status_from_tasks(
             for (status, executor_id, partitions) in info {
                 let input_partition_id = status.task_id.as_ref().unwrap(); // TODO unwrap
                 let executor_meta = executors.get(executor_id).map(|e| e.clone().into());
-                for shuffle_write_partition in partitions {
+                for shuffle_write_partition in
+                    partitions.iter().filter(|p| p.num_rows > 0)
+                {
                     let shuffle_input_partition_id = Some(protobuf::PartitionId {
                         job_id: input_partition_id.job_id.clone(),
                         stage_id: input_partition_id.stage_id,
                         partition_id: input_partition_id.partition_id,
                     });
+                    let executor_meta =
+                        match shuffle_service_metadata(shuffle_write_partition) {
+                            Some(executor_meta) => Some(executor_meta.into()),
+                            None => executor_meta.clone(),
+                        };
                     partition_location.push(protobuf::PartitionLocation {
                         partition_id: shuffle_input_partition_id.clone(),
-                        executor_meta: executor_meta.clone(),
+                        executor_meta,
                         partition_stats: Some(protobuf::PartitionStats {
                             num_batches: shuffle_write_partition.num_batches as i64,
                             num_rows: shuffle_write_partition.num_rows as i64,
@@ -458,7 +716,8 @@ fn get_job_status_from_tasks(
     if job_status.is_none() {
         // Update other statuses
         for task in tasks.iter() {
-            if let Some(task_status::Status::Failed(FailedTask { error })) = &task.status
+            if let Some(task_status::Status::Failed(FailedTask { error, .. })) =
+                &task.status
             {
                 let error = error.clone();
                 job_status = Some(job_status::Status::Failed(FailedJob { error }));
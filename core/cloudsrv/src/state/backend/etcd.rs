@@ -17,25 +17,82 @@
 
 //! Etcd config backend.
 
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::task::Poll;
+use std::time::Duration;
 
 use hetu_core::error::{ballista_error, Result};
+use hetu_core::serde::protobuf::LockHolder;
 
-use etcd_client::{GetOptions, LockResponse, WatchOptions, WatchStream, Watcher};
-use futures::{Stream, StreamExt};
+use etcd_client::{
+    Compare, CompareOp, DeleteOptions, GetOptions, LockOptions, PutOptions, WatchOptions,
+    WatchStream, Watcher,
+};
+use futures::{Future, Stream, StreamExt};
 use log::warn;
+use prost::Message;
+use tokio::runtime::Handle;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
 
-use crate::state::backend::{Lock, StateBackendClient, Watch, WatchEvent};
+use crate::state::backend::{
+    new_lock_owner, now_millis, Lock, StateBackendClient, Txn, TxnCompare, TxnOp, Watch,
+    WatchEvent,
+};
+
+const LOCK_PREFIX: &str = "/ballista_locks";
+const LOCK_HOLDER_PREFIX: &str = "/ballista_lock_holders";
 
 /// A [`StateBackendClient`] implementation that uses etcd to save cluster configuration.
 #[derive(Clone)]
 pub struct EtcdClient {
     etcd: etcd_client::Client,
+    // ttl seconds -> lease shared by the locks taken with that ttl
+    sessions: Arc<Mutex<HashMap<u64, EtcdSession>>>,
 }
 
 impl EtcdClient {
     pub fn new(etcd: etcd_client::Client) -> Self {
-        Self { etcd }
+        Self {
+            etcd,
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// The lease of the locks with this ttl, granted on the first lock and kept alive as long
+    /// as the client, so that a lock costs no lease round trip
+    async fn session_lease(&self, ttl: Duration) -> Result<i64> {
+        let mut sessions = self.sessions.lock().await;
+        if let Some(session) = sessions.get(&ttl.as_secs()) {
+            if session.alive.load(Ordering::SeqCst) {
+                return Ok(session.lease_id);
+            }
+        }
+        let lease_id = self
+            .etcd
+            .clone()
+            .lease_grant(ttl.as_secs() as i64, None)
+            .await
+            .map_err(|e| {
+                warn!("etcd lease grant failed: {}", e);
+                ballista_error("etcd lease grant failed")
+            })?
+            .id();
+        let alive = Arc::new(AtomicBool::new(true));
+        let keep_alive =
+            keep_lease_alive(self.etcd.clone(), lease_id, ttl, alive.clone());
+        // A session whose lease expired is replaced, its keep alive task has already exited
+        sessions.insert(
+            ttl.as_secs(),
+            EtcdSession {
+                lease_id,
+                alive,
+                keep_alive,
+            },
+        );
+        Ok(lease_id)
     }
 }
 
@@ -78,17 +135,117 @@ impl StateBackendClient for EtcdClient {
             .map(|_| ())
     }
 
-    async fn lock(&self) -> Result<Box<dyn Lock>> {
+    async fn delete(&self, key: &str) -> Result<()> {
+        let mut etcd = self.etcd.clone();
+        etcd.delete(key, None)
+            .await
+            .map_err(|e| {
+                warn!("etcd delete failed: {}", e);
+                ballista_error("etcd delete failed")
+            })
+            .map(|_| ())
+    }
+
+    async fn delete_prefix(&self, prefix: &str) -> Result<()> {
+        let mut etcd = self.etcd.clone();
+        etcd.delete(prefix, Some(DeleteOptions::new().with_prefix()))
+            .await
+            .map_err(|e| {
+                warn!("etcd delete failed: {}", e);
+                ballista_error("etcd delete failed")
+            })
+            .map(|_| ())
+    }
+
+    async fn txn(&self, txn: Txn) -> Result<bool> {
+        let compares = txn
+            .compares
+            .into_iter()
+            .map(|compare| match compare {
+                TxnCompare::Value(key, value) => {
+                    Compare::value(key, CompareOp::Equal, value)
+                }
+                // A key which does not exist has no create revision
+                TxnCompare::Absent(key) => {
+                    Compare::create_revision(key, CompareOp::Equal, 0)
+                }
+            })
+            .collect::<Vec<_>>();
+        let ops = txn
+            .ops
+            .into_iter()
+            .map(|op| match op {
+                TxnOp::Put(key, value) => etcd_client::TxnOp::put(key, value, None),
+                TxnOp::Delete(key) => etcd_client::TxnOp::delete(key, None),
+            })
+            .collect::<Vec<_>>();
+
+        let mut etcd = self.etcd.clone();
+        etcd.txn(etcd_client::Txn::new().when(compares).and_then(ops))
+            .await
+            .map_err(|e| {
+                warn!("etcd txn failed: {}", e);
+                ballista_error("etcd txn failed")
+            })
+            .map(|response| response.succeeded())
+    }
+
+    async fn lock(&self, key: &str, ttl: Duration) -> Result<Box<dyn Lock>> {
         let mut etcd = self.etcd.clone();
-        // TODO: make this a namespaced-lock
+        // The lock is bound to the session lease, so it is released by etcd once the ttl
+        // expires after this scheduler stops keeping the lease alive
+        let ttl = Duration::from_secs(ttl.as_secs().max(1));
+        let lease_id = self.session_lease(ttl).await?;
         let lock = etcd
-            .lock("/ballista_global_lock", None)
+            .lock(
+                format!("{}/{}", LOCK_PREFIX, key),
+                Some(LockOptions::new().with_lease(lease_id)),
+            )
             .await
             .map_err(|e| {
                 warn!("etcd lock failed: {}", e);
                 ballista_error("etcd lock failed")
             })?;
-        Ok(Box::new(EtcdLockGuard { etcd, lock }))
+
+        let acquired_at = now_millis();
+        let holder = LockHolder {
+            key: key.to_owned(),
+            owner: new_lock_owner(),
+            acquired_at,
+            expires_at: acquired_at + ttl.as_millis() as u64,
+        };
+        let holder_key = format!("{}/{}", LOCK_HOLDER_PREFIX, key);
+        let holder = holder.encode_to_vec();
+        if let Err(e) = etcd
+            .put(
+                holder_key.clone(),
+                holder.clone(),
+                Some(PutOptions::new().with_lease(lease_id)),
+            )
+            .await
+        {
+            warn!("Could not record the holder of etcd lock {}: {}", key, e);
+        }
+        Ok(Box::new(EtcdLockGuard {
+            etcd,
+            lock_key: lock.key().to_vec(),
+            holder_key,
+            holder,
+            unlocked: false,
+        }))
+    }
+
+    async fn lock_holders(&self) -> Result<Vec<LockHolder>> {
+        let prefix = format!("{}/", LOCK_HOLDER_PREFIX);
+        self.get_from_prefix(&prefix)
+            .await?
+            .into_iter()
+            .map(|(_, value)| {
+                LockHolder::decode(value.as_slice()).map_err(|e| {
+                    ballista_error(&format!("Could not decode lock holder: {}", e))
+                })
+            })
+            .collect()
     }
 
     async fn watch(&self, prefix: String) -> Result<Box<dyn Watch>> {
@@ -173,15 +330,119 @@ impl Stream for EtcdWatch {
     }
 }
 
+/// A lease kept alive until the session is dropped or etcd lets it expire
+struct EtcdSession {
+    lease_id: i64,
+    alive: Arc<AtomicBool>,
+    keep_alive: JoinHandle<()>,
+}
+
+impl Drop for EtcdSession {
+    fn drop(&mut self) {
+        self.keep_alive.abort();
+    }
+}
+
+/// Renew the lease every third of its ttl until the task is aborted, `alive` is cleared
+/// once the lease cannot be renewed anymore
+fn keep_lease_alive(
+    etcd: etcd_client::Client,
+    lease_id: i64,
+    ttl: Duration,
+    alive: Arc<AtomicBool>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        keep_lease_alive_until_failure(etcd, lease_id, ttl).await;
+        alive.store(false, Ordering::SeqCst);
+    })
+}
+
+async fn keep_lease_alive_until_failure(
+    mut etcd: etcd_client::Client,
+    lease_id: i64,
+    ttl: Duration,
+) {
+    let (mut keeper, mut responses) = match etcd.lease_keep_alive(lease_id).await {
+        Ok(keep_alive) => keep_alive,
+        Err(e) => {
+            warn!("Could not keep etcd lease {} alive: {}", lease_id, e);
+            return;
+        }
+    };
+    let mut interval = tokio::time::interval(ttl / 3);
+    loop {
+        interval.tick().await;
+        if let Err(e) = keeper.keep_alive().await {
+            warn!("etcd lease {} keep alive failed: {}", lease_id, e);
+            return;
+        }
+        match responses.message().await {
+            Ok(Some(response)) if response.ttl() > 0 => {}
+            Ok(_) => {
+                warn!("etcd lease {} expired", lease_id);
+                return;
+            }
+            Err(e) => {
+                warn!("etcd lease {} keep alive failed: {}", lease_id, e);
+                return;
+            }
+        }
+    }
+}
+
 struct EtcdLockGuard {
     etcd: etcd_client::Client,
-    lock: LockResponse,
+    // Key of the lock in etcd, which is unique to the lease holding it
+    lock_key: Vec<u8>,
+    holder_key: String,
+    holder: Vec<u8>,
+    unlocked: bool,
+}
+
+impl EtcdLockGuard {
+    /// Release the lock in one round trip, the holder record is only removed if it has not
+    /// been replaced by the next holder after this lock expired
+    fn release(&self) -> impl Future<Output = ()> {
+        let mut etcd = self.etcd.clone();
+        let txn = etcd_client::Txn::new()
+            .when(vec![Compare::value(
+                self.holder_key.clone(),
+                CompareOp::Equal,
+                self.holder.clone(),
+            )])
+            .and_then(vec![
+                etcd_client::TxnOp::delete(self.lock_key.clone(), None),
+                etcd_client::TxnOp::delete(self.holder_key.clone(), None),
+            ])
+            .or_else(vec![etcd_client::TxnOp::delete(
+                self.lock_key.clone(),
+                None,
+            )]);
+        async move {
+            if let Err(e) = etcd.txn(txn).await {
+                warn!("etcd unlock failed: {}", e);
+            }
+        }
+    }
+}
+
+impl Drop for EtcdLockGuard {
+    fn drop(&mut self) {
+        // The session lease outlives the lock, so a guard dropped without unlocking, like
+        // the one of a cancelled future, still has to release it
+        if !self.unlocked {
+            if let Ok(runtime) = Handle::try_current() {
+                runtime.spawn(self.release());
+            }
+        }
+    }
 }
 
-// Cannot use Drop because we need this to be async
+// Cannot use Drop to unlock because we need this to be async
 #[tonic::async_trait]
 impl Lock for EtcdLockGuard {
     async fn unlock(&mut self) {
-        self.etcd.unlock(self.lock.key()).await.unwrap();
+        self.release().await;
+        self.unlocked = true;
     }
 }
@@ -18,11 +18,15 @@
 use clap::ArgEnum;
 use futures::Stream;
 use hetu_core::error::Result;
+use hetu_core::serde::protobuf::LockHolder;
 use std::fmt;
-use tokio::sync::OwnedMutexGuard;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
 
 #[cfg(feature = "etcd")]
 pub mod etcd;
+#[cfg(feature = "raft")]
+pub mod raft;
 #[cfg(feature = "sled")]
 pub mod standalone;
 
@@ -32,6 +36,7 @@ pub mod standalone;
 pub enum StateBackend {
     Etcd,
     Standalone,
+    Raft,
 }
 
 impl std::str::FromStr for StateBackend {
@@ -62,7 +67,40 @@ pub trait StateBackendClient: Send + Sync {
     /// Saves the value into the provided key, overriding any previous data that might have been associated to that key.
     async fn put(&self, key: String, value: Vec<u8>) -> Result<()>;
 
-    async fn lock(&self) -> Result<Box<dyn Lock>>;
+    /// Deletes the key, nothing happens if it does not exist.
+    async fn delete(&self, key: &str) -> Result<()>;
+
+    /// Deletes all the keys starting with the prefix.
+    async fn delete_prefix(&self, prefix: &str) -> Result<()>;
+
+    /// Saves the value only if the key currently holds the expected value, where None expects
+    /// the key to be absent. Returns whether the value is saved.
+    async fn compare_and_put(
+        &self,
+        key: &str,
+        expected: Option<Vec<u8>>,
+        value: Vec<u8>,
+    ) -> Result<bool> {
+        let compare = match expected {
+            Some(expected) => TxnCompare::Value(key.to_owned(), expected),
+            None => TxnCompare::Absent(key.to_owned()),
+        };
+        self.txn(Txn::new().when(compare).put(key.to_owned(), value))
+            .await
+    }
+
+    /// Applies all the operations of the transaction atomically if all its conditions hold.
+    /// Returns whether the transaction is applied.
+    async fn txn(&self, txn: Txn) -> Result<bool>;
+
+    /// Acquire the lock of the key, waiting while another holder has it.
+    ///
+    /// The lock is released once its ttl expires, so that a crashed holder does not block
+    /// the others forever.
+    async fn lock(&self, key: &str, ttl: Duration) -> Result<Box<dyn Lock>>;
+
+    /// The current holders of the locks, for diagnostics.
+    async fn lock_holders(&self) -> Result<Vec<LockHolder>>;
 
     /// Watch all events that happen on a specific prefix.
     async fn watch(&self, prefix: String) -> Result<Box<dyn Watch>>;
@@ -83,12 +121,67 @@ pub enum WatchEvent {
     Delete(String),
 }
 
+/// A condition of a [Txn] on the current value of a key
+#[derive(Clone, Debug, PartialEq)]
+pub enum TxnCompare {
+    /// The key holds exactly the value
+    Value(String, Vec<u8>),
+
+    /// The key does not exist
+    Absent(String),
+}
+
+/// An operation of a [Txn]
+#[derive(Clone, Debug, PartialEq)]
+pub enum TxnOp {
+    Put(String, Vec<u8>),
+    Delete(String),
+}
+
+/// A multi-key transaction on the [StateBackendClient]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Txn {
+    pub compares: Vec<TxnCompare>,
+    pub ops: Vec<TxnOp>,
+}
+
+impl Txn {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn when(mut self, compare: TxnCompare) -> Self {
+        self.compares.push(compare);
+        self
+    }
+
+    pub fn put(mut self, key: String, value: Vec<u8>) -> Self {
+        self.ops.push(TxnOp::Put(key, value));
+        self
+    }
+
+    pub fn delete(mut self, key: String) -> Self {
+        self.ops.push(TxnOp::Delete(key));
+        self
+    }
+}
+
 #[tonic::async_trait]
 pub trait Lock: Send + Sync {
     async fn unlock(&mut self);
 }
 
-#[tonic::async_trait]
-impl<T: Send + Sync> Lock for OwnedMutexGuard<T> {
-    async fn unlock(&mut self) {}
+/// Ttl of the locks which only guard a few backend operations
+pub const DEFAULT_LOCK_TTL: Duration = Duration::from_secs(30);
+
+/// A unique lock owner, the pid tells which process holds a lock
+pub(crate) fn new_lock_owner() -> String {
+    format!("{}-{}", std::process::id(), Uuid::new_v4())
+}
+
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_millis() as u64
 }
@@ -0,0 +1,1037 @@
+// Copyright 2021 HetuDB.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A [`StateBackendClient`] replicated by the raft consensus among the cloud services, so
+//! that a highly available cluster does not need an external etcd.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use futures::Stream;
+use hetu_core::error::{BallistaError, Result};
+use hetu_core::serde::protobuf::LockHolder;
+use log::{debug, error, info, warn};
+use tokio::sync::mpsc::UnboundedReceiver;
+use tonic::transport::{ClientTlsConfig, Server as TonicServer, ServerTlsConfig};
+
+use crate::config::Config;
+use crate::state::backend::raft::node::{RaftError, RaftNode};
+use crate::state::backend::raft::protobuf::{
+    command::CommandType, raft_service_server::RaftServiceServer, read_request::Query,
+    AcquireLock, Command, Delete, DeletePrefix, ProposeRequest, Put, ReadRequest,
+    ReleaseLock,
+};
+use crate::state::backend::raft::transport::{GrpcTransport, RaftTransport};
+use crate::state::backend::{
+    new_lock_owner, now_millis, Lock, StateBackendClient, Txn, TxnCompare, TxnOp, Watch,
+    WatchEvent,
+};
+
+pub use transport::RaftGrpcService;
+
+// include the generated protobuf source as a submodule
+#[allow(clippy::all)]
+pub mod protobuf {
+    include!(concat!(env!("OUT_DIR"), "/raft.rs"));
+}
+
+mod node;
+mod raft_log;
+mod state_machine;
+mod storage;
+mod transport;
+
+/// Configuration of a raft node
+#[derive(Clone)]
+pub struct RaftConfig {
+    /// Positive id of this node, unique in the group
+    pub node_id: u64,
+    /// Raft addresses of the other nodes by their node id
+    pub peers: HashMap<u64, String>,
+    /// Directory of the log, the vote and the snapshot of this node
+    pub data_dir: PathBuf,
+    pub heartbeat_interval: Duration,
+    pub election_timeout_min: Duration,
+    pub election_timeout_max: Duration,
+    /// Number of applied entries after which the log is compacted into a snapshot
+    pub snapshot_threshold: u64,
+    pub max_entries_per_request: usize,
+    /// Timeout of the client requests, including waiting for a leader
+    pub request_timeout: Duration,
+    /// Shared secret the nodes present to each other, any node is accepted if none
+    pub auth_token: Option<String>,
+    /// TLS of the raft service, served over plain http if none
+    pub server_tls: Option<ServerTlsConfig>,
+    /// TLS of the connections to the other nodes, reached over plain http if none
+    pub client_tls: Option<ClientTlsConfig>,
+}
+
+impl fmt::Debug for RaftConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RaftConfig")
+            .field("node_id", &self.node_id)
+            .field("peers", &self.peers)
+            .field("data_dir", &self.data_dir)
+            .field("heartbeat_interval", &self.heartbeat_interval)
+            .field("election_timeout_min", &self.election_timeout_min)
+            .field("election_timeout_max", &self.election_timeout_max)
+            .field("snapshot_threshold", &self.snapshot_threshold)
+            .field("max_entries_per_request", &self.max_entries_per_request)
+            .field("request_timeout", &self.request_timeout)
+            .field(
+                "auth_token",
+                &self.auth_token.as_ref().map(|_| "<redacted>"),
+            )
+            .field("tls", &self.server_tls.is_some())
+            .finish()
+    }
+}
+
+impl RaftConfig {
+    pub fn new(node_id: u64, peers: HashMap<u64, String>, data_dir: PathBuf) -> Self {
+        Self {
+            node_id,
+            peers,
+            data_dir,
+            heartbeat_interval: Duration::from_millis(50),
+            election_timeout_min: Duration::from_millis(500),
+            election_timeout_max: Duration::from_millis(1000),
+            snapshot_threshold: 10000,
+            max_entries_per_request: 256,
+            request_timeout: Duration::from_secs(5),
+            auth_token: None,
+            server_tls: None,
+            client_tls: None,
+        }
+    }
+
+    pub fn with_heartbeat_interval(mut self, heartbeat_interval: Duration) -> Self {
+        self.heartbeat_interval = heartbeat_interval;
+        self
+    }
+
+    pub fn with_election_timeout(mut self, min: Duration, max: Duration) -> Self {
+        self.election_timeout_min = min;
+        self.election_timeout_max = max;
+        self
+    }
+
+    pub fn with_snapshot_threshold(mut self, snapshot_threshold: u64) -> Self {
+        self.snapshot_threshold = snapshot_threshold;
+        self
+    }
+
+    pub fn with_request_timeout(mut self, request_timeout: Duration) -> Self {
+        self.request_timeout = request_timeout;
+        self
+    }
+
+    /// Require the nodes to present the shared secret, empty accepts any node
+    pub fn with_auth_token(mut self, auth_token: impl Into<String>) -> Self {
+        let auth_token = auth_token.into();
+        self.auth_token = (!auth_token.is_empty()).then(|| auth_token);
+        self
+    }
+
+    /// Serve the raft service and reach the other nodes over TLS
+    pub fn with_tls(
+        mut self,
+        server_tls: ServerTlsConfig,
+        client_tls: ClientTlsConfig,
+    ) -> Self {
+        self.server_tls = Some(server_tls);
+        self.client_tls = Some(client_tls);
+        self
+    }
+
+    /// Parse the nodes of the group from `id=host:port` pairs separated by commas, the
+    /// entry of this node itself is skipped
+    pub fn parse_peers(node_id: u64, peers: &str) -> Result<HashMap<u64, String>> {
+        let mut result = HashMap::new();
+        for peer in peers
+            .split(',')
+            .map(str::trim)
+            .filter(|peer| !peer.is_empty())
+        {
+            let (id, address) = peer.split_once('=').ok_or_else(|| {
+                BallistaError::General(format!(
+                    "Invalid raft peer {}, expected id=host:port",
+                    peer
+                ))
+            })?;
+            let id: u64 = id.trim().parse().map_err(|_| {
+                BallistaError::General(format!("Invalid raft node id in {}", peer))
+            })?;
+            if id == 0 {
+                return Err(BallistaError::General(
+                    "The raft node id should be positive".to_owned(),
+                ));
+            }
+            if id != node_id {
+                result.insert(id, address.trim().to_owned());
+            }
+        }
+        Ok(result)
+    }
+}
+
+impl TryFrom<&Config> for RaftConfig {
+    type Error = BallistaError;
+
+    fn try_from(conf: &Config) -> Result<Self> {
+        // The log must survive restarts, so it is never kept in a temporary directory
+        if conf.raft_data_dir.is_empty() {
+            return Err(BallistaError::General(
+                "The raft data directory should be set with raft_data_dir".to_owned(),
+            ));
+        }
+        let peers = Self::parse_peers(conf.raft_node_id, &conf.raft_peers)?;
+        // Any host reaching the raft service could write the state of the cluster
+        if !peers.is_empty() && conf.raft_auth_token.is_empty() {
+            return Err(BallistaError::General(
+                "The raft nodes should share a secret set with raft_auth_token"
+                    .to_owned(),
+            ));
+        }
+        let mut config =
+            RaftConfig::new(conf.raft_node_id, peers, PathBuf::from(&conf.raft_data_dir))
+                .with_auth_token(&conf.raft_auth_token);
+        let tls_error =
+            |e| BallistaError::General(format!("Invalid raft TLS config: {}", e));
+        if let Some(client_tls) = conf.raft_client_tls_config().map_err(tls_error)? {
+            let server_tls = conf.server_tls_config().map_err(tls_error)?.ok_or_else(|| {
+                BallistaError::General(
+                    "The raft service is served over TLS with tls_cert_file and tls_key_file"
+                        .to_owned(),
+                )
+            })?;
+            config = config.with_tls(server_tls, client_tls);
+        }
+        Ok(config)
+    }
+}
+
+/// A [`StateBackendClient`] implementation backed by a raft node embedded in this process.
+///
+/// Writes and reads are routed to the leader of the group, so the reads are linearizable.
+/// The watches are served from the state applied on the local node.
+#[derive(Clone)]
+pub struct RaftClient {
+    node: Arc<RaftNode>,
+}
+
+impl RaftClient {
+    /// Start the raft node, whose [`RaftClient::service`] has to be served on the address
+    /// known by its peers
+    pub fn start(config: RaftConfig) -> Result<Self> {
+        let transport = Arc::new(GrpcTransport::new(&config));
+        Self::start_with_transport(config, transport)
+    }
+
+    /// Start the raft node and serve its grpc service on the address in the background. A
+    /// single node is not reached by other nodes, its service is only served on localhost.
+    pub fn serve(config: RaftConfig, addr: SocketAddr) -> Result<Self> {
+        let addr = if config.peers.is_empty() {
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), addr.port())
+        } else {
+            addr
+        };
+        info!(
+            "Starting raft node {} on {:?} with data in {:?}",
+            config.node_id, addr, config.data_dir
+        );
+        let mut server = TonicServer::builder();
+        if let Some(server_tls) = config.server_tls.clone() {
+            server = server.tls_config(server_tls).map_err(|e| {
+                BallistaError::General(format!("Invalid raft TLS config: {}", e))
+            })?;
+        }
+        let client = Self::start(config)?;
+        let service = client.service();
+        tokio::spawn(async move {
+            if let Err(e) = server.add_service(service).serve(addr).await {
+                error!("Raft service on {:?} stopped due to {}", addr, e);
+            }
+        });
+        Ok(client)
+    }
+
+    pub(crate) fn start_with_transport(
+        config: RaftConfig,
+        transport: Arc<dyn RaftTransport>,
+    ) -> Result<Self> {
+        let node = Arc::new(RaftNode::open(config, transport)?);
+        tokio::spawn(node.clone().run());
+        Ok(Self { node })
+    }
+
+    /// The grpc service receiving the messages of the other nodes
+    pub fn service(&self) -> RaftServiceServer<RaftGrpcService> {
+        RaftServiceServer::new(RaftGrpcService::new(self.node.clone()))
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.node.is_leader()
+    }
+
+    /// Stop taking part in the elections and the replication
+    pub fn stop(&self) {
+        self.node.stop();
+    }
+
+    /// The next node to send a request to, None if the request should wait for an election
+    fn next_target(&self, result: &RaftError, target: u64) -> Option<u64> {
+        match result {
+            RaftError::NotLeader(leader_id)
+                if *leader_id != 0 && *leader_id != target =>
+            {
+                Some(*leader_id)
+            }
+            _ => None,
+        }
+    }
+
+    async fn propose(&self, command: Command) -> Result<bool> {
+        let deadline = Instant::now() + self.node.config().request_timeout;
+        let mut target = self.node.leader_id();
+        loop {
+            let result = if target == 0 || target == self.node.id() {
+                self.node.propose(command.clone()).await
+            } else {
+                self.forward_propose(target, command.clone()).await
+            };
+            let error = match result {
+                Ok(applied) => return Ok(applied),
+                Err(error) => error,
+            };
+            if Instant::now() >= deadline {
+                return Err(match error {
+                    RaftError::Failed(e) => e,
+                    RaftError::NotLeader(_) => BallistaError::General(
+                        "Timeout waiting for the raft leader".to_owned(),
+                    ),
+                });
+            }
+            target = match self.next_target(&error, target) {
+                Some(leader_id) => leader_id,
+                None => {
+                    debug!("Raft proposal is retried: {:?}", error);
+                    tokio::time::sleep(self.node.config().heartbeat_interval).await;
+                    self.node.leader_id()
+                }
+            };
+        }
+    }
+
+    async fn forward_propose(
+        &self,
+        target: u64,
+        command: Command,
+    ) -> std::result::Result<bool, RaftError> {
+        let request = ProposeRequest {
+            command: Some(command),
+        };
+        match self.node.transport().propose(target, request).await {
+            Ok(response) if response.not_leader => {
+                Err(RaftError::NotLeader(response.leader_id))
+            }
+            Ok(response) if !response.error.is_empty() => {
+                Err(RaftError::Failed(BallistaError::General(response.error)))
+            }
+            Ok(response) => Ok(response.applied),
+            Err(e) => Err(RaftError::Failed(e)),
+        }
+    }
+
+    async fn read(&self, query: Query) -> Result<Vec<(String, Vec<u8>)>> {
+        let deadline = Instant::now() + self.node.config().request_timeout;
+        let mut target = self.node.leader_id();
+        loop {
+            let result = if target == 0 || target == self.node.id() {
+                self.node.read(query.clone()).await
+            } else {
+                self.forward_read(target, query.clone()).await
+            };
+            let error = match result {
+                Ok(entries) => return Ok(entries),
+                Err(error) => error,
+            };
+            if Instant::now() >= deadline {
+                return Err(match error {
+                    RaftError::Failed(e) => e,
+                    RaftError::NotLeader(_) => BallistaError::General(
+                        "Timeout waiting for the raft leader".to_owned(),
+                    ),
+                });
+            }
+            target = match self.next_target(&error, target) {
+                Some(leader_id) => leader_id,
+                None => {
+                    debug!("Raft read is retried: {:?}", error);
+                    tokio::time::sleep(self.node.config().heartbeat_interval).await;
+                    self.node.leader_id()
+                }
+            };
+        }
+    }
+
+    async fn forward_read(
+        &self,
+        target: u64,
+        query: Query,
+    ) -> std::result::Result<Vec<(String, Vec<u8>)>, RaftError> {
+        let request = ReadRequest { query: Some(query) };
+        match self.node.transport().read(target, request).await {
+            Ok(response) if response.not_leader => {
+                Err(RaftError::NotLeader(response.leader_id))
+            }
+            Ok(response) if !response.error.is_empty() => {
+                Err(RaftError::Failed(BallistaError::General(response.error)))
+            }
+            Ok(response) => Ok(response
+                .entries
+                .into_iter()
+                .map(|kv| (kv.key, kv.value))
+                .collect()),
+            Err(e) => Err(RaftError::Failed(e)),
+        }
+    }
+}
+
+fn acquire_lock(key: &str, owner: &str, ttl: Duration) -> Command {
+    Command {
+        command_type: Some(CommandType::AcquireLock(AcquireLock {
+            key: key.to_owned(),
+            owner: owner.to_owned(),
+            acquired_at: now_millis(),
+            ttl_ms: ttl.as_millis() as u64,
+        })),
+    }
+}
+
+fn release_lock(key: &str, owner: &str) -> Command {
+    Command {
+        command_type: Some(CommandType::ReleaseLock(ReleaseLock {
+            key: key.to_owned(),
+            owner: owner.to_owned(),
+        })),
+    }
+}
+
+#[tonic::async_trait]
+impl StateBackendClient for RaftClient {
+    async fn get(&self, key: &str) -> Result<Vec<u8>> {
+        Ok(self
+            .read(Query::Key(key.to_owned()))
+            .await?
+            .into_iter()
+            .next()
+            .map(|(_, value)| value)
+            .unwrap_or_default())
+    }
+
+    async fn get_from_prefix(&self, prefix: &str) -> Result<Vec<(String, Vec<u8>)>> {
+        self.read(Query::Prefix(prefix.to_owned())).await
+    }
+
+    async fn put(&self, key: String, value: Vec<u8>) -> Result<()> {
+        self.propose(Command {
+            command_type: Some(CommandType::Put(Put { key, value })),
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        self.propose(Command {
+            command_type: Some(CommandType::Delete(Delete {
+                key: key.to_owned(),
+            })),
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn delete_prefix(&self, prefix: &str) -> Result<()> {
+        self.propose(Command {
+            command_type: Some(CommandType::DeletePrefix(DeletePrefix {
+                prefix: prefix.to_owned(),
+            })),
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn txn(&self, txn: Txn) -> Result<bool> {
+        let compares = txn
+            .compares
+            .into_iter()
+            .map(|compare| match compare {
+                TxnCompare::Value(key, value) => protobuf::TxnCompare {
+                    key,
+                    absent: false,
+                    value,
+                },
+                TxnCompare::Absent(key) => protobuf::TxnCompare {
+                    key,
+                    absent: true,
+                    value: vec![],
+                },
+            })
+            .collect();
+        let ops = txn
+            .ops
+            .into_iter()
+            .map(|op| match op {
+                TxnOp::Put(key, value) => protobuf::TxnOp {
+                    key,
+                    delete: false,
+                    value,
+                },
+                TxnOp::Delete(key) => protobuf::TxnOp {
+                    key,
+                    delete: true,
+                    value: vec![],
+                },
+            })
+            .collect();
+        self.propose(Command {
+            command_type: Some(CommandType::Txn(protobuf::Txn { compares, ops })),
+        })
+        .await
+    }
+
+    async fn lock(&self, key: &str, ttl: Duration) -> Result<Box<dyn Lock>> {
+        let owner = new_lock_owner();
+        loop {
+            match self.propose(acquire_lock(key, &owner, ttl)).await {
+                Ok(true) => {
+                    return Ok(Box::new(RaftLock {
+                        client: self.clone(),
+                        key: key.to_owned(),
+                        owner,
+                    }))
+                }
+                // Wait for the holder to release the lock or for it to expire
+                Ok(false) => {
+                    self.node
+                        .wait_applied(self.node.config().heartbeat_interval)
+                        .await
+                }
+                Err(e) => {
+                    // The proposal may still be applied after the timeout
+                    let _ = self.propose(release_lock(key, &owner)).await;
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    async fn lock_holders(&self) -> Result<Vec<LockHolder>> {
+        let now = now_millis();
+        Ok(self
+            .node
+            .locks()
+            .into_iter()
+            .filter(|lock| lock.expires_at > now)
+            .map(|lock| LockHolder {
+                key: lock.key,
+                owner: lock.owner,
+                acquired_at: lock.acquired_at,
+                expires_at: lock.expires_at,
+            })
+            .collect())
+    }
+
+    async fn watch(&self, prefix: String) -> Result<Box<dyn Watch>> {
+        Ok(Box::new(RaftWatch {
+            receiver: self.node.watch(prefix),
+        }))
+    }
+}
+
+struct RaftLock {
+    client: RaftClient,
+    key: String,
+    owner: String,
+}
+
+#[tonic::async_trait]
+impl Lock for RaftLock {
+    async fn unlock(&mut self) {
+        let release = release_lock(&self.key, &self.owner);
+        if let Err(e) = self.client.propose(release).await {
+            warn!(
+                "Fail to release the raft lock {} of {}: {}",
+                self.key, self.owner, e
+            );
+        }
+    }
+}
+
+struct RaftWatch {
+    receiver: UnboundedReceiver<WatchEvent>,
+}
+
+#[tonic::async_trait]
+impl Watch for RaftWatch {
+    async fn cancel(&mut self) -> Result<()> {
+        self.receiver.close();
+        Ok(())
+    }
+}
+
+impl Stream for RaftWatch {
+    type Item = WatchEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().receiver.poll_recv(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::{HashMap, HashSet};
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use futures::StreamExt;
+    use hetu_core::error::{BallistaError, Result};
+    use parking_lot::RwLock;
+    use tonic::{Code, Request};
+
+    use crate::config::Config;
+    use crate::state::backend::raft::node::RaftNode;
+    use crate::state::backend::raft::protobuf::raft_service_server::RaftService;
+    use crate::state::backend::raft::protobuf::read_request::Query;
+    use crate::state::backend::raft::protobuf::{
+        AppendEntriesRequest, AppendEntriesResponse, InstallSnapshotRequest,
+        InstallSnapshotResponse, ProposeRequest, ProposeResponse, ReadRequest,
+        ReadResponse, RequestVoteRequest, RequestVoteResponse,
+    };
+    use crate::state::backend::raft::transport::RaftTransport;
+    use crate::state::backend::raft::{RaftClient, RaftConfig, RaftGrpcService};
+    use crate::state::backend::{StateBackendClient, Txn, TxnCompare, WatchEvent};
+
+    /// In-process network between the nodes, which can be partitioned
+    #[derive(Default)]
+    struct LocalNetwork {
+        nodes: RwLock<HashMap<u64, Arc<RaftNode>>>,
+        disconnected: RwLock<HashSet<u64>>,
+    }
+
+    impl LocalNetwork {
+        fn disconnect(&self, id: u64) {
+            self.disconnected.write().insert(id);
+        }
+
+        fn connect(&self, id: u64) {
+            self.disconnected.write().remove(&id);
+        }
+    }
+
+    struct LocalTransport {
+        id: u64,
+        network: Arc<LocalNetwork>,
+    }
+
+    impl LocalTransport {
+        fn node(&self, target: u64) -> Result<Arc<RaftNode>> {
+            let disconnected = self.network.disconnected.read();
+            if disconnected.contains(&self.id) || disconnected.contains(&target) {
+                return Err(BallistaError::General("Disconnected".to_owned()));
+            }
+            self.network
+                .nodes
+                .read()
+                .get(&target)
+                .cloned()
+                .ok_or_else(|| BallistaError::General("Unknown node".to_owned()))
+        }
+    }
+
+    #[tonic::async_trait]
+    impl RaftTransport for LocalTransport {
+        async fn request_vote(
+            &self,
+            target: u64,
+            request: RequestVoteRequest,
+        ) -> Result<RequestVoteResponse> {
+            self.node(target)?.handle_request_vote(request).await
+        }
+
+        async fn append_entries(
+            &self,
+            target: u64,
+            request: AppendEntriesRequest,
+        ) -> Result<AppendEntriesResponse> {
+            self.node(target)?.handle_append_entries(request).await
+        }
+
+        async fn install_snapshot(
+            &self,
+            target: u64,
+            request: InstallSnapshotRequest,
+        ) -> Result<InstallSnapshotResponse> {
+            self.node(target)?.handle_install_snapshot(request).await
+        }
+
+        async fn propose(
+            &self,
+            target: u64,
+            request: ProposeRequest,
+        ) -> Result<ProposeResponse> {
+            Ok(self.node(target)?.handle_propose(request).await)
+        }
+
+        async fn read(&self, target: u64, request: ReadRequest) -> Result<ReadResponse> {
+            Ok(self.node(target)?.handle_read(request).await)
+        }
+    }
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("hetu-raft-test-{}", uuid::Uuid::new_v4()))
+    }
+
+    fn test_config(node_id: u64, num_nodes: u64, data_dir: PathBuf) -> RaftConfig {
+        let peers = (1..=num_nodes)
+            .filter(|id| *id != node_id)
+            .map(|id| (id, format!("node{}", id)))
+            .collect();
+        RaftConfig::new(node_id, peers, data_dir)
+            .with_heartbeat_interval(Duration::from_millis(20))
+            .with_election_timeout(Duration::from_millis(150), Duration::from_millis(300))
+    }
+
+    fn start_node(network: &Arc<LocalNetwork>, config: RaftConfig) -> RaftClient {
+        let id = config.node_id;
+        let transport = Arc::new(LocalTransport {
+            id,
+            network: network.clone(),
+        });
+        let client = RaftClient::start_with_transport(config, transport).unwrap();
+        network.nodes.write().insert(id, client.node.clone());
+        client
+    }
+
+    fn start_cluster(
+        num_nodes: u64,
+        snapshot_threshold: u64,
+    ) -> (Arc<LocalNetwork>, Vec<RaftClient>, PathBuf) {
+        let dir = temp_dir();
+        let network = Arc::new(LocalNetwork::default());
+        let clients = (1..=num_nodes)
+            .map(|id| {
+                let config = test_config(id, num_nodes, dir.join(id.to_string()))
+                    .with_snapshot_threshold(snapshot_threshold);
+                start_node(&network, config)
+            })
+            .collect();
+        (network, clients, dir)
+    }
+
+    async fn wait_for_leader(network: &LocalNetwork, clients: &[RaftClient]) -> usize {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while Instant::now() < deadline {
+            let disconnected = network.disconnected.read().clone();
+            if let Some(idx) = clients.iter().position(|client| {
+                client.is_leader() && !disconnected.contains(&client.node.id())
+            }) {
+                return idx;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("No raft leader is elected");
+    }
+
+    #[tokio::test]
+    async fn test_replicated_put_get() {
+        let (network, clients, dir) = start_cluster(3, 10000);
+        let leader = wait_for_leader(&network, &clients).await;
+        let follower = &clients[(leader + 1) % clients.len()];
+
+        follower
+            .put("/a/1".to_owned(), b"1".to_vec())
+            .await
+            .unwrap();
+        clients[leader]
+            .put("/a/2".to_owned(), b"2".to_vec())
+            .await
+            .unwrap();
+        for client in clients.iter() {
+            assert_eq!(client.get("/a/1").await.unwrap(), b"1".to_vec());
+            assert_eq!(client.get_from_prefix("/a/").await.unwrap().len(), 2);
+            assert!(client.get("/b").await.unwrap().is_empty());
+        }
+
+        clients.iter().for_each(RaftClient::stop);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn test_delete_and_txn() {
+        let (network, clients, dir) = start_cluster(3, 10000);
+        wait_for_leader(&network, &clients).await;
+
+        clients[0]
+            .put("/a/1".to_owned(), b"1".to_vec())
+            .await
+            .unwrap();
+        clients[0]
+            .put("/a/2".to_owned(), b"2".to_vec())
+            .await
+            .unwrap();
+        clients[1].delete("/a/1").await.unwrap();
+        assert!(clients[2].get("/a/1").await.unwrap().is_empty());
+        clients[1].delete_prefix("/a/").await.unwrap();
+        assert!(clients[2].get_from_prefix("/a/").await.unwrap().is_empty());
+
+        assert!(clients[0]
+            .compare_and_put("/b", None, b"1".to_vec())
+            .await
+            .unwrap());
+        assert!(!clients[1]
+            .compare_and_put("/b", None, b"2".to_vec())
+            .await
+            .unwrap());
+        let txn = Txn::new()
+            .when(TxnCompare::Value("/b".to_owned(), b"1".to_vec()))
+            .put("/c".to_owned(), b"3".to_vec())
+            .delete("/b".to_owned());
+        assert!(clients[2].txn(txn).await.unwrap());
+        assert!(clients[0].get("/b").await.unwrap().is_empty());
+        assert_eq!(clients[0].get("/c").await.unwrap(), b"3".to_vec());
+
+        clients.iter().for_each(RaftClient::stop);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn test_leader_failover() {
+        let (network, clients, dir) = start_cluster(3, 10000);
+        let leader = wait_for_leader(&network, &clients).await;
+        clients[leader]
+            .put("key".to_owned(), b"1".to_vec())
+            .await
+            .unwrap();
+
+        network.disconnect(clients[leader].node.id());
+        let new_leader = wait_for_leader(&network, &clients).await;
+        assert_ne!(leader, new_leader);
+        clients[new_leader]
+            .put("key".to_owned(), b"2".to_vec())
+            .await
+            .unwrap();
+
+        // The old leader steps down and reads the latest value once it is reconnected
+        network.connect(clients[leader].node.id());
+        assert_eq!(clients[leader].get("key").await.unwrap(), b"2".to_vec());
+
+        clients.iter().for_each(RaftClient::stop);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn test_watch_on_follower() {
+        let (network, clients, dir) = start_cluster(3, 10000);
+        let leader = wait_for_leader(&network, &clients).await;
+        let follower = &clients[(leader + 1) % clients.len()];
+
+        let mut watch = follower.watch("/jobs/".to_owned()).await.unwrap();
+        clients[leader]
+            .put("/other".to_owned(), b"0".to_vec())
+            .await
+            .unwrap();
+        clients[leader]
+            .put("/jobs/1".to_owned(), b"1".to_vec())
+            .await
+            .unwrap();
+        let event = tokio::time::timeout(Duration::from_secs(5), watch.next())
+            .await
+            .unwrap();
+        assert_eq!(
+            event,
+            Some(WatchEvent::Put("/jobs/1".to_owned(), b"1".to_vec()))
+        );
+
+        clients.iter().for_each(RaftClient::stop);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn test_lock() {
+        let (network, clients, dir) = start_cluster(3, 10000);
+        wait_for_leader(&network, &clients).await;
+
+        let ttl = Duration::from_secs(30);
+        let mut lock = clients[0].lock("a", ttl).await.unwrap();
+        // Another key is not blocked
+        let mut other = clients[1].lock("b", ttl).await.unwrap();
+        other.unlock().await;
+
+        let client = clients[1].clone();
+        let mut waiter = tokio::spawn(async move { client.lock("a", ttl).await });
+        assert!(
+            tokio::time::timeout(Duration::from_millis(300), &mut waiter)
+                .await
+                .is_err()
+        );
+
+        lock.unlock().await;
+        let mut lock = tokio::time::timeout(Duration::from_secs(5), waiter)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        lock.unlock().await;
+
+        // A lock which is never released is taken over once it expires
+        let _lock = clients[0]
+            .lock("c", Duration::from_millis(200))
+            .await
+            .unwrap();
+        let mut lock =
+            tokio::time::timeout(Duration::from_secs(5), clients[1].lock("c", ttl))
+                .await
+                .unwrap()
+                .unwrap();
+        lock.unlock().await;
+
+        clients.iter().for_each(RaftClient::stop);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_restart() {
+        let (network, clients, dir) = start_cluster(1, 10);
+        wait_for_leader(&network, &clients).await;
+        for i in 0..25 {
+            clients[0]
+                .put(format!("/key/{:02}", i), vec![i as u8])
+                .await
+                .unwrap();
+        }
+        clients[0].stop();
+        assert!(dir.join("1").join("snapshot").exists());
+
+        // Restart from the snapshot and the log after it
+        let network = Arc::new(LocalNetwork::default());
+        let client = start_node(&network, test_config(1, 1, dir.join("1")));
+        let values = client.get_from_prefix("/key/").await.unwrap();
+        assert_eq!(values.len(), 25);
+        assert_eq!(values[24], ("/key/24".to_owned(), vec![24]));
+
+        client.stop();
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn test_install_snapshot_on_lagging_follower() {
+        let (network, clients, dir) = start_cluster(3, 10);
+        let leader = wait_for_leader(&network, &clients).await;
+        let lagging = &clients[(leader + 1) % clients.len()];
+        let mut watch = lagging.watch("/key/".to_owned()).await.unwrap();
+
+        network.disconnect(lagging.node.id());
+        let leader = wait_for_leader(&network, &clients).await;
+        for i in 0..25 {
+            clients[leader]
+                .put(format!("/key/{:02}", i), vec![i as u8])
+                .await
+                .unwrap();
+        }
+        network.connect(lagging.node.id());
+
+        // The compacted entries reach the follower through a snapshot
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let mut keys = HashSet::new();
+        while keys.len() < 25 && Instant::now() < deadline {
+            if let Ok(Some(WatchEvent::Put(key, _))) =
+                tokio::time::timeout(Duration::from_millis(100), watch.next()).await
+            {
+                keys.insert(key);
+            }
+        }
+        assert_eq!(keys.len(), 25);
+
+        clients.iter().for_each(RaftClient::stop);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn test_auth_token() {
+        let dir = temp_dir();
+        let network = Arc::new(LocalNetwork::default());
+        let client = start_node(
+            &network,
+            test_config(1, 1, dir.clone()).with_auth_token("secret"),
+        );
+        let service = RaftGrpcService::new(client.node.clone());
+        let read = || ReadRequest {
+            query: Some(Query::Key("/a".to_owned())),
+        };
+
+        let status = service
+            .read(Request::new(read()))
+            .await
+            .expect_err("Unauthenticated node is accepted");
+        assert_eq!(status.code(), Code::Unauthenticated);
+        let mut request = Request::new(read());
+        request
+            .metadata_mut()
+            .insert("authorization", "Bearer secreT".parse().unwrap());
+        let status = service
+            .read(request)
+            .await
+            .expect_err("Unauthenticated node is accepted");
+        assert_eq!(status.code(), Code::Unauthenticated);
+
+        let mut request = Request::new(read());
+        request
+            .metadata_mut()
+            .insert("authorization", "Bearer secret".parse().unwrap());
+        service
+            .read(request)
+            .await
+            .expect("Authenticated node is rejected");
+
+        client.stop();
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_auth_token_required_with_peers() {
+        let conf = Config {
+            raft_node_id: 1,
+            raft_peers: "1=a:50060,2=b:50060".to_owned(),
+            raft_data_dir: "/tmp".to_owned(),
+            ..Default::default()
+        };
+        assert!(RaftConfig::try_from(&conf).is_err());
+        let conf = Config {
+            raft_auth_token: "secret".to_owned(),
+            ..conf
+        };
+        let config = RaftConfig::try_from(&conf).unwrap();
+        assert_eq!(config.auth_token, Some("secret".to_owned()));
+        assert!(!format!("{:?}", config).contains("secret"));
+    }
+}
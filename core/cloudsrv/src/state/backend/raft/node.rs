@@ -0,0 +1,1034 @@
+// Copyright 2021 HetuDB.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Consensus of a raft node: leader election, log replication, snapshots and
+//! linearizable reads through the read index of the leader.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use hetu_core::error::{BallistaError, Result};
+use log::{debug, error, info, warn};
+use parking_lot::Mutex;
+use rand::Rng;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::{oneshot, Notify};
+
+use crate::state::backend::raft::protobuf::{
+    command::CommandType, read_request::Query, AppendEntriesRequest,
+    AppendEntriesResponse, Command, HardState, InstallSnapshotRequest,
+    InstallSnapshotResponse, KeyValue, LockState, LogEntry, Noop, ProposeRequest,
+    ProposeResponse, ReadRequest, ReadResponse, RequestVoteRequest, RequestVoteResponse,
+    Snapshot,
+};
+use crate::state::backend::raft::raft_log::RaftLog;
+use crate::state::backend::raft::state_machine::StateMachine;
+use crate::state::backend::raft::storage::{RaftStorage, StorageWrite, StorageWriter};
+use crate::state::backend::raft::transport::RaftTransport;
+use crate::state::backend::raft::RaftConfig;
+use crate::state::backend::WatchEvent;
+
+/// Error of a client request to a raft node
+#[derive(Debug)]
+pub(crate) enum RaftError {
+    /// The request has to be sent to the leader, 0 if the leader is unknown
+    NotLeader(u64),
+    Failed(BallistaError),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+enum ReplicationMessage {
+    AppendEntries(AppendEntriesRequest),
+    InstallSnapshot(InstallSnapshotRequest),
+}
+
+type PendingProposal = (u64, oneshot::Sender<std::result::Result<bool, RaftError>>);
+
+struct RaftCore {
+    role: Role,
+    current_term: u64,
+    // 0 if the node did not vote in the current term
+    voted_for: u64,
+    // 0 if the leader is unknown
+    leader_id: u64,
+    log: RaftLog,
+    // The latest snapshot, sent to the followers lagging behind the compacted log
+    snapshot: Option<Snapshot>,
+    commit_index: u64,
+    last_applied: u64,
+    state_machine: StateMachine,
+    storage: StorageWriter,
+    election_deadline: Instant,
+    votes: HashSet<u64>,
+    next_index: HashMap<u64, u64>,
+    match_index: HashMap<u64, u64>,
+    // Peers with a replication in flight
+    replicating: HashSet<u64>,
+    // log index -> term and result sender of the proposals waiting to be applied
+    pending: HashMap<u64, PendingProposal>,
+}
+
+impl RaftCore {
+    fn persist_hard_state(&mut self) -> Result<()> {
+        self.storage.submit(StorageWrite::HardState(HardState {
+            current_term: self.current_term,
+            voted_for: self.voted_for,
+        }))
+    }
+
+    fn reset_election_deadline(&mut self, config: &RaftConfig) {
+        let min = config.election_timeout_min.as_millis() as u64;
+        let max = std::cmp::max(config.election_timeout_max.as_millis() as u64, min + 1);
+        let timeout = rand::thread_rng().gen_range(min..max);
+        self.election_deadline = Instant::now() + Duration::from_millis(timeout);
+    }
+
+    /// Follow the leader of the term, the term is persisted if it is a new one
+    fn become_follower(&mut self, term: u64, leader_id: u64) -> Result<()> {
+        if term > self.current_term {
+            self.current_term = term;
+            self.voted_for = 0;
+            self.persist_hard_state()?;
+        }
+        if self.role != Role::Follower {
+            self.role = Role::Follower;
+            self.fail_pending(leader_id);
+        }
+        self.leader_id = leader_id;
+        Ok(())
+    }
+
+    fn become_leader(&mut self, id: u64, peers: &[u64]) -> Result<()> {
+        info!(
+            "Raft node {} becomes leader of term {}",
+            id, self.current_term
+        );
+        self.role = Role::Leader;
+        self.leader_id = id;
+        let next_index = self.log.last_index() + 1;
+        for peer in peers {
+            self.next_index.insert(*peer, next_index);
+            self.match_index.insert(*peer, 0);
+        }
+        // Entries of the previous terms are committed along with an entry of this term
+        self.append_local(Command {
+            command_type: Some(CommandType::Noop(Noop {})),
+        })?;
+        Ok(())
+    }
+
+    fn fail_pending(&mut self, leader_id: u64) {
+        for (_, (_, sender)) in self.pending.drain() {
+            let _ = sender.send(Err(RaftError::NotLeader(leader_id)));
+        }
+    }
+
+    fn append_local(&mut self, command: Command) -> Result<u64> {
+        let entry = LogEntry {
+            term: self.current_term,
+            index: self.log.last_index() + 1,
+            command: Some(command),
+        };
+        self.storage
+            .submit(StorageWrite::Entries(vec![entry.clone()]))?;
+        let index = entry.index;
+        self.log.append(entry);
+        Ok(index)
+    }
+
+    /// Commit the entries of the current term replicated on a majority
+    fn advance_commit_index(&mut self, quorum: usize) {
+        let mut index = self.log.last_index();
+        while index > self.commit_index {
+            if self.log.term_at(index) != Some(self.current_term) {
+                break;
+            }
+            // The entry of the leader counts once it is on its disk
+            let persisted = usize::from(self.storage.persisted_index() >= index);
+            let replicas = persisted
+                + self
+                    .match_index
+                    .values()
+                    .filter(|match_index| **match_index >= index)
+                    .count();
+            if replicas >= quorum {
+                self.commit_index = index;
+                break;
+            }
+            index -= 1;
+        }
+    }
+
+    /// Apply the committed entries, return whether any entry is applied
+    fn apply_committed(&mut self) -> bool {
+        let mut applied = false;
+        while self.last_applied < self.commit_index {
+            let index = self.last_applied + 1;
+            let (term, result) = match self.log.get(index) {
+                Some(entry) => (
+                    entry.term,
+                    entry
+                        .command
+                        .as_ref()
+                        .map(|command| self.state_machine.apply(command))
+                        .unwrap_or(true),
+                ),
+                None => break,
+            };
+            self.last_applied = index;
+            applied = true;
+            if let Some((proposed_term, sender)) = self.pending.remove(&index) {
+                let result = if proposed_term == term {
+                    Ok(result)
+                } else {
+                    Err(RaftError::NotLeader(self.leader_id))
+                };
+                let _ = sender.send(result);
+            }
+        }
+        applied
+    }
+
+    /// Take a snapshot once enough entries are applied after the previous one
+    fn maybe_snapshot(&mut self, threshold: u64) -> Result<()> {
+        if self.last_applied < self.log.snapshot_index() + threshold {
+            return Ok(());
+        }
+        let term = self.log.term_at(self.last_applied).unwrap_or_default();
+        let snapshot = self.state_machine.snapshot(self.last_applied, term);
+        self.storage.submit(StorageWrite::Snapshot {
+            snapshot: snapshot.clone(),
+            keep_entries: true,
+        })?;
+        self.log.compact(self.last_applied, term);
+        debug!("Raft snapshot taken at index {}", self.last_applied);
+        self.snapshot = Some(snapshot);
+        Ok(())
+    }
+
+    fn build_append_entries(
+        &self,
+        id: u64,
+        peer: u64,
+        max_entries: usize,
+    ) -> ReplicationMessage {
+        let next_index = self
+            .next_index
+            .get(&peer)
+            .cloned()
+            .unwrap_or(self.log.last_index() + 1);
+        if next_index <= self.log.snapshot_index() {
+            if let Some(snapshot) = self.snapshot.as_ref() {
+                return ReplicationMessage::InstallSnapshot(InstallSnapshotRequest {
+                    term: self.current_term,
+                    leader_id: id,
+                    snapshot: Some(snapshot.clone()),
+                });
+            }
+        }
+        let next_index = std::cmp::max(next_index, self.log.snapshot_index() + 1);
+        let prev_log_index = next_index - 1;
+        ReplicationMessage::AppendEntries(AppendEntriesRequest {
+            term: self.current_term,
+            leader_id: id,
+            prev_log_index,
+            prev_log_term: self.log.term_at(prev_log_index).unwrap_or_default(),
+            entries: self.log.entries_from(next_index, max_entries),
+            leader_commit: self.commit_index,
+        })
+    }
+
+    fn read(&self, query: &Query) -> Vec<(String, Vec<u8>)> {
+        match query {
+            Query::Key(key) => self
+                .state_machine
+                .get(key)
+                .map(|value| vec![(key.clone(), value)])
+                .unwrap_or_default(),
+            Query::Prefix(prefix) => self.state_machine.get_from_prefix(prefix),
+        }
+    }
+}
+
+/// A member of a raft group with a static membership.
+pub(crate) struct RaftNode {
+    id: u64,
+    peers: Vec<u64>,
+    config: RaftConfig,
+    core: Mutex<RaftCore>,
+    transport: Arc<dyn RaftTransport>,
+    // Notified whenever entries are applied
+    applied: Notify,
+    stopped: AtomicBool,
+}
+
+impl RaftNode {
+    /// Load the state of the node from its data directory
+    pub(crate) fn open(
+        config: RaftConfig,
+        transport: Arc<dyn RaftTransport>,
+    ) -> Result<Self> {
+        if config.node_id == 0 {
+            return Err(BallistaError::General(
+                "The raft node id should be positive".to_owned(),
+            ));
+        }
+        let (storage, persisted) = RaftStorage::open(&config.data_dir)?;
+
+        let mut state_machine = StateMachine::default();
+        let (snapshot_index, snapshot_term) = match persisted.snapshot.as_ref() {
+            Some(snapshot) => {
+                state_machine.restore(snapshot);
+                (snapshot.last_included_index, snapshot.last_included_term)
+            }
+            None => (0, 0),
+        };
+        let log = RaftLog::new(snapshot_index, snapshot_term, persisted.entries);
+        info!(
+            "Raft node {} starts at term {} with the log up to {}",
+            config.node_id,
+            persisted.hard_state.current_term,
+            log.last_index()
+        );
+
+        let mut core = RaftCore {
+            role: Role::Follower,
+            current_term: persisted.hard_state.current_term,
+            voted_for: persisted.hard_state.voted_for,
+            leader_id: 0,
+            log,
+            snapshot: persisted.snapshot,
+            commit_index: snapshot_index,
+            last_applied: snapshot_index,
+            state_machine,
+            storage: StorageWriter::start(storage, config.node_id)?,
+            election_deadline: Instant::now(),
+            votes: HashSet::new(),
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            replicating: HashSet::new(),
+            pending: HashMap::new(),
+        };
+        core.reset_election_deadline(&config);
+
+        let mut peers: Vec<u64> = config.peers.keys().cloned().collect();
+        peers.sort_unstable();
+        Ok(Self {
+            id: config.node_id,
+            peers,
+            config,
+            core: Mutex::new(core),
+            transport,
+            applied: Notify::new(),
+            stopped: AtomicBool::new(false),
+        })
+    }
+
+    pub(crate) fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// The leader as far as this node knows, 0 if it is unknown
+    pub(crate) fn leader_id(&self) -> u64 {
+        self.core.lock().leader_id
+    }
+
+    pub(crate) fn is_leader(&self) -> bool {
+        self.core.lock().role == Role::Leader
+    }
+
+    pub(crate) fn config(&self) -> &RaftConfig {
+        &self.config
+    }
+
+    pub(crate) fn transport(&self) -> &Arc<dyn RaftTransport> {
+        &self.transport
+    }
+
+    pub(crate) fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+
+    fn quorum(&self) -> usize {
+        (self.peers.len() + 1) / 2 + 1
+    }
+
+    /// Drive the elections and the heartbeats until the node is stopped
+    pub(crate) async fn run(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(self.config.heartbeat_interval);
+        loop {
+            interval.tick().await;
+            if self.stopped.load(Ordering::SeqCst) {
+                info!("Raft node {} stopped", self.id);
+                return;
+            }
+            let (role, election_timeout) = {
+                let mut core = self.core.lock();
+                if core.role == Role::Leader {
+                    // Commit the entries which reached the disk of the leader last
+                    self.advance_and_apply(&mut core);
+                }
+                (core.role, Instant::now() >= core.election_deadline)
+            };
+            if role == Role::Leader {
+                self.broadcast_append_entries();
+            } else if election_timeout {
+                self.start_election().await;
+            }
+        }
+    }
+
+    async fn start_election(self: &Arc<Self>) {
+        let (request, flushed) = {
+            let mut core = self.core.lock();
+            core.role = Role::Candidate;
+            core.current_term += 1;
+            core.voted_for = self.id;
+            core.leader_id = 0;
+            core.votes = HashSet::from([self.id]);
+            core.reset_election_deadline(&self.config);
+            if let Err(e) = core.persist_hard_state() {
+                error!("Raft node {} fail to persist its vote: {}", self.id, e);
+                return;
+            }
+            debug!(
+                "Raft node {} starts an election for term {}",
+                self.id, core.current_term
+            );
+            if core.votes.len() >= self.quorum() {
+                if let Err(e) = core.become_leader(self.id, &self.peers) {
+                    error!("Raft node {} fail to become leader: {}", self.id, e);
+                }
+                self.advance_and_apply(&mut core);
+                return;
+            }
+            (
+                RequestVoteRequest {
+                    term: core.current_term,
+                    candidate_id: self.id,
+                    last_log_index: core.log.last_index(),
+                    last_log_term: core.log.last_term(),
+                },
+                core.storage.flushed(),
+            )
+        };
+        // The node must not vote twice in the term if it restarts
+        if let Err(e) = flushed.wait().await {
+            error!("Raft node {} fail to persist its vote: {}", self.id, e);
+            return;
+        }
+
+        for peer in self.peers.clone() {
+            let node = self.clone();
+            let request = request.clone();
+            tokio::spawn(async move {
+                match node.transport.request_vote(peer, request.clone()).await {
+                    Ok(response) => node.on_vote_response(peer, &request, response),
+                    Err(e) => {
+                        debug!("Fail to request the vote of raft node {}: {}", peer, e)
+                    }
+                }
+            });
+        }
+    }
+
+    fn on_vote_response(
+        self: &Arc<Self>,
+        peer: u64,
+        request: &RequestVoteRequest,
+        response: RequestVoteResponse,
+    ) {
+        let elected = {
+            let mut core = self.core.lock();
+            if response.term > core.current_term {
+                if let Err(e) = core.become_follower(response.term, 0) {
+                    error!("Raft node {} fail to step down: {}", self.id, e);
+                }
+                false
+            } else if core.role == Role::Candidate
+                && core.current_term == request.term
+                && response.vote_granted
+            {
+                core.votes.insert(peer);
+                if core.votes.len() >= self.quorum() {
+                    match core.become_leader(self.id, &self.peers) {
+                        Ok(()) => true,
+                        Err(e) => {
+                            error!("Raft node {} fail to become leader: {}", self.id, e);
+                            false
+                        }
+                    }
+                } else {
+                    false
+                }
+            } else {
+                false
+            }
+        };
+        if elected {
+            self.broadcast_append_entries();
+        }
+    }
+
+    fn broadcast_append_entries(self: &Arc<Self>) {
+        for peer in self.peers.iter() {
+            tokio::spawn(self.clone().replicate_to(*peer));
+        }
+    }
+
+    /// Send the missing entries to a peer until it catches up with the log of the leader
+    async fn replicate_to(self: Arc<Self>, peer: u64) {
+        {
+            let mut core = self.core.lock();
+            if core.role != Role::Leader || !core.replicating.insert(peer) {
+                return;
+            }
+        }
+
+        loop {
+            let (term, message) = {
+                let core = self.core.lock();
+                if core.role != Role::Leader {
+                    break;
+                }
+                (
+                    core.current_term,
+                    core.build_append_entries(
+                        self.id,
+                        peer,
+                        self.config.max_entries_per_request,
+                    ),
+                )
+            };
+
+            let more = match message {
+                ReplicationMessage::AppendEntries(request) => {
+                    match self.transport.append_entries(peer, request.clone()).await {
+                        Ok(response) => self.on_append_response(peer, &request, response),
+                        Err(e) => {
+                            debug!("Fail to replicate to raft node {}: {}", peer, e);
+                            false
+                        }
+                    }
+                }
+                ReplicationMessage::InstallSnapshot(request) => {
+                    let snapshot_index = request
+                        .snapshot
+                        .as_ref()
+                        .map(|snapshot| snapshot.last_included_index)
+                        .unwrap_or_default();
+                    match self.transport.install_snapshot(peer, request).await {
+                        Ok(response) => self.on_snapshot_response(
+                            peer,
+                            term,
+                            snapshot_index,
+                            response,
+                        ),
+                        Err(e) => {
+                            debug!(
+                                "Fail to send the snapshot to raft node {}: {}",
+                                peer, e
+                            );
+                            false
+                        }
+                    }
+                }
+            };
+            if !more {
+                break;
+            }
+        }
+
+        self.core.lock().replicating.remove(&peer);
+    }
+
+    /// Handle the response of a peer, return whether more entries should be sent
+    fn on_append_response(
+        &self,
+        peer: u64,
+        request: &AppendEntriesRequest,
+        response: AppendEntriesResponse,
+    ) -> bool {
+        let mut guard = self.core.lock();
+        let core = &mut *guard;
+        if response.term > core.current_term {
+            if let Err(e) = core.become_follower(response.term, 0) {
+                error!("Raft node {} fail to step down: {}", self.id, e);
+            }
+            return false;
+        }
+        if core.role != Role::Leader || core.current_term != request.term {
+            return false;
+        }
+
+        if response.success {
+            let match_index = core.match_index.entry(peer).or_insert(0);
+            *match_index = std::cmp::max(*match_index, response.match_index);
+            let match_index = *match_index;
+            let next_index = core.next_index.entry(peer).or_insert(1);
+            *next_index = std::cmp::max(*next_index, match_index + 1);
+            let more = *next_index <= core.log.last_index();
+            self.advance_and_apply(core);
+            more
+        } else {
+            // The follower tells from which index its log may differ
+            let next_index = core.next_index.entry(peer).or_insert(1);
+            *next_index =
+                std::cmp::max(std::cmp::min(*next_index, response.match_index), 1);
+            true
+        }
+    }
+
+    fn on_snapshot_response(
+        &self,
+        peer: u64,
+        term: u64,
+        snapshot_index: u64,
+        response: InstallSnapshotResponse,
+    ) -> bool {
+        let mut guard = self.core.lock();
+        let core = &mut *guard;
+        if response.term > core.current_term {
+            if let Err(e) = core.become_follower(response.term, 0) {
+                error!("Raft node {} fail to step down: {}", self.id, e);
+            }
+            return false;
+        }
+        if core.role != Role::Leader || core.current_term != term {
+            return false;
+        }
+        let match_index = core.match_index.entry(peer).or_insert(0);
+        *match_index = std::cmp::max(*match_index, snapshot_index);
+        let next_index = core.next_index.entry(peer).or_insert(1);
+        *next_index = std::cmp::max(*next_index, snapshot_index + 1);
+        let more = *next_index <= core.log.last_index();
+        self.advance_and_apply(core);
+        more
+    }
+
+    fn advance_and_apply(&self, core: &mut RaftCore) {
+        if core.role == Role::Leader {
+            core.advance_commit_index(self.quorum());
+        }
+        self.apply(core);
+    }
+
+    fn apply(&self, core: &mut RaftCore) {
+        if core.apply_committed() {
+            if let Err(e) = core.maybe_snapshot(self.config.snapshot_threshold) {
+                warn!("Raft node {} fail to take a snapshot: {}", self.id, e);
+            }
+            self.applied.notify_waiters();
+        }
+    }
+
+    pub(crate) async fn handle_request_vote(
+        &self,
+        request: RequestVoteRequest,
+    ) -> Result<RequestVoteResponse> {
+        let (response, flushed) = {
+            let mut core = self.core.lock();
+            let response = self.request_vote(&mut core, request)?;
+            (response, core.storage.flushed())
+        };
+        // The vote and the term are persisted before they are told
+        flushed.wait().await?;
+        Ok(response)
+    }
+
+    fn request_vote(
+        &self,
+        core: &mut RaftCore,
+        request: RequestVoteRequest,
+    ) -> Result<RequestVoteResponse> {
+        if request.term > core.current_term {
+            core.become_follower(request.term, 0)?;
+        }
+        let up_to_date = (request.last_log_term, request.last_log_index)
+            >= (core.log.last_term(), core.log.last_index());
+        let vote_granted = request.term == core.current_term
+            && (core.voted_for == 0 || core.voted_for == request.candidate_id)
+            && up_to_date;
+        if vote_granted {
+            core.voted_for = request.candidate_id;
+            core.persist_hard_state()?;
+            core.reset_election_deadline(&self.config);
+        }
+        Ok(RequestVoteResponse {
+            term: core.current_term,
+            vote_granted,
+        })
+    }
+
+    pub(crate) async fn handle_append_entries(
+        &self,
+        request: AppendEntriesRequest,
+    ) -> Result<AppendEntriesResponse> {
+        let (response, flushed) = {
+            let mut core = self.core.lock();
+            let response = self.append_entries(&mut core, request)?;
+            (response, core.storage.flushed())
+        };
+        // The entries are acknowledged once they are persisted
+        flushed.wait().await?;
+        Ok(response)
+    }
+
+    fn append_entries(
+        &self,
+        core: &mut RaftCore,
+        request: AppendEntriesRequest,
+    ) -> Result<AppendEntriesResponse> {
+        if request.term < core.current_term {
+            return Ok(AppendEntriesResponse {
+                term: core.current_term,
+                success: false,
+                match_index: 0,
+            });
+        }
+        core.become_follower(request.term, request.leader_id)?;
+        core.reset_election_deadline(&self.config);
+
+        let prev_log_index = request.prev_log_index;
+        let consistent = if prev_log_index > core.log.last_index() {
+            false
+        } else if prev_log_index >= core.log.snapshot_index() {
+            core.log.term_at(prev_log_index) == Some(request.prev_log_term)
+        } else {
+            // The entries covered by the snapshot are committed
+            true
+        };
+        if !consistent {
+            // The committed entries always match the log of the leader
+            let next_index =
+                std::cmp::min(core.commit_index + 1, std::cmp::max(prev_log_index, 1));
+            return Ok(AppendEntriesResponse {
+                term: core.current_term,
+                success: false,
+                match_index: next_index,
+            });
+        }
+
+        let last_new_index = prev_log_index + request.entries.len() as u64;
+        let mut new_entries = vec![];
+        let mut truncated_from = None;
+        for entry in request.entries {
+            if entry.index <= core.log.snapshot_index() {
+                continue;
+            }
+            match core.log.term_at(entry.index) {
+                Some(term) if term == entry.term => continue,
+                Some(_) => {
+                    core.log.truncate_from(entry.index);
+                    truncated_from = Some(entry.index);
+                }
+                None => {}
+            }
+            core.log.append(entry.clone());
+            new_entries.push(entry);
+        }
+        if let Some(index) = truncated_from {
+            core.storage.submit(StorageWrite::TruncateFrom(index))?;
+        }
+        if !new_entries.is_empty() {
+            core.storage.submit(StorageWrite::Entries(new_entries))?;
+        }
+
+        if request.leader_commit > core.commit_index {
+            core.commit_index = std::cmp::max(
+                core.commit_index,
+                std::cmp::min(request.leader_commit, last_new_index),
+            );
+            self.apply(core);
+        }
+
+        Ok(AppendEntriesResponse {
+            term: core.current_term,
+            success: true,
+            match_index: last_new_index,
+        })
+    }
+
+    pub(crate) async fn handle_install_snapshot(
+        &self,
+        request: InstallSnapshotRequest,
+    ) -> Result<InstallSnapshotResponse> {
+        let (response, flushed) = {
+            let mut core = self.core.lock();
+            let response = self.install_snapshot(&mut core, request)?;
+            (response, core.storage.flushed())
+        };
+        flushed.wait().await?;
+        Ok(response)
+    }
+
+    fn install_snapshot(
+        &self,
+        core: &mut RaftCore,
+        request: InstallSnapshotRequest,
+    ) -> Result<InstallSnapshotResponse> {
+        if request.term < core.current_term {
+            return Ok(InstallSnapshotResponse {
+                term: core.current_term,
+            });
+        }
+        core.become_follower(request.term, request.leader_id)?;
+        core.reset_election_deadline(&self.config);
+
+        let snapshot = request.snapshot.unwrap_or_default();
+        let index = snapshot.last_included_index;
+        if index > core.commit_index {
+            info!(
+                "Raft node {} installs the snapshot at index {}",
+                self.id, index
+            );
+            let keep_entries = core
+                .log
+                .install_snapshot(index, snapshot.last_included_term);
+            core.storage.submit(StorageWrite::Snapshot {
+                snapshot: snapshot.clone(),
+                keep_entries,
+            })?;
+            core.state_machine.restore(&snapshot);
+            core.commit_index = index;
+            core.last_applied = index;
+            core.snapshot = Some(snapshot);
+            self.applied.notify_waiters();
+        }
+
+        Ok(InstallSnapshotResponse {
+            term: core.current_term,
+        })
+    }
+
+    /// Replicate a command through the log, return the result of applying it
+    pub(crate) async fn propose(
+        self: &Arc<Self>,
+        command: Command,
+    ) -> std::result::Result<bool, RaftError> {
+        let (receiver, flushed) = {
+            let mut core = self.core.lock();
+            if core.role != Role::Leader {
+                return Err(RaftError::NotLeader(core.leader_id));
+            }
+            let index = core.append_local(command).map_err(RaftError::Failed)?;
+            let (sender, receiver) = oneshot::channel();
+            let term = core.current_term;
+            core.pending.insert(index, (term, sender));
+            (receiver, core.storage.flushed())
+        };
+        self.broadcast_append_entries();
+        // The followers may acknowledge the entry before it reaches the local disk
+        flushed.wait().await.map_err(RaftError::Failed)?;
+        self.advance_and_apply(&mut self.core.lock());
+
+        match tokio::time::timeout(self.config.request_timeout, receiver).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(_)) => Err(RaftError::Failed(BallistaError::General(
+                "The raft proposal is dropped".to_owned(),
+            ))),
+            Err(_) => Err(RaftError::Failed(BallistaError::General(
+                "Timeout waiting for the raft proposal to be committed".to_owned(),
+            ))),
+        }
+    }
+
+    /// Linearizable read on the leader, which confirms it is still the leader after
+    /// recording the commit index and serves the read once that index is applied
+    pub(crate) async fn read(
+        self: &Arc<Self>,
+        query: Query,
+    ) -> std::result::Result<Vec<(String, Vec<u8>)>, RaftError> {
+        let deadline = Instant::now() + self.config.request_timeout;
+
+        // A new leader only knows the commit index after committing an entry of its term
+        let (read_index, term) = loop {
+            let notified = self.applied.notified();
+            {
+                let core = self.core.lock();
+                if core.role != Role::Leader {
+                    return Err(RaftError::NotLeader(core.leader_id));
+                }
+                if core.log.term_at(core.commit_index) == Some(core.current_term) {
+                    break (core.commit_index, core.current_term);
+                }
+            }
+            self.wait(notified, deadline).await?;
+        };
+
+        if !self.confirm_leadership(term).await {
+            return Err(RaftError::NotLeader(self.leader_id()));
+        }
+
+        loop {
+            let notified = self.applied.notified();
+            {
+                let core = self.core.lock();
+                if core.last_applied >= read_index {
+                    return Ok(core.read(&query));
+                }
+            }
+            self.wait(notified, deadline).await?;
+        }
+    }
+
+    /// Wait until entries are applied, the notification may be missed so poll as well
+    pub(crate) async fn wait_applied(&self, timeout: Duration) {
+        let _ = tokio::time::timeout(timeout, self.applied.notified()).await;
+    }
+
+    async fn wait(
+        &self,
+        notified: tokio::sync::futures::Notified<'_>,
+        deadline: Instant,
+    ) -> std::result::Result<(), RaftError> {
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(RaftError::Failed(BallistaError::General(
+                "Timeout waiting for the raft log to be applied".to_owned(),
+            )));
+        }
+        let poll_interval = std::cmp::min(deadline - now, self.config.heartbeat_interval);
+        let _ = tokio::time::timeout(poll_interval, notified).await;
+        Ok(())
+    }
+
+    /// Whether a majority still acknowledges this node as the leader of the term
+    async fn confirm_leadership(self: &Arc<Self>, term: u64) -> bool {
+        let requests = {
+            let core = self.core.lock();
+            if core.role != Role::Leader || core.current_term != term {
+                return false;
+            }
+            self.peers
+                .iter()
+                .filter_map(|peer| match core.build_append_entries(self.id, *peer, 0) {
+                    ReplicationMessage::AppendEntries(request) => Some((*peer, request)),
+                    // A peer which needs a snapshot still acknowledges the term
+                    ReplicationMessage::InstallSnapshot(_) => Some((
+                        *peer,
+                        AppendEntriesRequest {
+                            term,
+                            leader_id: self.id,
+                            prev_log_index: core.log.snapshot_index(),
+                            prev_log_term: core
+                                .log
+                                .term_at(core.log.snapshot_index())
+                                .unwrap_or_default(),
+                            entries: vec![],
+                            leader_commit: core.commit_index,
+                        },
+                    )),
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let responses = futures::future::join_all(
+            requests
+                .into_iter()
+                .map(|(peer, request)| self.transport.append_entries(peer, request)),
+        )
+        .await;
+
+        let mut acks = 1;
+        for response in responses.into_iter().flatten() {
+            if response.term > term {
+                let mut core = self.core.lock();
+                if let Err(e) = core.become_follower(response.term, 0) {
+                    error!("Raft node {} fail to step down: {}", self.id, e);
+                }
+                return false;
+            }
+            if response.term == term {
+                acks += 1;
+            }
+        }
+        acks >= self.quorum()
+    }
+
+    /// The locks held in the local state machine
+    pub(crate) fn locks(&self) -> Vec<LockState> {
+        self.core.lock().state_machine.locks()
+    }
+
+    /// Register a watcher on the local state machine
+    pub(crate) fn watch(&self, prefix: String) -> UnboundedReceiver<WatchEvent> {
+        self.core.lock().state_machine.watch(prefix)
+    }
+
+    /// Serve a proposal forwarded by another node
+    pub(crate) async fn handle_propose(
+        self: &Arc<Self>,
+        request: ProposeRequest,
+    ) -> ProposeResponse {
+        let command = request.command.unwrap_or_default();
+        match self.propose(command).await {
+            Ok(applied) => ProposeResponse {
+                applied,
+                ..Default::default()
+            },
+            Err(RaftError::NotLeader(leader_id)) => ProposeResponse {
+                not_leader: true,
+                leader_id,
+                ..Default::default()
+            },
+            Err(RaftError::Failed(e)) => ProposeResponse {
+                error: e.to_string(),
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Serve a read forwarded by another node
+    pub(crate) async fn handle_read(
+        self: &Arc<Self>,
+        request: ReadRequest,
+    ) -> ReadResponse {
+        let query = match request.query {
+            Some(query) => query,
+            None => {
+                return ReadResponse {
+                    error: "Missing query in the raft read request".to_owned(),
+                    ..Default::default()
+                }
+            }
+        };
+        match self.read(query).await {
+            Ok(entries) => ReadResponse {
+                entries: entries
+                    .into_iter()
+                    .map(|(key, value)| KeyValue { key, value })
+                    .collect(),
+                ..Default::default()
+            },
+            Err(RaftError::NotLeader(leader_id)) => ReadResponse {
+                not_leader: true,
+                leader_id,
+                ..Default::default()
+            },
+            Err(RaftError::Failed(e)) => ReadResponse {
+                error: e.to_string(),
+                ..Default::default()
+            },
+        }
+    }
+}
@@ -0,0 +1,175 @@
+// Copyright 2021 HetuDB.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! In-memory view of the raft log after the last snapshot.
+
+use crate::state::backend::raft::protobuf::LogEntry;
+
+pub(crate) struct RaftLog {
+    snapshot_index: u64,
+    snapshot_term: u64,
+    // entries[i] has the index snapshot_index + 1 + i
+    entries: Vec<LogEntry>,
+}
+
+impl RaftLog {
+    pub(crate) fn new(
+        snapshot_index: u64,
+        snapshot_term: u64,
+        entries: Vec<LogEntry>,
+    ) -> Self {
+        let entries = entries
+            .into_iter()
+            .filter(|entry| entry.index > snapshot_index)
+            .collect();
+        Self {
+            snapshot_index,
+            snapshot_term,
+            entries,
+        }
+    }
+
+    pub(crate) fn snapshot_index(&self) -> u64 {
+        self.snapshot_index
+    }
+
+    pub(crate) fn last_index(&self) -> u64 {
+        self.snapshot_index + self.entries.len() as u64
+    }
+
+    pub(crate) fn last_term(&self) -> u64 {
+        self.entries
+            .last()
+            .map(|entry| entry.term)
+            .unwrap_or(self.snapshot_term)
+    }
+
+    /// Term of the entry at the index, None if it is compacted or does not exist
+    pub(crate) fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.snapshot_index {
+            Some(self.snapshot_term)
+        } else {
+            self.get(index).map(|entry| entry.term)
+        }
+    }
+
+    pub(crate) fn get(&self, index: u64) -> Option<&LogEntry> {
+        if index <= self.snapshot_index {
+            return None;
+        }
+        self.entries.get((index - self.snapshot_index - 1) as usize)
+    }
+
+    /// At most max entries starting from the index
+    pub(crate) fn entries_from(&self, index: u64, max: usize) -> Vec<LogEntry> {
+        if index <= self.snapshot_index {
+            return vec![];
+        }
+        self.entries
+            .iter()
+            .skip((index - self.snapshot_index - 1) as usize)
+            .take(max)
+            .cloned()
+            .collect()
+    }
+
+    pub(crate) fn append(&mut self, entry: LogEntry) {
+        debug_assert_eq!(entry.index, self.last_index() + 1);
+        self.entries.push(entry);
+    }
+
+    /// Remove the entries from the index on
+    pub(crate) fn truncate_from(&mut self, index: u64) {
+        if index > self.snapshot_index {
+            self.entries
+                .truncate((index - self.snapshot_index - 1) as usize);
+        }
+    }
+
+    /// Drop the entries covered by a snapshot of the index
+    pub(crate) fn compact(&mut self, index: u64, term: u64) {
+        if index <= self.snapshot_index {
+            return;
+        }
+        let keep_from =
+            std::cmp::min((index - self.snapshot_index) as usize, self.entries.len());
+        self.entries.drain(..keep_from);
+        self.snapshot_index = index;
+        self.snapshot_term = term;
+    }
+
+    /// Replace the whole log with an installed snapshot, keeping the entries after it if
+    /// they do not conflict. Return whether the entries are kept.
+    pub(crate) fn install_snapshot(&mut self, index: u64, term: u64) -> bool {
+        if self.term_at(index) == Some(term) {
+            self.compact(index, term);
+            true
+        } else {
+            self.entries.clear();
+            self.snapshot_index = index;
+            self.snapshot_term = term;
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::state::backend::raft::protobuf::LogEntry;
+    use crate::state::backend::raft::raft_log::RaftLog;
+
+    fn entry(index: u64, term: u64) -> LogEntry {
+        LogEntry {
+            term,
+            index,
+            command: None,
+        }
+    }
+
+    #[test]
+    fn test_append_and_compact() {
+        let mut log = RaftLog::new(0, 0, vec![]);
+        for index in 1..=5 {
+            log.append(entry(index, 1));
+        }
+        log.append(entry(6, 2));
+        assert_eq!(log.last_index(), 6);
+        assert_eq!(log.last_term(), 2);
+        assert_eq!(log.term_at(0), Some(0));
+
+        log.compact(4, 1);
+        assert_eq!(log.snapshot_index(), 4);
+        assert_eq!(log.term_at(3), None);
+        assert_eq!(log.term_at(4), Some(1));
+        assert_eq!(log.get(5).map(|entry| entry.index), Some(5));
+        assert_eq!(log.entries_from(5, 10).len(), 2);
+        assert_eq!(log.last_index(), 6);
+
+        log.truncate_from(6);
+        assert_eq!(log.last_index(), 5);
+        assert_eq!(log.last_term(), 1);
+    }
+
+    #[test]
+    fn test_install_snapshot() {
+        let mut log = RaftLog::new(0, 0, vec![entry(1, 1), entry(2, 1), entry(3, 2)]);
+        assert!(log.install_snapshot(2, 1));
+        assert_eq!(log.last_index(), 3);
+
+        assert!(!log.install_snapshot(5, 3));
+        assert_eq!(log.last_index(), 5);
+        assert_eq!(log.last_term(), 3);
+        assert!(log.entries_from(6, 10).is_empty());
+    }
+}
@@ -0,0 +1,320 @@
+// Copyright 2021 HetuDB.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Key-value state machine replicated by the raft log.
+
+use std::collections::BTreeMap;
+
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+use crate::state::backend::raft::protobuf::{
+    command::CommandType, Command, KeyValue, LockState, Snapshot,
+};
+use crate::state::backend::WatchEvent;
+
+#[derive(Default)]
+pub(crate) struct StateMachine {
+    data: BTreeMap<String, Vec<u8>>,
+    locks: BTreeMap<String, LockState>,
+    // prefix -> sender of the local watchers
+    watchers: Vec<(String, UnboundedSender<WatchEvent>)>,
+}
+
+impl StateMachine {
+    /// Apply a committed command, return false if a lock is held by another owner or the
+    /// compares of a transaction do not hold
+    pub(crate) fn apply(&mut self, command: &Command) -> bool {
+        match &command.command_type {
+            Some(CommandType::Put(put)) => {
+                self.insert(put.key.clone(), put.value.clone());
+                true
+            }
+            Some(CommandType::AcquireLock(acquire)) => {
+                // Acquiring is idempotent since a client may retry a proposal
+                let free = match self.locks.get(&acquire.key) {
+                    Some(lock) => {
+                        lock.owner == acquire.owner
+                            || lock.expires_at <= acquire.acquired_at
+                    }
+                    None => true,
+                };
+                if free {
+                    self.locks.insert(
+                        acquire.key.clone(),
+                        LockState {
+                            key: acquire.key.clone(),
+                            owner: acquire.owner.clone(),
+                            acquired_at: acquire.acquired_at,
+                            expires_at: acquire.acquired_at + acquire.ttl_ms,
+                        },
+                    );
+                }
+                free
+            }
+            Some(CommandType::ReleaseLock(release)) => {
+                if matches!(
+                    self.locks.get(&release.key),
+                    Some(lock) if lock.owner == release.owner
+                ) {
+                    self.locks.remove(&release.key);
+                }
+                true
+            }
+            Some(CommandType::Delete(delete)) => {
+                self.remove(&delete.key);
+                true
+            }
+            Some(CommandType::DeletePrefix(delete)) => {
+                let keys = self
+                    .get_from_prefix(&delete.prefix)
+                    .into_iter()
+                    .map(|(key, _)| key)
+                    .collect::<Vec<_>>();
+                for key in keys {
+                    self.remove(&key);
+                }
+                true
+            }
+            Some(CommandType::Txn(txn)) => {
+                let holds = txn.compares.iter().all(|compare| {
+                    match (self.data.get(&compare.key), compare.absent) {
+                        (None, absent) => absent,
+                        (Some(value), false) => value == &compare.value,
+                        (Some(_), true) => false,
+                    }
+                });
+                if holds {
+                    for op in txn.ops.iter() {
+                        if op.delete {
+                            self.remove(&op.key);
+                        } else {
+                            self.insert(op.key.clone(), op.value.clone());
+                        }
+                    }
+                }
+                holds
+            }
+            Some(CommandType::Noop(_)) | None => true,
+        }
+    }
+
+    fn insert(&mut self, key: String, value: Vec<u8>) {
+        self.data.insert(key.clone(), value.clone());
+        self.notify(|| WatchEvent::Put(key.clone(), value.clone()), &key);
+    }
+
+    fn remove(&mut self, key: &str) {
+        if self.data.remove(key).is_some() {
+            self.notify(|| WatchEvent::Delete(key.to_owned()), key);
+        }
+    }
+
+    pub(crate) fn get(&self, key: &str) -> Option<Vec<u8>> {
+        self.data.get(key).cloned()
+    }
+
+    pub(crate) fn get_from_prefix(&self, prefix: &str) -> Vec<(String, Vec<u8>)> {
+        self.data
+            .range(prefix.to_owned()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect()
+    }
+
+    /// The locks which are not released, including the expired ones
+    pub(crate) fn locks(&self) -> Vec<LockState> {
+        self.locks.values().cloned().collect()
+    }
+
+    pub(crate) fn watch(&mut self, prefix: String) -> UnboundedReceiver<WatchEvent> {
+        let (sender, receiver) = unbounded_channel();
+        self.watchers.push((prefix, sender));
+        receiver
+    }
+
+    pub(crate) fn snapshot(&self, index: u64, term: u64) -> Snapshot {
+        Snapshot {
+            last_included_index: index,
+            last_included_term: term,
+            entries: self
+                .data
+                .iter()
+                .map(|(key, value)| KeyValue {
+                    key: key.clone(),
+                    value: value.clone(),
+                })
+                .collect(),
+            locks: self.locks(),
+        }
+    }
+
+    /// Replace the state with a snapshot, the watchers see the difference as events
+    pub(crate) fn restore(&mut self, snapshot: &Snapshot) {
+        let data: BTreeMap<String, Vec<u8>> = snapshot
+            .entries
+            .iter()
+            .map(|kv| (kv.key.clone(), kv.value.clone()))
+            .collect();
+        let old_data = std::mem::replace(&mut self.data, data);
+        for key in old_data.keys() {
+            if !self.data.contains_key(key) {
+                self.notify(|| WatchEvent::Delete(key.clone()), key);
+            }
+        }
+        let changed = self
+            .data
+            .iter()
+            .filter(|(key, value)| old_data.get(*key) != Some(value))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect::<Vec<_>>();
+        for (key, value) in changed {
+            self.notify(|| WatchEvent::Put(key.clone(), value.clone()), &key);
+        }
+        self.locks = snapshot
+            .locks
+            .iter()
+            .map(|lock| (lock.key.clone(), lock.clone()))
+            .collect();
+    }
+
+    fn notify<F: Fn() -> WatchEvent>(&mut self, event: F, key: &str) {
+        // Drop the watchers which are canceled
+        self.watchers.retain(|(prefix, sender)| {
+            !key.starts_with(prefix.as_str()) || sender.send(event()).is_ok()
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::state::backend::raft::protobuf::{
+        command::CommandType, AcquireLock, Command, DeletePrefix, Put, ReleaseLock, Txn,
+        TxnCompare, TxnOp,
+    };
+    use crate::state::backend::raft::state_machine::StateMachine;
+    use crate::state::backend::WatchEvent;
+
+    fn put(key: &str, value: &str) -> Command {
+        Command {
+            command_type: Some(CommandType::Put(Put {
+                key: key.to_owned(),
+                value: value.as_bytes().to_vec(),
+            })),
+        }
+    }
+
+    #[test]
+    fn test_prefix_and_watch() {
+        let mut state_machine = StateMachine::default();
+        let mut watch = state_machine.watch("/a".to_owned());
+        state_machine.apply(&put("/a/1", "1"));
+        state_machine.apply(&put("/b/1", "1"));
+        state_machine.apply(&put("/a/2", "2"));
+
+        assert_eq!(
+            state_machine.get_from_prefix("/a"),
+            vec![
+                ("/a/1".to_owned(), b"1".to_vec()),
+                ("/a/2".to_owned(), b"2".to_vec())
+            ]
+        );
+        assert_eq!(
+            watch.try_recv().unwrap(),
+            WatchEvent::Put("/a/1".to_owned(), b"1".to_vec())
+        );
+        assert_eq!(
+            watch.try_recv().unwrap(),
+            WatchEvent::Put("/a/2".to_owned(), b"2".to_vec())
+        );
+        assert!(watch.try_recv().is_err());
+
+        let snapshot = state_machine.snapshot(3, 1);
+        let mut restored = StateMachine::default();
+        restored.restore(&snapshot);
+        assert_eq!(restored.get("/b/1"), Some(b"1".to_vec()));
+    }
+
+    #[test]
+    fn test_delete_and_txn() {
+        let mut state_machine = StateMachine::default();
+        let mut watch = state_machine.watch("/a".to_owned());
+        state_machine.apply(&put("/a/1", "1"));
+        state_machine.apply(&put("/a/2", "2"));
+        state_machine.apply(&Command {
+            command_type: Some(CommandType::DeletePrefix(DeletePrefix {
+                prefix: "/a/".to_owned(),
+            })),
+        });
+        assert!(state_machine.get_from_prefix("/a").is_empty());
+        assert_eq!(
+            watch.try_recv().unwrap(),
+            WatchEvent::Put("/a/1".to_owned(), b"1".to_vec())
+        );
+        watch.try_recv().unwrap();
+        assert_eq!(
+            watch.try_recv().unwrap(),
+            WatchEvent::Delete("/a/1".to_owned())
+        );
+
+        let txn = Command {
+            command_type: Some(CommandType::Txn(Txn {
+                compares: vec![TxnCompare {
+                    key: "/b".to_owned(),
+                    absent: true,
+                    value: vec![],
+                }],
+                ops: vec![TxnOp {
+                    key: "/b".to_owned(),
+                    delete: false,
+                    value: b"1".to_vec(),
+                }],
+            })),
+        };
+        assert!(state_machine.apply(&txn));
+        assert!(!state_machine.apply(&txn));
+        assert_eq!(state_machine.get("/b"), Some(b"1".to_vec()));
+    }
+
+    #[test]
+    fn test_lock() {
+        let lock = |key: &str, owner: &str, acquired_at: u64| Command {
+            command_type: Some(CommandType::AcquireLock(AcquireLock {
+                key: key.to_owned(),
+                owner: owner.to_owned(),
+                acquired_at,
+                ttl_ms: 100,
+            })),
+        };
+        let unlock = |key: &str, owner: &str| Command {
+            command_type: Some(CommandType::ReleaseLock(ReleaseLock {
+                key: key.to_owned(),
+                owner: owner.to_owned(),
+            })),
+        };
+        let mut state_machine = StateMachine::default();
+        assert!(state_machine.apply(&lock("x", "a", 0)));
+        assert!(state_machine.apply(&lock("x", "a", 10)));
+        assert!(!state_machine.apply(&lock("x", "b", 20)));
+        // Locks of other keys are independent
+        assert!(state_machine.apply(&lock("y", "b", 20)));
+        // Only the owner releases the lock
+        state_machine.apply(&unlock("x", "b"));
+        assert!(!state_machine.apply(&lock("x", "b", 30)));
+        state_machine.apply(&unlock("x", "a"));
+        assert!(state_machine.apply(&lock("x", "b", 30)));
+        // An expired lock is taken over
+        assert!(state_machine.apply(&lock("x", "c", 130)));
+        assert_eq!(state_machine.locks().len(), 2);
+    }
+}
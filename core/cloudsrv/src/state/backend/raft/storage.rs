@@ -0,0 +1,471 @@
+// Copyright 2021 HetuDB.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Local disk storage of a raft node.
+//!
+//! The hard state and the snapshot are replaced atomically by renaming a temporary file.
+//! The log entries after the snapshot are appended to segment files of length-delimited
+//! protobuf messages, named after the index of their first entry, so compacting the log
+//! removes whole segments and a conflicting suffix is cut off the last ones.
+//!
+//! The disk is only written by a dedicated thread, see [`StorageWriter`].
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+
+use hetu_core::error::{BallistaError, Result};
+use log::{error, warn};
+use prost::Message;
+use tokio::sync::watch;
+
+use crate::state::backend::raft::protobuf::{HardState, LogEntry, Snapshot};
+
+const HARD_STATE_FILE: &str = "hard_state";
+const SNAPSHOT_FILE: &str = "snapshot";
+const SEGMENT_PREFIX: &str = "log-";
+// A new segment is started once the last one has that many entries
+const SEGMENT_ENTRIES: usize = 4096;
+
+/// The state of a raft node which is loaded from disk on startup
+#[derive(Default)]
+pub(crate) struct PersistedState {
+    pub(crate) hard_state: HardState,
+    pub(crate) snapshot: Option<Snapshot>,
+    pub(crate) entries: Vec<LogEntry>,
+}
+
+struct Segment {
+    first_index: u64,
+    path: PathBuf,
+    // End offset of each entry in the file
+    ends: Vec<u64>,
+}
+
+impl Segment {
+    fn next_index(&self) -> u64 {
+        self.first_index + self.ends.len() as u64
+    }
+}
+
+pub(crate) struct RaftStorage {
+    dir: PathBuf,
+    snapshot_index: u64,
+    segments: Vec<Segment>,
+}
+
+impl RaftStorage {
+    /// Open the storage in the directory and load the state persisted in it
+    pub(crate) fn open<P: AsRef<Path>>(dir: P) -> Result<(Self, PersistedState)> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let mut storage = Self {
+            dir,
+            snapshot_index: 0,
+            segments: vec![],
+        };
+        let state = storage.load()?;
+        Ok((storage, state))
+    }
+
+    fn load(&mut self) -> Result<PersistedState> {
+        let hard_state = match self.read_file(HARD_STATE_FILE)? {
+            Some(bytes) => decode(&bytes)?,
+            None => HardState::default(),
+        };
+        let snapshot = match self.read_file(SNAPSHOT_FILE)? {
+            Some(bytes) => Some(decode::<Snapshot>(&bytes)?),
+            None => None,
+        };
+        self.snapshot_index = snapshot
+            .as_ref()
+            .map(|snapshot| snapshot.last_included_index)
+            .unwrap_or_default();
+
+        let mut segment_indexes = vec![];
+        for dir_entry in fs::read_dir(&self.dir)? {
+            let name = dir_entry?.file_name();
+            if let Some(first_index) = name
+                .to_str()
+                .and_then(|name| name.strip_prefix(SEGMENT_PREFIX))
+                .and_then(|index| index.parse::<u64>().ok())
+            {
+                segment_indexes.push(first_index);
+            }
+        }
+        segment_indexes.sort_unstable();
+
+        let mut entries = vec![];
+        let mut broken = false;
+        for first_index in segment_indexes {
+            let path = self.segment_path(first_index);
+            if broken {
+                // Nothing after a broken tail was acknowledged
+                fs::remove_file(&path)?;
+                continue;
+            }
+            let mut bytes = vec![];
+            File::open(&path)?.read_to_end(&mut bytes)?;
+            let mut segment = Segment {
+                first_index,
+                path,
+                ends: vec![],
+            };
+            let mut buf = bytes.as_slice();
+            while !buf.is_empty() {
+                match LogEntry::decode_length_delimited(&mut buf) {
+                    Ok(entry) => {
+                        entries.push(entry);
+                        segment.ends.push((bytes.len() - buf.len()) as u64);
+                    }
+                    Err(e) => {
+                        // The tail of the log may be lost when the process crashes while
+                        // appending, those entries were never acknowledged
+                        warn!("Ignore the broken tail of the raft log: {}", e);
+                        let valid_len = segment.ends.last().cloned().unwrap_or_default();
+                        OpenOptions::new()
+                            .write(true)
+                            .open(&segment.path)?
+                            .set_len(valid_len)?;
+                        broken = true;
+                        break;
+                    }
+                }
+            }
+            self.segments.push(segment);
+        }
+
+        Ok(PersistedState {
+            hard_state,
+            snapshot,
+            entries,
+        })
+    }
+
+    fn save_hard_state(&self, hard_state: &HardState) -> Result<()> {
+        self.replace_file(HARD_STATE_FILE, &hard_state.encode_to_vec())
+    }
+
+    /// Save the snapshot and remove the segments it covers. The entries after the
+    /// snapshot are dropped as well unless they are kept in the log.
+    fn save_snapshot(&mut self, snapshot: &Snapshot, keep_entries: bool) -> Result<()> {
+        self.replace_file(SNAPSHOT_FILE, &snapshot.encode_to_vec())?;
+        if !keep_entries {
+            self.truncate_from(0)?;
+        }
+        let index = snapshot.last_included_index;
+        self.snapshot_index = index;
+        while self
+            .segments
+            .first()
+            .map(|segment| segment.next_index() <= index + 1)
+            .unwrap_or(false)
+        {
+            let segment = self.segments.remove(0);
+            fs::remove_file(&segment.path)?;
+        }
+        Ok(())
+    }
+
+    fn append_entries(&mut self, entries: &[LogEntry]) -> Result<()> {
+        let mut entries = entries.iter().peekable();
+        while let Some(first) = entries.peek() {
+            let start_segment = match self.segments.last() {
+                Some(segment) => {
+                    segment.ends.len() >= SEGMENT_ENTRIES
+                        || segment.next_index() != first.index
+                }
+                None => true,
+            };
+            if start_segment {
+                self.segments.push(Segment {
+                    first_index: first.index,
+                    path: self.segment_path(first.index),
+                    ends: vec![],
+                });
+            }
+
+            let segment = self.segments.last_mut().unwrap();
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&segment.path)?;
+            let offset = file.metadata()?.len();
+            let mut buf = vec![];
+            while segment.ends.len() < SEGMENT_ENTRIES {
+                match entries.next_if(|entry| entry.index == segment.next_index()) {
+                    Some(entry) => {
+                        entry
+                            .encode_length_delimited(&mut buf)
+                            .map_err(to_ballista_error)?;
+                        segment.ends.push(offset + buf.len() as u64);
+                    }
+                    None => break,
+                }
+            }
+            file.write_all(&buf)?;
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Remove the entries from the index on
+    fn truncate_from(&mut self, index: u64) -> Result<()> {
+        while let Some(segment) = self.segments.last_mut() {
+            if segment.first_index >= index {
+                fs::remove_file(&segment.path)?;
+                self.segments.pop();
+                continue;
+            }
+            if segment.next_index() > index {
+                segment
+                    .ends
+                    .truncate((index - segment.first_index) as usize);
+                let file = OpenOptions::new().write(true).open(&segment.path)?;
+                file.set_len(segment.ends.last().cloned().unwrap_or_default())?;
+                file.sync_all()?;
+            }
+            break;
+        }
+        Ok(())
+    }
+
+    /// The index of the last entry on disk, including the snapshot
+    fn last_index(&self) -> u64 {
+        self.segments
+            .last()
+            .map(|segment| segment.next_index() - 1)
+            .unwrap_or(self.snapshot_index)
+    }
+
+    fn write(&mut self, write: StorageWrite) -> Result<()> {
+        match write {
+            StorageWrite::HardState(hard_state) => self.save_hard_state(&hard_state),
+            StorageWrite::Entries(entries) => self.append_entries(&entries),
+            StorageWrite::TruncateFrom(index) => self.truncate_from(index),
+            StorageWrite::Snapshot {
+                snapshot,
+                keep_entries,
+            } => self.save_snapshot(&snapshot, keep_entries),
+        }
+    }
+
+    fn segment_path(&self, first_index: u64) -> PathBuf {
+        self.dir
+            .join(format!("{}{:020}", SEGMENT_PREFIX, first_index))
+    }
+
+    fn read_file(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        let path = self.dir.join(name);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let mut bytes = vec![];
+        File::open(path)?.read_to_end(&mut bytes)?;
+        Ok(Some(bytes))
+    }
+
+    fn replace_file(&self, name: &str, bytes: &[u8]) -> Result<()> {
+        let tmp_path = self.dir.join(format!("{}.tmp", name));
+        {
+            let mut file = File::create(&tmp_path)?;
+            file.write_all(bytes)?;
+            file.sync_all()?;
+        }
+        fs::rename(tmp_path, self.dir.join(name))?;
+        Ok(())
+    }
+}
+
+/// A change to the disk state of a raft node
+pub(crate) enum StorageWrite {
+    HardState(HardState),
+    Entries(Vec<LogEntry>),
+    /// Remove the entries from the index on
+    TruncateFrom(u64),
+    Snapshot {
+        snapshot: Snapshot,
+        keep_entries: bool,
+    },
+}
+
+#[derive(Clone, Copy, Default)]
+struct Persisted {
+    // Number of writes done
+    writes: u64,
+    // Index of the last entry on disk
+    last_index: u64,
+}
+
+/// Queues the writes of a raft node to a dedicated thread, which applies them in order
+/// so the async tasks never block on the disk. A node which fails to write stops
+/// persisting anything and has to be restarted.
+pub(crate) struct StorageWriter {
+    sender: mpsc::Sender<StorageWrite>,
+    persisted: watch::Receiver<Persisted>,
+    // Number of writes submitted
+    writes: u64,
+}
+
+impl StorageWriter {
+    pub(crate) fn start(mut storage: RaftStorage, node_id: u64) -> Result<Self> {
+        let (sender, receiver) = mpsc::channel::<StorageWrite>();
+        let (persisted_sender, persisted) = watch::channel(Persisted {
+            writes: 0,
+            last_index: storage.last_index(),
+        });
+        std::thread::Builder::new()
+            .name(format!("raft-storage-{}", node_id))
+            .spawn(move || {
+                let mut writes = 0;
+                while let Ok(write) = receiver.recv() {
+                    if let Err(e) = storage.write(write) {
+                        error!("Raft node {} fail to write its storage: {}", node_id, e);
+                        return;
+                    }
+                    writes += 1;
+                    let _ = persisted_sender.send(Persisted {
+                        writes,
+                        last_index: storage.last_index(),
+                    });
+                }
+            })?;
+        Ok(Self {
+            sender,
+            persisted,
+            writes: 0,
+        })
+    }
+
+    /// Queue a write, [`StorageWriter::flushed`] tells when it is done
+    pub(crate) fn submit(&mut self, write: StorageWrite) -> Result<()> {
+        self.sender.send(write).map_err(|_| {
+            BallistaError::Internal("The raft storage is stopped".to_owned())
+        })?;
+        self.writes += 1;
+        Ok(())
+    }
+
+    /// The index of the last log entry on disk
+    pub(crate) fn persisted_index(&self) -> u64 {
+        self.persisted.borrow().last_index
+    }
+
+    /// Wait for the writes submitted so far
+    pub(crate) fn flushed(&self) -> Flushed {
+        Flushed {
+            persisted: self.persisted.clone(),
+            writes: self.writes,
+        }
+    }
+}
+
+/// Waits for the writes submitted before it is created
+pub(crate) struct Flushed {
+    persisted: watch::Receiver<Persisted>,
+    writes: u64,
+}
+
+impl Flushed {
+    pub(crate) async fn wait(mut self) -> Result<()> {
+        loop {
+            if self.persisted.borrow().writes >= self.writes {
+                return Ok(());
+            }
+            self.persisted.changed().await.map_err(|_| {
+                BallistaError::Internal("The raft storage is stopped".to_owned())
+            })?;
+        }
+    }
+}
+
+fn decode<T: Message + Default>(bytes: &[u8]) -> Result<T> {
+    T::decode(bytes).map_err(|e| {
+        BallistaError::Internal(format!("Could not deserialize raft state: {}", e))
+    })
+}
+
+fn to_ballista_error(e: prost::EncodeError) -> BallistaError {
+    BallistaError::Internal(format!("Could not serialize raft log: {}", e))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::state::backend::raft::protobuf::{LogEntry, Snapshot};
+    use crate::state::backend::raft::storage::{RaftStorage, StorageWrite};
+
+    fn entries(from: u64, to: u64, term: u64) -> Vec<LogEntry> {
+        (from..=to)
+            .map(|index| LogEntry {
+                term,
+                index,
+                command: None,
+            })
+            .collect()
+    }
+
+    fn indexes(entries: &[LogEntry]) -> Vec<(u64, u64)> {
+        entries
+            .iter()
+            .map(|entry| (entry.index, entry.term))
+            .collect()
+    }
+
+    #[test]
+    fn test_append_truncate_and_compact() {
+        let dir = std::env::temp_dir()
+            .join(format!("hetu-raft-storage-test-{}", uuid::Uuid::new_v4()));
+        {
+            let (mut storage, state) = RaftStorage::open(&dir).unwrap();
+            assert!(state.entries.is_empty());
+            storage
+                .write(StorageWrite::Entries(entries(1, 5000, 1)))
+                .unwrap();
+            // The conflicting suffix spans the two segments
+            storage.write(StorageWrite::TruncateFrom(4000)).unwrap();
+            storage
+                .write(StorageWrite::Entries(entries(4000, 4200, 2)))
+                .unwrap();
+            assert_eq!(storage.last_index(), 4200);
+        }
+        {
+            let (mut storage, state) = RaftStorage::open(&dir).unwrap();
+            let mut expected = entries(1, 3999, 1);
+            expected.extend(entries(4000, 4200, 2));
+            assert_eq!(indexes(&state.entries), indexes(&expected));
+
+            let snapshot = Snapshot {
+                last_included_index: 4100,
+                last_included_term: 2,
+                ..Default::default()
+            };
+            storage
+                .write(StorageWrite::Snapshot {
+                    snapshot,
+                    keep_entries: true,
+                })
+                .unwrap();
+            // Only the segment covered by the snapshot is removed
+            assert_eq!(storage.segments.len(), 1);
+        }
+        {
+            let (_, state) = RaftStorage::open(&dir).unwrap();
+            assert_eq!(state.snapshot.unwrap().last_included_index, 4100);
+            assert_eq!(state.entries.first().map(|entry| entry.index), Some(4097));
+            assert_eq!(state.entries.last().map(|entry| entry.index), Some(4200));
+        }
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
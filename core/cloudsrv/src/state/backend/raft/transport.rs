@@ -0,0 +1,287 @@
+// Copyright 2021 HetuDB.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Messaging between the raft nodes.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use hetu_core::client::tokens_match;
+use hetu_core::error::{BallistaError, Result};
+use log::warn;
+use parking_lot::Mutex;
+use tonic::metadata::{Ascii, MetadataValue};
+use tonic::transport::{Channel, ClientTlsConfig, Endpoint};
+use tonic::{Request, Response, Status};
+
+use crate::state::backend::raft::node::RaftNode;
+use crate::state::backend::raft::protobuf::{
+    raft_service_client::RaftServiceClient, raft_service_server::RaftService,
+    AppendEntriesRequest, AppendEntriesResponse, InstallSnapshotRequest,
+    InstallSnapshotResponse, ProposeRequest, ProposeResponse, ReadRequest, ReadResponse,
+    RequestVoteRequest, RequestVoteResponse,
+};
+use crate::state::backend::raft::RaftConfig;
+
+/// Sends the raft messages to the other nodes, identified by their node id
+#[tonic::async_trait]
+pub(crate) trait RaftTransport: Send + Sync {
+    async fn request_vote(
+        &self,
+        target: u64,
+        request: RequestVoteRequest,
+    ) -> Result<RequestVoteResponse>;
+
+    async fn append_entries(
+        &self,
+        target: u64,
+        request: AppendEntriesRequest,
+    ) -> Result<AppendEntriesResponse>;
+
+    async fn install_snapshot(
+        &self,
+        target: u64,
+        request: InstallSnapshotRequest,
+    ) -> Result<InstallSnapshotResponse>;
+
+    async fn propose(
+        &self,
+        target: u64,
+        request: ProposeRequest,
+    ) -> Result<ProposeResponse>;
+
+    async fn read(&self, target: u64, request: ReadRequest) -> Result<ReadResponse>;
+}
+
+/// [`RaftTransport`] over the grpc service of the other nodes
+pub(crate) struct GrpcTransport {
+    peers: HashMap<u64, String>,
+    timeout: Duration,
+    auth_token: Option<String>,
+    tls: Option<ClientTlsConfig>,
+    clients: Mutex<HashMap<u64, RaftServiceClient<Channel>>>,
+}
+
+impl GrpcTransport {
+    pub(crate) fn new(config: &RaftConfig) -> Self {
+        Self {
+            peers: config.peers.clone(),
+            timeout: config.request_timeout,
+            auth_token: config.auth_token.clone(),
+            tls: config.client_tls.clone(),
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn client(&self, target: u64) -> Result<RaftServiceClient<Channel>> {
+        let mut clients = self.clients.lock();
+        if let Some(client) = clients.get(&target) {
+            return Ok(client.clone());
+        }
+        let address = self.peers.get(&target).ok_or_else(|| {
+            BallistaError::General(format!("Unknown raft node {}", target))
+        })?;
+        let invalid_address = |e| {
+            BallistaError::General(format!("Invalid raft address {}: {}", address, e))
+        };
+        let endpoint = match self.tls.as_ref() {
+            Some(tls) => Endpoint::from_shared(format!("https://{}", address))
+                .and_then(|endpoint| endpoint.tls_config(tls.clone()))
+                .map_err(invalid_address)?,
+            None => Endpoint::from_shared(format!("http://{}", address))
+                .map_err(invalid_address)?,
+        };
+        // The channel connects on the first request, so a node may start before its peers
+        let channel = endpoint
+            .connect_timeout(self.timeout)
+            .timeout(self.timeout)
+            .connect_lazy();
+        let client = RaftServiceClient::new(channel);
+        clients.insert(target, client.clone());
+        Ok(client)
+    }
+
+    /// A request carrying the shared secret of the nodes
+    fn request<T>(&self, message: T) -> Result<Request<T>> {
+        let mut request = Request::new(message);
+        if let Some(auth_token) = self.auth_token.as_ref() {
+            let value: MetadataValue<Ascii> =
+                format!("Bearer {}", auth_token).parse().map_err(|_| {
+                    BallistaError::General("Invalid raft auth token".to_owned())
+                })?;
+            request.metadata_mut().insert("authorization", value);
+        }
+        Ok(request)
+    }
+}
+
+#[tonic::async_trait]
+impl RaftTransport for GrpcTransport {
+    async fn request_vote(
+        &self,
+        target: u64,
+        request: RequestVoteRequest,
+    ) -> Result<RequestVoteResponse> {
+        Ok(self
+            .client(target)?
+            .request_vote(self.request(request)?)
+            .await?
+            .into_inner())
+    }
+
+    async fn append_entries(
+        &self,
+        target: u64,
+        request: AppendEntriesRequest,
+    ) -> Result<AppendEntriesResponse> {
+        Ok(self
+            .client(target)?
+            .append_entries(self.request(request)?)
+            .await?
+            .into_inner())
+    }
+
+    async fn install_snapshot(
+        &self,
+        target: u64,
+        request: InstallSnapshotRequest,
+    ) -> Result<InstallSnapshotResponse> {
+        Ok(self
+            .client(target)?
+            .install_snapshot(self.request(request)?)
+            .await?
+            .into_inner())
+    }
+
+    async fn propose(
+        &self,
+        target: u64,
+        request: ProposeRequest,
+    ) -> Result<ProposeResponse> {
+        Ok(self
+            .client(target)?
+            .propose(self.request(request)?)
+            .await?
+            .into_inner())
+    }
+
+    async fn read(&self, target: u64, request: ReadRequest) -> Result<ReadResponse> {
+        Ok(self
+            .client(target)?
+            .read(self.request(request)?)
+            .await?
+            .into_inner())
+    }
+}
+
+/// Grpc service receiving the raft messages of the other nodes
+#[derive(Clone)]
+pub struct RaftGrpcService {
+    node: Arc<RaftNode>,
+}
+
+impl RaftGrpcService {
+    pub(crate) fn new(node: Arc<RaftNode>) -> Self {
+        Self { node }
+    }
+
+    /// Reject a node which does not present the shared secret
+    fn check_auth_token<T>(
+        &self,
+        request: &Request<T>,
+    ) -> std::result::Result<(), Status> {
+        let expected = match self.node.config().auth_token.as_ref() {
+            Some(expected) => expected,
+            None => return Ok(()),
+        };
+        let auth_token = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        match auth_token {
+            Some(auth_token) if tokens_match(expected, auth_token) => Ok(()),
+            _ => {
+                warn!("Rejecting a raft request with a wrong auth token");
+                Err(Status::unauthenticated(
+                    "The raft node is not authenticated",
+                ))
+            }
+        }
+    }
+}
+
+fn to_status(e: BallistaError) -> Status {
+    Status::internal(e.to_string())
+}
+
+#[tonic::async_trait]
+impl RaftService for RaftGrpcService {
+    async fn request_vote(
+        &self,
+        request: Request<RequestVoteRequest>,
+    ) -> std::result::Result<Response<RequestVoteResponse>, Status> {
+        self.check_auth_token(&request)?;
+        self.node
+            .handle_request_vote(request.into_inner())
+            .await
+            .map(Response::new)
+            .map_err(to_status)
+    }
+
+    async fn append_entries(
+        &self,
+        request: Request<AppendEntriesRequest>,
+    ) -> std::result::Result<Response<AppendEntriesResponse>, Status> {
+        self.check_auth_token(&request)?;
+        self.node
+            .handle_append_entries(request.into_inner())
+            .await
+            .map(Response::new)
+            .map_err(to_status)
+    }
+
+    async fn install_snapshot(
+        &self,
+        request: Request<InstallSnapshotRequest>,
+    ) -> std::result::Result<Response<InstallSnapshotResponse>, Status> {
+        self.check_auth_token(&request)?;
+        self.node
+            .handle_install_snapshot(request.into_inner())
+            .await
+            .map(Response::new)
+            .map_err(to_status)
+    }
+
+    async fn propose(
+        &self,
+        request: Request<ProposeRequest>,
+    ) -> std::result::Result<Response<ProposeResponse>, Status> {
+        self.check_auth_token(&request)?;
+        Ok(Response::new(
+            self.node.handle_propose(request.into_inner()).await,
+        ))
+    }
+
+    async fn read(
+        &self,
+        request: Request<ReadRequest>,
+    ) -> std::result::Result<Response<ReadResponse>, Status> {
+        self.check_auth_token(&request)?;
+        Ok(Response::new(
+            self.node.handle_read(request.into_inner()).await,
+        ))
+    }
+}
@@ -15,22 +15,80 @@
 // specific language governing permissions and limitations
 // under the License.
 
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
 use std::{sync::Arc, task::Poll};
 
 use hetu_core::error::{ballista_error, BallistaError, Result};
+use hetu_core::serde::protobuf::LockHolder;
 
 use futures::{FutureExt, Stream};
 use log::warn;
+use parking_lot::Mutex;
+use sled::transaction::{ConflictableTransactionResult, TransactionError};
 use sled_package as sled;
-use tokio::sync::Mutex;
+use tokio::sync::Notify;
 
-use crate::state::backend::{Lock, StateBackendClient, Watch, WatchEvent};
+use crate::state::backend::{
+    new_lock_owner, now_millis, Lock, StateBackendClient, Txn, TxnCompare, TxnOp, Watch,
+    WatchEvent,
+};
 
 /// A [`StateBackendClient`] implementation that uses file-based storage to save cluster configuration.
 #[derive(Clone)]
 pub struct StandaloneClient {
     db: sled::Db,
-    lock: Arc<Mutex<()>>,
+    locks: Arc<StandaloneLocks>,
+}
+
+/// The locks of the process which owns the sled database
+#[derive(Default)]
+struct StandaloneLocks {
+    // key -> holder and when its lock expires
+    holders: Mutex<HashMap<String, (LockHolder, Instant)>>,
+    released: Notify,
+}
+
+impl StandaloneLocks {
+    /// Take the lock if it is free or expired, otherwise return how long it is still held
+    fn try_acquire(&self, key: &str, owner: &str, ttl: Duration) -> Option<Duration> {
+        let mut holders = self.holders.lock();
+        let now = Instant::now();
+        if let Some((holder, expires_at)) = holders.get(key) {
+            if *expires_at > now {
+                return Some(*expires_at - now);
+            }
+            warn!("Lock {} of {} expired", key, holder.owner);
+        }
+        let acquired_at = now_millis();
+        let holder = LockHolder {
+            key: key.to_owned(),
+            owner: owner.to_owned(),
+            acquired_at,
+            expires_at: acquired_at + ttl.as_millis() as u64,
+        };
+        holders.insert(key.to_owned(), (holder, now + ttl));
+        None
+    }
+
+    fn release(&self, key: &str, owner: &str) {
+        let mut holders = self.holders.lock();
+        if matches!(holders.get(key), Some((holder, _)) if holder.owner == owner) {
+            holders.remove(key);
+        }
+        drop(holders);
+        self.released.notify_waiters();
+    }
+
+    fn holders(&self) -> Vec<LockHolder> {
+        let now = Instant::now();
+        self.holders
+            .lock()
+            .values()
+            .filter(|(_, expires_at)| *expires_at > now)
+            .map(|(holder, _)| holder.clone())
+            .collect()
+    }
 }
 
 impl StandaloneClient {
@@ -38,7 +96,7 @@ impl StandaloneClient {
     pub fn try_new<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
         Ok(Self {
             db: sled::open(path).map_err(sled_to_ballista_error)?,
-            lock: Arc::new(Mutex::new(())),
+            locks: Arc::new(StandaloneLocks::default()),
         })
     }
 
@@ -49,7 +107,7 @@ impl StandaloneClient {
                 .temporary(true)
                 .open()
                 .map_err(sled_to_ballista_error)?,
-            lock: Arc::new(Mutex::new(())),
+            locks: Arc::new(StandaloneLocks::default()),
         })
     }
 }
@@ -98,8 +156,83 @@ impl StateBackendClient for StandaloneClient {
             .map(|_| ())
     }
 
-    async fn lock(&self) -> Result<Box<dyn Lock>> {
-        Ok(Box::new(self.lock.clone().lock_owned().await))
+    async fn delete(&self, key: &str) -> Result<()> {
+        self.db
+            .remove(key)
+            .map_err(|e| {
+                warn!("sled remove failed: {}", e);
+                ballista_error("sled remove failed")
+            })
+            .map(|_| ())
+    }
+
+    async fn delete_prefix(&self, prefix: &str) -> Result<()> {
+        let mut batch = sled::Batch::default();
+        for entry in self.db.scan_prefix(prefix) {
+            let (key, _) = entry.map_err(sled_to_ballista_error)?;
+            batch.remove(key);
+        }
+        self.db.apply_batch(batch).map_err(|e| {
+            warn!("sled remove failed: {}", e);
+            ballista_error("sled remove failed")
+        })
+    }
+
+    async fn txn(&self, txn: Txn) -> Result<bool> {
+        self.db
+            .transaction(|tx_db| -> ConflictableTransactionResult<bool, ()> {
+                for compare in txn.compares.iter() {
+                    let holds = match compare {
+                        TxnCompare::Value(key, value) => tx_db
+                            .get(key)?
+                            .map(|current| current.as_ref() == value.as_slice())
+                            .unwrap_or(false),
+                        TxnCompare::Absent(key) => tx_db.get(key)?.is_none(),
+                    };
+                    if !holds {
+                        return Ok(false);
+                    }
+                }
+                for op in txn.ops.iter() {
+                    match op {
+                        TxnOp::Put(key, value) => {
+                            tx_db.insert(key.as_str(), value.as_slice())?;
+                        }
+                        TxnOp::Delete(key) => {
+                            tx_db.remove(key.as_str())?;
+                        }
+                    }
+                }
+                Ok(true)
+            })
+            .map_err(|e| match e {
+                TransactionError::Storage(e) => sled_to_ballista_error(e),
+                TransactionError::Abort(()) => ballista_error("sled transaction aborted"),
+            })
+    }
+
+    async fn lock(&self, key: &str, ttl: Duration) -> Result<Box<dyn Lock>> {
+        let owner = new_lock_owner();
+        loop {
+            // Register for the release before checking, so that it is not missed
+            let released = self.locks.released.notified();
+            match self.locks.try_acquire(key, &owner, ttl) {
+                None => {
+                    return Ok(Box::new(StandaloneLock {
+                        locks: self.locks.clone(),
+                        key: key.to_owned(),
+                        owner,
+                    }))
+                }
+                Some(remaining) => {
+                    let _ = tokio::time::timeout(remaining, released).await;
+                }
+            }
+        }
+    }
+
+    async fn lock_holders(&self) -> Result<Vec<LockHolder>> {
+        Ok(self.locks.holders())
     }
 
     async fn watch(&self, prefix: String) -> Result<Box<dyn Watch>> {
@@ -109,6 +242,19 @@ impl StateBackendClient for StandaloneClient {
     }
 }
 
+struct StandaloneLock {
+    locks: Arc<StandaloneLocks>,
+    key: String,
+    owner: String,
+}
+
+#[tonic::async_trait]
+impl Lock for StandaloneLock {
+    async fn unlock(&mut self) {
+        self.locks.release(&self.key, &self.owner);
+    }
+}
+
 struct SledWatch {
     subscriber: sled::Subscriber,
 }
@@ -149,9 +295,11 @@ impl Stream for SledWatch {
 #[cfg(test)]
 mod tests {
     use super::{StandaloneClient, StateBackendClient, Watch, WatchEvent};
+    use crate::state::backend::{Txn, TxnCompare};
 
     use futures::StreamExt;
     use std::result::Result;
+    use std::time::Duration;
 
     fn create_instance() -> Result<StandaloneClient, Box<dyn std::error::Error>> {
         Ok(StandaloneClient::try_new_temporary()?)
@@ -193,6 +341,97 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn delete() -> Result<(), Box<dyn std::error::Error>> {
+        let client = create_instance()?;
+        let value = "value".as_bytes();
+        client.put("key/1".to_owned(), value.to_vec()).await?;
+        client.put("key/2".to_owned(), value.to_vec()).await?;
+        client.put("other".to_owned(), value.to_vec()).await?;
+        client.delete("key/1").await?;
+        assert!(client.get("key/1").await?.is_empty());
+        client.delete_prefix("key/").await?;
+        assert!(client.get_from_prefix("key/").await?.is_empty());
+        assert_eq!(client.get("other").await?, value);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn compare_and_put() -> Result<(), Box<dyn std::error::Error>> {
+        let client = create_instance()?;
+        assert!(client.compare_and_put("key", None, b"1".to_vec()).await?);
+        assert!(!client.compare_and_put("key", None, b"2".to_vec()).await?);
+        assert!(
+            !client
+                .compare_and_put("key", Some(b"0".to_vec()), b"2".to_vec())
+                .await?
+        );
+        assert!(
+            client
+                .compare_and_put("key", Some(b"1".to_vec()), b"2".to_vec())
+                .await?
+        );
+        assert_eq!(client.get("key").await?, b"2".to_vec());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn txn() -> Result<(), Box<dyn std::error::Error>> {
+        let client = create_instance()?;
+        client.put("a".to_owned(), b"1".to_vec()).await?;
+        let txn = Txn::new()
+            .when(TxnCompare::Value("a".to_owned(), b"1".to_vec()))
+            .when(TxnCompare::Absent("b".to_owned()))
+            .put("b".to_owned(), b"2".to_vec())
+            .delete("a".to_owned());
+        assert!(client.txn(txn.clone()).await?);
+        assert!(client.get("a").await?.is_empty());
+        assert_eq!(client.get("b").await?, b"2".to_vec());
+        // The conditions do not hold any more, so nothing is changed
+        assert!(!client.txn(txn).await?);
+        assert_eq!(client.get("b").await?, b"2".to_vec());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn lock() -> Result<(), Box<dyn std::error::Error>> {
+        let client = create_instance()?;
+        let mut lock = client.lock("a", Duration::from_secs(30)).await?;
+        // Another key is not blocked
+        let mut other = client.lock("b", Duration::from_secs(30)).await?;
+        assert_eq!(client.lock_holders().await?.len(), 2);
+        other.unlock().await;
+
+        let waiter = {
+            let client = client.clone();
+            tokio::spawn(async move { client.lock("a", Duration::from_secs(30)).await })
+        };
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let holders = client.lock_holders().await?;
+        assert_eq!(holders.len(), 1);
+        assert_eq!(holders[0].key, "a");
+
+        lock.unlock().await;
+        let mut lock = waiter.await??;
+        lock.unlock().await;
+        assert!(client.lock_holders().await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn lock_expired() -> Result<(), Box<dyn std::error::Error>> {
+        let client = create_instance()?;
+        // The holder never releases the lock
+        let _lock = client.lock("a", Duration::from_millis(200)).await?;
+        let mut lock = tokio::time::timeout(
+            Duration::from_secs(5),
+            client.lock("a", Duration::from_secs(30)),
+        )
+        .await??;
+        lock.unlock().await;
+        Ok(())
+    }
+
     #[tokio::test]
     async fn read_watch() -> Result<(), Box<dyn std::error::Error>> {
         let client = create_instance()?;
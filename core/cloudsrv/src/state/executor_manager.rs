@@ -17,9 +17,10 @@
 
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use crate::config::SchedulerConfig;
 use hetu_core::serde::protobuf::ExecutorHeartbeat;
-use hetu_core::serde::scheduler::{ExecutorData, ExecutorDataChange};
-use log::{error, info, warn};
+use hetu_core::serde::scheduler::{ExecutorData, ExecutorDataChange, ExecutorState};
+use log::{debug, error, info, warn};
 use parking_lot::RwLock;
 use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
@@ -28,21 +29,147 @@ use std::sync::Arc;
 pub(crate) struct ExecutorManager {
     executors_heartbeat: Arc<RwLock<HashMap<String, ExecutorHeartbeat>>>,
     executors_data: Arc<RwLock<HashMap<String, ExecutorData>>>,
+    // executors which get no new tasks
+    draining_executors: Arc<RwLock<HashSet<String>>>,
+    // draining executors which are asked to exit once drained
+    decommissioning_executors: Arc<RwLock<HashSet<String>>>,
+    // executors which are drained and not allowed to register again
+    decommissioned_executors: Arc<RwLock<HashSet<String>>>,
+    // executor -> cancelled jobs whose running tasks it is told to stop when polling work
+    cancelled_jobs: Arc<RwLock<HashMap<String, HashSet<String>>>>,
+    // executors using more of their memory or disk get no new tasks, 0 means no limit
+    max_memory_percent: u32,
+    max_disk_percent: u32,
 }
 
 impl ExecutorManager {
-    pub(crate) fn new() -> Self {
+    pub(crate) fn new(config: &SchedulerConfig) -> Self {
         Self {
             executors_heartbeat: Arc::new(RwLock::new(HashMap::new())),
             executors_data: Arc::new(RwLock::new(HashMap::new())),
+            draining_executors: Arc::new(RwLock::new(HashSet::new())),
+            decommissioning_executors: Arc::new(RwLock::new(HashSet::new())),
+            decommissioned_executors: Arc::new(RwLock::new(HashSet::new())),
+            cancelled_jobs: Arc::new(RwLock::new(HashMap::new())),
+            max_memory_percent: config.executor_max_memory_percent,
+            max_disk_percent: config.executor_max_disk_percent,
         }
     }
 
+    /// Stop assigning new tasks to an executor, the running ones are not affected
+    pub(crate) fn drain_executor(&self, executor_id: &str) {
+        if self
+            .draining_executors
+            .write()
+            .insert(executor_id.to_owned())
+        {
+            info!("Draining executor {}", executor_id);
+        }
+    }
+
+    pub(crate) fn is_draining(&self, executor_id: &str) -> bool {
+        self.draining_executors.read().contains(executor_id)
+    }
+
+    pub(crate) fn get_draining_executors(&self) -> HashSet<String> {
+        self.draining_executors.read().clone()
+    }
+
+    /// Drain an executor and ask it to exit once it is drained
+    pub(crate) fn start_decommission(&self, executor_id: &str) {
+        self.drain_executor(executor_id);
+        self.decommissioning_executors
+            .write()
+            .insert(executor_id.to_owned());
+    }
+
+    pub(crate) fn is_decommissioning(&self, executor_id: &str) -> bool {
+        self.decommissioning_executors.read().contains(executor_id)
+    }
+
+    /// Forget a drained executor and reject it from now on
+    pub(crate) fn decommission_executor(&self, executor_id: &str) {
+        info!("Decommissioning executor {}", executor_id);
+        self.executors_heartbeat.write().remove(executor_id);
+        self.executors_data.write().remove(executor_id);
+        self.draining_executors.write().remove(executor_id);
+        self.decommissioning_executors.write().remove(executor_id);
+        self.cancelled_jobs.write().remove(executor_id);
+        self.decommissioned_executors
+            .write()
+            .insert(executor_id.to_owned());
+    }
+
+    pub(crate) fn is_decommissioned(&self, executor_id: &str) -> bool {
+        self.decommissioned_executors.read().contains(executor_id)
+    }
+
+    /// Tell a polling executor to stop its running tasks of a cancelled job
+    pub(crate) fn cancel_tasks(&self, executor_id: &str, job_id: &str) {
+        self.cancelled_jobs
+            .write()
+            .entry(executor_id.to_owned())
+            .or_insert_with(HashSet::new)
+            .insert(job_id.to_owned());
+    }
+
+    /// The cancelled jobs whose tasks an executor has not been told to stop yet
+    pub(crate) fn take_cancelled_jobs(&self, executor_id: &str) -> Vec<String> {
+        self.cancelled_jobs
+            .write()
+            .remove(executor_id)
+            .map(|jobs| jobs.into_iter().collect())
+            .unwrap_or_default()
+    }
+
     pub(crate) fn save_executor_heartbeat(&self, heartbeat: ExecutorHeartbeat) {
         let mut executors_heartbeat = self.executors_heartbeat.write();
         executors_heartbeat.insert(heartbeat.executor_id.clone(), heartbeat);
     }
 
+    /// Whether the last heartbeat of an executor reports its memory or disk usage above the
+    /// limits, the executors reporting no state are never short of resources
+    pub(crate) fn is_short_of_resources(&self, executor_id: &str) -> bool {
+        let state: Option<ExecutorState> = self
+            .executors_heartbeat
+            .read()
+            .get(executor_id)
+            .and_then(|heartbeat| heartbeat.state.clone())
+            .map(Into::into);
+        match state {
+            Some(state) => {
+                let exceeds = |usage: f64, max_percent: u32| {
+                    max_percent > 0 && usage * 100.0 > max_percent as f64
+                };
+                let short = exceeds(state.memory_usage(), self.max_memory_percent)
+                    || exceeds(state.disk_usage(), self.max_disk_percent);
+                if short {
+                    debug!(
+                        "Executor {} is short of resources: {:?}",
+                        executor_id, state
+                    );
+                }
+                short
+            }
+            None => false,
+        }
+    }
+
+    /// Whether the last heartbeat of an executor is not older than the threshold
+    pub(crate) fn is_alive(&self, executor_id: &str, threshold: Duration) -> bool {
+        let now_epoch_ts = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards");
+        self.executors_heartbeat
+            .read()
+            .get(executor_id)
+            .map(|heartbeat| {
+                now_epoch_ts.as_secs().saturating_sub(heartbeat.timestamp)
+                    <= threshold.as_secs()
+            })
+            .unwrap_or(false)
+    }
+
     pub(crate) fn get_executors_heartbeat(&self) -> Vec<ExecutorHeartbeat> {
         let executors_heartbeat = self.executors_heartbeat.read();
         executors_heartbeat
@@ -113,20 +240,25 @@ impl ExecutorManager {
         executors_data.get(executor_id).cloned()
     }
 
-    /// There are two checks:
+    /// There are three checks:
     /// 1. firstly alive
     /// 2. secondly available task slots > 0
+    /// 3. thirdly not draining and not short of memory or disk
     #[cfg(not(test))]
     #[allow(dead_code)]
     pub(crate) fn get_available_executors_data(&self) -> Vec<ExecutorData> {
         let mut res = {
             let alive_executors = self.get_alive_executors_within_one_minute();
             let executors_data = self.executors_data.read();
+            let draining_executors = self.draining_executors.read();
             executors_data
                 .iter()
                 .filter_map(|(exec, data)| {
-                    (data.available_task_slots > 0 && alive_executors.contains(exec))
-                        .then(|| data.clone())
+                    (data.available_task_slots > 0
+                        && alive_executors.contains(exec)
+                        && !draining_executors.contains(exec)
+                        && !self.is_short_of_resources(exec))
+                    .then(|| data.clone())
                 })
                 .collect::<Vec<ExecutorData>>()
         };
@@ -137,9 +269,63 @@ impl ExecutorManager {
     #[cfg(test)]
     #[allow(dead_code)]
     pub(crate) fn get_available_executors_data(&self) -> Vec<ExecutorData> {
-        let mut res: Vec<ExecutorData> =
-            self.executors_data.read().values().cloned().collect();
+        let draining_executors = self.draining_executors.read();
+        let mut res: Vec<ExecutorData> = self
+            .executors_data
+            .read()
+            .values()
+            .filter(|data| {
+                !draining_executors.contains(&data.executor_id)
+                    && !self.is_short_of_resources(&data.executor_id)
+            })
+            .cloned()
+            .collect();
         res.sort_by(|a, b| Ord::cmp(&b.available_task_slots, &a.available_task_slots));
         res
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::config::SchedulerConfig;
+    use crate::state::executor_manager::ExecutorManager;
+    use hetu_core::serde::protobuf::ExecutorHeartbeat;
+    use hetu_core::serde::scheduler::{ExecutorData, ExecutorState};
+
+    #[test]
+    fn test_short_of_resources() {
+        let executor_manager = ExecutorManager::new(&SchedulerConfig::default());
+        for executor_id in ["idle", "busy", "unknown"] {
+            executor_manager.save_executor_data(ExecutorData {
+                executor_id: executor_id.to_owned(),
+                total_task_slots: 2,
+                available_task_slots: 2,
+            });
+        }
+        let heartbeat = |executor_id: &str, available_memory_size: u64| {
+            let state = ExecutorState {
+                available_memory_size,
+                total_memory_size: 100,
+                ..Default::default()
+            };
+            ExecutorHeartbeat {
+                executor_id: executor_id.to_owned(),
+                timestamp: 0,
+                state: Some(state.into()),
+            }
+        };
+        executor_manager.save_executor_heartbeat(heartbeat("idle", 50));
+        executor_manager.save_executor_heartbeat(heartbeat("busy", 5));
+
+        assert!(!executor_manager.is_short_of_resources("idle"));
+        assert!(executor_manager.is_short_of_resources("busy"));
+        assert!(!executor_manager.is_short_of_resources("unknown"));
+        let mut available = executor_manager
+            .get_available_executors_data()
+            .into_iter()
+            .map(|data| data.executor_id)
+            .collect::<Vec<_>>();
+        available.sort();
+        assert_eq!(available, vec!["idle".to_owned(), "unknown".to_owned()]);
+    }
+}
@@ -0,0 +1,425 @@
+// Copyright 2021 HetuDB.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use datafusion::physical_plan::ExecutionPlan;
+use log::{debug, info};
+use parking_lot::Mutex;
+
+use crate::config::SchedulerConfig;
+use hetu_core::config::BallistaConfig;
+
+/// A job which has been admitted and can be submitted to the stage scheduler
+pub(crate) type AdmittedJob = (String, Arc<dyn ExecutionPlan>);
+
+/// The tenant and the user a job is admitted for
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct JobOwner {
+    pub tenant: String,
+    // Empty if the job is not limited per user
+    pub user: String,
+}
+
+impl fmt::Display for JobOwner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.user.is_empty() {
+            write!(f, "tenant {}", self.tenant)
+        } else {
+            write!(f, "tenant {} and user {}", self.tenant, self.user)
+        }
+    }
+}
+
+/// The owner of a job from the settings of its session, jobs without an explicit tenant are
+/// limited per session
+pub(crate) fn job_owner(config: &BallistaConfig, session_id: &str) -> JobOwner {
+    let tenant = Some(config.job_tenant())
+        .filter(|tenant| !tenant.is_empty())
+        .unwrap_or_else(|| session_id.to_owned());
+    JobOwner {
+        tenant,
+        user: config.job_user(),
+    }
+}
+
+struct QueuedJobEntry {
+    job_id: String,
+    owner: JobOwner,
+    plan: Arc<dyn ExecutionPlan>,
+    queued_at: Instant,
+}
+
+#[derive(Default)]
+struct JobQueueInner {
+    // FIFO of the jobs waiting for admission
+    queued: VecDeque<QueuedJobEntry>,
+    // job_id -> owner for the admitted jobs
+    running: HashMap<String, JobOwner>,
+    // tenant -> number of admitted jobs
+    running_per_tenant: HashMap<String, usize>,
+    // user -> number of admitted jobs, the jobs without a user are not counted
+    running_per_user: HashMap<String, usize>,
+}
+
+impl JobQueueInner {
+    fn can_admit(&self, config: &JobQueueConfig, owner: &JobOwner) -> bool {
+        if config.max_running_jobs > 0 && self.running.len() >= config.max_running_jobs {
+            return false;
+        }
+        if config.max_running_jobs_per_tenant > 0 {
+            let tenant_running = self
+                .running_per_tenant
+                .get(&owner.tenant)
+                .cloned()
+                .unwrap_or(0);
+            if tenant_running >= config.max_running_jobs_per_tenant {
+                return false;
+            }
+        }
+        if config.max_running_jobs_per_user > 0 && !owner.user.is_empty() {
+            let user_running =
+                self.running_per_user.get(&owner.user).cloned().unwrap_or(0);
+            if user_running >= config.max_running_jobs_per_user {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn admit(&mut self, job_id: String, owner: JobOwner) {
+        *self
+            .running_per_tenant
+            .entry(owner.tenant.clone())
+            .or_insert(0) += 1;
+        if !owner.user.is_empty() {
+            *self.running_per_user.entry(owner.user.clone()).or_insert(0) += 1;
+        }
+        self.running.insert(job_id, owner);
+    }
+
+    /// Release the slot of an admitted job, return false if the job is not running
+    fn release(&mut self, job_id: &str) -> bool {
+        let owner = if let Some(owner) = self.running.remove(job_id) {
+            owner
+        } else {
+            return false;
+        };
+        decrement(&mut self.running_per_tenant, &owner.tenant);
+        if !owner.user.is_empty() {
+            decrement(&mut self.running_per_user, &owner.user);
+        }
+        true
+    }
+
+    /// Admit queued jobs in FIFO order, skipping the ones whose tenant or user is at its limit
+    fn admit_queued(&mut self, config: &JobQueueConfig) -> Vec<AdmittedJob> {
+        let mut admitted = vec![];
+        let mut idx = 0;
+        while idx < self.queued.len() {
+            if config.max_running_jobs > 0
+                && self.running.len() >= config.max_running_jobs
+            {
+                break;
+            }
+            if self.can_admit(config, &self.queued[idx].owner) {
+                let entry = self.queued.remove(idx).unwrap();
+                debug!(
+                    "Job {} admitted after waiting {}ms",
+                    entry.job_id,
+                    entry.queued_at.elapsed().as_millis()
+                );
+                self.admit(entry.job_id.clone(), entry.owner);
+                admitted.push((entry.job_id, entry.plan));
+            } else {
+                idx += 1;
+            }
+        }
+        admitted
+    }
+}
+
+fn decrement(counts: &mut HashMap<String, usize>, key: &str) {
+    let is_empty = if let Some(n) = counts.get_mut(key) {
+        *n -= 1;
+        *n == 0
+    } else {
+        false
+    };
+    if is_empty {
+        counts.remove(key);
+    }
+}
+
+#[derive(Clone, Debug)]
+struct JobQueueConfig {
+    max_running_jobs: usize,
+    max_running_jobs_per_tenant: usize,
+    max_running_jobs_per_user: usize,
+    queue_timeout: Option<Duration>,
+}
+
+/// Admission control in front of the stage scheduler.
+///
+/// Jobs are admitted as long as none of the global, tenant and user limits of running jobs
+/// is reached. The other ones wait in a FIFO queue until a running job is released or
+/// the queue timeout expires.
+#[derive(Clone)]
+pub(crate) struct JobQueue {
+    config: JobQueueConfig,
+    inner: Arc<Mutex<JobQueueInner>>,
+}
+
+impl JobQueue {
+    pub(crate) fn new(config: &SchedulerConfig) -> Self {
+        let queue_timeout = (config.job_queue_timeout_seconds > 0)
+            .then(|| Duration::from_secs(config.job_queue_timeout_seconds));
+        Self {
+            config: JobQueueConfig {
+                max_running_jobs: config.max_running_jobs,
+                max_running_jobs_per_tenant: config.max_running_jobs_per_tenant,
+                max_running_jobs_per_user: config.max_running_jobs_per_user,
+                queue_timeout,
+            },
+            inner: Arc::new(Mutex::new(JobQueueInner::default())),
+        }
+    }
+
+    pub(crate) fn queue_timeout(&self) -> Option<Duration> {
+        self.config.queue_timeout
+    }
+
+    /// Put a job into the queue and return the jobs which can be submitted now
+    pub(crate) fn enqueue(
+        &self,
+        job_id: &str,
+        owner: &JobOwner,
+        plan: Arc<dyn ExecutionPlan>,
+    ) -> Vec<AdmittedJob> {
+        let mut inner = self.inner.lock();
+        inner.queued.push_back(QueuedJobEntry {
+            job_id: job_id.to_owned(),
+            owner: owner.clone(),
+            plan,
+            queued_at: Instant::now(),
+        });
+        let admitted = inner.admit_queued(&self.config);
+        if !admitted.iter().any(|(id, _)| id == job_id) {
+            info!(
+                "Job {} of {} queued, {} jobs are waiting",
+                job_id,
+                owner,
+                inner.queued.len()
+            );
+        }
+        admitted
+    }
+
+    /// Release the slot held by a finished job and return the jobs which can be submitted now
+    pub(crate) fn release(&self, job_id: &str) -> Vec<AdmittedJob> {
+        let mut inner = self.inner.lock();
+        if !inner.release(job_id) {
+            // The job may also be removed while it is still waiting
+            inner.queued.retain(|entry| entry.job_id != job_id);
+        }
+        inner.admit_queued(&self.config)
+    }
+
+    /// Hold a slot for a job admitted by a previous leader, whatever the limits
+    pub(crate) fn resume(&self, job_id: &str, owner: &JobOwner) {
+        let mut inner = self.inner.lock();
+        if !inner.running.contains_key(job_id) {
+            inner.admit(job_id.to_owned(), owner.clone());
+        }
+    }
+
+    /// Forget all the jobs, the unfinished ones are queued or resumed again afterwards
+    pub(crate) fn clear(&self) {
+        *self.inner.lock() = JobQueueInner::default();
+    }
+
+    /// Remove the jobs which have waited longer than the queue timeout and return their ids
+    pub(crate) fn expire(&self) -> Vec<String> {
+        let queue_timeout = if let Some(queue_timeout) = self.config.queue_timeout {
+            queue_timeout
+        } else {
+            return vec![];
+        };
+        let mut inner = self.inner.lock();
+        let mut expired = vec![];
+        inner.queued.retain(|entry| {
+            if entry.queued_at.elapsed() >= queue_timeout {
+                expired.push(entry.job_id.clone());
+                false
+            } else {
+                true
+            }
+        });
+        expired
+    }
+
+    pub(crate) fn queued_jobs(&self) -> usize {
+        self.inner.lock().queued.len()
+    }
+
+    pub(crate) fn running_jobs(&self) -> usize {
+        self.inner.lock().running.len()
+    }
+
+    /// 1-based position of a job in the queue, or None if the job is not waiting
+    pub(crate) fn queue_position(&self, job_id: &str) -> Option<usize> {
+        self.inner
+            .lock()
+            .queued
+            .iter()
+            .position(|entry| entry.job_id == job_id)
+            .map(|idx| idx + 1)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use datafusion::arrow::datatypes::Schema;
+    use datafusion::physical_plan::empty::EmptyExec;
+    use datafusion::physical_plan::ExecutionPlan;
+
+    use hetu_core::config::{BallistaConfig, BALLISTA_JOB_TENANT, BALLISTA_JOB_USER};
+
+    use crate::config::SchedulerConfig;
+    use crate::state::job_queue::{job_owner, JobOwner, JobQueue};
+
+    fn test_plan() -> Arc<dyn ExecutionPlan> {
+        Arc::new(EmptyExec::new(false, Arc::new(Schema::empty())))
+    }
+
+    fn tenant(tenant: &str) -> JobOwner {
+        JobOwner {
+            tenant: tenant.to_owned(),
+            user: "".to_owned(),
+        }
+    }
+
+    fn user(tenant: &str, user: &str) -> JobOwner {
+        JobOwner {
+            tenant: tenant.to_owned(),
+            user: user.to_owned(),
+        }
+    }
+
+    fn admitted_ids(admitted: Vec<(String, Arc<dyn ExecutionPlan>)>) -> Vec<String> {
+        admitted.into_iter().map(|(job_id, _)| job_id).collect()
+    }
+
+    #[test]
+    fn test_unlimited_queue() {
+        let queue = JobQueue::new(&SchedulerConfig::default());
+        for i in 0..10 {
+            let job_id = format!("job{}", i);
+            assert_eq!(
+                admitted_ids(queue.enqueue(&job_id, &tenant("tenant"), test_plan())),
+                vec![job_id]
+            );
+        }
+        assert_eq!(queue.running_jobs(), 10);
+        assert_eq!(queue.queued_jobs(), 0);
+    }
+
+    #[test]
+    fn test_global_limit() {
+        let queue = JobQueue::new(&SchedulerConfig::default().with_max_running_jobs(2));
+        assert_eq!(queue.enqueue("job1", &tenant("a"), test_plan()).len(), 1);
+        assert_eq!(queue.enqueue("job2", &tenant("b"), test_plan()).len(), 1);
+        assert!(queue.enqueue("job3", &tenant("c"), test_plan()).is_empty());
+        assert_eq!(queue.queue_position("job3"), Some(1));
+        assert_eq!(queue.queued_jobs(), 1);
+
+        assert_eq!(admitted_ids(queue.release("job1")), vec!["job3".to_owned()]);
+        assert_eq!(queue.queue_position("job3"), None);
+        assert_eq!(queue.running_jobs(), 2);
+    }
+
+    #[test]
+    fn test_tenant_limit() {
+        let queue = JobQueue::new(
+            &SchedulerConfig::default().with_max_running_jobs_per_tenant(1),
+        );
+        assert_eq!(queue.enqueue("job1", &tenant("a"), test_plan()).len(), 1);
+        assert!(queue.enqueue("job2", &tenant("a"), test_plan()).is_empty());
+        // Another tenant is not blocked by the waiting job of tenant a
+        assert_eq!(
+            admitted_ids(queue.enqueue("job3", &tenant("b"), test_plan())),
+            vec!["job3".to_owned()]
+        );
+        assert_eq!(admitted_ids(queue.release("job1")), vec!["job2".to_owned()]);
+    }
+
+    #[test]
+    fn test_queue_timeout() {
+        let queue = JobQueue::new(
+            &SchedulerConfig::default()
+                .with_max_running_jobs(1)
+                .with_job_queue_timeout_seconds(1),
+        );
+        assert_eq!(queue.enqueue("job1", &tenant("a"), test_plan()).len(), 1);
+        assert!(queue.enqueue("job2", &tenant("a"), test_plan()).is_empty());
+        assert!(queue.expire().is_empty());
+        std::thread::sleep(Duration::from_millis(1100));
+        assert_eq!(queue.expire(), vec!["job2".to_owned()]);
+        assert_eq!(queue.queued_jobs(), 0);
+        assert!(queue.release("job1").is_empty());
+    }
+
+    #[test]
+    fn test_user_limit() {
+        let queue =
+            JobQueue::new(&SchedulerConfig::default().with_max_running_jobs_per_user(1));
+        assert_eq!(
+            queue
+                .enqueue("job1", &user("a", "alice"), test_plan())
+                .len(),
+            1
+        );
+        // The user is limited across its tenants
+        assert!(queue
+            .enqueue("job2", &user("b", "alice"), test_plan())
+            .is_empty());
+        assert_eq!(
+            admitted_ids(queue.enqueue("job3", &user("a", "bob"), test_plan())),
+            vec!["job3".to_owned()]
+        );
+        // The jobs without a user are not limited per user
+        assert_eq!(queue.enqueue("job4", &tenant("a"), test_plan()).len(), 1);
+        assert_eq!(queue.enqueue("job5", &tenant("a"), test_plan()).len(), 1);
+        assert_eq!(admitted_ids(queue.release("job1")), vec!["job2".to_owned()]);
+        assert_eq!(queue.running_jobs(), 4);
+    }
+
+    #[test]
+    fn test_job_owner() {
+        let config = BallistaConfig::new().unwrap();
+        assert_eq!(job_owner(&config, "session"), tenant("session"));
+        let config = BallistaConfig::builder()
+            .set(BALLISTA_JOB_TENANT, "a")
+            .set(BALLISTA_JOB_USER, "alice")
+            .build()
+            .unwrap();
+        assert_eq!(job_owner(&config, "session"), user("a", "alice"));
+    }
+}
@@ -0,0 +1,419 @@
+// Copyright 2021 HetuDB.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Leader election among the schedulers sharing one state backend.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use datafusion_proto::logical_plan::AsLogicalPlan;
+use futures::StreamExt;
+use hetu_core::error::{BallistaError, Result};
+use hetu_core::event_loop::EventSender;
+use hetu_core::serde::protobuf::SchedulerLeader;
+use hetu_core::serde::AsExecutionPlan;
+use log::{error, info, warn};
+use parking_lot::{Mutex, RwLock};
+
+use crate::config::SchedulerConfig;
+use crate::scheduler_server::event::QueryStageSchedulerEvent;
+use crate::state::backend::{StateBackendClient, Watch, WatchEvent};
+use crate::state::persistent_state::{decode_protobuf, encode_protobuf};
+use crate::state::SchedulerState;
+
+/// Campaigns for the leadership with a lease record in the state backend.
+///
+/// The record is only changed while holding the backend lock of the leader key. The leader
+/// renews its lease periodically by bumping the renewal count of the record, and a standby
+/// takes over once it has seen the record unchanged for the lease. The lease is timed with
+/// the monotonic clock of each scheduler, the clocks of the schedulers are never compared.
+pub(crate) struct LeaderElection {
+    config_client: Arc<dyn StateBackendClient>,
+    leader_key: String,
+    scheduler_id: String,
+    scheduler_address: String,
+    lease: Duration,
+    is_leader: AtomicBool,
+    leader_address: RwLock<Option<String>>,
+    // The record of another leader and when it was first seen
+    observed_leader: Mutex<Option<(SchedulerLeader, Instant)>>,
+}
+
+impl LeaderElection {
+    pub(crate) fn new(
+        config_client: Arc<dyn StateBackendClient>,
+        namespace: &str,
+        config: &SchedulerConfig,
+    ) -> Self {
+        Self {
+            config_client,
+            leader_key: get_leader_key(namespace),
+            scheduler_id: config.scheduler_id.clone(),
+            scheduler_address: config.scheduler_address.clone(),
+            lease: Duration::from_secs(std::cmp::max(config.leader_lease_seconds, 1)),
+            is_leader: AtomicBool::new(false),
+            leader_address: RwLock::new(None),
+            observed_leader: Mutex::new(None),
+        }
+    }
+
+    pub(crate) fn is_leader(&self) -> bool {
+        self.is_leader.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn scheduler_id(&self) -> &str {
+        &self.scheduler_id
+    }
+
+    /// Address of the current leader as far as this scheduler knows
+    pub(crate) fn leader_address(&self) -> Option<String> {
+        self.leader_address.read().clone()
+    }
+
+    /// Acquire or renew the leadership, return whether this scheduler is the leader
+    async fn try_acquire(&self) -> Result<bool> {
+        let mut lock = self
+            .config_client
+            .lock(&self.leader_key, self.lease)
+            .await?;
+        let result = self.try_acquire_locked().await;
+        lock.unlock().await;
+        result
+    }
+
+    async fn try_acquire_locked(&self) -> Result<bool> {
+        let value = self.config_client.get(&self.leader_key).await?;
+        let mut renewals = 0;
+        if !value.is_empty() {
+            let leader: SchedulerLeader = decode_protobuf(&value)?;
+            renewals = leader.renewals;
+            // A resigned leader leaves 0 renewals
+            if leader.scheduler_id != self.scheduler_id
+                && leader.renewals > 0
+                && !self.is_lease_expired(&leader)
+            {
+                *self.leader_address.write() = Some(leader.scheduler_address);
+                return Ok(false);
+            }
+        }
+
+        let leader = SchedulerLeader {
+            scheduler_id: self.scheduler_id.clone(),
+            scheduler_address: self.scheduler_address.clone(),
+            renewals: renewals + 1,
+        };
+        self.config_client
+            .put(self.leader_key.clone(), encode_protobuf(&leader)?)
+            .await?;
+        *self.leader_address.write() = Some(self.scheduler_address.clone());
+        *self.observed_leader.lock() = None;
+        Ok(true)
+    }
+
+    /// Whether the record of another leader has not changed for the lease since this
+    /// scheduler first saw it
+    fn is_lease_expired(&self, leader: &SchedulerLeader) -> bool {
+        let mut observed_leader = self.observed_leader.lock();
+        match observed_leader.as_ref() {
+            Some((observed, since)) if observed == leader => {
+                since.elapsed() >= self.lease
+            }
+            _ => {
+                *observed_leader = Some((leader.clone(), Instant::now()));
+                false
+            }
+        }
+    }
+
+    /// [`LeaderElection::try_acquire`] bounded by a third of the lease, so that a hanging
+    /// backend cannot keep this scheduler leading after its lease
+    async fn campaign(&self) -> Result<bool> {
+        tokio::time::timeout(self.lease / 3, self.try_acquire())
+            .await
+            .unwrap_or_else(|_| {
+                Err(BallistaError::General("The campaign timed out".to_owned()))
+            })
+    }
+
+    /// Stop leading once the lease is not renewed in time, whatever the backend answers,
+    /// since a standby may take over from then on
+    fn step_down_if_expired(&self, last_renewed: Instant) {
+        if self.is_leader() && last_renewed.elapsed() >= self.lease {
+            self.is_leader.store(false, Ordering::SeqCst);
+            warn!(
+                "Scheduler {} stepped down since the lease is expired",
+                self.scheduler_id
+            );
+        }
+    }
+
+    /// Give up the leadership so that a standby does not need to wait for the lease to expire
+    pub(crate) async fn resign(&self) -> Result<()> {
+        if !self.is_leader.swap(false, Ordering::SeqCst) {
+            return Ok(());
+        }
+        let mut lock = self
+            .config_client
+            .lock(&self.leader_key, self.lease)
+            .await?;
+        let result = async {
+            let value = self.config_client.get(&self.leader_key).await?;
+            if !value.is_empty() {
+                let mut leader: SchedulerLeader = decode_protobuf(&value)?;
+                if leader.scheduler_id == self.scheduler_id {
+                    leader.renewals = 0;
+                    self.config_client
+                        .put(self.leader_key.clone(), encode_protobuf(&leader)?)
+                        .await?;
+                }
+            }
+            Ok(())
+        }
+        .await;
+        lock.unlock().await;
+        result
+    }
+
+    /// Keep campaigning for the leadership until the process exits.
+    ///
+    /// When this scheduler takes over, the jobs and stages are replayed from the state backend
+    /// and the unfinished jobs are resumed before it starts to serve requests.
+    pub(crate) async fn run<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan>(
+        self: Arc<Self>,
+        state: Arc<SchedulerState<T, U>>,
+        query_stage_event_sender: EventSender<QueryStageSchedulerEvent>,
+    ) {
+        let renew_interval = self.lease / 3;
+        let mut watch = match self.config_client.watch(self.leader_key.clone()).await {
+            Ok(watch) => Some(watch),
+            Err(e) => {
+                warn!("Fail to watch the scheduler leader due to {}", e);
+                None
+            }
+        };
+        let mut last_renewed = Instant::now();
+
+        loop {
+            self.step_down_if_expired(last_renewed);
+            let started = Instant::now();
+            match self.campaign().await {
+                Ok(true) => {
+                    // The record may be seen by the standbys as soon as the campaign starts
+                    last_renewed = started;
+                    if !self.is_leader() {
+                        info!(
+                            "Scheduler {} is elected as leader, replaying the state",
+                            self.scheduler_id
+                        );
+                        match state.recover(&query_stage_event_sender).await {
+                            Ok(()) => self.is_leader.store(true, Ordering::SeqCst),
+                            Err(e) => {
+                                error!(
+                                    "Scheduler {} fail to take over due to {}",
+                                    self.scheduler_id, e
+                                );
+                                // Give another scheduler the chance to take over
+                                let _ = self.resign().await;
+                            }
+                        }
+                    }
+                }
+                Ok(false) => {
+                    if self.is_leader.swap(false, Ordering::SeqCst) {
+                        warn!("Scheduler {} lost the leadership", self.scheduler_id);
+                    }
+                }
+                Err(e) => {
+                    warn!(
+                        "Scheduler {} fail to campaign for the leadership due to {}",
+                        self.scheduler_id, e
+                    );
+                }
+            }
+            self.step_down_if_expired(last_renewed);
+
+            if self.is_leader() {
+                // Wake up in time to step down if the lease cannot be renewed
+                let remaining = self.lease.saturating_sub(last_renewed.elapsed());
+                tokio::time::sleep(renew_interval.min(remaining)).await;
+            } else {
+                self.wait_for_leader_change(&mut watch, renew_interval)
+                    .await;
+            }
+        }
+    }
+
+    /// Wait until the next campaign, which starts earlier if the leader resigned
+    async fn wait_for_leader_change(
+        &self,
+        watch: &mut Option<Box<dyn Watch>>,
+        timeout: Duration,
+    ) {
+        let watch = if let Some(watch) = watch.as_mut() {
+            watch
+        } else {
+            tokio::time::sleep(timeout).await;
+            return;
+        };
+
+        let deadline = tokio::time::sleep(timeout);
+        tokio::pin!(deadline);
+        loop {
+            tokio::select! {
+                _ = &mut deadline => return,
+                event = watch.next() => match event {
+                    Some(WatchEvent::Put(_, value)) => {
+                        if let Ok(leader) = decode_protobuf::<SchedulerLeader>(&value) {
+                            if leader.renewals == 0 {
+                                return;
+                            }
+                            *self.leader_address.write() = Some(leader.scheduler_address);
+                        }
+                    }
+                    Some(WatchEvent::Delete(_)) => return,
+                    None => {
+                        deadline.await;
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn get_leader_key(namespace: &str) -> String {
+    format!("/ballista/{}/leader", namespace)
+}
+
+#[cfg(all(test, feature = "sled"))]
+mod test {
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use crate::config::SchedulerConfig;
+    use crate::state::backend::standalone::StandaloneClient;
+    use crate::state::backend::StateBackendClient;
+    use crate::state::leader_election::LeaderElection;
+
+    fn leader_election(
+        config_client: Arc<dyn StateBackendClient>,
+        scheduler_id: &str,
+    ) -> LeaderElection {
+        LeaderElection::new(
+            config_client,
+            "default",
+            &SchedulerConfig::default()
+                .with_scheduler_id(scheduler_id)
+                .with_scheduler_address(format!("{}:50050", scheduler_id))
+                .with_leader_lease_seconds(1),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_single_leader() {
+        let config_client: Arc<dyn StateBackendClient> =
+            Arc::new(StandaloneClient::try_new_temporary().unwrap());
+        let scheduler1 = leader_election(config_client.clone(), "scheduler1");
+        let scheduler2 = leader_election(config_client.clone(), "scheduler2");
+
+        assert!(scheduler1.try_acquire().await.unwrap());
+        assert!(!scheduler2.try_acquire().await.unwrap());
+        assert_eq!(
+            scheduler2.leader_address(),
+            Some("scheduler1:50050".to_owned())
+        );
+        // The leader renews its lease
+        assert!(scheduler1.try_acquire().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_take_over_after_lease_expired() {
+        let config_client: Arc<dyn StateBackendClient> =
+            Arc::new(StandaloneClient::try_new_temporary().unwrap());
+        let scheduler1 = leader_election(config_client.clone(), "scheduler1");
+        let scheduler2 = leader_election(config_client.clone(), "scheduler2");
+
+        assert!(scheduler1.try_acquire().await.unwrap());
+        // The lease is timed from when scheduler2 first sees the record
+        assert!(!scheduler2.try_acquire().await.unwrap());
+        tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+        assert!(scheduler2.try_acquire().await.unwrap());
+        assert!(!scheduler1.try_acquire().await.unwrap());
+        assert_eq!(
+            scheduler1.leader_address(),
+            Some("scheduler2:50050".to_owned())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_renewal_keeps_the_lease() {
+        let config_client: Arc<dyn StateBackendClient> =
+            Arc::new(StandaloneClient::try_new_temporary().unwrap());
+        let scheduler1 = leader_election(config_client.clone(), "scheduler1");
+        let scheduler2 = leader_election(config_client.clone(), "scheduler2");
+
+        assert!(scheduler1.try_acquire().await.unwrap());
+        assert!(!scheduler2.try_acquire().await.unwrap());
+        tokio::time::sleep(std::time::Duration::from_millis(600)).await;
+        // The renewal changes the record, so scheduler2 times the lease from now on
+        assert!(scheduler1.try_acquire().await.unwrap());
+        assert!(!scheduler2.try_acquire().await.unwrap());
+        tokio::time::sleep(std::time::Duration::from_millis(600)).await;
+        assert!(!scheduler2.try_acquire().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_resign() {
+        let config_client: Arc<dyn StateBackendClient> =
+            Arc::new(StandaloneClient::try_new_temporary().unwrap());
+        let scheduler1 = leader_election(config_client.clone(), "scheduler1");
+        let scheduler2 = leader_election(config_client.clone(), "scheduler2");
+
+        assert!(scheduler1.try_acquire().await.unwrap());
+        scheduler1
+            .is_leader
+            .store(true, std::sync::atomic::Ordering::SeqCst);
+        scheduler1.resign().await.unwrap();
+        assert!(!scheduler1.is_leader());
+        assert!(scheduler2.try_acquire().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_step_down_when_backend_hangs() {
+        let config_client: Arc<dyn StateBackendClient> =
+            Arc::new(StandaloneClient::try_new_temporary().unwrap());
+        let scheduler1 = leader_election(config_client.clone(), "scheduler1");
+
+        assert!(scheduler1.campaign().await.unwrap());
+        scheduler1.is_leader.store(true, Ordering::SeqCst);
+        let last_renewed = Instant::now();
+        // Another holder keeps the lock of the leader key, so the renewal hangs
+        let mut lock = config_client
+            .lock(&scheduler1.leader_key, Duration::from_secs(60))
+            .await
+            .unwrap();
+        let started = Instant::now();
+        assert!(scheduler1.campaign().await.is_err());
+        assert!(started.elapsed() < Duration::from_millis(500));
+        scheduler1.step_down_if_expired(last_renewed);
+        assert!(scheduler1.is_leader());
+
+        tokio::time::sleep(Duration::from_millis(800)).await;
+        scheduler1.step_down_if_expired(last_renewed);
+        assert!(!scheduler1.is_leader());
+        lock.unlock().await;
+    }
+}
@@ -15,33 +15,47 @@
 // specific language governing permissions and limitations
 // under the License.
 
+use crate::config::SchedulerConfig;
+use crate::scheduler_server::event::QueryStageSchedulerEvent;
 use crate::scheduler_server::{SessionBuilder, SessionContextRegistry};
 use crate::state::backend::StateBackendClient;
 use crate::state::executor_manager::ExecutorManager;
-use crate::state::persistent_state::PersistentSchedulerState;
+use crate::state::job_queue::JobQueue;
+pub(crate) use crate::state::persistent_state::JobStatusWatch;
+use crate::state::persistent_state::{JobTiming, PersistentSchedulerState};
 use crate::state::stage_manager::StageManager;
 use datafusion::physical_plan::ExecutionPlan;
 use datafusion_proto::logical_plan::AsLogicalPlan;
 use hetu_core::error::Result;
-use hetu_core::serde::protobuf::{ExecutorHeartbeat, JobStatus, KeyValuePair};
+use hetu_core::event_loop::EventSender;
+use hetu_core::serde::protobuf::{
+    job_status, ExecutorHeartbeat, FailedJob, JobProfile, JobStatus, KeyValuePair,
+    LockHolder, QueryHistoryEntry,
+};
 use hetu_core::serde::scheduler::ExecutorMetadata;
 use hetu_core::serde::{AsExecutionPlan, BallistaCodec};
+use log::{info, warn};
 use std::collections::HashMap;
 use std::sync::Arc;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 pub mod backend;
 mod executor_manager;
+pub(crate) mod job_queue;
+pub(crate) mod leader_election;
 mod persistent_state;
 mod stage_manager;
 pub mod task_scheduler;
 
+pub(crate) use stage_manager::shuffle_service_id;
+
 #[derive(Clone)]
 pub(super) struct SchedulerState<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan>
 {
     persistent_state: PersistentSchedulerState<T, U>,
     pub executor_manager: ExecutorManager,
     pub stage_manager: StageManager,
+    pub(crate) job_queue: JobQueue,
 }
 
 impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan> SchedulerState<T, U> {
@@ -50,6 +64,22 @@ impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan> SchedulerState<T,
         namespace: String,
         session_builder: SessionBuilder,
         codec: BallistaCodec<T, U>,
+    ) -> Self {
+        Self::new_with_config(
+            config_client,
+            namespace,
+            session_builder,
+            codec,
+            &SchedulerConfig::default(),
+        )
+    }
+
+    pub fn new_with_config(
+        config_client: Arc<dyn StateBackendClient>,
+        namespace: String,
+        session_builder: SessionBuilder,
+        codec: BallistaCodec<T, U>,
+        scheduler_config: &SchedulerConfig,
     ) -> Self {
         Self {
             persistent_state: PersistentSchedulerState::new(
@@ -57,9 +87,11 @@ impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan> SchedulerState<T,
                 namespace,
                 session_builder,
                 codec,
-            ),
-            executor_manager: ExecutorManager::new(),
+            )
+            .with_query_history_size(scheduler_config.query_history_size),
+            executor_manager: ExecutorManager::new(scheduler_config),
             stage_manager: StageManager::new(),
+            job_queue: JobQueue::new(scheduler_config),
         }
     }
 
@@ -141,13 +173,161 @@ impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan> SchedulerState<T,
     ) -> Result<()> {
         self.persistent_state
             .save_job_metadata(job_id, status)
-            .await
+            .await?;
+        if persistent_state::is_finished(status) {
+            // The job is finished anyway, so failing to keep its history is not fatal
+            if let Err(e) = self.save_query_history(job_id, status).await {
+                warn!("Fail to save the query history of job {}: {}", job_id, e);
+            }
+        }
+        Ok(())
+    }
+
+    async fn save_query_history(&self, job_id: &str, status: &JobStatus) -> Result<()> {
+        let timing = self.get_job_timing(job_id);
+        let entry = QueryHistoryEntry {
+            job_id: job_id.to_owned(),
+            status: Some(status.clone()),
+            queued_at: timing.queued_at.unwrap_or_default(),
+            started_at: timing.started_at.unwrap_or_default(),
+            finished_at: timing.finished_at.unwrap_or_default(),
+            profile: Some(self.stage_manager.get_job_profile(job_id)),
+        };
+        self.persistent_state.save_query_history(entry).await
+    }
+
+    /// The operator metrics of a job, taken from the query history once its stages have
+    /// been forgotten
+    pub fn get_job_profile(&self, job_id: &str) -> Option<JobProfile> {
+        let profile = self.stage_manager.get_job_profile(job_id);
+        if !profile.stages.is_empty() {
+            return Some(profile);
+        }
+        self.persistent_state
+            .get_query_history_entry(job_id)
+            .and_then(|entry| entry.profile)
+    }
+
+    pub fn get_query_history_entry(&self, job_id: &str) -> Option<QueryHistoryEntry> {
+        self.persistent_state.get_query_history_entry(job_id)
+    }
+
+    /// The most recently finished jobs, the most recent first
+    pub fn get_query_history(&self) -> Vec<QueryHistoryEntry> {
+        self.persistent_state.get_query_history()
     }
 
     pub fn get_job_metadata(&self, job_id: &str) -> Option<JobStatus> {
         self.persistent_state.get_job_metadata(job_id)
     }
 
+    pub fn get_jobs_metadata(&self) -> Vec<(String, JobStatus)> {
+        self.persistent_state.get_jobs_metadata()
+    }
+
+    /// Watch the statuses of a job saved from now on
+    pub(crate) async fn watch_job_metadata(
+        &self,
+        job_id: &str,
+    ) -> Result<JobStatusWatch> {
+        self.persistent_state.watch_job_metadata(job_id).await
+    }
+
+    pub(crate) fn get_job_timing(&self, job_id: &str) -> JobTiming {
+        self.persistent_state.get_job_timing(job_id)
+    }
+
+    pub fn get_stage_ids(&self, job_id: &str) -> Vec<u32> {
+        self.persistent_state.get_stage_ids(job_id)
+    }
+
+    /// An executor is drained once no task runs on it and no unfinished job may still read
+    /// its shuffle files
+    pub fn is_executor_drained(&self, executor_id: &str) -> bool {
+        if self
+            .stage_manager
+            .get_running_tasks_of_executor(executor_id)
+            > 0
+        {
+            return false;
+        }
+        self.stage_manager
+            .get_jobs_with_shuffle_output(executor_id)
+            .iter()
+            .all(|job_id| {
+                self.get_job_metadata(job_id)
+                    .map(|status| persistent_state::is_finished(&status))
+                    .unwrap_or(true)
+            })
+    }
+
+    /// Load the state from the state backend and resume the queued and running jobs
+    pub(crate) async fn recover(
+        &self,
+        query_stage_event_sender: &EventSender<QueryStageSchedulerEvent>,
+    ) -> Result<()> {
+        self.init().await?;
+        for event in self.resume_unfinished_jobs().await? {
+            query_stage_event_sender.post_event(event).await?;
+        }
+        Ok(())
+    }
+
+    /// The events resuming the queued and running jobs. The running jobs run their saved
+    /// stages again, the queued ones and the ones not split into stages yet are queued
+    /// again with their saved plan, and the other ones fail.
+    async fn resume_unfinished_jobs(&self) -> Result<Vec<QueryStageSchedulerEvent>> {
+        // The progress kept in memory is either empty or stale
+        self.job_queue.clear();
+        let mut events = vec![];
+        for (job_id, status) in self.persistent_state.get_jobs_metadata() {
+            let is_running = match status.status {
+                Some(job_status::Status::Queued(_)) => false,
+                Some(job_status::Status::Running(_)) => true,
+                _ => continue,
+            };
+            self.stage_manager.remove_job(&job_id);
+            let owner = match self.persistent_state.get_job_owner(&job_id).await {
+                Ok(owner) => owner,
+                Err(e) => {
+                    self.fail_unresumable_job(&job_id, &e.to_string()).await?;
+                    continue;
+                }
+            };
+            if is_running && !self.get_stage_ids(&job_id).is_empty() {
+                info!("Resuming the stages of job {}", job_id);
+                events.push(QueryStageSchedulerEvent::JobResumed(job_id, owner));
+            } else if let Some(plan) = self.persistent_state.get_job_plan(&job_id) {
+                info!("Queuing job {} again", job_id);
+                events.push(QueryStageSchedulerEvent::JobQueued(job_id, owner, plan));
+            } else {
+                self.fail_unresumable_job(&job_id, "its plan was not saved")
+                    .await?;
+            }
+        }
+        Ok(events)
+    }
+
+    async fn fail_unresumable_job(&self, job_id: &str, reason: &str) -> Result<()> {
+        let msg = format!("Job {} could not be resumed since {}", job_id, reason);
+        warn!("{}", msg);
+        let status = JobStatus {
+            status: Some(job_status::Status::Failed(FailedJob { error: msg })),
+        };
+        self.save_job_metadata(job_id, &status).await
+    }
+
+    /// Remove the jobs which have been finished for longer than the retention, return the
+    /// number of removed jobs
+    pub async fn remove_expired_jobs(&self, retention: Duration) -> Result<usize> {
+        let expired_jobs = self.persistent_state.get_expired_jobs(retention);
+        for job_id in expired_jobs.iter() {
+            self.persistent_state.remove_job(job_id).await?;
+            self.stage_manager.remove_job(job_id);
+        }
+        Ok(expired_jobs.len())
+    }
+
     pub async fn save_stage_plan(
         &self,
         job_id: &str,
@@ -159,6 +339,16 @@ impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan> SchedulerState<T,
             .await
     }
 
+    /// Save the physical plan of a job, which is queued again if the scheduler fails over
+    /// before the job is split into stages
+    pub async fn save_job_plan(
+        &self,
+        job_id: &str,
+        plan: Arc<dyn ExecutionPlan>,
+    ) -> Result<()> {
+        self.persistent_state.save_job_plan(job_id, plan).await
+    }
+
     pub fn get_stage_plan(
         &self,
         job_id: &str,
@@ -167,6 +357,11 @@ impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan> SchedulerState<T,
         self.persistent_state.get_stage_plan(job_id, stage_id)
     }
 
+    /// The holders of the state backend locks, for diagnosing a stuck scheduler
+    pub async fn get_lock_holders(&self) -> Result<Vec<LockHolder>> {
+        self.persistent_state.get_lock_holders().await
+    }
+
     pub fn session_registry(&self) -> Arc<SessionContextRegistry> {
         self.persistent_state.session_registry()
     }
@@ -176,15 +371,21 @@ impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan> SchedulerState<T,
 mod test {
     use std::sync::Arc;
 
+    use datafusion::arrow::datatypes::Schema;
     use datafusion::execution::context::default_session_builder;
+    use datafusion::physical_plan::empty::EmptyExec;
     use datafusion_proto::protobuf::LogicalPlanNode;
     use hetu_core::error::BallistaError;
     use hetu_core::serde::protobuf::{
-        job_status, JobStatus, PhysicalPlanNode, QueuedJob,
+        job_status, task_status, CompletedJob, CompletedTask, JobStatus, PartitionId,
+        PhysicalPlanNode, QueuedJob, RunningJob, RunningTask, ShuffleWritePartition,
+        TaskStatus,
     };
     use hetu_core::serde::scheduler::{ExecutorMetadata, ExecutorSpecification};
     use hetu_core::serde::BallistaCodec;
 
+    use crate::scheduler_server::event::QueryStageSchedulerEvent;
+
     use super::{backend::standalone::StandaloneClient, SchedulerState};
 
     #[tokio::test]
@@ -253,4 +454,139 @@ mod test {
         assert!(result.is_none());
         Ok(())
     }
+
+    #[tokio::test]
+    async fn watch_job_metadata() -> Result<(), BallistaError> {
+        let state: SchedulerState<LogicalPlanNode, PhysicalPlanNode> =
+            SchedulerState::new(
+                Arc::new(StandaloneClient::try_new_temporary()?),
+                "test".to_string(),
+                default_session_builder,
+                BallistaCodec::default(),
+            );
+        let queued = JobStatus {
+            status: Some(job_status::Status::Queued(QueuedJob {})),
+        };
+        let running = JobStatus {
+            status: Some(job_status::Status::Running(RunningJob {})),
+        };
+        state.save_job_metadata("job", &queued).await?;
+        let mut watch = state.watch_job_metadata("job").await?;
+        // the key of job2 starts with the one of job
+        state.save_job_metadata("job2", &running).await?;
+        state.save_job_metadata("job", &running).await?;
+        assert_eq!(Some(running), watch.next().await);
+        watch.cancel().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn executor_drained() -> Result<(), BallistaError> {
+        let state: SchedulerState<LogicalPlanNode, PhysicalPlanNode> =
+            SchedulerState::new(
+                Arc::new(StandaloneClient::try_new_temporary()?),
+                "test".to_string(),
+                default_session_builder,
+                BallistaCodec::default(),
+            );
+        let running = JobStatus {
+            status: Some(job_status::Status::Running(RunningJob {})),
+        };
+        state.save_job_metadata("job", &running).await?;
+        state.stage_manager.add_running_stage("job", 1, 1);
+        let task_id = PartitionId {
+            job_id: "job".to_owned(),
+            stage_id: 1,
+            partition_id: 0,
+        };
+        assert!(state.is_executor_drained("localhost"));
+
+        // A running task
+        state.stage_manager.update_tasks_status(vec![TaskStatus {
+            status: Some(task_status::Status::Running(RunningTask {
+                executor_id: "localhost".to_owned(),
+            })),
+            task_id: Some(task_id.clone()),
+        }]);
+        assert!(!state.is_executor_drained("localhost"));
+        assert!(state.is_executor_drained("other"));
+
+        // A shuffle output read by the unfinished job
+        state.stage_manager.update_tasks_status(vec![TaskStatus {
+            status: Some(task_status::Status::Completed(CompletedTask {
+                executor_id: "localhost".to_owned(),
+                partitions: vec![ShuffleWritePartition {
+                    partition_id: 0,
+                    path: "/tmp/data.arrow".to_owned(),
+                    num_batches: 1,
+                    num_rows: 10,
+                    num_bytes: 100,
+                    remote_host: "".to_owned(),
+                    remote_port: 0,
+                }],
+                metrics: vec![],
+            })),
+            task_id: Some(task_id),
+        }]);
+        assert!(!state.is_executor_drained("localhost"));
+
+        let completed = JobStatus {
+            status: Some(job_status::Status::Completed(CompletedJob {
+                partition_location: vec![],
+            })),
+        };
+        state.save_job_metadata("job", &completed).await?;
+        assert!(state.is_executor_drained("localhost"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn resume_unfinished_jobs() -> Result<(), BallistaError> {
+        let state_storage = Arc::new(StandaloneClient::try_new_temporary()?);
+        let state: SchedulerState<LogicalPlanNode, PhysicalPlanNode> =
+            SchedulerState::new(
+                state_storage.clone(),
+                "test".to_string(),
+                default_session_builder,
+                BallistaCodec::default(),
+            );
+        let queued = JobStatus {
+            status: Some(job_status::Status::Queued(QueuedJob {})),
+        };
+        state.save_job_session("job1", "session", vec![]).await?;
+        state
+            .save_job_plan(
+                "job1",
+                Arc::new(EmptyExec::new(false, Arc::new(Schema::empty()))),
+            )
+            .await?;
+        state.save_job_metadata("job1", &queued).await?;
+        // job2 has no saved plan
+        state.save_job_session("job2", "session", vec![]).await?;
+        state.save_job_metadata("job2", &queued).await?;
+
+        // A standby scheduler takes over with the saved state only
+        let state: SchedulerState<LogicalPlanNode, PhysicalPlanNode> =
+            SchedulerState::new(
+                state_storage,
+                "test".to_string(),
+                default_session_builder,
+                BallistaCodec::default(),
+            );
+        state.init().await?;
+        let events = state.resume_unfinished_jobs().await?;
+        assert_eq!(1, events.len());
+        match &events[0] {
+            QueryStageSchedulerEvent::JobQueued(job_id, owner, _) => {
+                assert_eq!("job1", job_id);
+                assert_eq!("session", owner.tenant);
+            }
+            _ => panic!("Unexpected event"),
+        }
+        assert!(matches!(
+            state.get_job_metadata("job2").unwrap().status,
+            Some(job_status::Status::Failed(_))
+        ));
+        Ok(())
+    }
 }
@@ -15,25 +15,46 @@
 // specific language governing permissions and limitations
 // under the License.
 
+use crate::metrics;
 use crate::scheduler_server::{
     create_datafusion_context, SessionBuilder, SessionContextRegistry,
 };
-use crate::state::backend::StateBackendClient;
+use crate::state::backend::{
+    StateBackendClient, Txn, Watch, WatchEvent, DEFAULT_LOCK_TTL,
+};
+use crate::state::job_queue::{job_owner, JobOwner};
 use crate::state::stage_manager::StageKey;
 use datafusion::physical_plan::ExecutionPlan;
 use datafusion_proto::logical_plan::AsLogicalPlan;
+use futures::StreamExt;
 use hetu_core::config::BallistaConfig;
 use hetu_core::error::{BallistaError, Result};
-use hetu_core::serde::protobuf::{JobSessionConfig, JobStatus, KeyValuePair};
+use hetu_core::object_store::{
+    is_secret_setting, object_store_settings, register_object_stores,
+};
+use hetu_core::serde::protobuf::{
+    job_status, JobSessionConfig, JobStatus, KeyValuePair, LockHolder, QueryHistoryEntry,
+};
 use hetu_core::serde::scheduler::ExecutorMetadata;
 use hetu_core::serde::{protobuf, AsExecutionPlan, BallistaCodec};
-use log::{debug, error};
+use log::{debug, error, warn};
 use parking_lot::RwLock;
 use prost::Message;
 use std::any::type_name;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::ops::Deref;
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// When a job went through its states, in epoch milliseconds.
+///
+/// Only known for the jobs seen by this scheduler, it is not persisted.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct JobTiming {
+    pub(crate) queued_at: Option<u64>,
+    pub(crate) started_at: Option<u64>,
+    pub(crate) finished_at: Option<u64>,
+}
 
 #[derive(Clone)]
 pub(crate) struct PersistentSchedulerState<
@@ -48,10 +69,17 @@ pub(crate) struct PersistentSchedulerState<
     // for in-memory cache
     executors_metadata: Arc<RwLock<HashMap<String, ExecutorMetadata>>>,
 
-    // TODO add remove logic
     jobs: Arc<RwLock<HashMap<String, JobStatus>>>,
     stages: Arc<RwLock<HashMap<StageKey, Arc<dyn ExecutionPlan>>>>,
+    // job_id -> physical plan of a job, kept for resuming the job after a failover
+    job_plans: Arc<RwLock<HashMap<String, Arc<dyn ExecutionPlan>>>>,
     job2session: Arc<RwLock<HashMap<String, String>>>,
+    // job_id -> when the job is seen finished, for removing it after the retention
+    finished_jobs: Arc<RwLock<HashMap<String, Instant>>>,
+    job_timings: Arc<RwLock<HashMap<String, JobTiming>>>,
+    // The most recently finished jobs, the oldest first
+    query_history: Arc<RwLock<VecDeque<QueryHistoryEntry>>>,
+    query_history_size: usize,
 
     /// DataFusion session contexts that are registered within the Scheduler
     session_context_registry: Arc<SessionContextRegistry>,
@@ -75,17 +103,30 @@ impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan>
             executors_metadata: Arc::new(RwLock::new(HashMap::new())),
             jobs: Arc::new(RwLock::new(HashMap::new())),
             stages: Arc::new(RwLock::new(HashMap::new())),
+            job_plans: Arc::new(RwLock::new(HashMap::new())),
             job2session: Arc::new(RwLock::new(HashMap::new())),
+            finished_jobs: Arc::new(RwLock::new(HashMap::new())),
+            job_timings: Arc::new(RwLock::new(HashMap::new())),
+            query_history: Arc::new(RwLock::new(VecDeque::new())),
+            query_history_size: 100,
             session_context_registry: Arc::new(SessionContextRegistry::default()),
             session_builder,
         }
     }
 
+    /// Max number of the finished jobs kept in the query history, 0 disables the history
+    pub(crate) fn with_query_history_size(mut self, query_history_size: usize) -> Self {
+        self.query_history_size = query_history_size;
+        self
+    }
+
     /// Load the state stored in storage into memory
     pub(crate) async fn init(&self) -> Result<()> {
         self.init_executors_metadata_from_storage().await?;
         self.init_jobs_from_storage().await?;
         self.init_stages_from_storage().await?;
+        self.init_job_plans_from_storage().await?;
+        self.init_query_history_from_storage().await?;
 
         Ok(())
     }
@@ -108,15 +149,18 @@ impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan>
     async fn init_jobs_from_storage(&self) -> Result<()> {
         let entries = self
             .config_client
-            .get_from_prefix(&get_job_prefix(&self.namespace))
+            .get_from_prefix(&format!("{}/", get_job_prefix(&self.namespace)))
             .await?;
 
         let mut jobs = self.jobs.write();
+        let mut finished_jobs = self.finished_jobs.write();
         for (key, entry) in entries {
             let job: JobStatus = decode_protobuf(&entry)?;
-            let job_id = extract_job_id_from_job_key(&key)
-                .map(|job_id| job_id.to_string())
-                .unwrap();
+            let job_id = extract_job_id_from_job_key(&self.namespace, &key)?.to_owned();
+            // The retention of the jobs finished before a restart starts from now on
+            if is_finished(&job) {
+                finished_jobs.insert(job_id.clone(), Instant::now());
+            }
             jobs.insert(job_id, job);
         }
 
@@ -133,43 +177,7 @@ impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan>
         {
             for (key, entry) in entries {
                 let (job_id, stage_id) = extract_stage_id_from_stage_key(&key).unwrap();
-                let job_session = self
-                    .config_client
-                    .get(&get_job_config_key(&self.namespace, &job_id))
-                    .await?;
-                let job_session: JobSessionConfig = decode_protobuf(&job_session)?;
-
-                // Rebuild SessionContext from serialized settings
-                let mut config_builder = BallistaConfig::builder();
-                for kv_pair in &job_session.configs {
-                    config_builder = config_builder.set(&kv_pair.key, &kv_pair.value);
-                }
-                let config = config_builder.build().map_err(|e| {
-                    let msg = format!("Could not parse configs: {}", e);
-                    error!("{}", msg);
-                    BallistaError::Internal(format!(
-                        "Error building configs for job ID {}",
-                        job_id
-                    ))
-                })?;
-
-                let session_ctx =
-                    create_datafusion_context(&config, self.session_builder);
-                self.session_registry()
-                    .register_session(session_ctx.clone())
-                    .await;
-
-                let value = U::try_decode(&entry)?;
-                let runtime = session_ctx.runtime_env();
-                let plan = value.try_into_physical_plan(
-                    session_ctx.deref(),
-                    runtime.deref(),
-                    self.codec.physical_extension_codec(),
-                )?;
-
-                let mut job2_sess = self.job2session.write();
-                job2_sess.insert(job_id.clone(), job_session.session_id);
-
+                let plan = self.decode_job_plan(&job_id, &entry).await?;
                 tmp_stages.insert((job_id, stage_id), plan);
             }
         }
@@ -180,6 +188,98 @@ impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan>
         Ok(())
     }
 
+    async fn init_job_plans_from_storage(&self) -> Result<()> {
+        let entries = self
+            .config_client
+            .get_from_prefix(&format!("{}/", get_job_plan_prefix(&self.namespace)))
+            .await?;
+
+        let mut job_plans = HashMap::new();
+        for (key, entry) in entries {
+            let job_id = key.rsplit('/').next().unwrap_or_default().to_owned();
+            let plan = self.decode_job_plan(&job_id, &entry).await?;
+            job_plans.insert(job_id, plan);
+        }
+        *self.job_plans.write() = job_plans;
+        Ok(())
+    }
+
+    /// Decode a plan of a job within the session the job was submitted with, which is
+    /// registered again from the saved job settings
+    async fn decode_job_plan(
+        &self,
+        job_id: &str,
+        entry: &[u8],
+    ) -> Result<Arc<dyn ExecutionPlan>> {
+        let job_session = self
+            .config_client
+            .get(&get_job_config_key(&self.namespace, job_id))
+            .await?;
+        let job_session: JobSessionConfig = decode_protobuf(&job_session)?;
+
+        // Rebuild SessionContext from serialized settings
+        let mut config_builder = BallistaConfig::builder();
+        for kv_pair in &job_session.configs {
+            config_builder = config_builder.set(&kv_pair.key, &kv_pair.value);
+        }
+        let config = config_builder.build().map_err(|e| {
+            let msg = format!("Could not parse configs: {}", e);
+            error!("{}", msg);
+            BallistaError::Internal(format!(
+                "Error building configs for job ID {}",
+                job_id
+            ))
+        })?;
+
+        let session_ctx = create_datafusion_context(&config, self.session_builder);
+        self.session_registry()
+            .register_session(session_ctx.clone())
+            .await;
+        // The stages reading remote tables need the object stores to decode
+        let mut session_settings = object_store_settings(config.settings());
+        if let Err(e) =
+            register_object_stores(&session_ctx.runtime_env(), &session_settings)
+        {
+            warn!(
+                "Fail to register the object stores of job {}: {}",
+                job_id, e
+            );
+        }
+        session_settings.extend(config.shuffle_settings());
+        self.session_registry()
+            .set_task_settings(&session_ctx.session_id(), session_settings)
+            .await;
+
+        let value = U::try_decode(entry)?;
+        let runtime = session_ctx.runtime_env();
+        let plan = value.try_into_physical_plan(
+            session_ctx.deref(),
+            runtime.deref(),
+            self.codec.physical_extension_codec(),
+        )?;
+
+        let mut job2_sess = self.job2session.write();
+        job2_sess.insert(job_id.to_owned(), job_session.session_id);
+        Ok(plan)
+    }
+
+    async fn init_query_history_from_storage(&self) -> Result<()> {
+        let entries = self
+            .config_client
+            .get_from_prefix(&get_query_history_prefix(&self.namespace))
+            .await?;
+
+        let mut history = entries
+            .iter()
+            .map(|(_key, entry)| decode_protobuf::<QueryHistoryEntry>(entry))
+            .collect::<Result<Vec<_>>>()?;
+        history.sort_by_key(|entry| entry.finished_at);
+        *self.query_history.write() = history.into();
+
+        // The history size may have been lowered since the entries were saved
+        self.trim_query_history().await
+    }
+
     pub(crate) async fn save_executor_metadata(
         &self,
         executor_meta: ExecutorMetadata,
@@ -224,6 +324,11 @@ impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan>
         configs: Vec<KeyValuePair>,
     ) -> Result<()> {
         let key = get_job_config_key(&self.namespace, job_id);
+        // The secrets are not saved, the executors resolve them
+        let configs = configs
+            .into_iter()
+            .filter(|kv_pair| !is_secret_setting(&kv_pair.key))
+            .collect();
         let value = encode_protobuf(&protobuf::JobSessionConfig {
             session_id: session_id.to_string(),
             configs,
@@ -237,6 +342,23 @@ impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan>
         Ok(())
     }
 
+    /// The owner of a job, from the settings it was submitted with
+    pub(crate) async fn get_job_owner(&self, job_id: &str) -> Result<JobOwner> {
+        let job_session = self
+            .config_client
+            .get(&get_job_config_key(&self.namespace, job_id))
+            .await?;
+        let job_session: JobSessionConfig = decode_protobuf(&job_session)?;
+        let config = BallistaConfig::with_settings(
+            job_session
+                .configs
+                .into_iter()
+                .map(|kv_pair| (kv_pair.key, kv_pair.value))
+                .collect(),
+        )?;
+        Ok(job_owner(&config, &job_session.session_id))
+    }
+
     pub(crate) fn get_session_from_job(&self, job_id: &str) -> Option<String> {
         let job_session = self.job2session.read();
         job_session.get(job_id).cloned()
@@ -259,6 +381,42 @@ impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan>
             // Save in memory
             let mut jobs = self.jobs.write();
             jobs.insert(job_id.to_string(), status.clone());
+            if is_finished(status) {
+                self.finished_jobs
+                    .write()
+                    .entry(job_id.to_string())
+                    .or_insert_with(Instant::now);
+            }
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("Time went backwards")
+                .as_millis() as u64;
+            let mut job_timings = self.job_timings.write();
+            let timing = job_timings.entry(job_id.to_string()).or_default();
+            match status.status {
+                Some(job_status::Status::Queued(_)) => {
+                    if timing.queued_at.is_none() {
+                        timing.queued_at = Some(now);
+                        metrics::record_job_submitted();
+                    }
+                }
+                Some(job_status::Status::Running(_)) => {
+                    timing.started_at.get_or_insert(now);
+                }
+                ref finished => {
+                    if timing.finished_at.is_none() {
+                        timing.finished_at = Some(now);
+                        let status = match finished {
+                            Some(job_status::Status::Completed(_)) => "completed",
+                            _ => "failed",
+                        };
+                        let duration = timing
+                            .queued_at
+                            .map(|queued_at| Duration::from_millis(now - queued_at));
+                        metrics::record_job_finished(status, duration);
+                    }
+                }
+            }
         }
 
         Ok(())
@@ -269,6 +427,29 @@ impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan>
         jobs.get(job_id).cloned()
     }
 
+    /// Watch the statuses of a job saved from now on, by any scheduler sharing the state
+    /// backend
+    pub(crate) async fn watch_job_metadata(
+        &self,
+        job_id: &str,
+    ) -> Result<JobStatusWatch> {
+        let key = get_job_key(&self.namespace, job_id);
+        let watch = self.config_client.watch(key.clone()).await?;
+        Ok(JobStatusWatch { key, watch })
+    }
+
+    pub(crate) fn get_job_timing(&self, job_id: &str) -> JobTiming {
+        let job_timings = self.job_timings.read();
+        job_timings.get(job_id).cloned().unwrap_or_default()
+    }
+
+    pub(crate) fn get_jobs_metadata(&self) -> Vec<(String, JobStatus)> {
+        let jobs = self.jobs.read();
+        jobs.iter()
+            .map(|(job_id, status)| (job_id.clone(), status.clone()))
+            .collect()
+    }
+
     pub(crate) async fn save_stage_plan(
         &self,
         job_id: &str,
@@ -300,6 +481,42 @@ impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan>
         Ok(())
     }
 
+    /// Save the physical plan of a job before it is split into stages
+    pub(crate) async fn save_job_plan(
+        &self,
+        job_id: &str,
+        plan: Arc<dyn ExecutionPlan>,
+    ) -> Result<()> {
+        {
+            // Save in db
+            let key = get_job_plan_key(&self.namespace, job_id);
+            let value = {
+                let mut buf: Vec<u8> = vec![];
+                let proto = U::try_from_physical_plan(
+                    plan.clone(),
+                    self.codec.physical_extension_codec(),
+                )?;
+                proto.try_encode(&mut buf)?;
+
+                buf
+            };
+            self.synchronize_save(key, value).await?;
+        }
+
+        {
+            // Save in memory
+            let mut job_plans = self.job_plans.write();
+            job_plans.insert(job_id.to_string(), plan);
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn get_job_plan(&self, job_id: &str) -> Option<Arc<dyn ExecutionPlan>> {
+        let job_plans = self.job_plans.read();
+        job_plans.get(job_id).cloned()
+    }
+
     pub(crate) fn get_stage_plan(
         &self,
         job_id: &str,
@@ -310,12 +527,127 @@ impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan>
         stages.get(&key).cloned()
     }
 
+    /// The ids of the stages planned for a job, in ascending order
+    pub(crate) fn get_stage_ids(&self, job_id: &str) -> Vec<u32> {
+        let stages = self.stages.read();
+        let mut stage_ids = stages
+            .keys()
+            .filter(|(stage_job_id, _)| stage_job_id == job_id)
+            .map(|(_, stage_id)| *stage_id)
+            .collect::<Vec<_>>();
+        stage_ids.sort_unstable();
+        stage_ids
+    }
+
+    /// The jobs which have been finished for longer than the retention
+    pub(crate) fn get_expired_jobs(&self, retention: Duration) -> Vec<String> {
+        let finished_jobs = self.finished_jobs.read();
+        finished_jobs
+            .iter()
+            .filter(|(_, finished_at)| finished_at.elapsed() >= retention)
+            .map(|(job_id, _)| job_id.clone())
+            .collect()
+    }
+
+    /// Remove the status, the session and the stage plans of a job
+    pub(crate) async fn remove_job(&self, job_id: &str) -> Result<()> {
+        {
+            // Remove from db
+            let txn = Txn::new()
+                .delete(get_job_key(&self.namespace, job_id))
+                .delete(get_job_config_key(&self.namespace, job_id))
+                .delete(get_job_plan_key(&self.namespace, job_id));
+            self.config_client.txn(txn).await?;
+            self.config_client
+                .delete_prefix(&format!(
+                    "{}/",
+                    get_job_stage_prefix(&self.namespace, job_id)
+                ))
+                .await?;
+        }
+
+        {
+            // Remove from memory
+            self.jobs.write().remove(job_id);
+            self.stages
+                .write()
+                .retain(|(stage_job_id, _), _| stage_job_id != job_id);
+            self.job_plans.write().remove(job_id);
+            self.job2session.write().remove(job_id);
+            self.finished_jobs.write().remove(job_id);
+            self.job_timings.write().remove(job_id);
+        }
+
+        Ok(())
+    }
+
+    /// Add a finished job to the query history, dropping the oldest entries beyond the size
+    pub(crate) async fn save_query_history(
+        &self,
+        entry: QueryHistoryEntry,
+    ) -> Result<()> {
+        if self.query_history_size == 0 {
+            return Ok(());
+        }
+        {
+            // Save in db
+            let key = get_query_history_key(&self.namespace, &entry.job_id);
+            let value = encode_protobuf(&entry)?;
+            self.synchronize_save(key, value).await?;
+        }
+
+        {
+            // Save in memory
+            let mut query_history = self.query_history.write();
+            query_history.retain(|existing| existing.job_id != entry.job_id);
+            query_history.push_back(entry);
+        }
+
+        self.trim_query_history().await
+    }
+
+    async fn trim_query_history(&self) -> Result<()> {
+        let dropped = {
+            let mut query_history = self.query_history.write();
+            let excess = query_history.len().saturating_sub(self.query_history_size);
+            query_history.drain(..excess).collect::<Vec<_>>()
+        };
+        for entry in dropped {
+            self.config_client
+                .delete(&get_query_history_key(&self.namespace, &entry.job_id))
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// The finished jobs in the query history, the most recent first
+    pub(crate) fn get_query_history(&self) -> Vec<QueryHistoryEntry> {
+        let query_history = self.query_history.read();
+        query_history.iter().rev().cloned().collect()
+    }
+
+    pub(crate) fn get_query_history_entry(
+        &self,
+        job_id: &str,
+    ) -> Option<QueryHistoryEntry> {
+        let query_history = self.query_history.read();
+        query_history
+            .iter()
+            .find(|entry| entry.job_id == job_id)
+            .cloned()
+    }
+
+    pub(crate) async fn get_lock_holders(&self) -> Result<Vec<LockHolder>> {
+        self.config_client.lock_holders().await
+    }
+
     async fn synchronize_save(&self, key: String, value: Vec<u8>) -> Result<()> {
-        let mut lock = self.config_client.lock().await?;
-        self.config_client.put(key, value).await?;
+        let mut lock = self.config_client.lock(&key, DEFAULT_LOCK_TTL).await?;
+        let result = self.config_client.put(key, value).await;
         lock.unlock().await;
 
-        Ok(())
+        result
     }
 
     pub fn session_registry(&self) -> Arc<SessionContextRegistry> {
@@ -323,6 +655,38 @@ impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan>
     }
 }
 
+/// The statuses saved for a job, see [PersistentSchedulerState::watch_job_metadata]
+pub(crate) struct JobStatusWatch {
+    key: String,
+    watch: Box<dyn Watch>,
+}
+
+impl JobStatusWatch {
+    /// The next saved status, none once the job is removed or the watch ends
+    pub(crate) async fn next(&mut self) -> Option<JobStatus> {
+        // the watch is on a prefix, which the keys of other jobs may start with
+        while let Some(event) = self.watch.next().await {
+            match event {
+                WatchEvent::Put(key, value) if key == self.key => {
+                    match decode_protobuf(&value) {
+                        Ok(status) => return Some(status),
+                        Err(e) => warn!("Fail to decode the status at {}: {}", key, e),
+                    }
+                }
+                WatchEvent::Delete(key) if key == self.key => return None,
+                _ => {}
+            }
+        }
+        None
+    }
+
+    pub(crate) async fn cancel(&mut self) {
+        if let Err(e) = self.watch.cancel().await {
+            warn!("Fail to cancel the watch of {}: {}", self.key, e);
+        }
+    }
+}
+
 fn get_executors_metadata_prefix(namespace: &str) -> String {
     format!("/ballista/{}/executor_metadata", namespace)
 }
@@ -343,17 +707,48 @@ fn get_job_config_key(namespace: &str, id: &str) -> String {
     format!("config/{}/{}", get_job_prefix(namespace), id)
 }
 
+fn get_job_plan_prefix(namespace: &str) -> String {
+    format!("/ballista/{}/job_plans", namespace)
+}
+
+fn get_job_plan_key(namespace: &str, id: &str) -> String {
+    format!("{}/{}", get_job_plan_prefix(namespace), id)
+}
+
 fn get_stage_prefix(namespace: &str) -> String {
     format!("/ballista/{}/stages", namespace,)
 }
 
+fn get_job_stage_prefix(namespace: &str, job_id: &str) -> String {
+    format!("{}/{}", get_stage_prefix(namespace), job_id)
+}
+
 fn get_stage_plan_key(namespace: &str, job_id: &str, stage_id: u32) -> String {
-    format!("{}/{}/{}", get_stage_prefix(namespace), job_id, stage_id,)
+    format!("{}/{}", get_job_stage_prefix(namespace, job_id), stage_id)
 }
-fn extract_job_id_from_job_key(job_key: &str) -> Result<&str> {
-    job_key.split('/').nth(2).ok_or_else(|| {
-        BallistaError::Internal(format!("Unexpected task key: {}", job_key))
-    })
+
+fn get_query_history_prefix(namespace: &str) -> String {
+    format!("/ballista/{}/history", namespace)
+}
+
+fn get_query_history_key(namespace: &str, job_id: &str) -> String {
+    format!("{}/{}", get_query_history_prefix(namespace), job_id)
+}
+
+pub(super) fn is_finished(job: &JobStatus) -> bool {
+    matches!(
+        job.status,
+        Some(job_status::Status::Completed(_)) | Some(job_status::Status::Failed(_))
+    )
+}
+
+fn extract_job_id_from_job_key<'a>(namespace: &str, job_key: &'a str) -> Result<&'a str> {
+    job_key
+        .strip_prefix(&format!("{}/", get_job_prefix(namespace)))
+        .filter(|job_id| !job_id.is_empty() && !job_id.contains('/'))
+        .ok_or_else(|| {
+            BallistaError::Internal(format!("Unexpected job key: {}", job_key))
+        })
 }
 
 fn extract_stage_id_from_stage_key(stage_key: &str) -> Result<StageKey> {
@@ -376,7 +771,7 @@ fn extract_stage_id_from_stage_key(stage_key: &str) -> Result<StageKey> {
     }
 }
 
-fn decode_protobuf<T: Message + Default>(bytes: &[u8]) -> Result<T> {
+pub(super) fn decode_protobuf<T: Message + Default>(bytes: &[u8]) -> Result<T> {
     T::decode(bytes).map_err(|e| {
         BallistaError::Internal(format!(
             "Could not deserialize {}: {}",
@@ -386,7 +781,7 @@ fn decode_protobuf<T: Message + Default>(bytes: &[u8]) -> Result<T> {
     })
 }
 
-fn encode_protobuf<T: Message + Default>(msg: &T) -> Result<Vec<u8>> {
+pub(super) fn encode_protobuf<T: Message + Default>(msg: &T) -> Result<Vec<u8>> {
     let mut value: Vec<u8> = Vec::with_capacity(msg.encoded_len());
     msg.encode(&mut value).map_err(|e| {
         BallistaError::Internal(format!(
@@ -400,7 +795,10 @@ fn encode_protobuf<T: Message + Default>(msg: &T) -> Result<Vec<u8>> {
 
 #[cfg(test)]
 mod test {
-    use super::extract_stage_id_from_stage_key;
+    use super::{
+        decode_protobuf, extract_job_id_from_job_key, extract_stage_id_from_stage_key,
+        get_job_config_key,
+    };
     use crate::state::backend::standalone::StandaloneClient;
 
     use crate::state::persistent_state::PersistentSchedulerState;
@@ -409,11 +807,19 @@ mod test {
     use datafusion::logical_plan::LogicalPlanBuilder;
     use datafusion::prelude::SessionContext;
     use datafusion_proto::protobuf::LogicalPlanNode;
+    use hetu_core::object_store::{
+        OBJECT_STORE_S3_ACCESS_KEY_ID, OBJECT_STORE_S3_SECRET_ACCESS_KEY,
+    };
     use hetu_core::serde::protobuf::job_status::Status;
-    use hetu_core::serde::protobuf::{JobStatus, PhysicalPlanNode, QueuedJob};
+    use hetu_core::serde::protobuf::{
+        CompletedJob, JobSessionConfig, JobStatus, KeyValuePair, PhysicalPlanNode,
+        QueryHistoryEntry, QueuedJob,
+    };
     use hetu_core::serde::BallistaCodec;
 
+    use crate::state::backend::StateBackendClient;
     use std::sync::Arc;
+    use std::time::Duration;
 
     #[test]
     fn test_extract_stage_id_from_stage_key() {
@@ -439,6 +845,22 @@ mod test {
         assert_eq!(stage_id, 1);
     }
 
+    #[test]
+    fn test_extract_job_id_from_job_key() {
+        assert_eq!(
+            extract_job_id_from_job_key("default", "/ballista/default/jobs/2Yoyba8")
+                .expect("extracting job key"),
+            "2Yoyba8"
+        );
+        assert!(
+            extract_job_id_from_job_key("default", "/ballista/other/jobs/2Yoyba8")
+                .is_err()
+        );
+        assert!(
+            extract_job_id_from_job_key("default", "/ballista/default/jobs/").is_err()
+        );
+    }
+
     #[tokio::test]
     async fn test_init_from_storage() {
         let ctx = SessionContext::new();
@@ -511,6 +933,15 @@ mod test {
 
         persistent_state.init().await.expect("initializing state");
 
+        assert_eq!(
+            persistent_state.get_jobs_metadata(),
+            vec![(
+                job_id.clone(),
+                JobStatus {
+                    status: Some(Status::Queued(QueuedJob {})),
+                }
+            )]
+        );
         assert_eq!(
             persistent_state
                 .get_stage_plan(&job_id, 1)
@@ -522,4 +953,276 @@ mod test {
             Some("session-id".to_string())
         );
     }
+
+    #[tokio::test]
+    async fn test_save_job_session_without_secrets() {
+        let config_client = Arc::new(
+            StandaloneClient::try_new_temporary().expect("creating config client"),
+        );
+        let persistent_state: PersistentSchedulerState<
+            LogicalPlanNode,
+            PhysicalPlanNode,
+        > = PersistentSchedulerState::new(
+            config_client.clone(),
+            "default".to_string(),
+            default_session_builder,
+            BallistaCodec::default(),
+        );
+
+        let setting = |key: &str, value: &str| KeyValuePair {
+            key: key.to_owned(),
+            value: value.to_owned(),
+        };
+        persistent_state
+            .save_job_session(
+                "job-id",
+                "session-id",
+                vec![
+                    setting(OBJECT_STORE_S3_ACCESS_KEY_ID, "session"),
+                    setting(OBJECT_STORE_S3_SECRET_ACCESS_KEY, "secret"),
+                ],
+            )
+            .await
+            .expect("saving session");
+
+        let job_session = config_client
+            .get(&get_job_config_key("default", "job-id"))
+            .await
+            .expect("getting job session");
+        let job_session: JobSessionConfig =
+            decode_protobuf(&job_session).expect("decoding job session");
+        let keys = job_session
+            .configs
+            .iter()
+            .map(|kv_pair| kv_pair.key.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(keys, vec![OBJECT_STORE_S3_ACCESS_KEY_ID]);
+    }
+
+    #[tokio::test]
+    async fn test_remove_expired_job() {
+        let ctx = SessionContext::new();
+        let plan = LogicalPlanBuilder::empty(true)
+            .build()
+            .expect("create empty logical plan");
+        let plan = ctx
+            .create_physical_plan(&plan)
+            .await
+            .expect("create physical plan");
+
+        let config_client = Arc::new(
+            StandaloneClient::try_new_temporary().expect("creating config client"),
+        );
+        let persistent_state: PersistentSchedulerState<
+            LogicalPlanNode,
+            PhysicalPlanNode,
+        > = PersistentSchedulerState::new(
+            config_client.clone(),
+            "default".to_string(),
+            default_session_builder,
+            BallistaCodec::default(),
+        );
+
+        for job_id in ["job1", "job2"] {
+            persistent_state
+                .save_job_session(job_id, "session-id", vec![])
+                .await
+                .expect("saving session");
+            persistent_state
+                .save_stage_plan(job_id, 1, plan.clone())
+                .await
+                .expect("saving stage plan");
+        }
+        persistent_state
+            .save_job_metadata(
+                "job1",
+                &JobStatus {
+                    status: Some(Status::Completed(CompletedJob {
+                        partition_location: vec![],
+                    })),
+                },
+            )
+            .await
+            .expect("saving job metadata");
+        persistent_state
+            .save_job_metadata(
+                "job2",
+                &JobStatus {
+                    status: Some(Status::Queued(QueuedJob {})),
+                },
+            )
+            .await
+            .expect("saving job metadata");
+
+        assert!(persistent_state
+            .get_expired_jobs(Duration::from_secs(3600))
+            .is_empty());
+        let expired = persistent_state.get_expired_jobs(Duration::from_secs(0));
+        assert_eq!(expired, vec!["job1".to_owned()]);
+
+        persistent_state
+            .remove_job("job1")
+            .await
+            .expect("removing job");
+        assert!(persistent_state.get_job_metadata("job1").is_none());
+        assert!(persistent_state.get_stage_plan("job1", 1).is_none());
+        assert!(persistent_state.get_stage_plan("job2", 1).is_some());
+        assert!(config_client
+            .get_from_prefix("/ballista/default/stages/job1")
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(config_client
+            .get("/ballista/default/jobs/job1")
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_remove_expired_job_after_restart() {
+        let ctx = SessionContext::new();
+        let plan = LogicalPlanBuilder::empty(true)
+            .build()
+            .expect("create empty logical plan");
+        let plan = ctx
+            .create_physical_plan(&plan)
+            .await
+            .expect("create physical plan");
+
+        let config_client = Arc::new(
+            StandaloneClient::try_new_temporary().expect("creating config client"),
+        );
+        let persistent_state: PersistentSchedulerState<
+            LogicalPlanNode,
+            PhysicalPlanNode,
+        > = PersistentSchedulerState::new(
+            config_client.clone(),
+            "default".to_string(),
+            default_session_builder,
+            BallistaCodec::default(),
+        );
+        persistent_state
+            .save_job_session("job1", "session-id", vec![])
+            .await
+            .expect("saving session");
+        persistent_state
+            .save_job_plan("job1", plan.clone())
+            .await
+            .expect("saving job plan");
+        persistent_state
+            .save_stage_plan("job1", 1, plan)
+            .await
+            .expect("saving stage plan");
+        persistent_state
+            .save_job_metadata(
+                "job1",
+                &JobStatus {
+                    status: Some(Status::Completed(CompletedJob {
+                        partition_location: vec![],
+                    })),
+                },
+            )
+            .await
+            .expect("saving job metadata");
+
+        // The finished jobs are removed by the scheduler loading them after a restart
+        let persistent_state: PersistentSchedulerState<
+            LogicalPlanNode,
+            PhysicalPlanNode,
+        > = PersistentSchedulerState::new(
+            config_client.clone(),
+            "default".to_string(),
+            default_session_builder,
+            BallistaCodec::default(),
+        );
+        persistent_state.init().await.expect("initializing state");
+        let expired = persistent_state.get_expired_jobs(Duration::from_secs(0));
+        assert_eq!(expired, vec!["job1".to_owned()]);
+        persistent_state
+            .remove_job("job1")
+            .await
+            .expect("removing job");
+        for prefix in [
+            "/ballista/default/jobs/",
+            "/ballista/default/job_plans/",
+            "/ballista/default/stages/",
+            "config//ballista/default/jobs/",
+        ] {
+            assert!(
+                config_client
+                    .get_from_prefix(prefix)
+                    .await
+                    .unwrap()
+                    .is_empty(),
+                "{}",
+                prefix
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_query_history() {
+        let config_client = Arc::new(
+            StandaloneClient::try_new_temporary().expect("creating config client"),
+        );
+        let persistent_state: PersistentSchedulerState<
+            LogicalPlanNode,
+            PhysicalPlanNode,
+        > = PersistentSchedulerState::new(
+            config_client.clone(),
+            "default".to_string(),
+            default_session_builder,
+            BallistaCodec::default(),
+        )
+        .with_query_history_size(2);
+
+        for (finished_at, job_id) in ["job1", "job2", "job3"].into_iter().enumerate() {
+            persistent_state
+                .save_query_history(QueryHistoryEntry {
+                    job_id: job_id.to_owned(),
+                    finished_at: finished_at as u64 + 1,
+                    ..Default::default()
+                })
+                .await
+                .expect("saving query history");
+        }
+
+        let job_ids = |history: Vec<QueryHistoryEntry>| {
+            history
+                .into_iter()
+                .map(|entry| entry.job_id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            job_ids(persistent_state.get_query_history()),
+            vec!["job3".to_owned(), "job2".to_owned()]
+        );
+        assert!(persistent_state.get_query_history_entry("job1").is_none());
+
+        // Restore the history with a lower size
+        let persistent_state: PersistentSchedulerState<
+            LogicalPlanNode,
+            PhysicalPlanNode,
+        > = PersistentSchedulerState::new(
+            config_client.clone(),
+            "default".to_string(),
+            default_session_builder,
+            BallistaCodec::default(),
+        )
+        .with_query_history_size(1);
+        persistent_state.init().await.expect("initializing state");
+        assert_eq!(
+            job_ids(persistent_state.get_query_history()),
+            vec!["job3".to_owned()]
+        );
+        assert_eq!(
+            config_client
+                .get_from_prefix("/ballista/default/history")
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+    }
 }
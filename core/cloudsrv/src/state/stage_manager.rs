@@ -17,20 +17,26 @@
 
 use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
+use std::time::Instant;
 
 use log::{debug, error, warn};
 use parking_lot::RwLock;
 use rand::Rng;
 
+use crate::metrics;
 use crate::scheduler_server::event::QueryStageSchedulerEvent;
 use crate::state::task_scheduler::StageScheduler;
 use hetu_core::error::{BallistaError, Result};
 use hetu_core::serde::protobuf;
-use hetu_core::serde::protobuf::{task_status, FailedTask, TaskStatus};
+use hetu_core::serde::protobuf::{task_status, FailedTask, RunningTask, TaskStatus};
 
 /// job_id + stage_id
 pub type StageKey = (String, u32);
 
+/// Times a completed stage runs again to recover its shuffle output lost with an executor,
+/// before the jobs reading it fail
+const MAX_STAGE_RESUBMISSIONS: usize = 3;
+
 #[derive(Clone)]
 pub struct StageManager {
     stage_distribution: Arc<RwLock<StageDistribution>>,
@@ -166,7 +172,11 @@ impl StageManager {
     pub fn is_running_stage(&self, job_id: &str, stage_id: u32) -> bool {
         let stage_key = (job_id.to_owned(), stage_id);
         let stage_distribution = self.stage_distribution.read();
-        stage_distribution.stages_running.get(&stage_key).is_some()
+        stage_distribution
+            .stages_running
+            .get(&stage_key)
+            .map(|stage| !stage.cancelled)
+            .unwrap_or(false)
     }
 
     pub fn is_completed_stage(&self, job_id: &str, stage_id: u32) -> bool {
@@ -214,37 +224,99 @@ impl StageManager {
         let mut ret = vec![];
         let mut stage_distribution = self.stage_distribution.write();
         for (stage_key, stage_tasks_status) in all_tasks_status.into_iter() {
-            if let Some(stage) = stage_distribution.stages_running.get_mut(&stage_key) {
+            let fetch_failures = if let Some(stage) =
+                stage_distribution.stages_running.get_mut(&stage_key)
+            {
                 for task_status in &stage_tasks_status {
                     stage.update_task_status(task_status);
                 }
-                if let Some(fail_message) = stage.get_fail_message() {
-                    ret.push(QueryStageSchedulerEvent::JobFailed(
-                        stage_key.0.clone(),
-                        stage_key.1,
-                        fail_message,
-                    ));
-                } else if stage.is_completed() {
-                    stage_distribution.complete_stage(stage_key.clone());
-                    if self.is_final_stage(&stage_key.0, stage_key.1) {
-                        ret.push(QueryStageSchedulerEvent::JobFinished(
-                            stage_key.0.clone(),
-                        ));
-                    } else {
-                        ret.push(QueryStageSchedulerEvent::StageFinished(
-                            stage_key.0.clone(),
-                            stage_key.1,
-                        ));
+                if stage.cancelled {
+                    // The stage of a cancelled job is gone once its running tasks report
+                    if stage.get_running_tasks().is_empty() {
+                        stage_distribution.stages_running.remove(&stage_key);
                     }
+                    continue;
                 }
+                stage.get_fetch_failures()
             } else {
                 error!("Fail to find stage for {:?}/{}", &stage_key.0, stage_key.1);
+                continue;
+            };
+
+            // The tasks which lost their input run again once the input stage recovered it
+            for (task_idx, input_stage_id, executor_id) in fetch_failures {
+                let input_stage_key = (stage_key.0.clone(), input_stage_id);
+                let waiting_for = match stage_distribution
+                    .recover_lost_output(&input_stage_key, &executor_id)
+                {
+                    Some(LostOutput::Resubmitted) => {
+                        ret.push(QueryStageSchedulerEvent::StageResubmitted(
+                            stage_key.0.clone(),
+                            input_stage_id,
+                        ));
+                        Some(input_stage_id)
+                    }
+                    Some(LostOutput::Recovering) => Some(input_stage_id),
+                    Some(LostOutput::Recovered) => None,
+                    None => continue,
+                };
+                if let Some(stage) = stage_distribution.stages_running.get_mut(&stage_key)
+                {
+                    stage.retry_task(task_idx, waiting_for);
+                }
+            }
+
+            let stage = match stage_distribution.stages_running.get_mut(&stage_key) {
+                Some(stage) => stage,
+                None => continue,
+            };
+            if let Some(fail_message) = stage.get_fail_message() {
+                ret.push(QueryStageSchedulerEvent::JobFailed(
+                    stage_key.0.clone(),
+                    stage_key.1,
+                    fail_message,
+                ));
+            } else if stage.is_completed() {
+                metrics::record_stage_completed(stage.started_at.elapsed());
+                stage_distribution.complete_stage(stage_key.clone());
+                if self.is_final_stage(&stage_key.0, stage_key.1) {
+                    ret.push(QueryStageSchedulerEvent::JobFinished(stage_key.0.clone()));
+                } else {
+                    ret.push(QueryStageSchedulerEvent::StageFinished(
+                        stage_key.0.clone(),
+                        stage_key.1,
+                    ));
+                }
             }
         }
 
         ret
     }
 
+    /// The running stages of a job waiting for an input stage to recover its lost shuffle
+    /// output
+    pub fn get_stages_waiting_for(&self, job_id: &str, input_stage_id: u32) -> Vec<u32> {
+        let stage_distribution = self.stage_distribution.read();
+        stage_distribution
+            .stages_running
+            .iter()
+            .filter(|((stage_job_id, _), stage)| {
+                stage_job_id == job_id && stage.waiting_stages.contains(&input_stage_id)
+            })
+            .map(|((_, stage_id), _)| *stage_id)
+            .collect()
+    }
+
+    /// Schedule the tasks of a stage again once an input stage it waited for recovered its
+    /// shuffle output
+    pub fn stop_waiting(&self, job_id: &str, stage_id: u32, input_stage_id: u32) {
+        let stage_key = (job_id.to_owned(), stage_id);
+        let mut stage_distribution = self.stage_distribution.write();
+        if let Some(stage) = stage_distribution.stages_running.get_mut(&stage_key) {
+            stage.waiting_stages.remove(&input_stage_id);
+        }
+    }
+
     pub fn fetch_pending_tasks<F>(
         &self,
         max_num: usize,
@@ -284,6 +356,128 @@ impl StageManager {
             .map(|stage| stage.find_pending_tasks(max_num))
     }
 
+    /// Operator metrics of the running and completed stages of a job, ordered by stage id
+    pub(crate) fn get_job_profile(&self, job_id: &str) -> protobuf::JobProfile {
+        let stage_distribution = self.stage_distribution.read();
+        let mut stages = stage_distribution
+            .stages_running
+            .iter()
+            .chain(stage_distribution.stages_completed.iter())
+            .filter(|((stage_job_id, _), _)| stage_job_id == job_id)
+            .map(|(_, stage)| stage.profile())
+            .collect::<Vec<_>>();
+        stages.sort_by_key(|stage| stage.stage_id);
+        protobuf::JobProfile { stages }
+    }
+
+    /// Forget all the stages of a job
+    pub fn remove_job(&self, job_id: &str) {
+        {
+            let mut stage_distribution = self.stage_distribution.write();
+            stage_distribution
+                .stages_running
+                .retain(|(stage_job_id, _), _| stage_job_id != job_id);
+            stage_distribution
+                .stages_completed
+                .retain(|(stage_job_id, _), _| stage_job_id != job_id);
+        }
+        self.final_stages.write().remove(job_id);
+        self.stages_dependency
+            .write()
+            .retain(|(stage_job_id, _), _| stage_job_id != job_id);
+        self.pending_stages.write().remove(job_id);
+    }
+
+    /// Stop scheduling the tasks of a job, return the executors running its tasks. The
+    /// running stages are kept until their running tasks report, so that those tasks still
+    /// count on their executors, and the completed stages are kept for inspection.
+    pub fn cancel_job(&self, job_id: &str) -> HashSet<String> {
+        let mut executors = HashSet::new();
+        self.stage_distribution.write().stages_running.retain(
+            |(stage_job_id, _), stage| {
+                if stage_job_id != job_id {
+                    return true;
+                }
+                stage.cancelled = true;
+                let running_tasks = stage.get_running_tasks();
+                executors.extend(running_tasks.iter().filter_map(
+                    |task| match &task.status {
+                        Some(task_status::Status::Running(RunningTask {
+                            executor_id,
+                        })) => Some(executor_id.clone()),
+                        _ => None,
+                    },
+                ));
+                !running_tasks.is_empty()
+            },
+        );
+        self.pending_stages.write().remove(job_id);
+        executors
+    }
+
+    /// Number of the tasks running on an executor
+    pub fn get_running_tasks_of_executor(&self, executor_id: &str) -> usize {
+        let stage_distribution = self.stage_distribution.read();
+        stage_distribution
+            .stages_running
+            .values()
+            .flat_map(|stage| stage.get_running_tasks())
+            .filter(|task| {
+                matches!(
+                    &task.status,
+                    Some(task_status::Status::Running(RunningTask { executor_id: id }))
+                        if id == executor_id
+                )
+            })
+            .count()
+    }
+
+    /// The jobs having shuffle output kept on an executor by its completed tasks, the
+    /// partitions pushed to a shuffle service do not need the executor anymore
+    pub fn get_jobs_with_shuffle_output(&self, executor_id: &str) -> HashSet<String> {
+        let stage_distribution = self.stage_distribution.read();
+        stage_distribution
+            .stages_running
+            .iter()
+            .chain(stage_distribution.stages_completed.iter())
+            .filter(|(_, stage)| {
+                stage.tasks.iter().any(|task| {
+                    matches!(
+                        &task.status,
+                        Some(task_status::Status::Completed(completed))
+                            if completed.executor_id == executor_id
+                                && completed
+                                    .partitions
+                                    .iter()
+                                    .any(|partition| partition.remote_host.is_empty()
+                                        && partition.num_rows > 0)
+                    )
+                })
+            })
+            .map(|((job_id, _), _)| job_id.clone())
+            .collect()
+    }
+
+    /// Number of the pending and of the running tasks of the running stages
+    pub fn get_tasks_count(&self) -> (usize, usize) {
+        let stage_distribution = self.stage_distribution.read();
+        stage_distribution.stages_running.values().fold(
+            (0, 0),
+            |(pending, running), stage| {
+                let distribution = &stage.tasks_distribution;
+                let stage_pending = if stage.cancelled {
+                    0
+                } else {
+                    distribution.pending_indicator.n_of_true
+                };
+                (
+                    pending + stage_pending,
+                    running + distribution.running_indicator.n_of_true,
+                )
+            },
+        )
+    }
+
     pub fn has_running_tasks(&self) -> bool {
         let stage_distribution = self.stage_distribution.read();
         for stage in stage_distribution.stages_running.values() {
@@ -323,6 +517,66 @@ impl StageScheduler for StageManager {
     }
 }
 
+/// The id a shuffle service is known by to the readers of the partitions pushed to it
+pub(crate) fn shuffle_service_id(host: &str, port: u32) -> String {
+    format!("shuffle-service-{}:{}", host, port)
+}
+
+// The executors or shuffle services keeping the non empty shuffle partitions of a
+// completed task
+fn task_output_location_ids(task: &TaskStatus) -> impl Iterator<Item = String> + '_ {
+    let completed = match &task.status {
+        Some(task_status::Status::Completed(completed)) => Some(completed),
+        _ => None,
+    };
+    completed.into_iter().flat_map(|completed| {
+        completed
+            .partitions
+            .iter()
+            .filter(|partition| partition.num_rows > 0)
+            .map(move |partition| {
+                if partition.remote_host.is_empty() {
+                    completed.executor_id.clone()
+                } else {
+                    shuffle_service_id(&partition.remote_host, partition.remote_port)
+                }
+            })
+    })
+}
+
+// The tasks of a stage run the same plan, so their operators are matched by position
+fn merge_operator_metrics(
+    operators: &mut Vec<protobuf::OperatorMetricsSet>,
+    task_operators: &[protobuf::OperatorMetricsSet],
+) {
+    if operators.is_empty() {
+        operators.extend_from_slice(task_operators);
+        return;
+    }
+    for (operator, task_operator) in operators.iter_mut().zip(task_operators) {
+        for task_metric in &task_operator.metrics {
+            match operator
+                .metrics
+                .iter_mut()
+                .find(|metric| metric.name == task_metric.name)
+            {
+                Some(metric) => metric.value += task_metric.value,
+                None => operator.metrics.push(task_metric.clone()),
+            }
+        }
+    }
+}
+
+// What becomes of the shuffle output of a stage lost with an executor
+enum LostOutput {
+    // The completed stage runs again
+    Resubmitted,
+    // The stage already runs again
+    Recovering,
+    // The stage ran again and wrote the output elsewhere
+    Recovered,
+}
+
 struct StageDistribution {
     // The key is (job_id, stage_id)
     stages_running: HashMap<StageKey, Stage>,
@@ -337,6 +591,37 @@ impl StageDistribution {
         }
     }
 
+    /// Run the tasks of a stage which wrote shuffle output to a lost executor or shuffle
+    /// service again, None if the output cannot be recovered anymore
+    fn recover_lost_output(
+        &mut self,
+        stage_key: &StageKey,
+        executor_id: &str,
+    ) -> Option<LostOutput> {
+        if let Some(stage) = self.stages_running.get_mut(stage_key) {
+            // The stage already runs again, its other tasks on the executor run as well
+            stage.reset_lost_tasks(executor_id);
+            return Some(LostOutput::Recovering);
+        }
+        let stage = self.stages_completed.get(stage_key)?;
+        if !stage.has_output_on(executor_id) {
+            return Some(LostOutput::Recovered);
+        }
+        if stage.resubmissions >= MAX_STAGE_RESUBMISSIONS {
+            warn!(
+                "Stage {}/{} lost its shuffle output on {} too many times",
+                stage_key.0, stage_key.1, executor_id
+            );
+            return None;
+        }
+        let mut stage = self.stages_completed.remove(stage_key)?;
+        stage.resubmissions += 1;
+        stage.completed_at = None;
+        stage.reset_lost_tasks(executor_id);
+        self.stages_running.insert(stage_key.clone(), stage);
+        Some(LostOutput::Resubmitted)
+    }
+
     fn complete_stage(&mut self, stage_key: StageKey) {
         if let Some(stage) = self.stages_running.remove(&stage_key) {
             assert!(
@@ -345,7 +630,13 @@ impl StageDistribution {
                 stage_key.0,
                 stage_key.1
             );
-            self.stages_completed.insert(stage_key, stage);
+            self.stages_completed.insert(
+                stage_key,
+                Stage {
+                    completed_at: Some(Instant::now()),
+                    ..stage
+                },
+            );
         } else {
             warn!(
                 "Fail to find running stage {:?}/{}",
@@ -358,8 +649,19 @@ impl StageDistribution {
 pub struct Stage {
     pub stage_id: u32,
     tasks: Vec<Arc<TaskStatus>>,
+    // When the running tasks were assigned, for the task latency metrics
+    tasks_started_at: Vec<Option<Instant>>,
 
     tasks_distribution: TaskStatusDistribution,
+    started_at: Instant,
+    completed_at: Option<Instant>,
+    // The job is cancelled, the pending tasks are not scheduled anymore
+    cancelled: bool,
+    // The input stages running again to recover their lost shuffle output, the pending
+    // tasks are not scheduled until they complete
+    waiting_stages: HashSet<u32>,
+    // Times the stage ran again to recover its lost shuffle output
+    resubmissions: usize,
 }
 
 impl Stage {
@@ -381,7 +683,13 @@ impl Stage {
         Stage {
             stage_id,
             tasks,
+            tasks_started_at: vec![None; num_partitions as usize],
             tasks_distribution: TaskStatusDistribution::new(num_partitions as usize),
+            started_at: Instant::now(),
+            completed_at: None,
+            cancelled: false,
+            waiting_stages: HashSet::new(),
+            resubmissions: 0,
         }
     }
 
@@ -397,6 +705,7 @@ impl Stage {
                     &task.status,
                 ) {
                     self.tasks[task_idx] = Arc::new(task.clone());
+                    self.record_task_metrics(task_idx);
                 } else {
                     error!(
                         "Fail to update status from {:?} to {:?} for task: {:?}/{:?}/{:?}", &existing_task_status.status, &task.status,
@@ -414,8 +723,131 @@ impl Stage {
         }
     }
 
+    fn record_task_metrics(&mut self, task_idx: usize) {
+        match &self.tasks[task_idx].status {
+            Some(task_status::Status::Running(_)) => {
+                self.tasks_started_at[task_idx] = Some(Instant::now());
+            }
+            Some(task_status::Status::Completed(completed)) => {
+                let written_bytes =
+                    completed.partitions.iter().map(|p| p.num_bytes).sum();
+                if let Some(started_at) = self.tasks_started_at[task_idx].take() {
+                    metrics::record_task_finished(
+                        true,
+                        started_at.elapsed(),
+                        written_bytes,
+                    );
+                }
+            }
+            Some(task_status::Status::Failed(_)) => {
+                if let Some(started_at) = self.tasks_started_at[task_idx].take() {
+                    metrics::record_task_finished(false, started_at.elapsed(), 0);
+                }
+            }
+            None => {}
+        }
+    }
+
+    /// Sum up the operator metrics reported by the completed tasks
+    fn profile(&self) -> protobuf::StageProfile {
+        let mut operators: Vec<protobuf::OperatorMetricsSet> = vec![];
+        let mut completed_tasks = 0;
+        let mut failed_tasks = 0;
+        for task in &self.tasks {
+            match &task.status {
+                Some(task_status::Status::Completed(completed)) => {
+                    completed_tasks += 1;
+                    merge_operator_metrics(&mut operators, &completed.metrics);
+                }
+                Some(task_status::Status::Failed(_)) => failed_tasks += 1,
+                _ => {}
+            }
+        }
+        let elapsed = match self.completed_at {
+            Some(completed_at) => completed_at.duration_since(self.started_at),
+            None => self.started_at.elapsed(),
+        };
+        protobuf::StageProfile {
+            stage_id: self.stage_id,
+            partitions: self.tasks.len() as u32,
+            completed_tasks,
+            failed_tasks,
+            elapsed_ms: elapsed.as_millis() as u64,
+            operators,
+        }
+    }
+
     fn is_schedulable(&self) -> bool {
-        self.tasks_distribution.is_schedulable()
+        !self.cancelled
+            && self.waiting_stages.is_empty()
+            && self.tasks_distribution.is_schedulable()
+    }
+
+    /// The failed tasks which could not fetch their input, with the input stage and the
+    /// executor or shuffle service the input was lost with
+    fn get_fetch_failures(&self) -> Vec<(usize, u32, String)> {
+        self.tasks
+            .iter()
+            .enumerate()
+            .filter(|(task_idx, _)| {
+                self.tasks_distribution.failed_indicator.indicator[*task_idx]
+            })
+            .filter_map(|(task_idx, task)| match &task.status {
+                Some(task_status::Status::Failed(FailedTask {
+                    fetch_partition_error: Some(fetch_error),
+                    ..
+                })) => Some((
+                    task_idx,
+                    fetch_error.stage_id,
+                    fetch_error.executor_id.clone(),
+                )),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Set a failed task pending again, after the input stage it waits for if any
+    fn retry_task(&mut self, task_idx: usize, input_stage_id: Option<u32>) {
+        if self.reset_task(task_idx) {
+            if let Some(input_stage_id) = input_stage_id {
+                self.waiting_stages.insert(input_stage_id);
+            }
+        }
+    }
+
+    // Set a failed or completed task pending again
+    fn reset_task(&mut self, task_idx: usize) -> bool {
+        let task = self.tasks[task_idx].clone();
+        if !self
+            .tasks_distribution
+            .update(task_idx, &task.status, &None)
+        {
+            return false;
+        }
+        self.tasks[task_idx] = Arc::new(TaskStatus {
+            task_id: task.task_id.clone(),
+            status: None,
+        });
+        self.tasks_started_at[task_idx] = None;
+        true
+    }
+
+    /// Whether a completed task wrote shuffle output to an executor or shuffle service
+    fn has_output_on(&self, executor_id: &str) -> bool {
+        self.tasks
+            .iter()
+            .any(|task| task_output_location_ids(task).any(|id| id == executor_id))
+    }
+
+    /// Set the completed tasks which wrote shuffle output to a lost executor or shuffle
+    /// service pending again
+    fn reset_lost_tasks(&mut self, executor_id: &str) {
+        for task_idx in 0..self.tasks.len() {
+            if task_output_location_ids(&self.tasks[task_idx]).any(|id| id == executor_id)
+            {
+                self.reset_task(task_idx);
+            }
+        }
     }
 
     fn is_completed(&self) -> bool {
@@ -427,10 +859,27 @@ impl Stage {
         if self.tasks_distribution.is_failed() {
             let task_idx = self.tasks_distribution.sample_failed_index();
             if let Some(task) = self.tasks.get(task_idx) {
-                if let Some(task_status::Status::Failed(FailedTask { error })) =
-                    &task.status
+                if let Some(task_status::Status::Failed(FailedTask {
+                    error,
+                    fetch_partition_error,
+                })) = &task.status
                 {
-                    Some(error.clone())
+                    match fetch_partition_error {
+                        // The producing stage could not run again to recover it
+                        Some(fetch_error) => {
+                            warn!(
+                                "The shuffle output of stage {}/{} on {} is lost",
+                                fetch_error.job_id,
+                                fetch_error.stage_id,
+                                fetch_error.executor_id
+                            );
+                            Some(format!(
+                                "{}, the shuffle output of stage {} on {} is lost",
+                                error, fetch_error.stage_id, fetch_error.executor_id
+                            ))
+                        }
+                        None => Some(error.clone()),
+                    }
                 } else {
                     warn!("task {:?} is not failed", task);
                     None
@@ -606,10 +1055,14 @@ impl TaskStatusIndicator {
 
 #[cfg(test)]
 mod test {
+    use std::collections::HashSet;
+
+    use crate::scheduler_server::event::QueryStageSchedulerEvent;
     use crate::state::stage_manager::StageManager;
     use hetu_core::error::Result;
     use hetu_core::serde::protobuf::{
-        task_status, CompletedTask, FailedTask, PartitionId, RunningTask, TaskStatus,
+        task_status, CompletedTask, FailedTask, FetchPartitionError, OperatorMetric,
+        OperatorMetricsSet, PartitionId, RunningTask, ShuffleWritePartition, TaskStatus,
     };
 
     #[tokio::test]
@@ -633,6 +1086,7 @@ mod test {
             stage_manager.update_tasks_status(vec![TaskStatus {
                 status: Some(task_status::Status::Failed(FailedTask {
                     error: "error".to_owned(),
+                    ..Default::default()
                 })),
                 task_id: Some(task_id.clone()),
             }]);
@@ -657,6 +1111,7 @@ mod test {
             stage_manager.update_tasks_status(vec![TaskStatus {
                 status: Some(task_status::Status::Failed(FailedTask {
                     error: "error".to_owned(),
+                    ..Default::default()
                 })),
                 task_id: Some(task_id.clone()),
             }]);
@@ -762,6 +1217,279 @@ mod test {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_cancel_job() -> Result<()> {
+        let stage_manager = StageManager::new();
+
+        let job_id = "job";
+        stage_manager.add_running_stage(job_id, 1, 2);
+        stage_manager.add_pending_stage(job_id, 2);
+        stage_manager.update_tasks_status(vec![TaskStatus {
+            status: Some(task_status::Status::Running(RunningTask {
+                executor_id: "localhost".to_owned(),
+            })),
+            task_id: Some(PartitionId {
+                job_id: job_id.to_owned(),
+                stage_id: 1,
+                partition_id: 0,
+            }),
+        }]);
+        assert_eq!(stage_manager.get_running_tasks_of_executor("localhost"), 1);
+        assert_eq!(stage_manager.get_running_tasks_of_executor("other"), 0);
+
+        let executors = stage_manager.cancel_job(job_id);
+        assert_eq!(executors, HashSet::from(["localhost".to_owned()]));
+        assert!(!stage_manager.is_running_stage(job_id, 1));
+        assert!(!stage_manager.is_pending_stage(job_id, 2));
+        assert!(stage_manager.fetch_pending_tasks(1, |_| true).is_none());
+        assert_eq!(stage_manager.get_tasks_count(), (0, 1));
+        // The running task still counts until it reports
+        assert_eq!(stage_manager.get_running_tasks_of_executor("localhost"), 1);
+
+        let events = stage_manager.update_tasks_status(vec![TaskStatus {
+            status: Some(task_status::Status::Failed(FailedTask {
+                error: "cancelled".to_owned(),
+                fetch_partition_error: None,
+            })),
+            task_id: Some(PartitionId {
+                job_id: job_id.to_owned(),
+                stage_id: 1,
+                partition_id: 0,
+            }),
+        }]);
+        assert!(events.is_empty());
+        assert_eq!(stage_manager.get_running_tasks_of_executor("localhost"), 0);
+        assert!(!stage_manager.has_running_tasks());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_jobs_with_shuffle_output() -> Result<()> {
+        let stage_manager = StageManager::new();
+
+        let partition = |num_rows: u64, remote_host: &str| ShuffleWritePartition {
+            partition_id: 0,
+            path: "/tmp/data.arrow".to_owned(),
+            num_batches: 1,
+            num_rows,
+            num_bytes: 100,
+            remote_host: remote_host.to_owned(),
+            remote_port: 0,
+        };
+        let complete =
+            |job_id: &str, executor_id: &str, partition: ShuffleWritePartition| {
+                let task_id = PartitionId {
+                    job_id: job_id.to_owned(),
+                    stage_id: 1,
+                    partition_id: 0,
+                };
+                stage_manager.add_running_stage(job_id, 1, 1);
+                stage_manager.update_tasks_status(vec![TaskStatus {
+                    status: Some(task_status::Status::Running(RunningTask {
+                        executor_id: executor_id.to_owned(),
+                    })),
+                    task_id: Some(task_id.clone()),
+                }]);
+                stage_manager.update_tasks_status(vec![TaskStatus {
+                    status: Some(task_status::Status::Completed(CompletedTask {
+                        executor_id: executor_id.to_owned(),
+                        partitions: vec![partition],
+                        metrics: vec![],
+                    })),
+                    task_id: Some(task_id),
+                }]);
+            };
+        complete("local_output", "localhost", partition(10, ""));
+        complete("empty_output", "localhost", partition(0, ""));
+        complete(
+            "pushed_output",
+            "localhost",
+            partition(10, "shuffle-service"),
+        );
+        complete("other_executor", "other", partition(10, ""));
+
+        assert_eq!(
+            stage_manager.get_jobs_with_shuffle_output("localhost"),
+            HashSet::from(["local_output".to_owned()])
+        );
+        assert_eq!(
+            stage_manager.get_jobs_with_shuffle_output("other"),
+            HashSet::from(["other_executor".to_owned()])
+        );
+        assert!(stage_manager
+            .get_jobs_with_shuffle_output("unknown")
+            .is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_recover_lost_shuffle_output() -> Result<()> {
+        let stage_manager = StageManager::new();
+
+        let job_id = "job";
+        let task_id = |stage_id: u32, partition_id: u32| PartitionId {
+            job_id: job_id.to_owned(),
+            stage_id,
+            partition_id,
+        };
+        let run = |task_id: PartitionId, executor_id: &str| {
+            stage_manager.update_tasks_status(vec![TaskStatus {
+                status: Some(task_status::Status::Running(RunningTask {
+                    executor_id: executor_id.to_owned(),
+                })),
+                task_id: Some(task_id),
+            }])
+        };
+        let complete = |task_id: PartitionId, executor_id: &str| {
+            stage_manager.update_tasks_status(vec![TaskStatus {
+                status: Some(task_status::Status::Completed(CompletedTask {
+                    executor_id: executor_id.to_owned(),
+                    partitions: vec![ShuffleWritePartition {
+                        partition_id: 0,
+                        path: "/tmp/data.arrow".to_owned(),
+                        num_batches: 1,
+                        num_rows: 10,
+                        num_bytes: 100,
+                        remote_host: "".to_owned(),
+                        remote_port: 0,
+                    }],
+                    metrics: vec![],
+                })),
+                task_id: Some(task_id),
+            }])
+        };
+        let fail_fetch = |task_id: PartitionId, executor_id: &str| {
+            stage_manager.update_tasks_status(vec![TaskStatus {
+                status: Some(task_status::Status::Failed(FailedTask {
+                    error: "fetch failed".to_owned(),
+                    fetch_partition_error: Some(FetchPartitionError {
+                        executor_id: executor_id.to_owned(),
+                        job_id: job_id.to_owned(),
+                        stage_id: 1,
+                        partition_id: 0,
+                    }),
+                })),
+                task_id: Some(task_id),
+            }])
+        };
+
+        // Stage 1 writes its shuffle output on two executors, stage 2 reads it
+        stage_manager.add_running_stage(job_id, 1, 2);
+        run(task_id(1, 0), "lost");
+        complete(task_id(1, 0), "lost");
+        run(task_id(1, 1), "other");
+        complete(task_id(1, 1), "other");
+        assert!(stage_manager.is_completed_stage(job_id, 1));
+        stage_manager.add_running_stage(job_id, 2, 2);
+        run(task_id(2, 0), "other");
+        run(task_id(2, 1), "other");
+
+        // The task of stage 1 on the lost executor runs again instead of failing the job
+        let events = fail_fetch(task_id(2, 0), "lost");
+        assert!(matches!(
+            &events[..],
+            [QueryStageSchedulerEvent::StageResubmitted(id, 1)] if id == job_id
+        ));
+        assert!(stage_manager.is_running_stage(job_id, 1));
+        assert_eq!(stage_manager.get_stages_waiting_for(job_id, 1), vec![2]);
+        // Stage 2 waits for stage 1, the other task failing on the lost executor as well
+        assert!(fail_fetch(task_id(2, 1), "lost").is_empty());
+        assert_eq!(
+            stage_manager.fetch_pending_tasks(2, |_| true),
+            Some((job_id.to_owned(), 1, vec![0]))
+        );
+
+        run(task_id(1, 0), "other");
+        let events = complete(task_id(1, 0), "other");
+        assert!(matches!(
+            &events[..],
+            [QueryStageSchedulerEvent::StageFinished(id, 1)] if id == job_id
+        ));
+        stage_manager.stop_waiting(job_id, 2, 1);
+        assert!(stage_manager.get_stages_waiting_for(job_id, 1).is_empty());
+        assert_eq!(
+            stage_manager.fetch_pending_tasks(2, |_| true),
+            Some((job_id.to_owned(), 2, vec![0, 1]))
+        );
+
+        // A stage runs again a limited number of times
+        for _ in 0..2 {
+            let stage_1_partitions = stage_manager
+                .get_stage_tasks(job_id, 1)
+                .unwrap()
+                .iter()
+                .filter(|task| task.status.is_none())
+                .count();
+            assert_eq!(stage_1_partitions, 0);
+            run(task_id(2, 0), "other");
+            fail_fetch(task_id(2, 0), "other");
+            run(task_id(1, 0), "other");
+            complete(task_id(1, 0), "other");
+            run(task_id(1, 1), "other");
+            complete(task_id(1, 1), "other");
+            stage_manager.stop_waiting(job_id, 2, 1);
+        }
+        run(task_id(2, 0), "other");
+        let events = fail_fetch(task_id(2, 0), "other");
+        assert!(matches!(
+            &events[..],
+            [QueryStageSchedulerEvent::JobFailed(id, 2, _)] if id == job_id
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_job_profile() -> Result<()> {
+        let stage_manager = StageManager::new();
+
+        let job_id = "job";
+        stage_manager.add_running_stage(job_id, 2, 2);
+        stage_manager.add_running_stage(job_id, 1, 2);
+        for partition_id in 0..2 {
+            let task_id = PartitionId {
+                job_id: job_id.to_owned(),
+                stage_id: 1,
+                partition_id,
+            };
+            stage_manager.update_tasks_status(vec![TaskStatus {
+                status: Some(task_status::Status::Running(RunningTask {
+                    executor_id: "localhost".to_owned(),
+                })),
+                task_id: Some(task_id.clone()),
+            }]);
+            stage_manager.update_tasks_status(vec![TaskStatus {
+                status: Some(task_status::Status::Completed(CompletedTask {
+                    executor_id: "localhost".to_owned(),
+                    partitions: Vec::new(),
+                    metrics: vec![OperatorMetricsSet {
+                        operator: "ShuffleWriterExec".to_owned(),
+                        metrics: vec![OperatorMetric {
+                            name: "output_rows".to_owned(),
+                            value: 10,
+                        }],
+                    }],
+                })),
+                task_id: Some(task_id),
+            }]);
+        }
+
+        let profile = stage_manager.get_job_profile(job_id);
+        assert_eq!(profile.stages.len(), 2);
+        let stage = &profile.stages[0];
+        assert_eq!(stage.stage_id, 1);
+        assert_eq!(stage.partitions, 2);
+        assert_eq!(stage.completed_tasks, 2);
+        assert_eq!(stage.operators.len(), 1);
+        assert_eq!(stage.operators[0].metrics[0].value, 20);
+        assert_eq!(profile.stages[1].completed_tasks, 0);
+        assert!(profile.stages[1].operators.is_empty());
+
+        Ok(())
+    }
+
     fn task_from_pending_to_completed(
         stage_manager: &StageManager,
         task_id: &PartitionId,
@@ -776,6 +1504,7 @@ mod test {
             status: Some(task_status::Status::Completed(CompletedTask {
                 executor_id: "localhost".to_owned(),
                 partitions: Vec::new(),
+                metrics: vec![],
             })),
             task_id: Some(task_id.clone()),
         }]);
@@ -21,6 +21,7 @@ use async_trait::async_trait;
 use datafusion_proto::logical_plan::AsLogicalPlan;
 use hetu_core::error::BallistaError;
 use hetu_core::execution_plans::ShuffleWriterExec;
+use hetu_core::object_store::without_secrets;
 use hetu_core::serde::protobuf::{
     job_status, task_status, FailedJob, KeyValuePair, RunningTask, TaskDefinition,
     TaskStatus,
@@ -156,13 +157,19 @@ impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan> TaskScheduler
                         })?;
 
                         let session_id = self.get_session_from_job(&job_id).expect("session id does not exist for job");
-                        let session_props = self
-                            .session_registry()
-                            .lookup_session(&session_id)
-                            .await
-                            .expect("SessionContext does not exist in SessionContextRegistry.")
-                            .copied_config()
-                            .to_props();
+                        let mut session_props = without_secrets(
+                            self.session_registry()
+                                .lookup_session(&session_id)
+                                .await
+                                .expect("SessionContext does not exist in SessionContextRegistry.")
+                                .copied_config()
+                                .to_props(),
+                        );
+                        // The executors build the object stores and configure the
+                        // shuffles of the session from them, they resolve the secrets
+                        session_props.extend(
+                            self.session_registry().task_settings(&session_id).await,
+                        );
                         let task_props = session_props
                             .iter()
                             .map(|(k, v)| KeyValuePair {
@@ -18,6 +18,7 @@
 //! Client API for sending requests to executors.
 
 use std::collections::HashMap;
+use std::fs::File;
 use std::sync::Arc;
 
 use std::{
@@ -29,10 +30,14 @@ use crate::error::{ballista_error, BallistaError, Result};
 use crate::serde::protobuf::{self};
 use crate::serde::scheduler::Action;
 
-use arrow_flight::utils::flight_data_to_arrow_batch;
-use arrow_flight::Ticket;
+use arrow_flight::flight_descriptor::DescriptorType;
+use arrow_flight::utils::{flight_data_from_arrow_batch, flight_data_to_arrow_batch};
 use arrow_flight::{flight_service_client::FlightServiceClient, FlightData};
+use arrow_flight::{FlightDescriptor, SchemaAsIpc, Ticket};
 use datafusion::arrow::array::ArrayRef;
+use datafusion::arrow::ipc;
+use datafusion::arrow::ipc::reader::{read_dictionary, FileReader};
+use datafusion::arrow::ipc::writer::IpcWriteOptions;
 use datafusion::arrow::{
     datatypes::{Schema, SchemaRef},
     error::{ArrowError, Result as ArrowResult},
@@ -40,11 +45,78 @@ use datafusion::arrow::{
 };
 
 use datafusion::physical_plan::{RecordBatchStream, SendableRecordBatchStream};
-use futures::{Stream, StreamExt};
+use futures::{ready, Stream, StreamExt};
 use log::debug;
+use once_cell::sync::OnceCell;
 use prost::Message;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::transport::{ClientTlsConfig, Endpoint};
 use tonic::Streaming;
 
+/// Type of the Flight action fetching the blocks of a sort based shuffle partition
+pub const FETCH_SHUFFLE_BLOCK_ACTION: &str = "fetch_shuffle_block";
+
+/// TLS config of the connections to the executor services, they use plain http if unset
+static EXECUTOR_TLS_CONFIG: OnceCell<ClientTlsConfig> = OnceCell::new();
+
+/// Reach the Flight and gRPC services of the executors over TLS from this process on, it
+/// is set once before connecting to any executor
+pub fn set_executor_tls_config(tls_config: ClientTlsConfig) -> Result<()> {
+    EXECUTOR_TLS_CONFIG.set(tls_config).map_err(|_| {
+        BallistaError::General("The executor TLS config is already set".to_owned())
+    })
+}
+
+/// Decode a message of a Flight stream following its schema, None for a dictionary batch
+/// which is kept in dictionaries_by_id for the record batches after it
+pub fn decode_flight_data(
+    data: &FlightData,
+    schema: SchemaRef,
+    dictionaries_by_id: &mut HashMap<i64, ArrayRef>,
+) -> ArrowResult<Option<RecordBatch>> {
+    let message = ipc::root_as_message(&data.data_header).map_err(|e| {
+        ArrowError::ParseError(format!("Unable to get the Flight message: {:?}", e))
+    })?;
+    match message.header_as_dictionary_batch() {
+        Some(dictionary_batch) => {
+            read_dictionary(
+                &data.data_body,
+                dictionary_batch,
+                &schema,
+                dictionaries_by_id,
+            )?;
+            Ok(None)
+        }
+        None => flight_data_to_arrow_batch(data, schema, dictionaries_by_id).map(Some),
+    }
+}
+
+/// Compare a token with the expected one in a time which does not depend on where they
+/// differ
+pub fn tokens_match(expected: &str, actual: &str) -> bool {
+    expected.len() == actual.len()
+        && expected
+            .bytes()
+            .zip(actual.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// The endpoint of an executor service, over TLS if the executor TLS config is set
+pub fn executor_endpoint(host: &str, port: u16) -> Result<Endpoint> {
+    let endpoint = match EXECUTOR_TLS_CONFIG.get() {
+        Some(tls_config) => Endpoint::from_shared(format!("https://{}:{}", host, port))
+            .and_then(|endpoint| endpoint.tls_config(tls_config.clone())),
+        None => Endpoint::from_shared(format!("http://{}:{}", host, port)),
+    };
+    endpoint.map_err(|e| {
+        BallistaError::General(format!(
+            "Invalid executor address {}:{}: {}",
+            host, port, e
+        ))
+    })
+}
+
 /// Client for interacting with Ballista executors.
 #[derive(Clone)]
 pub struct BallistaClient {
@@ -55,20 +127,20 @@ impl BallistaClient {
     /// Create a new BallistaClient to connect to the executor listening on the specified
     /// host and port
     pub async fn try_new(host: &str, port: u16) -> Result<Self> {
-        let addr = format!("http://{}:{}", host, port);
+        let endpoint = executor_endpoint(host, port)?;
+        let addr = endpoint.uri().to_string();
         debug!("BallistaClient connecting to {}", addr);
-        let flight_client =
-            FlightServiceClient::connect(addr.clone())
-                .await
-                .map_err(|e| {
-                    BallistaError::General(format!(
-                        "Error connecting to Ballista scheduler or executor at {}: {:?}",
-                        addr, e
-                    ))
-                })?;
+        let channel = endpoint.connect().await.map_err(|e| {
+            BallistaError::General(format!(
+                "Error connecting to Ballista scheduler or executor at {}: {:?}",
+                addr, e
+            ))
+        })?;
         debug!("BallistaClient connected OK");
 
-        Ok(Self { flight_client })
+        Ok(Self {
+            flight_client: FlightServiceClient::new(channel),
+        })
     }
 
     /// Fetch a partition from an executor
@@ -88,6 +160,112 @@ impl BallistaClient {
         self.execute_action(&action).await
     }
 
+    /// Fetch the compressed blocks of a partition of a sort based shuffle file from an
+    /// executor, the executor looks up their byte range in the index of the file
+    pub async fn fetch_shuffle_block(
+        &mut self,
+        job_id: &str,
+        stage_id: usize,
+        partition_id: usize,
+        path: &str,
+    ) -> Result<Vec<u8>> {
+        let action: protobuf::Action = Action::FetchPartition {
+            job_id: job_id.to_owned(),
+            stage_id,
+            partition_id,
+            path: path.to_owned(),
+        }
+        .try_into()?;
+        let request = tonic::Request::new(arrow_flight::Action {
+            r#type: FETCH_SHUFFLE_BLOCK_ACTION.to_owned(),
+            body: action.encode_to_vec(),
+        });
+
+        let mut stream = self
+            .flight_client
+            .do_action(request)
+            .await
+            .map_err(|e| BallistaError::General(format!("{:?}", e)))?
+            .into_inner();
+        let mut block = vec![];
+        while let Some(chunk) = stream
+            .message()
+            .await
+            .map_err(|e| BallistaError::General(format!("{:?}", e)))?
+        {
+            block.extend_from_slice(&chunk.body);
+        }
+        Ok(block)
+    }
+
+    /// Push a shuffle partition file to a shuffle service, return the path of the partition
+    /// on the service. The service checks the auth token shared by the executors
+    #[allow(clippy::too_many_arguments)]
+    pub async fn push_partition(
+        &mut self,
+        job_id: &str,
+        stage_id: usize,
+        partition_id: usize,
+        input_partition_id: usize,
+        path: &str,
+        auth_token: &str,
+    ) -> Result<String> {
+        let action: protobuf::Action = Action::PushPartition {
+            job_id: job_id.to_owned(),
+            stage_id,
+            partition_id,
+            input_partition_id,
+            auth_token: auth_token.to_owned(),
+        }
+        .try_into()?;
+        let descriptor = FlightDescriptor {
+            r#type: DescriptorType::Cmd as i32,
+            cmd: action.encode_to_vec(),
+            path: vec![],
+        };
+
+        // The Arrow IPC reader is not Send, so the file is read on a blocking thread
+        let (tx, rx) = tokio::sync::mpsc::channel(2);
+        let path = path.to_owned();
+        let reader = tokio::task::spawn_blocking(move || -> Result<()> {
+            let reader = FileReader::try_new(File::open(&path)?, None)?;
+            let options = IpcWriteOptions::default();
+            let mut schema: FlightData =
+                SchemaAsIpc::new(&reader.schema(), &options).into();
+            schema.flight_descriptor = Some(descriptor);
+            if tx.blocking_send(schema).is_err() {
+                return Ok(());
+            }
+            for batch in reader {
+                let (dictionaries, batch) =
+                    flight_data_from_arrow_batch(&batch?, &options);
+                for data in dictionaries.into_iter().chain(std::iter::once(batch)) {
+                    if tx.blocking_send(data).is_err() {
+                        return Ok(());
+                    }
+                }
+            }
+            Ok(())
+        });
+
+        let response = self
+            .flight_client
+            .do_put(ReceiverStream::new(rx))
+            .await
+            .map_err(|e| BallistaError::General(format!("{:?}", e)));
+        reader
+            .await
+            .map_err(|e| BallistaError::General(format!("{:?}", e)))??;
+        let result = response?
+            .into_inner()
+            .message()
+            .await
+            .map_err(|e| BallistaError::General(format!("{:?}", e)))?
+            .ok_or_else(|| ballista_error("Shuffle service did not return the path"))?;
+        String::from_utf8(result.app_metadata)
+            .map_err(|e| BallistaError::General(format!("{:?}", e)))
+    }
+
     /// Execute an action and retrieve the results
     pub async fn execute_action(
         &mut self,
@@ -153,21 +331,28 @@ impl Stream for FlightDataStream {
         mut self: std::pin::Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<Option<Self::Item>> {
-        self.stream.poll_next_unpin(cx).map(|x| match x {
-            Some(flight_data_chunk_result) => {
-                let converted_chunk = flight_data_chunk_result
-                    .map_err(|e| ArrowError::from_external_error(Box::new(e)))
-                    .and_then(|flight_data_chunk| {
-                        flight_data_to_arrow_batch(
-                            &flight_data_chunk,
-                            self.schema.clone(),
-                            &self.dictionaries_by_id,
-                        )
-                    });
-                Some(converted_chunk)
+        // The dictionary batches are kept for the record batches following them
+        loop {
+            let flight_data = match ready!(self.stream.poll_next_unpin(cx)) {
+                Some(Ok(flight_data)) => flight_data,
+                Some(Err(e)) => {
+                    return Poll::Ready(Some(Err(ArrowError::from_external_error(
+                        Box::new(e),
+                    ))))
+                }
+                None => return Poll::Ready(None),
+            };
+            let this = &mut *self;
+            match decode_flight_data(
+                &flight_data,
+                this.schema.clone(),
+                &mut this.dictionaries_by_id,
+            ) {
+                Ok(Some(batch)) => return Poll::Ready(Some(Ok(batch))),
+                Ok(None) => continue,
+                Err(e) => return Poll::Ready(Some(Err(e))),
             }
-            None => None,
-        })
+        }
     }
 }
 
@@ -24,6 +24,10 @@ use std::collections::HashMap;
 use std::result;
 
 use crate::error::{BallistaError, Result};
+use crate::object_store::{
+    OBJECT_STORE_HDFS_NAMENODE, OBJECT_STORE_HDFS_USER, OBJECT_STORE_S3_ACCESS_KEY_ID,
+    OBJECT_STORE_S3_ENDPOINT, OBJECT_STORE_S3_REGION, OBJECT_STORE_S3_SECRET_ACCESS_KEY,
+};
 
 use datafusion::arrow::datatypes::DataType;
 
@@ -34,6 +38,31 @@ pub const BALLISTA_REPARTITION_AGGREGATIONS: &str = "ballista.repartition.aggreg
 pub const BALLISTA_REPARTITION_WINDOWS: &str = "ballista.repartition.windows";
 pub const BALLISTA_PARQUET_PRUNING: &str = "ballista.parquet.pruning";
 pub const BALLISTA_WITH_INFORMATION_SCHEMA: &str = "ballista.with_information_schema";
+/// tenant used by the scheduler for admission control, defaults to the session id when empty
+pub const BALLISTA_JOB_TENANT: &str = "ballista.job.tenant";
+/// user used by the scheduler for admission control, the jobs are not limited per user when empty
+pub const BALLISTA_JOB_USER: &str = "ballista.job.user";
+/// layout of the hash partitioned shuffle output, see [ShuffleFormat]
+pub const BALLISTA_SHUFFLE_FORMAT: &str = "ballista.shuffle.format";
+/// codec of the sort based shuffle files, see [ShuffleCompression]
+pub const BALLISTA_SHUFFLE_COMPRESSION: &str = "ballista.shuffle.compression";
+/// max number of shuffle partitions a task fetches at the same time
+pub const BALLISTA_SHUFFLE_MAX_CONCURRENT_FETCHES: &str =
+    "ballista.shuffle.max_concurrent_fetches";
+/// max bytes of the shuffle partitions a task holds while fetching them
+pub const BALLISTA_SHUFFLE_MAX_IN_FLIGHT_BYTES: &str =
+    "ballista.shuffle.max_in_flight_bytes";
+/// times a failed shuffle fetch is retried
+pub const BALLISTA_SHUFFLE_FETCH_RETRIES: &str = "ballista.shuffle.fetch_retries";
+/// milliseconds to wait before the first retry of a shuffle fetch, doubled at each retry
+pub const BALLISTA_SHUFFLE_FETCH_RETRY_BACKOFF_MS: &str =
+    "ballista.shuffle.fetch_retry_backoff_ms";
+/// milliseconds a query may run before its job is cancelled, 0 for no limit
+pub const BALLISTA_QUERY_MAX_EXECUTION_TIME: &str = "ballista.query.max_execution_time";
+/// max number of rows a query may return, 0 for no limit
+pub const BALLISTA_QUERY_MAX_RESULT_ROWS: &str = "ballista.query.max_result_rows";
+/// max bytes of the batches a query may return, 0 for no limit
+pub const BALLISTA_QUERY_MAX_RESULT_BYTES: &str = "ballista.query.max_result_bytes";
 /// give a plugin files dir, and then the dynamic library files in this dir will be load when scheduler state init.
 pub const BALLISTA_PLUGIN_DIR: &str = "ballista.plugin_dir";
 
@@ -126,6 +155,23 @@ impl BallistaConfig {
             }
         }
 
+        if let Some(v) = settings.get(BALLISTA_SHUFFLE_FORMAT) {
+            v.parse::<ShuffleFormat>().map_err(|e| {
+                BallistaError::General(format!(
+                    "Failed to parse user-supplied value '{}' for configuration setting '{}': {}",
+                    BALLISTA_SHUFFLE_FORMAT, v, e
+                ))
+            })?;
+        }
+        if let Some(v) = settings.get(BALLISTA_SHUFFLE_COMPRESSION) {
+            v.parse::<ShuffleCompression>().map_err(|e| {
+                BallistaError::General(format!(
+                    "Failed to parse user-supplied value '{}' for configuration setting '{}': {}",
+                    BALLISTA_SHUFFLE_COMPRESSION, v, e
+                ))
+            })?;
+        }
+
         Ok(Self { settings })
     }
 
@@ -136,6 +182,11 @@ impl BallistaConfig {
                     .parse::<usize>()
                     .map_err(|e| format!("{:?}", e))?;
             }
+            DataType::UInt64 => {
+                val.to_string()
+                    .parse::<u64>()
+                    .map_err(|e| format!("{:?}", e))?;
+            }
             DataType::Boolean => {
                 val.to_string()
                     .parse::<bool>()
@@ -179,6 +230,57 @@ impl BallistaConfig {
             ConfigEntry::new(BALLISTA_PLUGIN_DIR.to_string(),
                              "Sets the plugin dir".to_string(),
                              DataType::Utf8,Some("".to_string())),
+            ConfigEntry::new(BALLISTA_JOB_TENANT.to_string(),
+                             "Sets the tenant whose concurrency limit the jobs of this session count against".to_string(),
+                             DataType::Utf8,Some("".to_string())),
+            ConfigEntry::new(BALLISTA_JOB_USER.to_string(),
+                             "Sets the user whose concurrency limit the jobs of this session count against".to_string(),
+                             DataType::Utf8,Some("".to_string())),
+            ConfigEntry::new(BALLISTA_SHUFFLE_FORMAT.to_string(),
+                             "Sets the layout of the shuffle output, 'hash' writes one file per output partition and 'sort' one data file and its index per task".to_string(),
+                             DataType::Utf8,Some("hash".to_string())),
+            ConfigEntry::new(BALLISTA_SHUFFLE_COMPRESSION.to_string(),
+                             "Sets the codec of the 'sort' shuffle files, one of 'none', 'lz4' or 'zstd'".to_string(),
+                             DataType::Utf8,Some("lz4".to_string())),
+            ConfigEntry::new(BALLISTA_SHUFFLE_MAX_CONCURRENT_FETCHES.to_string(),
+                             "Sets the max number of shuffle partitions a task fetches at the same time".to_string(),
+                             DataType::UInt16, Some("8".to_string())),
+            ConfigEntry::new(BALLISTA_SHUFFLE_MAX_IN_FLIGHT_BYTES.to_string(),
+                             "Sets the max bytes of the shuffle partitions a task holds while fetching them".to_string(),
+                             DataType::UInt64, Some("50331648".to_string())),
+            ConfigEntry::new(BALLISTA_SHUFFLE_FETCH_RETRIES.to_string(),
+                             "Sets the number of times a failed shuffle fetch is retried".to_string(),
+                             DataType::UInt16, Some("3".to_string())),
+            ConfigEntry::new(BALLISTA_SHUFFLE_FETCH_RETRY_BACKOFF_MS.to_string(),
+                             "Sets the milliseconds to wait before the first retry of a shuffle fetch, doubled at each retry".to_string(),
+                             DataType::UInt16, Some("100".to_string())),
+            ConfigEntry::new(BALLISTA_QUERY_MAX_EXECUTION_TIME.to_string(),
+                             "Sets the milliseconds a query may run before its job is cancelled, 0 for no limit".to_string(),
+                             DataType::UInt16, Some("0".to_string())),
+            ConfigEntry::new(BALLISTA_QUERY_MAX_RESULT_ROWS.to_string(),
+                             "Sets the max number of rows a query may return, 0 for no limit".to_string(),
+                             DataType::UInt16, Some("0".to_string())),
+            ConfigEntry::new(BALLISTA_QUERY_MAX_RESULT_BYTES.to_string(),
+                             "Sets the max bytes of the batches a query may return, 0 for no limit".to_string(),
+                             DataType::UInt16, Some("0".to_string())),
+            ConfigEntry::new(OBJECT_STORE_S3_ENDPOINT.to_string(),
+                             "Sets the endpoint of the S3 compatible service, AWS S3 if empty".to_string(),
+                             DataType::Utf8,Some("".to_string())),
+            ConfigEntry::new(OBJECT_STORE_S3_REGION.to_string(),
+                             "Sets the region of the S3 buckets".to_string(),
+                             DataType::Utf8,Some("".to_string())),
+            ConfigEntry::new(OBJECT_STORE_S3_ACCESS_KEY_ID.to_string(),
+                             "Sets the access key id of the S3 requests".to_string(),
+                             DataType::Utf8,Some("".to_string())),
+            ConfigEntry::new(OBJECT_STORE_S3_SECRET_ACCESS_KEY.to_string(),
+                             "Sets the secret access key of the S3 requests".to_string(),
+                             DataType::Utf8,Some("".to_string())),
+            ConfigEntry::new(OBJECT_STORE_HDFS_NAMENODE.to_string(),
+                             "Sets the WebHDFS url of the namenode reading hdfs:// urls".to_string(),
+                             DataType::Utf8,Some("".to_string())),
+            ConfigEntry::new(OBJECT_STORE_HDFS_USER.to_string(),
+                             "Sets the user of the WebHDFS requests".to_string(),
+                             DataType::Utf8,Some("".to_string())),
         ];
         entries
             .iter()
@@ -222,6 +324,66 @@ impl BallistaConfig {
         self.get_bool_setting(BALLISTA_WITH_INFORMATION_SCHEMA)
     }
 
+    pub fn job_tenant(&self) -> String {
+        self.get_string_setting(BALLISTA_JOB_TENANT)
+    }
+
+    pub fn job_user(&self) -> String {
+        self.get_string_setting(BALLISTA_JOB_USER)
+    }
+
+    pub fn shuffle_format(&self) -> ShuffleFormat {
+        // infallible because we validate all configs in the constructor
+        self.get_string_setting(BALLISTA_SHUFFLE_FORMAT)
+            .parse()
+            .unwrap()
+    }
+
+    pub fn shuffle_compression(&self) -> ShuffleCompression {
+        // infallible because we validate all configs in the constructor
+        self.get_string_setting(BALLISTA_SHUFFLE_COMPRESSION)
+            .parse()
+            .unwrap()
+    }
+
+    pub fn shuffle_max_concurrent_fetches(&self) -> usize {
+        self.get_usize_setting(BALLISTA_SHUFFLE_MAX_CONCURRENT_FETCHES)
+    }
+
+    pub fn shuffle_max_in_flight_bytes(&self) -> usize {
+        self.get_usize_setting(BALLISTA_SHUFFLE_MAX_IN_FLIGHT_BYTES)
+    }
+
+    pub fn shuffle_fetch_retries(&self) -> usize {
+        self.get_usize_setting(BALLISTA_SHUFFLE_FETCH_RETRIES)
+    }
+
+    pub fn shuffle_fetch_retry_backoff_ms(&self) -> usize {
+        self.get_usize_setting(BALLISTA_SHUFFLE_FETCH_RETRY_BACKOFF_MS)
+    }
+
+    pub fn query_max_execution_time_ms(&self) -> usize {
+        self.get_usize_setting(BALLISTA_QUERY_MAX_EXECUTION_TIME)
+    }
+
+    pub fn query_max_result_rows(&self) -> usize {
+        self.get_usize_setting(BALLISTA_QUERY_MAX_RESULT_ROWS)
+    }
+
+    pub fn query_max_result_bytes(&self) -> usize {
+        self.get_usize_setting(BALLISTA_QUERY_MAX_RESULT_BYTES)
+    }
+
+    /// The `ballista.shuffle.*` settings set by the user, which the executors read from
+    /// the task props
+    pub fn shuffle_settings(&self) -> HashMap<String, String> {
+        self.settings
+            .iter()
+            .filter(|(key, _)| key.starts_with("ballista.shuffle."))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect()
+    }
+
     fn get_usize_setting(&self, key: &str) -> usize {
         if let Some(v) = self.settings.get(key) {
             // infallible because we validate all configs in the constructor
@@ -280,6 +442,48 @@ impl parse_arg::ParseArgFromStr for TaskSchedulingPolicy {
     }
 }
 
+/// Layout of the hash partitioned output of a shuffle write task
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShuffleFormat {
+    /// One Arrow IPC file per output partition
+    Hash,
+    /// One data file holding all the output partitions, located through an index file
+    Sort,
+}
+
+impl std::str::FromStr for ShuffleFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "hash" => Ok(Self::Hash),
+            "sort" => Ok(Self::Sort),
+            _ => Err(format!("unknown shuffle format {}", s)),
+        }
+    }
+}
+
+/// Codec of the blocks of the sort based shuffle files
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShuffleCompression {
+    None,
+    Lz4,
+    Zstd,
+}
+
+impl std::str::FromStr for ShuffleCompression {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "none" | "" => Ok(Self::None),
+            "lz4" => Ok(Self::Lz4),
+            "zstd" => Ok(Self::Zstd),
+            _ => Err(format!("unknown shuffle compression {}", s)),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -290,6 +494,11 @@ mod tests {
         assert_eq!(2, config.default_shuffle_partitions());
         assert!(!config.default_with_information_schema());
         assert_eq!("", config.default_plugin_dir().as_str());
+        assert_eq!(ShuffleFormat::Hash, config.shuffle_format());
+        assert_eq!(ShuffleCompression::Lz4, config.shuffle_compression());
+        assert_eq!(0, config.query_max_execution_time_ms());
+        assert_eq!(0, config.query_max_result_rows());
+        assert_eq!(48 * 1024 * 1024, config.shuffle_max_in_flight_bytes());
         Ok(())
     }
 
@@ -301,6 +510,29 @@ mod tests {
             .build()?;
         assert_eq!(123, config.default_shuffle_partitions());
         assert!(config.default_with_information_schema());
+
+        let config = BallistaConfig::builder()
+            .set(BALLISTA_SHUFFLE_FORMAT, "sort")
+            .set(BALLISTA_SHUFFLE_COMPRESSION, "ZSTD")
+            .build()?;
+        assert_eq!(ShuffleFormat::Sort, config.shuffle_format());
+        assert_eq!(ShuffleCompression::Zstd, config.shuffle_compression());
+        assert!(BallistaConfig::builder()
+            .set(BALLISTA_SHUFFLE_COMPRESSION, "snappy")
+            .build()
+            .is_err());
+
+        let config = BallistaConfig::builder()
+            .set(BALLISTA_QUERY_MAX_EXECUTION_TIME, "1500")
+            .set(BALLISTA_QUERY_MAX_RESULT_BYTES, "1024")
+            .build()?;
+        assert_eq!(1500, config.query_max_execution_time_ms());
+        assert_eq!(1024, config.query_max_result_bytes());
+
+        let config = BallistaConfig::builder()
+            .set(BALLISTA_SHUFFLE_MAX_IN_FLIGHT_BYTES, "1073741824")
+            .build()?;
+        assert_eq!(1024 * 1024 * 1024, config.shuffle_max_in_flight_bytes());
         Ok(())
     }
 
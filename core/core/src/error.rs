@@ -47,6 +47,62 @@ pub enum BallistaError {
     TonicError(tonic::transport::Error),
     GrpcError(tonic::Status),
     TokioError(tokio::task::JoinError),
+    FetchFailed(FetchFailure),
+}
+
+/// A shuffle partition which could not be fetched from the executor holding it, the
+/// stage which produced it has to run again
+#[derive(Debug, Clone, PartialEq)]
+pub struct FetchFailure {
+    /// Executor or shuffle service the partition was fetched from
+    pub executor_id: String,
+    pub job_id: String,
+    /// Stage which produced the partition
+    pub stage_id: usize,
+    pub partition_id: usize,
+    pub message: String,
+}
+
+impl BallistaError {
+    /// The failed shuffle fetch causing this error, looked up through the DataFusion and
+    /// Arrow errors wrapping it
+    pub fn fetch_failure(&self) -> Option<&FetchFailure> {
+        match self {
+            BallistaError::FetchFailed(failure) => Some(failure),
+            BallistaError::DataFusionError(e) => datafusion_fetch_failure(e),
+            BallistaError::ArrowError(e) => arrow_fetch_failure(e),
+            _ => None,
+        }
+    }
+}
+
+fn datafusion_fetch_failure(e: &DataFusionError) -> Option<&FetchFailure> {
+    match e {
+        DataFusionError::External(e) => external_fetch_failure(e.as_ref()),
+        DataFusionError::ArrowError(e) => arrow_fetch_failure(e),
+        _ => None,
+    }
+}
+
+fn arrow_fetch_failure(e: &ArrowError) -> Option<&FetchFailure> {
+    match e {
+        ArrowError::ExternalError(e) => external_fetch_failure(e.as_ref()),
+        _ => None,
+    }
+}
+
+fn external_fetch_failure<'a>(
+    e: &'a (dyn Error + Send + Sync + 'static),
+) -> Option<&'a FetchFailure> {
+    if let Some(e) = e.downcast_ref::<BallistaError>() {
+        e.fetch_failure()
+    } else if let Some(e) = e.downcast_ref::<DataFusionError>() {
+        datafusion_fetch_failure(e)
+    } else if let Some(e) = e.downcast_ref::<ArrowError>() {
+        arrow_fetch_failure(e)
+    } else {
+        None
+    }
 }
 
 #[allow(clippy::from_over_into)]
@@ -178,8 +234,47 @@ impl Display for BallistaError {
                 write!(f, "Internal Ballista error: {}", desc)
             }
             BallistaError::TokioError(desc) => write!(f, "Tokio join error: {}", desc),
+            BallistaError::FetchFailed(failure) => write!(
+                f,
+                "Fail to fetch partition {} of stage {}/{} from {}: {}",
+                failure.partition_id,
+                failure.job_id,
+                failure.stage_id,
+                failure.executor_id,
+                failure.message
+            ),
         }
     }
 }
 
 impl Error for BallistaError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fetch_failure() {
+        let failure = FetchFailure {
+            executor_id: "executor-1".to_owned(),
+            job_id: "job".to_owned(),
+            stage_id: 1,
+            partition_id: 2,
+            message: "connection refused".to_owned(),
+        };
+        // as the shuffle reader returns it to the operators reading it
+        let wrapped = DataFusionError::ArrowError(ArrowError::ExternalError(Box::new(
+            DataFusionError::External(Box::new(BallistaError::FetchFailed(
+                failure.clone(),
+            ))),
+        )));
+        assert_eq!(
+            Some(&failure),
+            BallistaError::DataFusionError(wrapped).fetch_failure()
+        );
+        assert_eq!(
+            None,
+            BallistaError::General("connection refused".to_owned()).fetch_failure()
+        );
+    }
+}
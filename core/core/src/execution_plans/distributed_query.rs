@@ -20,8 +20,8 @@ use crate::config::BallistaConfig;
 use crate::serde::protobuf::execute_query_params::OptionalSessionId;
 use crate::serde::protobuf::{
     execute_query_params::Query, job_status, scheduler_grpc_client::SchedulerGrpcClient,
-    ExecuteQueryParams, GetJobStatusParams, GetJobStatusResult, KeyValuePair,
-    PartitionLocation,
+    CancelJobParams, ExecuteQueryParams, KeyValuePair, PartitionLocation,
+    WatchJobStatusParams,
 };
 use datafusion::arrow::datatypes::SchemaRef;
 use datafusion::arrow::error::{ArrowError, Result as ArrowResult};
@@ -29,6 +29,7 @@ use datafusion::arrow::record_batch::RecordBatch;
 use datafusion::error::{DataFusionError, Result};
 use datafusion::execution::context::TaskContext;
 use datafusion::logical_plan::LogicalPlan;
+use datafusion::physical_plan::common::batch_byte_size;
 use datafusion::physical_plan::expressions::PhysicalSortExpr;
 use datafusion::physical_plan::stream::RecordBatchStreamAdapter;
 use datafusion::physical_plan::{
@@ -38,16 +39,19 @@ use datafusion_proto::logical_plan::{
     AsLogicalPlan, DefaultLogicalExtensionCodec, LogicalExtensionCodec,
 };
 use futures::{Stream, StreamExt, TryFutureExt, TryStreamExt};
-use log::{error, info};
+use log::{error, info, warn};
 use std::any::Any;
 use std::fmt::Debug;
 use std::marker::PhantomData;
 use std::sync::Arc;
 use std::time::Duration;
+use tokio::runtime::Handle;
+use tokio::time::Instant;
+use tonic::transport::Channel;
 
 /// This operator sends a logical plan to a Ballista scheduler for execution and
-/// polls the scheduler until the query is complete and then fetches the resulting
-/// batches directly from the executors that hold the results from the final
+/// watches the status of the job until the query is complete and then fetches the
+/// resulting batches directly from the executors that hold the results from the final
 /// query stage.
 #[derive(Debug, Clone)]
 pub struct DistributedQueryExec<T: 'static + AsLogicalPlan> {
@@ -194,6 +198,7 @@ impl<T: 'static + AsLogicalPlan> ExecutionPlan for DistributedQueryExec<T> {
             )),
         };
 
+        let limits = QueryLimits::start(&self.config);
         let stream = futures::stream::once(
             execute_query(self.scheduler_url.clone(), self.session_id.clone(), query)
                 .map_err(|e| ArrowError::ExternalError(Box::new(e))),
@@ -201,7 +206,10 @@ impl<T: 'static + AsLogicalPlan> ExecutionPlan for DistributedQueryExec<T> {
         .try_flatten();
 
         let schema = self.schema();
-        Ok(Box::pin(RecordBatchStreamAdapter::new(schema, stream)))
+        Ok(Box::pin(RecordBatchStreamAdapter::new(
+            schema,
+            limit_results(stream, limits),
+        )))
     }
 
     fn fmt_as(
@@ -228,6 +236,81 @@ impl<T: 'static + AsLogicalPlan> ExecutionPlan for DistributedQueryExec<T> {
     }
 }
 
+/// The limits of a query set by the `ballista.query.*` settings
+#[derive(Debug, Clone, Copy)]
+struct QueryLimits {
+    /// When the job is cancelled, none without max_execution_time
+    deadline: Option<Instant>,
+    max_execution_time: Duration,
+    max_result_rows: usize,
+    max_result_bytes: usize,
+}
+
+impl QueryLimits {
+    /// The limits of a query starting now
+    fn start(config: &BallistaConfig) -> Self {
+        let max_execution_time =
+            Duration::from_millis(config.query_max_execution_time_ms() as u64);
+        Self {
+            deadline: (!max_execution_time.is_zero())
+                .then(|| Instant::now() + max_execution_time),
+            max_execution_time,
+            max_result_rows: config.query_max_result_rows(),
+            max_result_bytes: config.query_max_result_bytes(),
+        }
+    }
+}
+
+/// Fail the result stream once the deadline passes or once it returns more rows or bytes
+/// than allowed, 0 meaning no limit
+fn limit_results(
+    input: impl Stream<Item = ArrowResult<RecordBatch>> + Send + 'static,
+    limits: QueryLimits,
+) -> impl Stream<Item = ArrowResult<RecordBatch>> + Send {
+    let state = (Box::pin(input), 0usize, 0usize, false);
+    futures::stream::unfold(state, move |(mut input, rows, bytes, done)| async move {
+        if done {
+            return None;
+        }
+        let next = match limits.deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline, input.next())
+                .await
+                .unwrap_or_else(|_| {
+                    Some(Err(limit_error(format!(
+                        "Query exceeded max_execution_time of {} ms",
+                        limits.max_execution_time.as_millis()
+                    ))))
+                }),
+            None => input.next().await,
+        };
+        let batch = match next? {
+            Ok(batch) => batch,
+            Err(e) => return Some((Err(e), (input, rows, bytes, true))),
+        };
+        let rows = rows + batch.num_rows();
+        let bytes = bytes + batch_byte_size(&batch);
+        let result = if limits.max_result_rows > 0 && rows > limits.max_result_rows {
+            Err(limit_error(format!(
+                "Query returned more than max_result_rows of {} rows",
+                limits.max_result_rows
+            )))
+        } else if limits.max_result_bytes > 0 && bytes > limits.max_result_bytes {
+            Err(limit_error(format!(
+                "Query returned more than max_result_bytes of {} bytes",
+                limits.max_result_bytes
+            )))
+        } else {
+            Ok(batch)
+        };
+        let done = result.is_err();
+        Some((result, (input, rows, bytes, done)))
+    })
+}
+
+fn limit_error(msg: String) -> ArrowError {
+    ArrowError::ExternalError(Box::new(DataFusionError::Execution(msg)))
+}
+
 async fn execute_query(
     scheduler_url: String,
     session_id: String,
@@ -252,42 +335,50 @@ async fn execute_query(
     );
 
     let job_id = query_result.job_id;
-    let mut prev_status: Option<job_status::Status> = None;
+    // cancel the job if the query is dropped before it ends, e.g. on its deadline
+    let mut cancel = CancelJobOnDrop {
+        scheduler: Some(scheduler.clone()),
+        job_id: job_id.clone(),
+    };
+    let mut statuses = scheduler
+        .watch_job_status(WatchJobStatusParams {
+            job_id: job_id.clone(),
+        })
+        .await
+        .map_err(|e| DataFusionError::Execution(format!("{:?}", e)))?
+        .into_inner();
 
     loop {
-        let GetJobStatusResult { status } = scheduler
-            .get_job_status(GetJobStatusParams {
-                job_id: job_id.clone(),
-            })
+        let status = statuses
+            .message()
             .await
             .map_err(|e| DataFusionError::Execution(format!("{:?}", e)))?
-            .into_inner();
-        let status = status.and_then(|s| s.status).ok_or_else(|| {
-            DataFusionError::Internal("Received empty status message".to_owned())
-        })?;
-        let wait_future = tokio::time::sleep(Duration::from_millis(100));
-        let has_status_change = prev_status.map(|x| x != status).unwrap_or(true);
+            .ok_or_else(|| {
+                DataFusionError::Execution(format!(
+                    "Job {} was removed before it finished",
+                    job_id
+                ))
+            })?
+            .status
+            .ok_or_else(|| {
+                DataFusionError::Internal("Received empty status message".to_owned())
+            })?;
+        // the scheduler only sends the changes of the status
         match status {
             job_status::Status::Queued(_) => {
-                if has_status_change {
-                    info!("Job {} still queued...", job_id);
-                }
-                wait_future.await;
-                prev_status = Some(status);
+                info!("Job {} still queued...", job_id);
             }
             job_status::Status::Running(_) => {
-                if has_status_change {
-                    info!("Job {} is running...", job_id);
-                }
-                wait_future.await;
-                prev_status = Some(status);
+                info!("Job {} is running...", job_id);
             }
             job_status::Status::Failed(err) => {
+                cancel.disarm();
                 let msg = format!("Job {} failed: {}", job_id, err.error);
                 error!("{}", msg);
                 break Err(DataFusionError::Execution(msg));
             }
             job_status::Status::Completed(completed) => {
+                cancel.disarm();
                 let streams = completed.partition_location.into_iter().map(|p| {
                     let f = fetch_partition(p)
                         .map_err(|e| ArrowError::ExternalError(Box::new(e)));
@@ -301,6 +392,49 @@ async fn execute_query(
     }
 }
 
+/// Cancels a job from a detached task once dropped, unless the job ended first. The
+/// future watching the job may be dropped at any await point, the cancellation must
+/// not be dropped with it.
+struct CancelJobOnDrop {
+    scheduler: Option<SchedulerGrpcClient<Channel>>,
+    job_id: String,
+}
+
+impl CancelJobOnDrop {
+    fn disarm(&mut self) {
+        self.scheduler = None;
+    }
+}
+
+impl Drop for CancelJobOnDrop {
+    fn drop(&mut self) {
+        let mut scheduler = match self.scheduler.take() {
+            Some(scheduler) => scheduler,
+            None => return,
+        };
+        let job_id = std::mem::take(&mut self.job_id);
+        let handle = match Handle::try_current() {
+            Ok(handle) => handle,
+            Err(_) => {
+                warn!("Failed to cancel job {}: no tokio runtime", job_id);
+                return;
+            }
+        };
+        handle.spawn(async move {
+            match scheduler
+                .cancel_job(CancelJobParams {
+                    job_id: job_id.clone(),
+                })
+                .await
+            {
+                Ok(r) if r.get_ref().cancelled => info!("Cancelled job {}", job_id),
+                Ok(_) => info!("Job {} ended before it was cancelled", job_id),
+                Err(e) => warn!("Failed to cancel job {}: {:?}", job_id, e),
+            }
+        });
+    }
+}
+
 async fn fetch_partition(
     location: PartitionLocation,
 ) -> Result<SendableRecordBatchStream> {
@@ -324,3 +458,265 @@ async fn fetch_partition(
         .await
         .map_err(|e| DataFusionError::Execution(format!("{:?}", e)))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{
+        BALLISTA_QUERY_MAX_EXECUTION_TIME, BALLISTA_QUERY_MAX_RESULT_ROWS,
+    };
+    use crate::serde::protobuf::scheduler_grpc_server::{
+        SchedulerGrpc, SchedulerGrpcServer,
+    };
+    use crate::serde::protobuf::{
+        CancelJobResult, CompletedJob, DrainExecutorParams, DrainExecutorResult,
+        ExecuteQueryResult, FailedJob, GetFileMetadataParams, GetFileMetadataResult,
+        GetJobStatusParams, GetJobStatusResult, HeartBeatParams, HeartBeatResult,
+        JobStatus, PollWorkParams, PollWorkResult, QueuedJob, RegisterExecutorParams,
+        RegisterExecutorResult, RunningJob, UpdateTaskStatusParams,
+        UpdateTaskStatusResult,
+    };
+    use datafusion::arrow::array::Int32Array;
+    use datafusion::arrow::datatypes::{DataType, Field, Schema};
+    use parking_lot::Mutex;
+    use std::pin::Pin;
+    use tokio::net::TcpListener;
+    use tonic::transport::Server;
+    use tonic::{Request, Response, Status};
+
+    fn batch(rows: i32) -> ArrowResult<RecordBatch> {
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int32, false)]));
+        RecordBatch::try_new(
+            schema,
+            vec![Arc::new(Int32Array::from_iter_values(0..rows))],
+        )
+    }
+
+    fn limits(key: &str, value: &str) -> QueryLimits {
+        QueryLimits::start(&BallistaConfig::builder().set(key, value).build().unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_max_result_rows() {
+        let input = futures::stream::iter(vec![batch(3), batch(3), batch(3)]);
+        let results: Vec<_> =
+            limit_results(input, limits(BALLISTA_QUERY_MAX_RESULT_ROWS, "5"))
+                .collect()
+                .await;
+        assert_eq!(2, results.len());
+        assert_eq!(3, results[0].as_ref().unwrap().num_rows());
+        let err = results[1].as_ref().unwrap_err().to_string();
+        assert!(err.contains("max_result_rows of 5 rows"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_max_execution_time() {
+        let input =
+            futures::stream::once(async { batch(1) }).chain(futures::stream::pending());
+        let results: Vec<_> =
+            limit_results(input, limits(BALLISTA_QUERY_MAX_EXECUTION_TIME, "50"))
+                .collect()
+                .await;
+        assert_eq!(2, results.len());
+        assert!(results[0].is_ok());
+        let err = results[1].as_ref().unwrap_err().to_string();
+        assert!(err.contains("max_execution_time of 50 ms"), "{}", err);
+    }
+
+    /// A scheduler sending the given statuses of its job, the job then stays in the
+    /// last one
+    #[derive(Clone)]
+    struct StatusScheduler {
+        statuses: Vec<JobStatus>,
+        cancelled: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl StatusScheduler {
+        fn new(statuses: Vec<job_status::Status>) -> Self {
+            Self {
+                statuses: statuses
+                    .into_iter()
+                    .map(|status| JobStatus {
+                        status: Some(status),
+                    })
+                    .collect(),
+                cancelled: Arc::default(),
+            }
+        }
+
+        /// Serve the scheduler, return its URL
+        async fn serve(&self) -> String {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            tokio::spawn(
+                Server::builder()
+                    .add_service(SchedulerGrpcServer::new(self.clone()))
+                    .serve_with_incoming(tokio_stream::wrappers::TcpListenerStream::new(
+                        listener,
+                    )),
+            );
+            format!("http://{}", addr)
+        }
+
+        /// The results of a query run by the scheduler
+        async fn run(&self, limits: QueryLimits) -> Vec<ArrowResult<RecordBatch>> {
+            let query = ExecuteQueryParams {
+                query: None,
+                settings: vec![],
+                optional_session_id: Some(OptionalSessionId::SessionId("s1".to_owned())),
+            };
+            let input = futures::stream::once(
+                execute_query(self.serve().await, "s1".to_owned(), query)
+                    .map_err(|e| ArrowError::ExternalError(Box::new(e))),
+            )
+            .try_flatten();
+            limit_results(input, limits).collect().await
+        }
+    }
+
+    #[tonic::async_trait]
+    impl SchedulerGrpc for StatusScheduler {
+        async fn poll_work(
+            &self,
+            _request: Request<PollWorkParams>,
+        ) -> std::result::Result<Response<PollWorkResult>, Status> {
+            Err(Status::unimplemented("poll_work"))
+        }
+
+        async fn register_executor(
+            &self,
+            _request: Request<RegisterExecutorParams>,
+        ) -> std::result::Result<Response<RegisterExecutorResult>, Status> {
+            Err(Status::unimplemented("register_executor"))
+        }
+
+        async fn heart_beat_from_executor(
+            &self,
+            _request: Request<HeartBeatParams>,
+        ) -> std::result::Result<Response<HeartBeatResult>, Status> {
+            Err(Status::unimplemented("heart_beat_from_executor"))
+        }
+
+        async fn update_task_status(
+            &self,
+            _request: Request<UpdateTaskStatusParams>,
+        ) -> std::result::Result<Response<UpdateTaskStatusResult>, Status> {
+            Err(Status::unimplemented("update_task_status"))
+        }
+
+        async fn get_file_metadata(
+            &self,
+            _request: Request<GetFileMetadataParams>,
+        ) -> std::result::Result<Response<GetFileMetadataResult>, Status> {
+            Err(Status::unimplemented("get_file_metadata"))
+        }
+
+        async fn execute_query(
+            &self,
+            request: Request<ExecuteQueryParams>,
+        ) -> std::result::Result<Response<ExecuteQueryResult>, Status> {
+            let session_id = match request.into_inner().optional_session_id {
+                Some(OptionalSessionId::SessionId(session_id)) => session_id,
+                None => String::new(),
+            };
+            Ok(Response::new(ExecuteQueryResult {
+                job_id: "job1".to_owned(),
+                session_id,
+            }))
+        }
+
+        async fn get_job_status(
+            &self,
+            _request: Request<GetJobStatusParams>,
+        ) -> std::result::Result<Response<GetJobStatusResult>, Status> {
+            Err(Status::unimplemented("get_job_status"))
+        }
+
+        type WatchJobStatusStream = Pin<
+            Box<
+                dyn Stream<Item = std::result::Result<JobStatus, Status>>
+                    + Send
+                    + 'static,
+            >,
+        >;
+
+        async fn watch_job_status(
+            &self,
+            _request: Request<WatchJobStatusParams>,
+        ) -> std::result::Result<Response<Self::WatchJobStatusStream>, Status> {
+            let statuses = self.statuses.clone().into_iter().map(Ok);
+            Ok(Response::new(Box::pin(
+                futures::stream::iter(statuses).chain(futures::stream::pending()),
+            )))
+        }
+
+        async fn cancel_job(
+            &self,
+            request: Request<CancelJobParams>,
+        ) -> std::result::Result<Response<CancelJobResult>, Status> {
+            self.cancelled.lock().push(request.into_inner().job_id);
+            Ok(Response::new(CancelJobResult { cancelled: true }))
+        }
+
+        async fn drain_executor(
+            &self,
+            _request: Request<DrainExecutorParams>,
+        ) -> std::result::Result<Response<DrainExecutorResult>, Status> {
+            Err(Status::unimplemented("drain_executor"))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_execute_query() {
+        let scheduler = StatusScheduler::new(vec![
+            job_status::Status::Queued(QueuedJob {}),
+            job_status::Status::Running(RunningJob {}),
+            job_status::Status::Completed(CompletedJob {
+                partition_location: vec![],
+            }),
+        ]);
+        let results = scheduler
+            .run(limits(BALLISTA_QUERY_MAX_RESULT_ROWS, "0"))
+            .await;
+        assert!(results.is_empty());
+
+        let scheduler = StatusScheduler::new(vec![
+            job_status::Status::Running(RunningJob {}),
+            job_status::Status::Failed(FailedJob {
+                error: "boom".to_owned(),
+            }),
+        ]);
+        let results = scheduler
+            .run(limits(BALLISTA_QUERY_MAX_RESULT_ROWS, "0"))
+            .await;
+        assert_eq!(1, results.len());
+        let err = results[0].as_ref().unwrap_err().to_string();
+        assert!(err.contains("Job job1 failed: boom"), "{}", err);
+
+        // the ended jobs are not cancelled
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(scheduler.cancelled.lock().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_cancel_on_max_execution_time() {
+        let scheduler =
+            StatusScheduler::new(vec![job_status::Status::Queued(QueuedJob {})]);
+        let results = scheduler
+            .run(limits(BALLISTA_QUERY_MAX_EXECUTION_TIME, "200"))
+            .await;
+        assert_eq!(1, results.len());
+        let err = results[0].as_ref().unwrap_err().to_string();
+        assert!(err.contains("max_execution_time of 200 ms"), "{}", err);
+
+        // the job is cancelled once the query is dropped on its deadline
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while scheduler.cancelled.lock().is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(*scheduler.cancelled.lock(), vec!["job1".to_owned()]);
+    }
+}
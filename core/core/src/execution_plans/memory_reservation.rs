@@ -0,0 +1,309 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+use std::any::Any;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use datafusion::arrow::datatypes::SchemaRef;
+use datafusion::arrow::error::ArrowError;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::execution::context::TaskContext;
+use datafusion::execution::memory_manager::{
+    ConsumerType, MemoryConsumer, MemoryConsumerId, MemoryManager,
+};
+use datafusion::execution::runtime_env::RuntimeEnv;
+use datafusion::physical_plan::aggregates::{AggregateExec, AggregateMode};
+use datafusion::physical_plan::common::batch_byte_size;
+use datafusion::physical_plan::expressions::PhysicalSortExpr;
+use datafusion::physical_plan::hash_join::HashJoinExec;
+use datafusion::physical_plan::stream::RecordBatchStreamAdapter;
+use datafusion::physical_plan::{
+    with_new_children_if_necessary, DisplayFormatType, ExecutionPlan, Partitioning,
+    SendableRecordBatchStream, Statistics,
+};
+use futures::StreamExt;
+use parking_lot::Mutex;
+
+/// MemoryReservationExec reserves the memory of the batches of its input from the
+/// memory manager of the task, for the operators keeping them in memory that can't
+/// spill. The task fails with a resources exhausted error instead of growing past
+/// the memory pool, the memory is released once the plan is dropped.
+#[derive(Debug)]
+pub struct MemoryReservationExec {
+    input: Arc<dyn ExecutionPlan>,
+    /// Reservations of the executed partitions, held as long as the plan
+    reservations: Mutex<Vec<Arc<MemoryReservation>>>,
+}
+
+impl MemoryReservationExec {
+    pub fn new(input: Arc<dyn ExecutionPlan>) -> Self {
+        Self {
+            input,
+            reservations: Mutex::new(vec![]),
+        }
+    }
+
+    /// Bytes reserved by the executed partitions
+    pub fn reserved_bytes(&self) -> usize {
+        self.reservations.lock().iter().map(|r| r.mem_used()).sum()
+    }
+}
+
+/// Reserve the memory of the build sides of the hash joins and of the inputs of the
+/// final aggregations, which are kept in memory until the operators are done. The
+/// partial aggregations are not limited, the states they keep depend on the groups
+/// of their input rather than on its size.
+pub fn with_memory_reservations(
+    plan: Arc<dyn ExecutionPlan>,
+) -> Result<Arc<dyn ExecutionPlan>> {
+    let children = plan
+        .children()
+        .into_iter()
+        .map(with_memory_reservations)
+        .collect::<Result<Vec<_>>>()?;
+    let reserve = |child: Arc<dyn ExecutionPlan>| -> Arc<dyn ExecutionPlan> {
+        Arc::new(MemoryReservationExec::new(child))
+    };
+    let children = if plan.as_any().is::<HashJoinExec>() {
+        // the left side is the build side
+        children
+            .into_iter()
+            .enumerate()
+            .map(|(i, child)| if i == 0 { reserve(child) } else { child })
+            .collect()
+    } else if matches!(
+        plan.as_any()
+            .downcast_ref::<AggregateExec>()
+            .map(|agg| agg.mode()),
+        Some(AggregateMode::Final | AggregateMode::FinalPartitioned)
+    ) {
+        children.into_iter().map(reserve).collect()
+    } else {
+        children
+    };
+    with_new_children_if_necessary(plan, children)
+}
+
+impl ExecutionPlan for MemoryReservationExec {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.input.schema()
+    }
+
+    fn output_partitioning(&self) -> Partitioning {
+        self.input.output_partitioning()
+    }
+
+    fn output_ordering(&self) -> Option<&[PhysicalSortExpr]> {
+        self.input.output_ordering()
+    }
+
+    fn relies_on_input_order(&self) -> bool {
+        false
+    }
+
+    fn maintains_input_order(&self) -> bool {
+        true
+    }
+
+    fn children(&self) -> Vec<Arc<dyn ExecutionPlan>> {
+        vec![self.input.clone()]
+    }
+
+    fn with_new_children(
+        self: Arc<Self>,
+        children: Vec<Arc<dyn ExecutionPlan>>,
+    ) -> Result<Arc<dyn ExecutionPlan>> {
+        match children.len() {
+            1 => Ok(Arc::new(MemoryReservationExec::new(children[0].clone()))),
+            _ => Err(DataFusionError::Internal(
+                "MemoryReservationExec wrong number of children".to_string(),
+            )),
+        }
+    }
+
+    fn execute(
+        &self,
+        partition: usize,
+        context: Arc<TaskContext>,
+    ) -> Result<SendableRecordBatchStream> {
+        let reservation = Arc::new(MemoryReservation::new(
+            format!("MemoryReservationExec[{}]", partition),
+            MemoryConsumerId::new(partition),
+            context.runtime_env(),
+        ));
+        self.reservations.lock().push(reservation.clone());
+
+        let input = self.input.execute(partition, context)?;
+        let stream = input.then(move |batch| {
+            let reservation = reservation.clone();
+            async move {
+                let batch = batch?;
+                reservation
+                    .reserve(batch_byte_size(&batch))
+                    .await
+                    .map_err(|e| ArrowError::ExternalError(Box::new(e)))?;
+                Ok(batch)
+            }
+        });
+        Ok(Box::pin(RecordBatchStreamAdapter::new(
+            self.schema(),
+            stream,
+        )))
+    }
+
+    fn fmt_as(&self, t: DisplayFormatType, f: &mut fmt::Formatter) -> fmt::Result {
+        match t {
+            DisplayFormatType::Default => write!(f, "MemoryReservationExec"),
+        }
+    }
+
+    fn statistics(&self) -> Statistics {
+        self.input.statistics()
+    }
+}
+
+/// Memory consumer that can't spill, it fails to grow once the pool is exhausted
+struct MemoryReservation {
+    name: String,
+    id: MemoryConsumerId,
+    runtime: Arc<RuntimeEnv>,
+    used: AtomicUsize,
+}
+
+impl MemoryReservation {
+    fn new(name: String, id: MemoryConsumerId, runtime: Arc<RuntimeEnv>) -> Self {
+        runtime.register_requester(&id);
+        Self {
+            name,
+            id,
+            runtime,
+            used: AtomicUsize::new(0),
+        }
+    }
+
+    async fn reserve(&self, bytes: usize) -> Result<()> {
+        self.try_grow(bytes).await?;
+        self.used.fetch_add(bytes, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+impl fmt::Debug for MemoryReservation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MemoryReservation")
+            .field("name", &self.name)
+            .field("id", &self.id)
+            .field("used", &self.mem_used())
+            .finish()
+    }
+}
+
+#[async_trait]
+impl MemoryConsumer for MemoryReservation {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn id(&self) -> &MemoryConsumerId {
+        &self.id
+    }
+
+    fn memory_manager(&self) -> Arc<MemoryManager> {
+        self.runtime.memory_manager.clone()
+    }
+
+    fn type_(&self) -> &ConsumerType {
+        &ConsumerType::Requesting
+    }
+
+    async fn spill(&self) -> Result<usize> {
+        Err(DataFusionError::ResourcesExhausted(format!(
+            "{} can't keep more than {} bytes in memory",
+            self.name,
+            self.mem_used()
+        )))
+    }
+
+    fn mem_used(&self) -> usize {
+        self.used.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for MemoryReservation {
+    fn drop(&mut self) {
+        self.runtime.drop_consumer(&self.id, self.mem_used());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use datafusion::arrow::array::UInt32Array;
+    use datafusion::arrow::datatypes::{DataType, Field, Schema};
+    use datafusion::arrow::record_batch::RecordBatch;
+    use datafusion::execution::memory_manager::MemoryManagerConfig;
+    use datafusion::execution::runtime_env::RuntimeConfig;
+    use datafusion::physical_plan::common::collect;
+    use datafusion::physical_plan::memory::MemoryExec;
+    use datafusion::prelude::{SessionConfig, SessionContext};
+
+    fn input(num_batches: usize) -> Result<Arc<dyn ExecutionPlan>> {
+        let schema =
+            Arc::new(Schema::new(vec![Field::new("a", DataType::UInt32, false)]));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![Arc::new(UInt32Array::from((0..1024).collect::<Vec<u32>>()))],
+        )?;
+        Ok(Arc::new(MemoryExec::try_new(
+            &[vec![batch; num_batches]],
+            schema,
+            None,
+        )?))
+    }
+
+    fn task_ctx(max_memory: usize) -> Result<Arc<TaskContext>> {
+        let config = RuntimeConfig::new()
+            .with_memory_manager(MemoryManagerConfig::try_new_limit(max_memory, 1.0)?);
+        let runtime = Arc::new(RuntimeEnv::new(config)?);
+        Ok(SessionContext::with_config_rt(SessionConfig::new(), runtime).task_ctx())
+    }
+
+    #[tokio::test]
+    async fn test_reserve_input() -> Result<()> {
+        let exec = MemoryReservationExec::new(input(2)?);
+        let batches = collect(exec.execute(0, task_ctx(1024 * 1024)?)?).await?;
+        assert_eq!(batches.len(), 2);
+        assert_eq!(exec.reserved_bytes(), 2 * batch_byte_size(&batches[0]));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pool_exhausted() -> Result<()> {
+        let exec = MemoryReservationExec::new(input(16)?);
+        let result = collect(exec.execute(0, task_ctx(16 * 1024)?)?).await;
+        let error = result.unwrap_err().to_string();
+        assert!(error.contains("can't keep more than"), "{}", error);
+        Ok(())
+    }
+}
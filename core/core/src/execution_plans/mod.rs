@@ -19,11 +19,17 @@
 //! several Ballista executors.
 
 mod distributed_query;
+mod memory_reservation;
 mod shuffle_reader;
 mod shuffle_writer;
+pub mod sort_shuffle;
 mod unresolved_shuffle;
 
 pub use distributed_query::DistributedQueryExec;
-pub use shuffle_reader::ShuffleReaderExec;
+pub use memory_reservation::{with_memory_reservations, MemoryReservationExec};
+pub use shuffle_reader::{
+    set_shuffle_fetch_runtime, with_shuffle_fetch_options, ShuffleFetchOptions,
+    ShuffleReaderExec,
+};
 pub use shuffle_writer::ShuffleWriterExec;
 pub use unresolved_shuffle::UnresolvedShuffleExec;
@@ -16,12 +16,24 @@
 // under the License.
 
 use std::any::Any;
+use std::collections::HashMap;
+use std::fs::File;
+use std::pin::Pin;
 use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
 
 use crate::client::BallistaClient;
+use crate::config::BallistaConfig;
+use crate::error::{BallistaError, FetchFailure};
+use crate::execution_plans::sort_shuffle::{
+    decode_first_shuffle_block, is_sort_shuffle_file, read_shuffle_block,
+};
 use crate::serde::scheduler::{PartitionLocation, PartitionStats};
 
 use datafusion::arrow::datatypes::SchemaRef;
+use datafusion::arrow::ipc::reader::FileReader;
+use datafusion::arrow::record_batch::RecordBatch;
 
 use datafusion::error::{DataFusionError, Result};
 use datafusion::physical_plan::expressions::PhysicalSortExpr;
@@ -29,14 +41,63 @@ use datafusion::physical_plan::metrics::{
     ExecutionPlanMetricsSet, MetricBuilder, MetricsSet,
 };
 use datafusion::physical_plan::{
-    DisplayFormatType, ExecutionPlan, Partitioning, SendableRecordBatchStream, Statistics,
+    with_new_children_if_necessary, DisplayFormatType, ExecutionPlan, Partitioning,
+    SendableRecordBatchStream, Statistics,
 };
-use futures::{StreamExt, TryStreamExt};
+use futures::stream::{BoxStream, SelectAll};
+use futures::{Stream, StreamExt, TryStreamExt};
 
 use datafusion::arrow::error::ArrowError;
 use datafusion::execution::context::TaskContext;
 use datafusion::physical_plan::stream::RecordBatchStreamAdapter;
-use log::info;
+use log::{info, warn};
+use once_cell::sync::{Lazy, OnceCell};
+use parking_lot::Mutex;
+use tokio::runtime::Handle;
+use tokio::sync::{mpsc, Semaphore};
+use tokio_stream::wrappers::ReceiverStream;
+
+/// How a [ShuffleReaderExec] fetches its partitions
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShuffleFetchOptions {
+    /// Max number of partitions fetched at the same time
+    pub max_concurrent_fetches: usize,
+    /// Max bytes of the partitions fetched and not read yet
+    pub max_in_flight_bytes: usize,
+    /// Times a failed fetch is retried before failing the task
+    pub max_retries: usize,
+    /// Wait before the first retry, doubled at each retry
+    pub retry_backoff: Duration,
+    /// Id of the executor running the reader, the partitions it wrote are read
+    /// from its work dir instead of fetched
+    pub local_executor_id: Option<String>,
+}
+
+impl Default for ShuffleFetchOptions {
+    fn default() -> Self {
+        Self {
+            max_concurrent_fetches: 8,
+            max_in_flight_bytes: 48 * 1024 * 1024,
+            max_retries: 3,
+            retry_backoff: Duration::from_millis(100),
+            local_executor_id: None,
+        }
+    }
+}
+
+impl From<&BallistaConfig> for ShuffleFetchOptions {
+    fn from(config: &BallistaConfig) -> Self {
+        Self {
+            max_concurrent_fetches: config.shuffle_max_concurrent_fetches(),
+            max_in_flight_bytes: config.shuffle_max_in_flight_bytes(),
+            max_retries: config.shuffle_fetch_retries(),
+            retry_backoff: Duration::from_millis(
+                config.shuffle_fetch_retry_backoff_ms() as u64
+            ),
+            local_executor_id: None,
+        }
+    }
+}
 
 /// ShuffleReaderExec reads partitions that have already been materialized by a ShuffleWriterExec
 /// being executed by an executor
@@ -45,6 +106,8 @@ pub struct ShuffleReaderExec {
     /// Each partition of a shuffle can read data from multiple locations
     pub(crate) partition: Vec<Vec<PartitionLocation>>,
     pub(crate) schema: SchemaRef,
+    /// How the partitions are fetched
+    fetch_options: ShuffleFetchOptions,
     /// Execution metrics
     metrics: ExecutionPlanMetricsSet,
 }
@@ -58,9 +121,43 @@ impl ShuffleReaderExec {
         Ok(Self {
             partition,
             schema,
+            fetch_options: ShuffleFetchOptions::default(),
             metrics: ExecutionPlanMetricsSet::new(),
         })
     }
+
+    /// Set how the partitions are fetched
+    pub fn with_fetch_options(mut self, fetch_options: ShuffleFetchOptions) -> Self {
+        self.fetch_options = fetch_options;
+        self
+    }
+
+    pub fn fetch_options(&self) -> &ShuffleFetchOptions {
+        &self.fetch_options
+    }
+
+    /// The locations of the shuffle partitions each partition reads
+    pub fn partition_locations(&self) -> &[Vec<PartitionLocation>] {
+        &self.partition
+    }
+}
+
+/// Set how the shuffle readers of a plan fetch their partitions
+pub fn with_shuffle_fetch_options(
+    plan: Arc<dyn ExecutionPlan>,
+    fetch_options: &ShuffleFetchOptions,
+) -> Result<Arc<dyn ExecutionPlan>> {
+    if let Some(reader) = plan.as_any().downcast_ref::<ShuffleReaderExec>() {
+        return Ok(Arc::new(
+            reader.clone().with_fetch_options(fetch_options.clone()),
+        ));
+    }
+    let children = plan
+        .children()
+        .into_iter()
+        .map(|child| with_shuffle_fetch_options(child, fetch_options))
+        .collect::<Result<Vec<_>>>()?;
+    with_new_children_if_necessary(plan, children)
 }
 
 impl ExecutionPlan for ShuffleReaderExec {
@@ -108,24 +205,86 @@ impl ExecutionPlan for ShuffleReaderExec {
 
         let fetch_time =
             MetricBuilder::new(&self.metrics).subset_time("fetch_time", partition);
-
-        let locations = self.partition[partition].clone();
-        let stream = locations.into_iter().map(move |p| {
-            let fetch_time = fetch_time.clone();
-            futures::stream::once(async move {
-                let timer = fetch_time.timer();
-                let r = fetch_partition(&p).await;
-                timer.done();
-
-                r.map_err(|e| ArrowError::ExternalError(Box::new(e)))
+        let fetched_bytes =
+            MetricBuilder::new(&self.metrics).counter("fetched_bytes", partition);
+        let local_bytes =
+            MetricBuilder::new(&self.metrics).counter("local_bytes", partition);
+        let remote_bytes =
+            MetricBuilder::new(&self.metrics).counter("remote_bytes", partition);
+
+        // the empty partitions have nothing to fetch
+        let locations = self.partition[partition]
+            .iter()
+            .filter(|p| p.partition_stats.num_rows != Some(0))
+            .cloned()
+            .collect::<Vec<_>>();
+        let schema = self.schema.clone();
+        let options = self.fetch_options.clone();
+        let max_in_flight_bytes = options
+            .max_in_flight_bytes
+            .clamp(1, Semaphore::MAX_PERMITS >> 1);
+        let in_flight_bytes = Arc::new(Semaphore::new(max_in_flight_bytes));
+        let max_concurrent_fetches = options.max_concurrent_fetches.max(1);
+        let concurrent_fetches = Arc::new(Semaphore::new(max_concurrent_fetches));
+        let fetches = futures::stream::iter(locations)
+            .map(move |p| {
+                let fetch_time = fetch_time.clone();
+                let fetched_bytes = fetched_bytes.clone();
+                let local_bytes = local_bytes.clone();
+                let remote_bytes = remote_bytes.clone();
+                let concurrent_fetches = concurrent_fetches.clone();
+                let in_flight_bytes = in_flight_bytes.clone();
+                let schema = schema.clone();
+                let options = options.clone();
+                async move {
+                    let fetch_permit = concurrent_fetches
+                        .acquire_owned()
+                        .await
+                        .map_err(|e| DataFusionError::Execution(format!("{:?}", e)))?;
+                    // a partition larger than the limit is fetched alone
+                    let num_bytes = p.partition_stats.num_bytes.unwrap_or(0) as usize;
+                    let permits = num_bytes.clamp(1, max_in_flight_bytes);
+                    let bytes_permit = in_flight_bytes
+                        .acquire_many_owned(permits.min(u32::MAX as usize) as u32)
+                        .await
+                        .map_err(|e| DataFusionError::Execution(format!("{:?}", e)))?;
+
+                    let local = options.local_executor_id.as_deref()
+                        == Some(p.executor_meta.id.as_str());
+                    let timer = fetch_time.timer();
+                    let stream = if local {
+                        read_local_partition(&p)
+                    } else {
+                        fetch_partition_with_retries(&p, schema, &options).await?
+                    };
+                    timer.done();
+                    // The shuffle file is sent as a whole
+                    fetched_bytes.add(num_bytes);
+                    if local {
+                        local_bytes.add(num_bytes);
+                    } else {
+                        remote_bytes.add(num_bytes);
+                    }
+
+                    // the partition is in flight until it is read, an error in the
+                    // middle of its stream fails the fetch
+                    let permits = (fetch_permit, bytes_permit);
+                    Ok::<_, DataFusionError>(
+                        stream
+                            .map(move |batch| {
+                                let _permits = &permits;
+                                batch.map_err(|e| fetch_failure(&p, e))
+                            })
+                            .boxed(),
+                    )
+                }
             })
-            .try_flatten()
-        });
+            .buffer_unordered(max_concurrent_fetches);
+        let stream = MergedPartitions::new(fetches.boxed())
+            .map_err(|e| ArrowError::ExternalError(Box::new(e)));
 
-        let result = RecordBatchStreamAdapter::new(
-            Arc::new(self.schema.as_ref().clone()),
-            futures::stream::iter(stream).flatten(),
-        );
+        let result =
+            RecordBatchStreamAdapter::new(Arc::new(self.schema.as_ref().clone()), stream);
         Ok(Box::pin(result))
     }
 
@@ -200,16 +359,211 @@ fn stats_for_partitions(
     )
 }
 
+/// Clients of the executors the shuffle partitions are fetched from, their channel
+/// multiplexes the concurrent fetches
+static CLIENT_POOL: Lazy<Mutex<HashMap<(String, u16), BallistaClient>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Runtime driving the connections of the pooled clients, the plans reading the
+/// partitions may run on another one
+static CLIENT_RUNTIME: OnceCell<Handle> = OnceCell::new();
+
+/// Drive the connections the shuffle partitions are fetched through on an I/O
+/// runtime, the readers only decode the partitions on the runtime running them
+pub fn set_shuffle_fetch_runtime(runtime: Handle) {
+    let _ = CLIENT_RUNTIME.set(runtime);
+}
+
+async fn pooled_client(host: &str, port: u16) -> Result<BallistaClient> {
+    let key = (host.to_owned(), port);
+    if let Some(client) = CLIENT_POOL.lock().get(&key) {
+        return Ok(client.clone());
+    }
+    let client = match CLIENT_RUNTIME.get() {
+        // the tasks of a connection are spawned on the runtime connecting it
+        Some(runtime) => {
+            let host = host.to_owned();
+            runtime
+                .spawn(async move { BallistaClient::try_new(&host, port).await })
+                .await
+                .map_err(|e| DataFusionError::Execution(format!("{:?}", e)))?
+        }
+        None => BallistaClient::try_new(host, port).await,
+    }
+    .map_err(|e| DataFusionError::Execution(format!("{:?}", e)))?;
+    CLIENT_POOL.lock().insert(key, client.clone());
+    Ok(client)
+}
+
+/// Record batches of a fetched partition, decoded as they are read
+type PartitionStream = BoxStream<'static, Result<RecordBatch>>;
+
+/// Start fetching a partition, retrying until its stream is established
+async fn fetch_partition_with_retries(
+    location: &PartitionLocation,
+    schema: SchemaRef,
+    options: &ShuffleFetchOptions,
+) -> Result<PartitionStream> {
+    let metadata = &location.executor_meta;
+    let mut retries = 0;
+    loop {
+        match fetch_partition(location, schema.clone()).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) if retries < options.max_retries => {
+                let backoff = options.retry_backoff * 2u32.saturating_pow(retries as u32);
+                warn!(
+                    "Fail to fetch {} from {}, retrying in {:?}: {}",
+                    location.path, metadata.id, backoff, e
+                );
+                // the next attempt reconnects
+                CLIENT_POOL
+                    .lock()
+                    .remove(&(metadata.host.clone(), metadata.port));
+                tokio::time::sleep(backoff).await;
+                retries += 1;
+            }
+            Err(e) => return Err(fetch_failure(location, e)),
+        }
+    }
+}
+
+fn fetch_failure(location: &PartitionLocation, e: DataFusionError) -> DataFusionError {
+    let partition_id = &location.partition_id;
+    DataFusionError::External(Box::new(BallistaError::FetchFailed(FetchFailure {
+        executor_id: location.executor_meta.id.clone(),
+        job_id: partition_id.job_id.clone(),
+        stage_id: partition_id.stage_id,
+        partition_id: partition_id.partition_id,
+        message: e.to_string(),
+    })))
+}
+
+/// The batches of the partitions fetched at the same time, in the order they are
+/// read. The partitions are polled by the task reading them so that the memory
+/// they allocate is attributed to it.
+struct MergedPartitions {
+    fetches: BoxStream<'static, Result<PartitionStream>>,
+    fetches_done: bool,
+    partitions: SelectAll<PartitionStream>,
+}
+
+impl MergedPartitions {
+    fn new(fetches: BoxStream<'static, Result<PartitionStream>>) -> Self {
+        Self {
+            fetches,
+            fetches_done: false,
+            partitions: SelectAll::new(),
+        }
+    }
+}
+
+impl Stream for MergedPartitions {
+    type Item = Result<RecordBatch>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        while !self.fetches_done {
+            match self.fetches.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(partition))) => self.partitions.push(partition),
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
+                Poll::Ready(None) => self.fetches_done = true,
+                Poll::Pending => break,
+            }
+        }
+        match self.partitions.poll_next_unpin(cx) {
+            // the next partitions are still being fetched
+            Poll::Ready(None) if !self.fetches_done => Poll::Pending,
+            poll => poll,
+        }
+    }
+}
+
+/// Read a partition written by the local executor from its shuffle file, skipping
+/// the flight service
+fn read_local_partition(location: &PartitionLocation) -> PartitionStream {
+    let path = location.path.clone();
+    let partition_id = location.partition_id.partition_id;
+    if is_sort_shuffle_file(&path) {
+        return futures::stream::once(async move {
+            let blocks = tokio::task::spawn_blocking(move || {
+                read_shuffle_block(&path, partition_id)
+            })
+            .await
+            .map_err(|e| DataFusionError::Execution(format!("{:?}", e)))??;
+            Ok(shuffle_block_stream(blocks))
+        })
+        .try_flatten()
+        .boxed();
+    }
+
+    let (tx, rx) = mpsc::channel(2);
+    tokio::task::spawn_blocking(move || {
+        let reader = File::open(&path)
+            .map_err(DataFusionError::from)
+            .and_then(|file| Ok(FileReader::try_new(file, None)?));
+        let reader = match reader {
+            Ok(reader) => reader,
+            Err(e) => {
+                let _ = tx.blocking_send(Err(e));
+                return;
+            }
+        };
+        for batch in reader {
+            if tx
+                .blocking_send(batch.map_err(DataFusionError::from))
+                .is_err()
+            {
+                return;
+            }
+        }
+    });
+    ReceiverStream::new(rx).boxed()
+}
+
+/// Decode the blocks of a sort based shuffle partition one at a time as they are read
+fn shuffle_block_stream(blocks: Vec<u8>) -> PartitionStream {
+    futures::stream::try_unfold((blocks, 0), |(blocks, offset)| async move {
+        if offset >= blocks.len() {
+            return Ok(None);
+        }
+        let (batches, blocks, offset) = tokio::task::spawn_blocking(move || {
+            let (batches, rest) = decode_first_shuffle_block(&blocks[offset..])?;
+            let offset = blocks.len() - rest.len();
+            Ok::<_, DataFusionError>((batches, blocks, offset))
+        })
+        .await
+        .map_err(|e| DataFusionError::Execution(format!("{:?}", e)))??;
+        let batches = futures::stream::iter(batches.into_iter().map(Ok));
+        Ok(Some((batches, (blocks, offset))))
+    })
+    .try_flatten()
+    .boxed()
+}
+
+/// Connect to the executor holding a partition and start streaming it
 async fn fetch_partition(
     location: &PartitionLocation,
-) -> Result<SendableRecordBatchStream> {
+    schema: SchemaRef,
+) -> Result<PartitionStream> {
     let metadata = &location.executor_meta;
     let partition_id = &location.partition_id;
     let mut ballista_client =
-        BallistaClient::try_new(metadata.host.as_str(), metadata.port as u16)
+        pooled_client(metadata.host.as_str(), metadata.port as u16).await?;
+    if is_sort_shuffle_file(&location.path) {
+        let blocks = ballista_client
+            .fetch_shuffle_block(
+                &partition_id.job_id,
+                partition_id.stage_id as usize,
+                partition_id.partition_id as usize,
+                &location.path,
+            )
             .await
             .map_err(|e| DataFusionError::Execution(format!("{:?}", e)))?;
-    ballista_client
+        return Ok(shuffle_block_stream(blocks));
+    }
+    let stream = ballista_client
         .fetch_partition(
             &partition_id.job_id,
             partition_id.stage_id as usize,
@@ -217,12 +571,21 @@ async fn fetch_partition(
             &location.path,
         )
         .await
-        .map_err(|e| DataFusionError::Execution(format!("{:?}", e)))
+        .map_err(|e| DataFusionError::Execution(format!("{:?}", e)))?;
+    debug_assert_eq!(stream.schema().fields().len(), schema.fields().len());
+    Ok(stream.map_err(DataFusionError::from).boxed())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::serde::scheduler::{ExecutorMetadata, ExecutorSpecification, PartitionId};
+    use datafusion::arrow::array::Int32Array;
+    use datafusion::arrow::datatypes::{DataType, Field, Schema};
+    use datafusion::arrow::ipc::writer::FileWriter;
+    use datafusion::physical_plan::coalesce_partitions::CoalescePartitionsExec;
+    use datafusion::prelude::SessionContext;
+    use tempfile::TempDir;
 
     #[tokio::test]
     async fn test_stats_for_partitions_empty() {
@@ -291,4 +654,98 @@ mod tests {
 
         assert_eq!(result, exptected);
     }
+
+    #[tokio::test]
+    async fn test_fetch_failure() -> Result<()> {
+        // nothing listens on the port once the listener is dropped
+        let port = std::net::TcpListener::bind("127.0.0.1:0")?
+            .local_addr()?
+            .port();
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int32, true)]));
+        let location = PartitionLocation {
+            partition_id: PartitionId::new("job", 1, 0),
+            executor_meta: ExecutorMetadata {
+                id: "executor-1".to_owned(),
+                host: "127.0.0.1".to_owned(),
+                port,
+                grpc_port: port,
+                specification: ExecutorSpecification { task_slots: 1 },
+            },
+            partition_stats: PartitionStats::new(Some(1), Some(1), Some(8)),
+            path: "/tmp/job/1/0/data.arrow".to_owned(),
+        };
+        let options = ShuffleFetchOptions {
+            max_retries: 1,
+            retry_backoff: Duration::from_millis(1),
+            ..Default::default()
+        };
+        let reader = ShuffleReaderExec::try_new(vec![vec![location]], schema.clone())?
+            .with_fetch_options(options.clone());
+        let plan = with_shuffle_fetch_options(
+            Arc::new(CoalescePartitionsExec::new(Arc::new(
+                ShuffleReaderExec::try_new(vec![], schema)?,
+            ))),
+            &options,
+        )?;
+        let rewritten = plan.children()[0].clone();
+        let rewritten = rewritten
+            .as_any()
+            .downcast_ref::<ShuffleReaderExec>()
+            .unwrap();
+        assert_eq!(rewritten.fetch_options(), &options);
+
+        let task_ctx = SessionContext::new().task_ctx();
+        let result = reader.execute(0, task_ctx)?.try_collect::<Vec<_>>().await;
+        let error = BallistaError::from(DataFusionError::from(result.unwrap_err()));
+        let failure = error.fetch_failure().expect("fetch failure");
+        assert_eq!(failure.executor_id, "executor-1");
+        assert_eq!(failure.job_id, "job");
+        assert_eq!(failure.stage_id, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_local_read() -> Result<()> {
+        let work_dir = TempDir::new()?;
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int32, true)]));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![Arc::new(Int32Array::from(vec![Some(1), None, Some(3)]))],
+        )?;
+        let path = work_dir.path().join("data.arrow");
+        let mut writer = FileWriter::try_new(File::create(&path)?, &schema)?;
+        writer.write(&batch)?;
+        writer.finish()?;
+
+        // the executor is not listening, the file can only be read locally
+        let location = PartitionLocation {
+            partition_id: PartitionId::new("job", 1, 0),
+            executor_meta: ExecutorMetadata {
+                id: "executor-1".to_owned(),
+                host: "127.0.0.1".to_owned(),
+                port: 0,
+                grpc_port: 0,
+                specification: ExecutorSpecification { task_slots: 1 },
+            },
+            partition_stats: PartitionStats::new(Some(3), Some(1), Some(64)),
+            path: path.to_str().unwrap().to_owned(),
+        };
+        let reader = ShuffleReaderExec::try_new(vec![vec![location]], schema)?
+            .with_fetch_options(ShuffleFetchOptions {
+                max_retries: 0,
+                local_executor_id: Some("executor-1".to_owned()),
+                ..Default::default()
+            });
+
+        let task_ctx = SessionContext::new().task_ctx();
+        let batches = reader.execute(0, task_ctx)?.try_collect::<Vec<_>>().await?;
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].column(0).data(), batch.column(0).data());
+
+        let metrics = reader.metrics().unwrap();
+        let bytes = |name: &str| metrics.sum_by_name(name).map(|v| v.as_usize());
+        assert_eq!(bytes("local_bytes"), Some(64));
+        assert_eq!(bytes("remote_bytes"), Some(0));
+        Ok(())
+    }
 }
@@ -29,6 +29,8 @@ use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Instant;
 
+use crate::config::{ShuffleCompression, ShuffleFormat};
+use crate::execution_plans::sort_shuffle::SortShuffleWriter;
 use crate::utils;
 
 use crate::serde::protobuf::ShuffleWritePartition;
@@ -73,6 +75,10 @@ pub struct ShuffleWriterExec {
     work_dir: String,
     /// Optional shuffle output partitioning
     shuffle_output_partitioning: Option<Partitioning>,
+    /// Layout of the hash partitioned output
+    shuffle_format: ShuffleFormat,
+    /// Codec of the sort based shuffle files
+    shuffle_compression: ShuffleCompression,
     /// Execution metrics
     metrics: ExecutionPlanMetricsSet,
 }
@@ -84,6 +90,8 @@ struct ShuffleWriteMetrics {
     repart_time: metrics::Time,
     input_rows: metrics::Count,
     output_rows: metrics::Count,
+    /// Bytes written to the shuffle files
+    output_bytes: metrics::Count,
 }
 
 impl ShuffleWriteMetrics {
@@ -96,11 +104,14 @@ impl ShuffleWriteMetrics {
 
         let output_rows = MetricBuilder::new(metrics).output_rows(partition);
 
+        let output_bytes = MetricBuilder::new(metrics).counter("output_bytes", partition);
+
         Self {
             write_time,
             repart_time,
             input_rows,
             output_rows,
+            output_bytes,
         }
     }
 }
@@ -120,10 +131,24 @@ impl ShuffleWriterExec {
             plan,
             work_dir,
             shuffle_output_partitioning,
+            shuffle_format: ShuffleFormat::Hash,
+            shuffle_compression: ShuffleCompression::None,
             metrics: ExecutionPlanMetricsSet::new(),
         })
     }
 
+    /// Set the layout of the hash partitioned output and the codec of the sort based
+    /// shuffle files
+    pub fn with_shuffle_format(
+        mut self,
+        shuffle_format: ShuffleFormat,
+        shuffle_compression: ShuffleCompression,
+    ) -> Self {
+        self.shuffle_format = shuffle_format;
+        self.shuffle_compression = shuffle_compression;
+        self
+    }
+
     /// Get the Job ID for this query stage
     pub fn job_id(&self) -> &str {
         &self.job_id
@@ -150,6 +175,8 @@ impl ShuffleWriterExec {
 
         let write_metrics = ShuffleWriteMetrics::new(input_partition, &self.metrics);
         let output_partitioning = self.shuffle_output_partitioning.clone();
+        let shuffle_format = self.shuffle_format;
+        let shuffle_compression = self.shuffle_compression;
         let plan = self.plan.clone();
 
         async move {
@@ -180,6 +207,9 @@ impl ShuffleWriterExec {
                     write_metrics
                         .output_rows
                         .add(stats.num_rows.unwrap_or(0) as usize);
+                    write_metrics
+                        .output_bytes
+                        .add(stats.num_bytes.unwrap_or(0) as usize);
                     timer.done();
 
                     info!(
@@ -195,9 +225,60 @@ impl ShuffleWriterExec {
                         num_batches: stats.num_batches.unwrap_or(0),
                         num_rows: stats.num_rows.unwrap_or(0),
                         num_bytes: stats.num_bytes.unwrap_or(0),
+                        ..Default::default()
                     }])
                 }
 
+                Some(Partitioning::Hash(exprs, num_output_partitions))
+                    if shuffle_format == ShuffleFormat::Sort =>
+                {
+                    let mut writer = SortShuffleWriter::try_new(
+                        &path,
+                        input_partition,
+                        num_output_partitions,
+                        stream.schema(),
+                        shuffle_compression,
+                    )?;
+                    let mut partitioner = BatchPartitioner::try_new(
+                        Partitioning::Hash(exprs, num_output_partitions),
+                        write_metrics.repart_time.clone(),
+                    )?;
+
+                    while let Some(result) = stream.next().await {
+                        let input_batch = result?;
+
+                        write_metrics.input_rows.add(input_batch.num_rows());
+
+                        partitioner.partition(
+                            input_batch,
+                            |output_partition, output_batch| {
+                                if output_batch.num_rows() == 0 {
+                                    return Ok(());
+                                }
+                                let timer = write_metrics.write_time.timer();
+                                writer.write(output_partition, &output_batch)?;
+                                write_metrics.output_rows.add(output_batch.num_rows());
+                                timer.done();
+                                Ok(())
+                            },
+                        )?;
+                    }
+
+                    let timer = write_metrics.write_time.timer();
+                    let part_locs = writer.finish()?;
+                    timer.done();
+                    for loc in &part_locs {
+                        write_metrics.output_bytes.add(loc.num_bytes as usize);
+                    }
+                    info!(
+                        "Finished writing {} shuffle partitions of input partition {} to {:?}",
+                        part_locs.len(),
+                        input_partition,
+                        part_locs.first().map(|loc| &loc.path)
+                    );
+                    Ok(part_locs)
+                }
+
                 Some(Partitioning::Hash(exprs, num_output_partitions)) => {
                     // we won't necessary produce output for every possible partition, so we
                     // create writers on demand
@@ -219,10 +300,11 @@ impl ShuffleWriterExec {
                         partitioner.partition(
                             input_batch,
                             |output_partition, output_batch| {
-                                // write non-empty batch out
-
-                                // TODO optimize so we don't write or fetch empty partitions
-                                // if output_batch.num_rows() > 0 {
+                                // only non-empty batches are written out, so that the empty
+                                // partitions have no file
+                                if output_batch.num_rows() == 0 {
+                                    return Ok(());
+                                }
                                 let timer = write_metrics.write_time.timer();
                                 match &mut writers[output_partition] {
                                     Some(w) => {
@@ -269,6 +351,7 @@ impl ShuffleWriterExec {
                                     w.num_rows,
                                     w.num_bytes
                                 );
+                                write_metrics.output_bytes.add(w.num_bytes as usize);
 
                                 part_locs.push(ShuffleWritePartition {
                                     partition_id: i as u64,
@@ -276,9 +359,16 @@ impl ShuffleWriterExec {
                                     num_batches: w.num_batches,
                                     num_rows: w.num_rows,
                                     num_bytes: w.num_bytes,
+                                    ..Default::default()
+                                });
+                            }
+                            None => {
+                                // reported without a path, the readers skip it
+                                part_locs.push(ShuffleWritePartition {
+                                    partition_id: i as u64,
+                                    ..Default::default()
                                 });
                             }
-                            None => {}
                         }
                     }
                     Ok(part_locs)
@@ -325,13 +415,16 @@ impl ExecutionPlan for ShuffleWriterExec {
         self: Arc<Self>,
         children: Vec<Arc<dyn ExecutionPlan>>,
     ) -> Result<Arc<dyn ExecutionPlan>> {
-        Ok(Arc::new(ShuffleWriterExec::try_new(
-            self.job_id.clone(),
-            self.stage_id,
-            children[0].clone(),
-            self.work_dir.clone(),
-            self.shuffle_output_partitioning.clone(),
-        )?))
+        Ok(Arc::new(
+            ShuffleWriterExec::try_new(
+                self.job_id.clone(),
+                self.stage_id,
+                children[0].clone(),
+                self.work_dir.clone(),
+                self.shuffle_output_partitioning.clone(),
+            )?
+            .with_shuffle_format(self.shuffle_format, self.shuffle_compression),
+        ))
     }
 
     fn execute(
@@ -433,6 +526,7 @@ fn result_schema() -> SchemaRef {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::execution_plans::sort_shuffle;
     use datafusion::arrow::array::{StringArray, StructArray, UInt32Array, UInt64Array};
     use datafusion::physical_plan::coalesce_partitions::CoalescePartitionsExec;
     use datafusion::physical_plan::expressions::Column;
@@ -538,6 +632,78 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    // number of rows in each partition is a function of the hash output, so don't test here
+    #[cfg(not(feature = "force_hash_collisions"))]
+    async fn test_sort_shuffle() -> Result<()> {
+        let session_ctx = SessionContext::new();
+        let task_ctx = session_ctx.task_ctx();
+
+        let work_dir = TempDir::new()?;
+        let query_stage = ShuffleWriterExec::try_new(
+            "jobOne".to_owned(),
+            1,
+            create_input_plan()?,
+            work_dir.path().to_str().unwrap().to_owned(),
+            Some(Partitioning::Hash(vec![Arc::new(Column::new("a", 0))], 2)),
+        )?
+        .with_shuffle_format(ShuffleFormat::Sort, ShuffleCompression::Zstd);
+        let part_locs = query_stage.execute_shuffle_write(1, task_ctx).await?;
+        assert_eq!(2, part_locs.len());
+        for loc in &part_locs {
+            assert!(loc.path.ends_with("shuffle-1.data"));
+            assert_eq!(2, loc.num_rows);
+            let batches = sort_shuffle::decode_shuffle_block(
+                &sort_shuffle::read_shuffle_block(&loc.path, loc.partition_id as usize)?,
+            )?;
+            let num_rows: usize = batches.iter().map(|b| b.num_rows()).sum();
+            assert_eq!(2, num_rows);
+        }
+        // one data and one index file for all the output partitions
+        assert_eq!(
+            2,
+            std::fs::read_dir(work_dir.path().join("jobOne/1"))?.count()
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    // number of rows in each partition is a function of the hash output, so don't test here
+    #[cfg(not(feature = "force_hash_collisions"))]
+    async fn test_empty_partitions() -> Result<()> {
+        let session_ctx = SessionContext::new();
+        let task_ctx = session_ctx.task_ctx();
+
+        let work_dir = TempDir::new()?;
+        let query_stage = ShuffleWriterExec::try_new(
+            "jobOne".to_owned(),
+            1,
+            create_input_plan()?,
+            work_dir.path().to_str().unwrap().to_owned(),
+            Some(Partitioning::Hash(vec![Arc::new(Column::new("a", 0))], 8)),
+        )?;
+        let part_locs = query_stage.execute_shuffle_write(0, task_ctx).await?;
+        // all the partitions are reported, only the non-empty ones have a file
+        assert_eq!(8, part_locs.len());
+        let written = part_locs
+            .iter()
+            .filter(|loc| !loc.path.is_empty())
+            .collect::<Vec<_>>();
+        assert!(!written.is_empty() && written.len() <= 2);
+        assert_eq!(4, written.iter().map(|loc| loc.num_rows).sum::<u64>());
+        assert!(part_locs
+            .iter()
+            .filter(|loc| loc.path.is_empty())
+            .all(|loc| loc.num_rows == 0 && loc.num_batches == 0));
+        assert_eq!(
+            written.len(),
+            std::fs::read_dir(work_dir.path().join("jobOne/1"))?.count()
+        );
+
+        Ok(())
+    }
+
     fn create_input_plan() -> Result<Arc<dyn ExecutionPlan>> {
         let schema = Arc::new(Schema::new(vec![
             Field::new("a", DataType::UInt32, true),
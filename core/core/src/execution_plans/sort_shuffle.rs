@@ -0,0 +1,378 @@
+// Copyright 2021 HetuDB.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Sort based shuffle files: a shuffle write task writes all its output partitions to
+//! one `.data` file, ordered by partition, and the offsets of the partitions to an
+//! `.index` file next to it.
+//!
+//! The bytes of a partition are a sequence of blocks, each block being a header (the
+//! codec as one byte and the length of the payload as a little endian u64) followed by
+//! an Arrow IPC file compressed with the codec. A partition has more than one block when
+//! the writer spilled its buffers before the end of the task.
+
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use datafusion::arrow::datatypes::SchemaRef;
+use datafusion::arrow::error::Result as ArrowResult;
+use datafusion::arrow::ipc::reader::FileReader;
+use datafusion::arrow::ipc::writer::FileWriter;
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::physical_plan::common::batch_byte_size;
+
+use crate::config::ShuffleCompression;
+use crate::serde::protobuf::ShuffleWritePartition;
+
+/// Extension of the data file of a sort based shuffle
+pub const SORT_SHUFFLE_DATA_EXTENSION: &str = "data";
+/// Extension of the index file of a sort based shuffle
+pub const SORT_SHUFFLE_INDEX_EXTENSION: &str = "index";
+
+/// Bytes of batches the writer buffers in memory before spilling them to disk
+const SPILL_THRESHOLD_BYTES: usize = 64 * 1024 * 1024;
+
+const BLOCK_HEADER_LEN: usize = 9;
+
+/// Whether a shuffle partition path is the data file of a sort based shuffle
+pub fn is_sort_shuffle_file(path: &str) -> bool {
+    Path::new(path).extension().and_then(|ext| ext.to_str())
+        == Some(SORT_SHUFFLE_DATA_EXTENSION)
+}
+
+/// Writes the output partitions of a shuffle write task to one data file and its index
+pub(crate) struct SortShuffleWriter {
+    data_path: PathBuf,
+    schema: SchemaRef,
+    compression: ShuffleCompression,
+    /// In memory Arrow IPC file of each partition
+    buffers: Vec<Option<FileWriter<Vec<u8>>>>,
+    buffered_bytes: usize,
+    /// File of the spilled blocks and the (offset, length) of the blocks of each partition
+    spill: Option<(File, PathBuf)>,
+    spilled_blocks: Vec<Vec<(u64, u64)>>,
+    num_rows: Vec<u64>,
+    num_batches: Vec<u64>,
+    num_bytes: Vec<u64>,
+}
+
+impl SortShuffleWriter {
+    /// Create a writer of `dir/shuffle-{input_partition}.data`
+    pub(crate) fn try_new(
+        dir: &Path,
+        input_partition: usize,
+        num_partitions: usize,
+        schema: SchemaRef,
+        compression: ShuffleCompression,
+    ) -> Result<Self> {
+        fs::create_dir_all(dir)?;
+        let data_path = dir
+            .join(format!("shuffle-{}", input_partition))
+            .with_extension(SORT_SHUFFLE_DATA_EXTENSION);
+        Ok(Self {
+            data_path,
+            schema,
+            compression,
+            buffers: (0..num_partitions).map(|_| None).collect(),
+            buffered_bytes: 0,
+            spill: None,
+            spilled_blocks: vec![vec![]; num_partitions],
+            num_rows: vec![0; num_partitions],
+            num_batches: vec![0; num_partitions],
+            num_bytes: vec![0; num_partitions],
+        })
+    }
+
+    pub(crate) fn write(&mut self, partition: usize, batch: &RecordBatch) -> Result<()> {
+        let writer = match &mut self.buffers[partition] {
+            Some(writer) => writer,
+            buffer => buffer.insert(FileWriter::try_new(vec![], &self.schema)?),
+        };
+        writer.write(batch)?;
+        let batch_bytes = batch_byte_size(batch);
+        self.num_rows[partition] += batch.num_rows() as u64;
+        self.num_batches[partition] += 1;
+        self.num_bytes[partition] += batch_bytes as u64;
+        self.buffered_bytes += batch_bytes;
+        if self.buffered_bytes >= SPILL_THRESHOLD_BYTES {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    // Move the buffered partitions to the spill file as blocks
+    fn spill(&mut self) -> Result<()> {
+        if self.spill.is_none() {
+            let path = self.data_path.with_extension("spill");
+            let file = File::options()
+                .create(true)
+                .truncate(true)
+                .read(true)
+                .write(true)
+                .open(&path)?;
+            self.spill = Some((file, path));
+        }
+        let (file, _) = self.spill.as_mut().unwrap();
+        for (partition, buffer) in self.buffers.iter_mut().enumerate() {
+            if let Some(writer) = buffer.take() {
+                let block = encode_block(writer, self.compression)?;
+                let offset = file.seek(SeekFrom::End(0))?;
+                file.write_all(&block)?;
+                self.spilled_blocks[partition].push((offset, block.len() as u64));
+            }
+        }
+        self.buffered_bytes = 0;
+        Ok(())
+    }
+
+    /// Write the data file and its index, return all the partitions, the ones which got
+    /// no rows without a path
+    pub(crate) fn finish(mut self) -> Result<Vec<ShuffleWritePartition>> {
+        let mut data = BufWriter::new(File::create(&self.data_path)?);
+        let mut offsets = Vec::with_capacity(self.buffers.len() + 1);
+        let mut offset = 0u64;
+        offsets.push(offset);
+        for partition in 0..self.buffers.len() {
+            if let Some((spill, _)) = self.spill.as_mut() {
+                for (block_offset, block_len) in &self.spilled_blocks[partition] {
+                    spill.seek(SeekFrom::Start(*block_offset))?;
+                    io::copy(&mut spill.by_ref().take(*block_len), &mut data)?;
+                    offset += block_len;
+                }
+            }
+            if let Some(writer) = self.buffers[partition].take() {
+                let block = encode_block(writer, self.compression)?;
+                data.write_all(&block)?;
+                offset += block.len() as u64;
+            }
+            offsets.push(offset);
+        }
+        data.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+
+        let mut index = Vec::with_capacity(offsets.len() * 8);
+        for offset in &offsets {
+            index.extend_from_slice(&offset.to_le_bytes());
+        }
+        fs::write(
+            self.data_path.with_extension(SORT_SHUFFLE_INDEX_EXTENSION),
+            index,
+        )?;
+        if let Some((_, spill_path)) = self.spill.take() {
+            fs::remove_file(spill_path)?;
+        }
+
+        let path = self.data_path.to_string_lossy().to_string();
+        Ok((0..self.num_batches.len())
+            .map(|partition| ShuffleWritePartition {
+                partition_id: partition as u64,
+                path: if self.num_rows[partition] > 0 {
+                    path.clone()
+                } else {
+                    "".to_owned()
+                },
+                num_batches: self.num_batches[partition],
+                num_rows: self.num_rows[partition],
+                num_bytes: self.num_bytes[partition],
+                ..Default::default()
+            })
+            .collect())
+    }
+}
+
+fn encode_block(
+    mut writer: FileWriter<Vec<u8>>,
+    compression: ShuffleCompression,
+) -> Result<Vec<u8>> {
+    writer.finish()?;
+    let ipc = writer.into_inner()?;
+    let (codec, payload) = match compression {
+        ShuffleCompression::None => (0u8, ipc),
+        ShuffleCompression::Lz4 => {
+            let mut encoder = lz4_flex::frame::FrameEncoder::new(vec![]);
+            encoder.write_all(&ipc)?;
+            let payload = encoder.finish().map_err(|e| {
+                DataFusionError::Execution(format!("LZ4 compression failed: {}", e))
+            })?;
+            (1, payload)
+        }
+        ShuffleCompression::Zstd => (2, zstd::stream::encode_all(ipc.as_slice(), 0)?),
+    };
+    let mut block = Vec::with_capacity(BLOCK_HEADER_LEN + payload.len());
+    block.push(codec);
+    block.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    block.extend_from_slice(&payload);
+    Ok(block)
+}
+
+/// Read the blocks of a partition from the data file of a sort based shuffle, looking up
+/// their range in the index file
+pub fn read_shuffle_block(data_path: &str, partition: usize) -> Result<Vec<u8>> {
+    let index_path = Path::new(data_path).with_extension(SORT_SHUFFLE_INDEX_EXTENSION);
+    let mut index = File::open(&index_path)?;
+    let mut offsets = [0u8; 16];
+    index.seek(SeekFrom::Start(partition as u64 * 8))?;
+    index.read_exact(&mut offsets).map_err(|e| {
+        DataFusionError::Execution(format!(
+            "Partition {} is missing from {:?}: {}",
+            partition, index_path, e
+        ))
+    })?;
+    let start = u64::from_le_bytes(offsets[..8].try_into().unwrap());
+    let end = u64::from_le_bytes(offsets[8..].try_into().unwrap());
+
+    let mut data = File::open(data_path)?;
+    data.seek(SeekFrom::Start(start))?;
+    let mut block = vec![0; (end - start) as usize];
+    data.read_exact(&mut block)?;
+    Ok(block)
+}
+
+/// Decode the record batches of the blocks read by [read_shuffle_block]
+pub fn decode_shuffle_block(mut blocks: &[u8]) -> Result<Vec<RecordBatch>> {
+    let mut batches = vec![];
+    while !blocks.is_empty() {
+        let (block_batches, rest) = decode_first_shuffle_block(blocks)?;
+        batches.extend(block_batches);
+        blocks = rest;
+    }
+    Ok(batches)
+}
+
+/// Decode the record batches of the first of the blocks read by [read_shuffle_block],
+/// return them with the blocks left to decode
+pub fn decode_first_shuffle_block(blocks: &[u8]) -> Result<(Vec<RecordBatch>, &[u8])> {
+    if blocks.len() < BLOCK_HEADER_LEN {
+        return Err(DataFusionError::Execution(
+            "Truncated shuffle block header".to_owned(),
+        ));
+    }
+    let codec = blocks[0];
+    let len =
+        u64::from_le_bytes(blocks[1..BLOCK_HEADER_LEN].try_into().unwrap()) as usize;
+    let payload = blocks
+        .get(BLOCK_HEADER_LEN..BLOCK_HEADER_LEN + len)
+        .ok_or_else(|| {
+            DataFusionError::Execution("Truncated shuffle block".to_owned())
+        })?;
+    let ipc = match codec {
+        0 => payload.to_vec(),
+        1 => {
+            let mut ipc = vec![];
+            lz4_flex::frame::FrameDecoder::new(payload).read_to_end(&mut ipc)?;
+            ipc
+        }
+        2 => zstd::stream::decode_all(payload)?,
+        _ => {
+            return Err(DataFusionError::Execution(format!(
+                "Unknown shuffle block codec {}",
+                codec
+            )))
+        }
+    };
+    let batches =
+        FileReader::try_new(Cursor::new(ipc), None)?.collect::<ArrowResult<Vec<_>>>()?;
+    Ok((batches, &blocks[BLOCK_HEADER_LEN + len..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use datafusion::arrow::array::{StringArray, UInt32Array};
+    use datafusion::arrow::datatypes::{DataType, Field, Schema};
+    use std::sync::Arc;
+    use tempfile::TempDir;
+
+    fn batch(values: Vec<u32>) -> RecordBatch {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("a", DataType::UInt32, false),
+            Field::new("b", DataType::Utf8, false),
+        ]));
+        let strings = values.iter().map(|v| format!("v{}", v)).collect::<Vec<_>>();
+        RecordBatch::try_new(
+            schema,
+            vec![
+                Arc::new(UInt32Array::from(values)),
+                Arc::new(StringArray::from(strings)),
+            ],
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_sort_shuffle_roundtrip() -> Result<()> {
+        for compression in [
+            ShuffleCompression::None,
+            ShuffleCompression::Lz4,
+            ShuffleCompression::Zstd,
+        ] {
+            let work_dir = TempDir::new()?;
+            let mut writer = SortShuffleWriter::try_new(
+                work_dir.path(),
+                1,
+                3,
+                batch(vec![]).schema(),
+                compression,
+            )?;
+            writer.write(2, &batch(vec![1, 2]))?;
+            writer.write(0, &batch(vec![3]))?;
+            // a spill in the middle splits partition 2 in two blocks
+            writer.spill()?;
+            writer.write(2, &batch(vec![4, 5, 6]))?;
+            let partitions = writer.finish()?;
+
+            assert_eq!(
+                partitions
+                    .iter()
+                    .map(|p| (p.partition_id, p.num_rows, p.num_batches))
+                    .collect::<Vec<_>>(),
+                vec![(0, 1, 1), (1, 0, 0), (2, 5, 2)]
+            );
+            assert!(partitions[1].path.is_empty());
+            let path = &partitions[0].path;
+            assert!(is_sort_shuffle_file(path));
+            assert!(path.ends_with("shuffle-1.data"));
+            assert!(!Path::new(path).with_extension("spill").exists());
+
+            let rows = |partition| -> Result<Vec<u32>> {
+                let batches =
+                    decode_shuffle_block(&read_shuffle_block(path, partition)?)?;
+                Ok(batches
+                    .iter()
+                    .flat_map(|b| {
+                        b.column(0)
+                            .as_any()
+                            .downcast_ref::<UInt32Array>()
+                            .unwrap()
+                            .values()
+                            .to_vec()
+                    })
+                    .collect())
+            };
+            assert_eq!(rows(0)?, vec![3]);
+            assert!(rows(1)?.is_empty());
+            assert_eq!(rows(2)?, vec![1, 2, 4, 5, 6]);
+            // the blocks can be decoded one at a time
+            let blocks = read_shuffle_block(path, 2)?;
+            let (first, rest) = decode_first_shuffle_block(&blocks)?;
+            assert_eq!(first.iter().map(|b| b.num_rows()).sum::<usize>(), 2);
+            let (second, rest) = decode_first_shuffle_block(rest)?;
+            assert_eq!(second.iter().map(|b| b.num_rows()).sum::<usize>(), 3);
+            assert!(rest.is_empty());
+            assert!(read_shuffle_block(path, 3).is_err());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,398 @@
+// Copyright 2021 HetuDB.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Object stores keyed by the scheme of the table urls.
+//!
+//! `file://` (and plain paths) are served by the local file system, `s3://` by an S3
+//! compatible service such as AWS S3 or MinIO and `hdfs://` by the WebHDFS REST API of
+//! a HDFS compatible cluster. The stores are configured by the `ballista.object_store.*`
+//! settings, the ones of the cluster are overridden by the ones of a session.
+
+use std::collections::HashMap;
+use std::io::{self, Cursor, Read};
+use std::sync::mpsc;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use datafusion::datafusion_data_access::object_store::local::LocalFileSystem;
+use datafusion::datafusion_data_access::object_store::{ObjectReader, ObjectStore};
+use datafusion::datafusion_data_access::Result as DataAccessResult;
+use datafusion::execution::runtime_env::RuntimeEnv;
+use futures::AsyncRead;
+use once_cell::sync::Lazy;
+use tokio::runtime::Runtime;
+
+use crate::config::BallistaConfig;
+use crate::error::{BallistaError, Result};
+
+pub mod s3;
+pub mod webhdfs;
+
+use s3::{S3Config, S3Store};
+use webhdfs::{WebHdfsConfig, WebHdfsStore};
+
+pub const OBJECT_STORE_S3_ENDPOINT: &str = "ballista.object_store.s3.endpoint";
+pub const OBJECT_STORE_S3_REGION: &str = "ballista.object_store.s3.region";
+pub const OBJECT_STORE_S3_ACCESS_KEY_ID: &str = "ballista.object_store.s3.access_key_id";
+pub const OBJECT_STORE_S3_SECRET_ACCESS_KEY: &str =
+    "ballista.object_store.s3.secret_access_key";
+pub const OBJECT_STORE_HDFS_NAMENODE: &str = "ballista.object_store.hdfs.namenode";
+pub const OBJECT_STORE_HDFS_USER: &str = "ballista.object_store.hdfs.user";
+
+/// Scheme of the S3 compatible object stores
+pub const S3_SCHEME: &str = "s3";
+/// Schemes of the HDFS compatible object stores
+pub const HDFS_SCHEMES: [&str; 2] = ["hdfs", "webhdfs"];
+
+/// The object store settings among a set of key-value settings
+pub fn object_store_settings(
+    settings: &HashMap<String, String>,
+) -> HashMap<String, String> {
+    settings
+        .iter()
+        .filter(|(key, value)| {
+            key.starts_with("ballista.object_store.") && !value.is_empty()
+        })
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect()
+}
+
+/// Override the object store settings of the cluster with the ones of a session
+pub fn merge_object_store_settings(
+    cluster: &HashMap<String, String>,
+    session: &BallistaConfig,
+) -> HashMap<String, String> {
+    let mut settings = cluster.clone();
+    settings.extend(object_store_settings(session.settings()));
+    settings
+}
+
+/// Whether a setting is a secret, the secrets are kept out of the tasks and the saved
+/// jobs and resolved by the executors from their own configuration
+pub fn is_secret_setting(key: &str) -> bool {
+    key == OBJECT_STORE_S3_SECRET_ACCESS_KEY
+}
+
+/// The settings without their secrets
+pub fn without_secrets(settings: HashMap<String, String>) -> HashMap<String, String> {
+    settings
+        .into_iter()
+        .filter(|(key, _)| !is_secret_setting(key))
+        .collect()
+}
+
+/// Override the object store settings of an executor with the ones a task is sent with.
+/// The task carries no secret, the secret access key of its access key id is the one of
+/// the executor, or the one among the credentials of the executor for another id
+pub fn resolve_task_object_store_settings(
+    executor: &HashMap<String, String>,
+    s3_credentials: &HashMap<String, String>,
+    task: &HashMap<String, String>,
+) -> HashMap<String, String> {
+    let task = without_secrets(object_store_settings(task));
+    let mut settings = executor.clone();
+    if let Some(access_key_id) = task.get(OBJECT_STORE_S3_ACCESS_KEY_ID) {
+        if executor.get(OBJECT_STORE_S3_ACCESS_KEY_ID) != Some(access_key_id) {
+            settings.remove(OBJECT_STORE_S3_SECRET_ACCESS_KEY);
+            if let Some(secret) = s3_credentials.get(access_key_id) {
+                settings
+                    .insert(OBJECT_STORE_S3_SECRET_ACCESS_KEY.to_owned(), secret.clone());
+            }
+        }
+    }
+    settings.extend(task);
+    settings
+}
+
+/// Parse the S3 credentials of an executor, one `access_key_id secret_access_key` pair
+/// per line, the empty lines and the ones starting with # are skipped
+pub fn parse_s3_credentials(content: &str) -> Result<HashMap<String, String>> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(
+            |line| match line.split_whitespace().collect::<Vec<_>>()[..] {
+                [access_key_id, secret_access_key] => {
+                    Ok((access_key_id.to_owned(), secret_access_key.to_owned()))
+                }
+                _ => Err(BallistaError::General(format!(
+                "Invalid S3 credentials line, expected an access key id and a secret \
+                 access key: {}",
+                line.split_whitespace().next().unwrap_or_default()
+            ))),
+            },
+        )
+        .collect()
+}
+
+/// Create the object store of a url scheme, None for a scheme which is not supported
+pub fn create_object_store(
+    scheme: &str,
+    settings: &HashMap<String, String>,
+) -> Result<Option<Arc<dyn ObjectStore>>> {
+    let setting = |key: &str| settings.get(key).filter(|v| !v.is_empty()).cloned();
+    let store: Arc<dyn ObjectStore> = match scheme {
+        "" | "file" => Arc::new(LocalFileSystem {}),
+        S3_SCHEME => Arc::new(S3Store::try_new(S3Config {
+            endpoint: setting(OBJECT_STORE_S3_ENDPOINT),
+            region: setting(OBJECT_STORE_S3_REGION)
+                .unwrap_or_else(|| "us-east-1".to_owned()),
+            access_key_id: setting(OBJECT_STORE_S3_ACCESS_KEY_ID),
+            secret_access_key: setting(OBJECT_STORE_S3_SECRET_ACCESS_KEY),
+        })?),
+        scheme if HDFS_SCHEMES.contains(&scheme) => {
+            match setting(OBJECT_STORE_HDFS_NAMENODE) {
+                Some(namenode) => Arc::new(WebHdfsStore::try_new(WebHdfsConfig {
+                    namenode,
+                    user: setting(OBJECT_STORE_HDFS_USER),
+                })?),
+                None => {
+                    return Err(BallistaError::General(format!(
+                        "{} must be set to read {}:// urls",
+                        OBJECT_STORE_HDFS_NAMENODE, scheme
+                    )))
+                }
+            }
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(store))
+}
+
+/// Register the object stores configured by the settings into a runtime, the local file
+/// system is always registered by the runtime itself
+pub fn register_object_stores(
+    runtime: &RuntimeEnv,
+    settings: &HashMap<String, String>,
+) -> Result<()> {
+    if let Some(store) = create_object_store(S3_SCHEME, settings)? {
+        runtime.register_object_store(S3_SCHEME, store);
+    }
+    if settings.contains_key(OBJECT_STORE_HDFS_NAMENODE) {
+        for scheme in HDFS_SCHEMES {
+            if let Some(store) = create_object_store(scheme, settings)? {
+                runtime.register_object_store(scheme, store);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The object store of a table location and the location without its scheme, which is
+/// what the stores list and read
+pub fn object_store_for_location(
+    location: &str,
+    settings: &HashMap<String, String>,
+) -> Result<(Arc<dyn ObjectStore>, String)> {
+    let (scheme, path) = match location.split_once("://") {
+        Some((scheme, path)) => (scheme.to_ascii_lowercase(), path),
+        None => ("file".to_owned(), location),
+    };
+    // The hdfs paths keep their leading '/' as it tells an empty authority
+    let path = if scheme == S3_SCHEME {
+        path.trim_start_matches('/').to_owned()
+    } else {
+        path.to_owned()
+    };
+    match create_object_store(&scheme, settings)? {
+        Some(store) => Ok((store, path)),
+        None => Err(BallistaError::General(format!(
+            "No object store for the scheme of {}",
+            location
+        ))),
+    }
+}
+
+/// Strip the scheme the paths given to the stores may start with
+pub(crate) fn strip_scheme<'a>(path: &'a str, schemes: &[&str]) -> &'a str {
+    match path.split_once("://") {
+        Some((scheme, rest)) if schemes.contains(&scheme) => rest,
+        _ => path,
+    }
+}
+
+/// Runtime the synchronous readers hand their requests to, it is shared by all the
+/// readers so that no thread or runtime is created per chunk
+static IO_RUNTIME: Lazy<Runtime> = Lazy::new(|| {
+    tokio::runtime::Builder::new_multi_thread()
+        .thread_name("object-store-io")
+        .worker_threads(2)
+        .enable_all()
+        .build()
+        .expect("Failed to create the object store runtime")
+});
+
+pub(crate) fn io_error(e: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
+
+/// Reads the byte ranges of a remote object
+#[async_trait]
+pub(crate) trait RangeRead: Send + Sync + 'static {
+    async fn read_range(
+        &self,
+        path: &str,
+        start: u64,
+        length: usize,
+    ) -> io::Result<Vec<u8>>;
+}
+
+/// Reader of one remote object, the ranges are fetched when the readers are created
+pub(crate) struct RemoteObjectReader<R: RangeRead> {
+    source: Arc<R>,
+    path: String,
+    size: u64,
+}
+
+impl<R: RangeRead> RemoteObjectReader<R> {
+    pub(crate) fn new(source: Arc<R>, path: String, size: u64) -> Self {
+        Self { source, path, size }
+    }
+
+    // Clamp a range to the object, the readers ask for the whole remaining file at once
+    fn clamp(&self, start: u64, length: usize) -> usize {
+        std::cmp::min(length as u64, self.size.saturating_sub(start)) as usize
+    }
+}
+
+#[async_trait]
+impl<R: RangeRead> ObjectReader for RemoteObjectReader<R> {
+    async fn chunk_reader(
+        &self,
+        start: u64,
+        length: usize,
+    ) -> DataAccessResult<Box<dyn AsyncRead>> {
+        let length = self.clamp(start, length);
+        let bytes = self.source.read_range(&self.path, start, length).await?;
+        Ok(Box::new(futures::io::Cursor::new(bytes)))
+    }
+
+    fn sync_chunk_reader(
+        &self,
+        start: u64,
+        length: usize,
+    ) -> DataAccessResult<Box<dyn Read + Send + Sync>> {
+        let length = self.clamp(start, length);
+        if length == 0 {
+            return Ok(Box::new(Cursor::new(vec![])));
+        }
+        // The file formats read synchronously from within the tokio runtime of the
+        // caller, so the request is driven by the shared object store runtime
+        let source = self.source.clone();
+        let path = self.path.clone();
+        let (tx, rx) = mpsc::channel();
+        IO_RUNTIME.spawn(async move {
+            let _ = tx.send(source.read_range(&path, start, length).await);
+        });
+        let bytes = rx.recv().map_err(io_error)??;
+        Ok(Box::new(Cursor::new(bytes)))
+    }
+
+    fn length(&self) -> u64 {
+        self.size
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_object_store_for_location() -> Result<()> {
+        let settings = HashMap::from([(
+            OBJECT_STORE_S3_ENDPOINT.to_owned(),
+            "http://localhost:9000".to_owned(),
+        )]);
+        let (store, path) = object_store_for_location("/tmp/data.csv", &settings)?;
+        assert!(format!("{:?}", store).contains("LocalFileSystem"));
+        assert_eq!(path, "/tmp/data.csv");
+
+        let (store, path) =
+            object_store_for_location("s3://bucket/dir/data.csv", &settings)?;
+        assert!(format!("{:?}", store).contains("S3Store"));
+        assert_eq!(path, "bucket/dir/data.csv");
+
+        // HDFS needs the address of its namenode
+        assert!(object_store_for_location("hdfs:///dir/data.csv", &settings).is_err());
+        assert!(object_store_for_location("ftp://host/data.csv", &settings).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_object_store_settings() -> Result<()> {
+        let cluster = HashMap::from([
+            (OBJECT_STORE_S3_REGION.to_owned(), "eu-west-1".to_owned()),
+            (
+                OBJECT_STORE_S3_ACCESS_KEY_ID.to_owned(),
+                "cluster".to_owned(),
+            ),
+        ]);
+        let session = BallistaConfig::builder()
+            .set(OBJECT_STORE_S3_ACCESS_KEY_ID, "session")
+            .build()?;
+        let settings = merge_object_store_settings(&cluster, &session);
+        assert_eq!(settings[OBJECT_STORE_S3_REGION], "eu-west-1");
+        assert_eq!(settings[OBJECT_STORE_S3_ACCESS_KEY_ID], "session");
+        // The settings a session leaves empty do not override the cluster ones
+        assert!(!settings.contains_key(OBJECT_STORE_S3_ENDPOINT));
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_task_object_store_settings() -> Result<()> {
+        let executor = HashMap::from([
+            (OBJECT_STORE_S3_REGION.to_owned(), "eu-west-1".to_owned()),
+            (
+                OBJECT_STORE_S3_ACCESS_KEY_ID.to_owned(),
+                "cluster".to_owned(),
+            ),
+            (
+                OBJECT_STORE_S3_SECRET_ACCESS_KEY.to_owned(),
+                "cluster-secret".to_owned(),
+            ),
+        ]);
+        let credentials = parse_s3_credentials("# sessions\nsession session-secret\n\n")?;
+        assert!(parse_s3_credentials("session").is_err());
+
+        // A secret sent with the task is ignored
+        let task = HashMap::from([(
+            OBJECT_STORE_S3_SECRET_ACCESS_KEY.to_owned(),
+            "sent".to_owned(),
+        )]);
+        let settings = resolve_task_object_store_settings(&executor, &credentials, &task);
+        assert_eq!(settings, executor);
+
+        let task = HashMap::from([(
+            OBJECT_STORE_S3_ACCESS_KEY_ID.to_owned(),
+            "session".to_owned(),
+        )]);
+        let settings = resolve_task_object_store_settings(&executor, &credentials, &task);
+        assert_eq!(settings[OBJECT_STORE_S3_ACCESS_KEY_ID], "session");
+        assert_eq!(
+            settings[OBJECT_STORE_S3_SECRET_ACCESS_KEY],
+            "session-secret"
+        );
+        assert_eq!(settings[OBJECT_STORE_S3_REGION], "eu-west-1");
+
+        // The secret of the cluster is not sent for another access key id
+        let task = HashMap::from([(
+            OBJECT_STORE_S3_ACCESS_KEY_ID.to_owned(),
+            "unknown".to_owned(),
+        )]);
+        let settings = resolve_task_object_store_settings(&executor, &credentials, &task);
+        assert!(!settings.contains_key(OBJECT_STORE_S3_SECRET_ACCESS_KEY));
+        Ok(())
+    }
+}
@@ -0,0 +1,379 @@
+// Copyright 2021 HetuDB.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Object store of the HDFS compatible file systems, read through the WebHDFS REST API
+//! so that no native HDFS client is needed.
+
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use chrono::{TimeZone, Utc};
+use datafusion::datafusion_data_access::object_store::{
+    FileMetaStream, ListEntryStream, ObjectReader, ObjectStore,
+};
+use datafusion::datafusion_data_access::{
+    FileMeta, ListEntry, Result as DataAccessResult, SizedFile,
+};
+use serde::Deserialize;
+
+use super::{io_error, strip_scheme, RangeRead, RemoteObjectReader, HDFS_SCHEMES};
+use crate::error::{BallistaError, Result};
+
+/// Connection settings of a WebHDFS service
+#[derive(Debug, Clone)]
+pub struct WebHdfsConfig {
+    /// Url of the WebHDFS service of the namenode, like `http://namenode:9870`
+    pub namenode: String,
+    /// User name of the requests, the service decides if none
+    pub user: Option<String>,
+}
+
+/// Object store reading `hdfs://[authority]/path` urls, the authority of the urls is
+/// kept in the listed paths but the configured namenode is always asked
+#[derive(Debug, Clone)]
+pub struct WebHdfsStore {
+    client: Arc<WebHdfsClient>,
+}
+
+impl WebHdfsStore {
+    pub fn try_new(config: WebHdfsConfig) -> Result<Self> {
+        let http = reqwest::Client::builder().build().map_err(|e| {
+            BallistaError::General(format!("Invalid WebHDFS client: {}", e))
+        })?;
+        Ok(Self {
+            client: Arc::new(WebHdfsClient { config, http }),
+        })
+    }
+
+    // The files under a path, recursively if all_levels, and the sub directories
+    async fn list(
+        &self,
+        prefix: &str,
+        all_levels: bool,
+    ) -> io::Result<(Vec<FileMeta>, Vec<String>)> {
+        let (authority, path) = split_path(prefix);
+        let mut files = vec![];
+        let mut dirs = vec![];
+        let mut pending = vec![path.trim_end_matches('/').to_owned()];
+        while let Some(dir) = pending.pop() {
+            for status in self.client.list_status(&dir).await? {
+                let path = if status.path_suffix.is_empty() {
+                    dir.clone()
+                } else {
+                    format!("{}/{}", dir, status.path_suffix)
+                };
+                if status.file_type == "DIRECTORY" {
+                    if all_levels {
+                        pending.push(path);
+                    } else {
+                        dirs.push(format!("{}{}/", authority, path));
+                    }
+                } else {
+                    files.push(FileMeta {
+                        sized_file: SizedFile {
+                            path: format!("{}{}", authority, path),
+                            size: status.length,
+                        },
+                        last_modified: Some(
+                            Utc.timestamp_millis(status.modification_time),
+                        ),
+                    });
+                }
+            }
+        }
+        files.sort_by(|a, b| a.sized_file.path.cmp(&b.sized_file.path));
+        Ok((files, dirs))
+    }
+}
+
+#[async_trait]
+impl ObjectStore for WebHdfsStore {
+    async fn list_file(&self, prefix: &str) -> DataAccessResult<FileMetaStream> {
+        let (files, _) = self.list(prefix, true).await?;
+        Ok(Box::pin(futures::stream::iter(files.into_iter().map(Ok))))
+    }
+
+    async fn list_dir(
+        &self,
+        prefix: &str,
+        delimiter: Option<String>,
+    ) -> DataAccessResult<ListEntryStream> {
+        let (files, dirs) = self.list(prefix, delimiter.is_none()).await?;
+        let entries = files
+            .into_iter()
+            .map(ListEntry::FileMeta)
+            .chain(dirs.into_iter().map(ListEntry::Prefix));
+        Ok(Box::pin(futures::stream::iter(entries.map(Ok))))
+    }
+
+    fn file_reader(&self, file: SizedFile) -> DataAccessResult<Arc<dyn ObjectReader>> {
+        let (_, path) = split_path(&file.path);
+        Ok(Arc::new(RemoteObjectReader::new(
+            self.client.clone(),
+            path.to_owned(),
+            file.size,
+        )))
+    }
+}
+
+// The authority and the absolute path of a `[authority]/path` path
+fn split_path(path: &str) -> (&str, &str) {
+    let path = strip_scheme(path, &HDFS_SCHEMES);
+    match path.find('/') {
+        Some(pos) => path.split_at(pos),
+        None => (path, "/"),
+    }
+}
+
+struct WebHdfsClient {
+    config: WebHdfsConfig,
+    http: reqwest::Client,
+}
+
+impl fmt::Debug for WebHdfsClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.config.fmt(f)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ListStatusResponse {
+    file_statuses: FileStatuses,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct FileStatuses {
+    file_status: Vec<FileStatus>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FileStatus {
+    path_suffix: String,
+    #[serde(rename = "type")]
+    file_type: String,
+    length: u64,
+    modification_time: i64,
+}
+
+impl WebHdfsClient {
+    fn request(&self, path: &str, op: &str) -> reqwest::RequestBuilder {
+        let url = format!(
+            "{}/webhdfs/v1{}",
+            self.config.namenode.trim_end_matches('/'),
+            path
+        );
+        let request = self.http.get(url).query(&[("op", op)]);
+        match self.config.user.as_ref() {
+            Some(user) => request.query(&[("user.name", user)]),
+            None => request,
+        }
+    }
+
+    async fn list_status(&self, path: &str) -> io::Result<Vec<FileStatus>> {
+        let path = if path.is_empty() { "/" } else { path };
+        let response: ListStatusResponse = self
+            .request(path, "LISTSTATUS")
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(io_error)?
+            .json()
+            .await
+            .map_err(io_error)?;
+        Ok(response.file_statuses.file_status)
+    }
+}
+
+#[async_trait]
+impl RangeRead for WebHdfsClient {
+    async fn read_range(
+        &self,
+        path: &str,
+        start: u64,
+        length: usize,
+    ) -> io::Result<Vec<u8>> {
+        if length == 0 {
+            return Ok(vec![]);
+        }
+        // The namenode redirects to a datanode holding the data
+        let bytes = self
+            .request(path, "OPEN")
+            .query(&[("offset", start), ("length", length as u64)])
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(io_error)?
+            .bytes()
+            .await
+            .map_err(io_error)?;
+        Ok(bytes.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::StreamExt;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server, StatusCode};
+    use std::convert::Infallible;
+    use std::io::Read;
+    use std::net::SocketAddr;
+    use std::path::{Path, PathBuf};
+
+    // A WebHDFS stand-in serving the files of a root dir
+    async fn serve_webhdfs(root: PathBuf) -> SocketAddr {
+        let make_service = make_service_fn(move |_| {
+            let root = root.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let root = root.clone();
+                    async move { Ok::<_, Infallible>(handle(&root, request)) }
+                }))
+            }
+        });
+        let server =
+            Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    fn handle(root: &Path, request: Request<Body>) -> Response<Body> {
+        let path = request
+            .uri()
+            .path()
+            .strip_prefix("/webhdfs/v1/")
+            .unwrap_or("");
+        let path = root.join(path);
+        let param = |name: &str| {
+            request
+                .uri()
+                .query()
+                .unwrap_or("")
+                .split('&')
+                .filter_map(|kv| kv.split_once('='))
+                .find(|(k, _)| *k == name)
+                .map(|(_, v)| v.to_owned())
+        };
+        if !path.exists() || param("user.name").as_deref() != Some("hetu") {
+            return Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::empty())
+                .unwrap();
+        }
+        match param("op").as_deref() {
+            Some("LISTSTATUS") => {
+                let status = |suffix: &str, path: &Path| {
+                    format!(
+                        r#"{{"pathSuffix":"{}","type":"{}","length":{},"modificationTime":1654084800000}}"#,
+                        suffix,
+                        if path.is_dir() { "DIRECTORY" } else { "FILE" },
+                        if path.is_dir() {
+                            0
+                        } else {
+                            path.metadata().unwrap().len()
+                        }
+                    )
+                };
+                let statuses = if path.is_dir() {
+                    std::fs::read_dir(&path)
+                        .unwrap()
+                        .map(|entry| {
+                            let entry = entry.unwrap();
+                            status(entry.file_name().to_str().unwrap(), &entry.path())
+                        })
+                        .collect::<Vec<_>>()
+                } else {
+                    vec![status("", &path)]
+                };
+                Response::new(Body::from(format!(
+                    r#"{{"FileStatuses":{{"FileStatus":[{}]}}}}"#,
+                    statuses.join(",")
+                )))
+            }
+            Some("OPEN") => {
+                let data = std::fs::read(&path).unwrap();
+                let offset = param("offset").unwrap().parse::<usize>().unwrap();
+                let length = param("length").unwrap().parse::<usize>().unwrap();
+                let end = std::cmp::min(offset + length, data.len());
+                Response::new(Body::from(data[offset..end].to_vec()))
+            }
+            _ => Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::empty())
+                .unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_webhdfs_store() -> Result<()> {
+        let root = tempfile::TempDir::new()?;
+        std::fs::create_dir_all(root.path().join("data/year=2022"))?;
+        std::fs::write(root.path().join("data/a.csv"), "a,b\n1,2\n")?;
+        std::fs::write(root.path().join("data/year=2022/b.csv"), "a,b\n3,4\n")?;
+        let addr = serve_webhdfs(root.path().to_path_buf()).await;
+        let store = WebHdfsStore::try_new(WebHdfsConfig {
+            namenode: format!("http://{}", addr),
+            user: Some("hetu".to_owned()),
+        })?;
+
+        let files = store
+            .list_file("hdfs://namenode:8020/data")
+            .await?
+            .map(|file| file.unwrap().sized_file)
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(
+            files,
+            vec![
+                SizedFile {
+                    path: "namenode:8020/data/a.csv".to_owned(),
+                    size: 8
+                },
+                SizedFile {
+                    path: "namenode:8020/data/year=2022/b.csv".to_owned(),
+                    size: 8
+                },
+            ]
+        );
+
+        let mut entries = store
+            .list_dir("/data/", Some("/".to_owned()))
+            .await?
+            .map(|entry| match entry.unwrap() {
+                ListEntry::FileMeta(meta) => meta.sized_file.path,
+                ListEntry::Prefix(prefix) => prefix,
+            })
+            .collect::<Vec<_>>()
+            .await;
+        entries.sort();
+        assert_eq!(entries, vec!["/data/a.csv", "/data/year=2022/"]);
+
+        let reader = store.file_reader(files[1].clone())?;
+        let mut content = String::new();
+        reader
+            .sync_chunk_reader(4, 100)?
+            .read_to_string(&mut content)?;
+        assert_eq!(content, "3,4\n");
+
+        assert!(store.list_file("/missing").await.is_err());
+        Ok(())
+    }
+}
@@ -42,6 +42,15 @@ pub enum Action {
         partition_id: usize,
         path: String,
     },
+    /// Push a shuffle partition to a shuffle service
+    PushPartition {
+        job_id: String,
+        stage_id: usize,
+        partition_id: usize,
+        input_partition_id: usize,
+        /// Auth token shared by the executors, checked by the shuffle service
+        auth_token: String,
+    },
 }
 
 /// Unique identifier for the output partition of an operator.
@@ -218,15 +227,63 @@ impl From<protobuf::ExecutorData> for ExecutorData {
 pub struct ExecutorState {
     // in bytes
     pub available_memory_size: u64,
+    pub used_memory_size: u64,
+    pub total_memory_size: u64,
+    /// Load average of the last minute divided by the number of cores
+    pub cpu_load: f64,
+    // in bytes, of the disk holding the work dir
+    pub available_disk_size: u64,
+    pub total_disk_size: u64,
+}
+
+impl Default for ExecutorState {
+    /// The state of an executor which reports nothing, it is never considered short of
+    /// resources
+    fn default() -> Self {
+        Self {
+            available_memory_size: u64::MAX,
+            used_memory_size: 0,
+            total_memory_size: 0,
+            cpu_load: 0.0,
+            available_disk_size: u64::MAX,
+            total_disk_size: 0,
+        }
+    }
+}
+
+impl ExecutorState {
+    /// Fraction of the memory in use, 0 when the total memory is unknown
+    pub fn memory_usage(&self) -> f64 {
+        usage(self.available_memory_size, self.total_memory_size)
+    }
+
+    /// Fraction of the disk space in use, 0 when the disk size is unknown
+    pub fn disk_usage(&self) -> f64 {
+        usage(self.available_disk_size, self.total_disk_size)
+    }
+}
+
+fn usage(available: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        1.0 - available.min(total) as f64 / total as f64
+    }
 }
 
 #[allow(clippy::from_over_into)]
 impl Into<protobuf::ExecutorState> for ExecutorState {
     fn into(self) -> protobuf::ExecutorState {
+        use protobuf::executor_metric::Metric;
         protobuf::ExecutorState {
-            metrics: vec![protobuf::executor_metric::Metric::AvailableMemory(
-                self.available_memory_size,
-            )]
+            metrics: vec![
+                Metric::AvailableMemory(self.available_memory_size),
+                Metric::UsedMemory(self.used_memory_size),
+                Metric::TotalMemory(self.total_memory_size),
+                Metric::CpuLoad(self.cpu_load),
+                Metric::AvailableDisk(self.available_disk_size),
+                Metric::TotalDisk(self.total_disk_size),
+            ]
             .into_iter()
             .map(|m| protobuf::ExecutorMetric { metric: Some(m) })
             .collect(),
@@ -236,15 +293,17 @@ impl Into<protobuf::ExecutorState> for ExecutorState {
 
 impl From<protobuf::ExecutorState> for ExecutorState {
     fn from(input: protobuf::ExecutorState) -> Self {
-        let mut ret = Self {
-            available_memory_size: u64::MAX,
-        };
+        use protobuf::executor_metric::Metric;
+        let mut ret = Self::default();
         for metric in input.metrics {
-            if let Some(protobuf::executor_metric::Metric::AvailableMemory(
-                available_memory_size,
-            )) = metric.metric
-            {
-                ret.available_memory_size = available_memory_size
+            match metric.metric {
+                Some(Metric::AvailableMemory(size)) => ret.available_memory_size = size,
+                Some(Metric::UsedMemory(size)) => ret.used_memory_size = size,
+                Some(Metric::TotalMemory(size)) => ret.total_memory_size = size,
+                Some(Metric::CpuLoad(load)) => ret.cpu_load = load,
+                Some(Metric::AvailableDisk(size)) => ret.available_disk_size = size,
+                Some(Metric::TotalDisk(size)) => ret.total_disk_size = size,
+                None => {}
             }
         }
         ret
@@ -42,6 +42,22 @@ impl TryInto<protobuf::Action> for Action {
                 })),
                 settings: vec![],
             }),
+            Action::PushPartition {
+                job_id,
+                stage_id,
+                partition_id,
+                input_partition_id,
+                auth_token,
+            } => Ok(protobuf::Action {
+                action_type: Some(ActionType::PushPartition(protobuf::PushPartition {
+                    job_id,
+                    stage_id: stage_id as u32,
+                    partition_id: partition_id as u32,
+                    input_partition_id: input_partition_id as u32,
+                    auth_token,
+                })),
+                settings: vec![],
+            }),
         }
     }
 }
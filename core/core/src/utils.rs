@@ -20,6 +20,8 @@ use crate::error::{BallistaError, Result};
 use crate::execution_plans::{
     DistributedQueryExec, ShuffleWriterExec, UnresolvedShuffleExec,
 };
+use crate::object_store::{object_store_settings, register_object_stores};
+use crate::serde::protobuf;
 use crate::serde::scheduler::PartitionStats;
 use async_trait::async_trait;
 use datafusion::arrow::datatypes::Schema;
@@ -40,11 +42,15 @@ use datafusion::physical_plan::filter::FilterExec;
 use datafusion::physical_plan::hash_join::HashJoinExec;
 use datafusion::physical_plan::projection::ProjectionExec;
 use datafusion::physical_plan::sorts::sort::SortExec;
-use datafusion::physical_plan::{metrics, ExecutionPlan, RecordBatchStream};
+use datafusion::physical_plan::{
+    metrics, DisplayFormatType, ExecutionPlan, RecordBatchStream,
+};
 use datafusion_proto::logical_plan::{
     AsLogicalPlan, DefaultLogicalExtensionCodec, LogicalExtensionCodec,
 };
 use futures::StreamExt;
+use log::warn;
+use std::fmt;
 use std::io::{BufWriter, Write};
 use std::marker::PhantomData;
 use std::sync::atomic::{AtomicUsize, Ordering};
@@ -219,6 +225,43 @@ fn build_exec_plan_diagram(
     Ok(node_id)
 }
 
+/// Collect the metrics of all the operators of a plan in pre-order, the metrics of the
+/// partitions are summed up and the timestamps are left out
+pub fn collect_plan_metrics(
+    plan: &dyn ExecutionPlan,
+) -> Vec<protobuf::OperatorMetricsSet> {
+    struct OperatorDisplay<'a>(&'a dyn ExecutionPlan);
+
+    impl fmt::Display for OperatorDisplay<'_> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            self.0.fmt_as(DisplayFormatType::Default, f)
+        }
+    }
+
+    let mut operators = vec![protobuf::OperatorMetricsSet {
+        operator: OperatorDisplay(plan).to_string(),
+        metrics: plan
+            .metrics()
+            .map(|metrics| {
+                metrics
+                    .aggregate_by_partition()
+                    .sorted_for_display()
+                    .timestamps_removed()
+                    .iter()
+                    .map(|metric| protobuf::OperatorMetric {
+                        name: metric.value().name().to_owned(),
+                        value: metric.value().as_usize() as u64,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default(),
+    }];
+    for child in plan.children() {
+        operators.extend(collect_plan_metrics(child.as_ref()));
+    }
+    operators
+}
+
 /// Create a client DataFusion context that uses the BallistaQueryPlanner to send logical plans
 /// to a Ballista scheduler
 pub fn create_df_ctx_with_ballista_query_planner<T: 'static + AsLogicalPlan>(
@@ -232,11 +275,16 @@ pub fn create_df_ctx_with_ballista_query_planner<T: 'static + AsLogicalPlan>(
     let session_config = SessionConfig::new()
         .with_target_partitions(config.default_shuffle_partitions())
         .with_information_schema(true);
-    let mut session_state = SessionState::with_config_rt(
-        session_config,
-        Arc::new(RuntimeEnv::new(RuntimeConfig::default()).unwrap()),
-    )
-    .with_query_planner(planner);
+    let runtime = Arc::new(RuntimeEnv::new(RuntimeConfig::default()).unwrap());
+    // the client side context lists the files of the tables it registers, so it needs
+    // the object stores of the session as well
+    if let Err(e) =
+        register_object_stores(&runtime, &object_store_settings(config.settings()))
+    {
+        warn!("Failed to register the object stores of the session: {}", e);
+    }
+    let mut session_state =
+        SessionState::with_config_rt(session_config, runtime).with_query_planner(planner);
     session_state.session_id = session_id;
     // the SessionContext created here is the client side context, but the session_id is from server side.
     SessionContext::with_state(session_state)
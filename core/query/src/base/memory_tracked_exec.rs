@@ -0,0 +1,254 @@
+// Copyright 2021 HetuDB.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::any::Any;
+use std::fmt;
+use std::sync::Arc;
+
+use datafusion::arrow::datatypes::SchemaRef;
+use datafusion::arrow::error::ArrowError;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::execution::context::TaskContext;
+use datafusion::physical_plan::expressions::PhysicalSortExpr;
+use datafusion::physical_plan::stream::RecordBatchStreamAdapter;
+use datafusion::physical_plan::{
+    with_new_children_if_necessary, DisplayFormatType, ExecutionPlan, Partitioning,
+    SendableRecordBatchStream, Statistics,
+};
+use futures::StreamExt;
+use hetu_error::HetuError;
+
+use crate::base::runtime_tracker::MemoryTracker;
+
+/// MemoryTrackedExec attributes the memory allocated while its input is polled to
+/// the memory tracker of a query. The operators poll their inputs from the tasks
+/// they spawn, the input of each operator is tracked so that the memory of these
+/// tasks is attributed to the query too.
+pub struct MemoryTrackedExec {
+    input: Arc<dyn ExecutionPlan>,
+    query_id: String,
+    tracker: Arc<MemoryTracker>,
+}
+
+impl MemoryTrackedExec {
+    pub fn new(
+        input: Arc<dyn ExecutionPlan>,
+        query_id: &str,
+        tracker: &Arc<MemoryTracker>,
+    ) -> Self {
+        Self {
+            input,
+            query_id: query_id.to_owned(),
+            tracker: tracker.clone(),
+        }
+    }
+}
+
+/// Attribute the memory allocated by every operator of a plan to a query
+pub fn with_memory_tracker(
+    plan: Arc<dyn ExecutionPlan>,
+    query_id: &str,
+    tracker: &Arc<MemoryTracker>,
+) -> Result<Arc<dyn ExecutionPlan>> {
+    let children = plan
+        .children()
+        .into_iter()
+        .map(|child| with_memory_tracker(child, query_id, tracker))
+        .collect::<Result<Vec<_>>>()?;
+    let plan = with_new_children_if_necessary(plan, children)?;
+    Ok(Arc::new(MemoryTrackedExec::new(plan, query_id, tracker)))
+}
+
+impl fmt::Debug for MemoryTrackedExec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MemoryTrackedExec")
+            .field("input", &self.input)
+            .field("query_id", &self.query_id)
+            .finish()
+    }
+}
+
+impl ExecutionPlan for MemoryTrackedExec {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.input.schema()
+    }
+
+    fn output_partitioning(&self) -> Partitioning {
+        self.input.output_partitioning()
+    }
+
+    fn output_ordering(&self) -> Option<&[PhysicalSortExpr]> {
+        self.input.output_ordering()
+    }
+
+    fn relies_on_input_order(&self) -> bool {
+        false
+    }
+
+    fn maintains_input_order(&self) -> bool {
+        true
+    }
+
+    fn children(&self) -> Vec<Arc<dyn ExecutionPlan>> {
+        vec![self.input.clone()]
+    }
+
+    fn with_new_children(
+        self: Arc<Self>,
+        children: Vec<Arc<dyn ExecutionPlan>>,
+    ) -> Result<Arc<dyn ExecutionPlan>> {
+        match children.len() {
+            1 => Ok(Arc::new(MemoryTrackedExec::new(
+                children[0].clone(),
+                &self.query_id,
+                &self.tracker,
+            ))),
+            _ => Err(DataFusionError::Internal(
+                "MemoryTrackedExec wrong number of children".to_string(),
+            )),
+        }
+    }
+
+    fn execute(
+        &self,
+        partition: usize,
+        context: Arc<TaskContext>,
+    ) -> Result<SendableRecordBatchStream> {
+        let input = self.input.execute(partition, context)?;
+        let query_id = self.query_id.clone();
+        let stream = self
+            .tracker
+            .track_stream(input)
+            .map(move |batch| match batch {
+                Ok(batch) => batch,
+                Err(HetuError::ResourcesExhausted(desc)) => {
+                    Err(ArrowError::ExternalError(Box::new(
+                        DataFusionError::ResourcesExhausted(format!(
+                            "query {} {}",
+                            query_id, desc
+                        )),
+                    )))
+                }
+                Err(e) => Err(ArrowError::ExternalError(Box::new(e))),
+            });
+        Ok(Box::pin(RecordBatchStreamAdapter::new(
+            self.schema(),
+            stream,
+        )))
+    }
+
+    fn fmt_as(&self, t: DisplayFormatType, f: &mut fmt::Formatter) -> fmt::Result {
+        match t {
+            DisplayFormatType::Default => {
+                write!(f, "MemoryTrackedExec: query={}", self.query_id)
+            }
+        }
+    }
+
+    fn statistics(&self) -> Statistics {
+        self.input.statistics()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::base::ProcessList;
+    use datafusion::arrow::array::UInt64Array;
+    use datafusion::arrow::datatypes::{DataType, Field, Schema};
+    use datafusion::arrow::record_batch::RecordBatch;
+    use datafusion::logical_plan::Operator;
+    use datafusion::physical_plan::coalesce_partitions::CoalescePartitionsExec;
+    use datafusion::physical_plan::collect;
+    use datafusion::physical_plan::expressions::{binary, col, lit};
+    use datafusion::physical_plan::memory::MemoryExec;
+    use datafusion::physical_plan::projection::ProjectionExec;
+    use datafusion::physical_plan::repartition::RepartitionExec;
+    use datafusion::prelude::SessionContext;
+    use datafusion::scalar::ScalarValue;
+
+    /// A plan whose projections run in the tasks spawned by its repartition, each
+    /// one allocating 8MB of output
+    fn plan() -> Result<Arc<dyn ExecutionPlan>> {
+        let schema =
+            Arc::new(Schema::new(vec![Field::new("a", DataType::UInt64, false)]));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![Arc::new(UInt64Array::from(
+                (0..64 * 1024).collect::<Vec<u64>>(),
+            ))],
+        )?;
+        let input = Arc::new(MemoryExec::try_new(
+            &[vec![batch.clone(); 8], vec![batch; 8]],
+            schema.clone(),
+            None,
+        )?);
+        let expr = binary(
+            col("a", &schema)?,
+            Operator::Plus,
+            lit(ScalarValue::UInt64(Some(1))),
+            &schema,
+        )?;
+        let projection = Arc::new(ProjectionExec::try_new(
+            vec![(expr, "b".to_owned())],
+            input,
+        )?);
+        let repartition = Arc::new(RepartitionExec::try_new(
+            projection,
+            Partitioning::RoundRobinBatch(4),
+        )?);
+        Ok(Arc::new(CoalescePartitionsExec::new(repartition)))
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_track_spawned_tasks() -> Result<()> {
+        let process_list = Arc::new(ProcessList::new());
+        let task = process_list.enter("q1", "job q1", 0);
+        let plan = with_memory_tracker(plan()?, "q1", task.tracker())?;
+        let task_ctx = SessionContext::new().task_ctx();
+        let batches = task
+            .run(collect(plan, task_ctx))
+            .await
+            .map_err(|e| DataFusionError::External(Box::new(e)))??;
+        assert_eq!(batches.iter().map(|b| b.num_rows()).sum::<usize>(), 1 << 20);
+
+        // most of the 8MB output of the projections is attributed to the query
+        let memory_usage = process_list.processes()[0].memory_usage;
+        assert!(memory_usage >= 4 * 1024 * 1024, "{}", memory_usage);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_memory_limit() -> Result<()> {
+        let process_list = Arc::new(ProcessList::new());
+        let task = process_list.enter("q2", "job q2", 4 * 1024 * 1024);
+        let plan = with_memory_tracker(plan()?, "q2", task.tracker())?;
+        let task_ctx = SessionContext::new().task_ctx();
+        let error = match task.run(collect(plan, task_ctx)).await {
+            Ok(result) => result.unwrap_err().to_string(),
+            Err(e) => e.to_string(),
+        };
+        assert!(
+            error.contains("query q2 memory usage")
+                && error.contains("exceeds the limit"),
+            "{}",
+            error
+        );
+        Ok(())
+    }
+}
@@ -12,6 +12,9 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+mod allocator;
+mod memory_tracked_exec;
+mod process_list;
 mod runtime;
 mod runtime_tracker;
 mod shutdown_signal;
@@ -19,11 +22,20 @@ mod stop_handle;
 mod stoppable;
 mod thread;
 
+pub use allocator::TrackingAllocator;
+pub use memory_tracked_exec::with_memory_tracker;
+pub use memory_tracked_exec::MemoryTrackedExec;
+pub use process_list::ProcessGuard;
+pub use process_list::ProcessInfo;
+pub use process_list::ProcessList;
 pub use runtime::Dropper;
 pub use runtime::Runtime;
 pub use runtime::TrySpawn;
+pub use runtime_tracker::MemoryTracker;
 pub use runtime_tracker::RuntimeTracker;
 pub use runtime_tracker::ThreadTracker;
+pub use runtime_tracker::TrackedFuture;
+pub use runtime_tracker::TrackedStream;
 pub use shutdown_signal::signal_stream;
 pub use shutdown_signal::DummySignalStream;
 pub use shutdown_signal::SignalStream;
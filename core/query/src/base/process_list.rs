@@ -0,0 +1,205 @@
+// Copyright 2021 HetuDB.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use datafusion::physical_plan::ExecutionPlan;
+use hetu_error::{HetuError, Result};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+
+use crate::base::memory_tracked_exec::with_memory_tracker;
+use crate::base::runtime_tracker::MemoryTracker;
+
+static PROCESS_LIST: Lazy<Arc<ProcessList>> = Lazy::new(|| Arc::new(ProcessList::new()));
+
+struct Process {
+    info: String,
+    started: Instant,
+    tasks: usize,
+    tracker: Arc<MemoryTracker>,
+}
+
+/// A query running in the process
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProcessInfo {
+    pub query_id: String,
+    /// The SQL or the job of the query
+    pub info: String,
+    pub elapsed: Duration,
+    /// Number of the tasks of the query running in the process
+    pub tasks: usize,
+    pub memory_usage: i64,
+    /// Max memory usage of the query in the process, 0 means unlimited
+    pub memory_limit: i64,
+}
+
+/// The queries running in the process and the memory attributed to them
+#[derive(Default)]
+pub struct ProcessList {
+    processes: Mutex<HashMap<String, Process>>,
+}
+
+impl ProcessList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The process list of the process
+    pub fn global() -> Arc<ProcessList> {
+        PROCESS_LIST.clone()
+    }
+
+    /// Register a task of a query until the guard is dropped. The tasks of a query
+    /// share its memory tracker, which fails them once they use more than
+    /// memory_limit bytes together, 0 means unlimited.
+    pub fn enter(
+        self: &Arc<Self>,
+        query_id: &str,
+        info: &str,
+        memory_limit: i64,
+    ) -> ProcessGuard {
+        let mut processes = self.processes.lock();
+        let process = processes
+            .entry(query_id.to_owned())
+            .or_insert_with(|| Process {
+                info: info.to_owned(),
+                started: Instant::now(),
+                tasks: 0,
+                tracker: MemoryTracker::create_with_limit(None, memory_limit),
+            });
+        process.tasks += 1;
+        ProcessGuard {
+            process_list: self.clone(),
+            query_id: query_id.to_owned(),
+            tracker: MemoryTracker::create(Some(process.tracker.clone())),
+        }
+    }
+
+    /// The running queries, the oldest first
+    pub fn processes(&self) -> Vec<ProcessInfo> {
+        let mut processes = self
+            .processes
+            .lock()
+            .iter()
+            .map(|(query_id, process)| ProcessInfo {
+                query_id: query_id.clone(),
+                info: process.info.clone(),
+                elapsed: process.started.elapsed(),
+                tasks: process.tasks,
+                memory_usage: process.tracker.get_memory_usage(),
+                memory_limit: process.tracker.get_memory_limit(),
+            })
+            .collect::<Vec<_>>();
+        processes.sort_by(|a, b| b.elapsed.cmp(&a.elapsed));
+        processes
+    }
+
+    fn exit(&self, query_id: &str) {
+        let mut processes = self.processes.lock();
+        if let Some(process) = processes.get_mut(query_id) {
+            process.tasks -= 1;
+            if process.tasks == 0 {
+                processes.remove(query_id);
+            }
+        }
+    }
+}
+
+/// A task registered in a [ProcessList], the memory allocated while it runs its
+/// futures is attributed to it and to its query
+pub struct ProcessGuard {
+    process_list: Arc<ProcessList>,
+    query_id: String,
+    tracker: Arc<MemoryTracker>,
+}
+
+impl ProcessGuard {
+    /// The memory tracker of the task, child of the tracker of the query
+    pub fn tracker(&self) -> &Arc<MemoryTracker> {
+        &self.tracker
+    }
+
+    /// Attribute the memory allocated by the operators of a plan of the task to it,
+    /// including the memory of the tasks they spawn
+    pub fn track_plan(
+        &self,
+        plan: Arc<dyn ExecutionPlan>,
+    ) -> datafusion::error::Result<Arc<dyn ExecutionPlan>> {
+        with_memory_tracker(plan, &self.query_id, &self.tracker)
+    }
+
+    /// Run a future of the task, it fails once the query exceeds its memory limit
+    pub async fn run<F: Future>(&self, future: F) -> Result<F::Output> {
+        self.tracker.track(future).await.map_err(|e| match e {
+            HetuError::ResourcesExhausted(desc) => {
+                HetuError::ResourcesExhausted(format!("query {} {}", self.query_id, desc))
+            }
+            e => e,
+        })
+    }
+}
+
+impl Drop for ProcessGuard {
+    fn drop(&mut self) {
+        self.process_list.exit(&self.query_id);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_process_list() {
+        let process_list = Arc::new(ProcessList::new());
+        let task1 = process_list.enter("q1", "select 1", 1024);
+        let task2 = process_list.enter("q1", "select 1", 1024);
+        task1.tracker().alloc_memory(300);
+        task2.tracker().alloc_memory(200);
+
+        let processes = process_list.processes();
+        assert_eq!(processes.len(), 1);
+        assert_eq!(processes[0].query_id, "q1");
+        assert_eq!(processes[0].tasks, 2);
+        assert_eq!(processes[0].memory_usage, 500);
+        assert_eq!(processes[0].memory_limit, 1024);
+        assert_eq!(task1.tracker().get_memory_usage(), 300);
+
+        drop(task1);
+        assert_eq!(process_list.processes()[0].tasks, 1);
+        drop(task2);
+        assert!(process_list.processes().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_memory_limit() {
+        let process_list = Arc::new(ProcessList::new());
+        let task = process_list.enter("q1", "select 1", 1024);
+        let result = task.run(async { 1 }).await;
+        assert!(matches!(result, Ok(1)));
+
+        // the query fails instead of using more than its limit
+        task.tracker().alloc_memory(2048);
+        let error = task.run(async { 1 }).await.unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Resources exhausted: query q1 memory usage 2048 bytes exceeds the limit \
+             of 1024 bytes"
+        );
+    }
+}
@@ -13,13 +13,24 @@
 // limitations under the License.
 // #![feature(thread_local)]
 
-use std::alloc::Layout;
+use std::cell::Cell;
+use std::future::Future;
+use std::pin::Pin;
 use std::sync::atomic::AtomicI64;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::task::{Context, Poll};
 
-// #[thread_local]
-static mut TRACKER: *mut ThreadTracker = std::ptr::null_mut();
+use futures::Stream;
+use hetu_error::{HetuError, Result};
+
+thread_local! {
+    static TRACKER: Cell<*mut ThreadTracker> = const { Cell::new(std::ptr::null_mut()) };
+
+    // The memory tracker of the query polled by the thread, see MemoryTracker::track
+    static QUERY_TRACKER: Cell<*const MemoryTracker> = const { Cell::new(std::ptr::null()) };
+    static QUERY_UNTRACKED_MEMORY: Cell<i64> = const { Cell::new(0) };
+}
 
 static UNTRACKED_MEMORY_LIMIT: i64 = 4 * 1024 * 1024;
 
@@ -30,63 +41,69 @@ pub struct ThreadTracker {
 
 impl ThreadTracker {
     pub fn create(rt_tracker: Arc<RuntimeTracker>) -> *mut ThreadTracker {
-        unsafe {
-            TRACKER = Box::into_raw(Box::new(ThreadTracker {
-                rt_tracker,
-                untracked_memory: 0,
-            }));
-
-            TRACKER
-        }
+        let tracker = Box::into_raw(Box::new(ThreadTracker {
+            rt_tracker,
+            untracked_memory: 0,
+        }));
+        TRACKER.with(|current| current.set(tracker));
+        tracker
     }
 
     #[inline]
     pub fn current() -> *mut ThreadTracker {
-        unsafe { TRACKER }
+        // the thread locals are gone while the thread exits
+        TRACKER
+            .try_with(|tracker| tracker.get())
+            .unwrap_or(std::ptr::null_mut())
     }
 
     #[inline]
     pub fn current_runtime_tracker() -> Option<Arc<RuntimeTracker>> {
+        let tracker = Self::current();
         unsafe {
-            match TRACKER.is_null() {
+            match tracker.is_null() {
                 true => None,
-                false => Some((*TRACKER).rt_tracker.clone()),
+                false => Some((*tracker).rt_tracker.clone()),
             }
         }
     }
 
     #[inline]
     pub fn alloc_memory(size: i64) {
+        let tracker = Self::current();
         unsafe {
-            if !TRACKER.is_null() {
-                (*TRACKER).untracked_memory += size;
+            if !tracker.is_null() {
+                (*tracker).untracked_memory += size;
 
-                if (*TRACKER).untracked_memory > UNTRACKED_MEMORY_LIMIT {
-                    (*TRACKER)
+                if (*tracker).untracked_memory > UNTRACKED_MEMORY_LIMIT {
+                    (*tracker)
                         .rt_tracker
                         .memory_tracker
-                        .alloc_memory((*TRACKER).untracked_memory);
-                    (*TRACKER).untracked_memory = 0;
+                        .alloc_memory((*tracker).untracked_memory);
+                    (*tracker).untracked_memory = 0;
                 }
             }
         }
+        Self::track_query_memory(size);
     }
 
     #[inline]
     pub fn dealloc_memory(size: i64) {
+        let tracker = Self::current();
         unsafe {
-            if !TRACKER.is_null() {
-                (*TRACKER).untracked_memory -= size;
+            if !tracker.is_null() {
+                (*tracker).untracked_memory -= size;
 
-                if (*TRACKER).untracked_memory < -UNTRACKED_MEMORY_LIMIT {
-                    (*TRACKER)
+                if (*tracker).untracked_memory < -UNTRACKED_MEMORY_LIMIT {
+                    (*tracker)
                         .rt_tracker
                         .memory_tracker
-                        .dealloc_memory(-(*TRACKER).untracked_memory);
-                    (*TRACKER).untracked_memory = 0;
+                        .dealloc_memory(-(*tracker).untracked_memory);
+                    (*tracker).untracked_memory = 0;
                 }
             }
         }
+        Self::track_query_memory(-size);
     }
 
     #[inline]
@@ -97,19 +114,69 @@ impl ThreadTracker {
             false => Self::dealloc_memory(-addition),
         }
     }
+
+    #[inline]
+    fn track_query_memory(size: i64) {
+        let _ = QUERY_TRACKER.try_with(|query_tracker| {
+            let query_tracker = query_tracker.get();
+            if query_tracker.is_null() {
+                return;
+            }
+            QUERY_UNTRACKED_MEMORY.with(|untracked| {
+                let untracked_memory = untracked.get() + size;
+                if untracked_memory.abs() > UNTRACKED_MEMORY_LIMIT {
+                    // the tracker outlives the poll which set it
+                    unsafe { (*query_tracker).alloc_memory(untracked_memory) };
+                    untracked.set(0);
+                } else {
+                    untracked.set(untracked_memory);
+                }
+            });
+        });
+    }
+
+    /// Attribute the memory allocated by the thread to a query tracker until the
+    /// previous one is restored
+    fn enter_query(query_tracker: *const MemoryTracker) -> *const MemoryTracker {
+        Self::flush_query_memory();
+        QUERY_TRACKER.with(|current| current.replace(query_tracker))
+    }
+
+    fn flush_query_memory() {
+        QUERY_TRACKER.with(|query_tracker| {
+            let untracked_memory =
+                QUERY_UNTRACKED_MEMORY.with(|untracked| untracked.take());
+            let query_tracker = query_tracker.get();
+            if !query_tracker.is_null() && untracked_memory != 0 {
+                unsafe { (*query_tracker).alloc_memory(untracked_memory) };
+            }
+        });
+    }
 }
 
 pub struct MemoryTracker {
     memory_usage: AtomicI64,
+    // Max memory usage, 0 means unlimited
+    memory_limit: i64,
     parent_memory_tracker: Option<Arc<MemoryTracker>>,
 }
 
 impl MemoryTracker {
     pub fn create(
         parent_memory_tracker: Option<Arc<MemoryTracker>>,
+    ) -> Arc<MemoryTracker> {
+        Self::create_with_limit(parent_memory_tracker, 0)
+    }
+
+    /// Create a tracker whose usage must stay under memory_limit bytes, 0 means
+    /// unlimited
+    pub fn create_with_limit(
+        parent_memory_tracker: Option<Arc<MemoryTracker>>,
+        memory_limit: i64,
     ) -> Arc<MemoryTracker> {
         Arc::new(MemoryTracker {
             parent_memory_tracker,
+            memory_limit,
             memory_usage: AtomicI64::new(0),
         })
     }
@@ -147,6 +214,90 @@ impl MemoryTracker {
     pub fn get_memory_usage(&self) -> i64 {
         self.memory_usage.load(Ordering::Relaxed)
     }
+
+    pub fn get_memory_limit(&self) -> i64 {
+        self.memory_limit
+    }
+
+    /// Error if the tracker or one of its parents is over its limit
+    pub fn check_memory_limit(&self) -> Result<()> {
+        let memory_usage = self.get_memory_usage();
+        if self.memory_limit > 0 && memory_usage > self.memory_limit {
+            return Err(HetuError::ResourcesExhausted(format!(
+                "memory usage {} bytes exceeds the limit of {} bytes",
+                memory_usage, self.memory_limit
+            )));
+        }
+        match &self.parent_memory_tracker {
+            Some(parent_memory_tracker) => parent_memory_tracker.check_memory_limit(),
+            None => Ok(()),
+        }
+    }
+
+    /// Attribute the memory allocated while polling a future to this tracker, the
+    /// future fails once the memory limit is exceeded. The tasks the future spawns
+    /// are not tracked, the streams they poll must be tracked too.
+    pub fn track<F: Future>(self: &Arc<Self>, future: F) -> TrackedFuture<F> {
+        TrackedFuture {
+            tracker: self.clone(),
+            future: Box::pin(future),
+        }
+    }
+
+    /// Attribute the memory allocated while polling a stream to this tracker,
+    /// whichever task polls it. The stream fails once the memory limit is exceeded.
+    pub fn track_stream<S: Stream>(self: &Arc<Self>, stream: S) -> TrackedStream<S> {
+        TrackedStream {
+            tracker: self.clone(),
+            stream: Box::pin(stream),
+        }
+    }
+}
+
+/// A future whose memory is attributed to a [MemoryTracker], see [MemoryTracker::track]
+pub struct TrackedFuture<F: Future> {
+    tracker: Arc<MemoryTracker>,
+    future: Pin<Box<F>>,
+}
+
+impl<F: Future> Future for TrackedFuture<F> {
+    type Output = Result<F::Output>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.tracker.check_memory_limit()?;
+        let previous = ThreadTracker::enter_query(Arc::as_ptr(&self.tracker));
+        let poll = self.future.as_mut().poll(cx);
+        ThreadTracker::enter_query(previous);
+        self.tracker.check_memory_limit()?;
+        poll.map(Ok)
+    }
+}
+
+/// A stream whose memory is attributed to a [MemoryTracker], see
+/// [MemoryTracker::track_stream]
+pub struct TrackedStream<S: Stream> {
+    tracker: Arc<MemoryTracker>,
+    stream: Pin<Box<S>>,
+}
+
+impl<S: Stream> Stream for TrackedStream<S> {
+    type Item = Result<S::Item>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        if let Err(e) = self.tracker.check_memory_limit() {
+            return Poll::Ready(Some(Err(e)));
+        }
+        let previous = ThreadTracker::enter_query(Arc::as_ptr(&self.tracker));
+        let poll = self.stream.as_mut().poll_next(cx);
+        ThreadTracker::enter_query(previous);
+        if let Err(e) = self.tracker.check_memory_limit() {
+            return Poll::Ready(Some(Err(e)));
+        }
+        poll.map(|item| item.map(Ok))
+    }
 }
 
 pub struct RuntimeTracker {
@@ -167,11 +318,11 @@ impl RuntimeTracker {
     }
 
     pub fn on_stop_thread(self: &Arc<Self>) -> impl Fn() {
-        move || unsafe {
-            let tracker = std::mem::replace(&mut TRACKER, std::ptr::null_mut());
-
-            std::ptr::drop_in_place(tracker as usize as *mut ThreadTracker);
-            std::alloc::dealloc(tracker as *mut u8, Layout::new::<ThreadTracker>());
+        move || {
+            let tracker = TRACKER.with(|tracker| tracker.replace(std::ptr::null_mut()));
+            if !tracker.is_null() {
+                drop(unsafe { Box::from_raw(tracker) });
+            }
         }
     }
 
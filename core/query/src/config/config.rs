@@ -12,17 +12,28 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::collections::HashMap;
 use std::env;
+use std::fmt;
+use std::fs;
 
 use clap::Parser;
+use datafusion::execution::memory_manager::MemoryManagerConfig;
+use datafusion::execution::runtime_env::RuntimeConfig;
 use serde::Deserialize;
 use serde::Serialize;
 use serfig::collectors::{from_env, from_file, from_self};
 use serfig::parsers::Toml;
+use tonic::transport::{Certificate, ClientTlsConfig, Identity, ServerTlsConfig};
 
+use hetu_core::object_store::{
+    object_store_settings, parse_s3_credentials, OBJECT_STORE_HDFS_NAMENODE,
+    OBJECT_STORE_HDFS_USER, OBJECT_STORE_S3_ACCESS_KEY_ID, OBJECT_STORE_S3_ENDPOINT,
+    OBJECT_STORE_S3_REGION, OBJECT_STORE_S3_SECRET_ACCESS_KEY,
+};
 use hetu_error::{HetuError, Result};
 
-#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize, Parser)]
+#[derive(Clone, Default, PartialEq, Serialize, Deserialize, Parser)]
 #[clap(author, version, about, long_about = None)]
 #[serde(default)]
 pub struct Config {
@@ -38,6 +49,10 @@ pub struct Config {
     #[clap(long, default_value = "50050")]
     pub scheduler_port: u16,
 
+    /// Comma separated `host:port` addresses of all the schedulers to fail over between, scheduler_host and scheduler_port are used when empty
+    #[clap(long, default_value = "")]
+    pub scheduler_urls: String,
+
     /// The hetu query local IP address to bind to.
     #[clap(long, default_value = "0.0.0.0")]
     pub bind_host: String,
@@ -85,6 +100,157 @@ pub struct Config {
     /// Hetu hetu query mysql handler local bind port. Default: 3307
     #[clap(long, default_value = "3307")]
     pub mysql_handler_port: i32,
+
+    /// Max number of the open MySQL connections, the next ones are rejected with a too many connections error, 0 means unlimited. Default: 256
+    #[clap(long, default_value = "256")]
+    pub mysql_handler_max_connections: usize,
+
+    /// Seconds after which a MySQL connection sending no command is closed, 0 means never. Default: 28800
+    #[clap(long, default_value = "28800")]
+    pub mysql_handler_idle_timeout: u64,
+
+    /// Port serving the Prometheus metrics on /metrics, 0 disables it. Default: 50053
+    #[clap(long, default_value = "50053")]
+    pub metrics_port: u16,
+
+    /// Shared secret presented to the scheduler when registering, it must match the executor_auth_token of the scheduler. Default: ""
+    #[clap(long, default_value = "")]
+    pub executor_auth_token: String,
+
+    /// PEM CA certificate verifying the scheduler, the scheduler is reached over TLS when it is set. Default: ""
+    #[clap(long, default_value = "")]
+    pub scheduler_tls_ca_file: String,
+
+    /// Domain name expected in the scheduler certificate, the scheduler host is used when empty. Default: ""
+    #[clap(long, default_value = "")]
+    pub scheduler_tls_domain: String,
+
+    /// PEM certificate of the executor, its Flight and gRPC services are served over TLS when it is set with tls_key_file. It is also presented to the scheduler and to the other executors when they require client certificates (mTLS). Default: ""
+    #[clap(long, default_value = "")]
+    pub tls_cert_file: String,
+
+    /// PEM private key of tls_cert_file. Default: ""
+    #[clap(long, default_value = "")]
+    pub tls_key_file: String,
+
+    /// PEM CA certificate verifying the client certificates of the Flight and gRPC services, the clients must present one signed by it when it is set (mTLS). Default: ""
+    #[clap(long, default_value = "")]
+    pub tls_client_ca_file: String,
+
+    /// PEM CA certificate verifying the other executors and the shuffle service, their Flight services are reached over TLS when it is set. Default: ""
+    #[clap(long, default_value = "")]
+    pub executor_tls_ca_file: String,
+
+    /// Endpoint of the S3 compatible service reading s3:// urls, like http://minio:9000, AWS S3 when empty. Default: ""
+    #[clap(long, default_value = "")]
+    pub s3_endpoint: String,
+
+    /// Region of the S3 buckets, us-east-1 when empty. Default: ""
+    #[clap(long, default_value = "")]
+    pub s3_region: String,
+
+    /// Access key id of the S3 requests. Default: ""
+    #[clap(long, default_value = "")]
+    pub s3_access_key_id: String,
+
+    /// Secret access key of the S3 requests, the one of s3_access_key_id in s3_credentials_file is used when empty. Only read from the config file or the S3_SECRET_ACCESS_KEY environment variable. Default: ""
+    #[clap(skip)]
+    pub s3_secret_access_key: String,
+
+    /// File of the secret access keys of the S3 access key ids the sessions may use, one `access_key_id secret_access_key` pair per line. The tasks are sent without secrets. Default: ""
+    #[clap(long, default_value = "")]
+    pub s3_credentials_file: String,
+
+    /// WebHDFS url of the namenode reading hdfs:// urls, like http://namenode:9870. Default: ""
+    #[clap(long, default_value = "")]
+    pub hdfs_namenode_url: String,
+
+    /// User of the WebHDFS requests. Default: ""
+    #[clap(long, default_value = "")]
+    pub hdfs_user: String,
+
+    /// Run as a remote shuffle service keeping the shuffle partitions pushed by the executors in work_dir, instead of as an executor. Default: false
+    #[clap(long, parse(try_from_str = true_or_false), default_value_t)]
+    pub shuffle_service_enable: bool,
+
+    /// Host of the remote shuffle service the executor pushes its shuffle partitions to, so that they outlive the executor. The partitions stay in work_dir when empty. Default: ""
+    #[clap(long, default_value = "")]
+    pub shuffle_service_host: String,
+
+    /// Port of the remote shuffle service. Default: 50051
+    #[clap(long, default_value = "50051")]
+    pub shuffle_service_port: u16,
+
+    /// Max bytes of memory the operators of the executor tasks share, the sorts spill to work_dir beyond it while the hash joins and final aggregations fail, 0 means unlimited. Default: 0
+    #[clap(long, default_value = "0")]
+    pub executor_memory_limit: usize,
+
+    /// Max bytes of memory a query uses in the process, it fails beyond it, 0 means unlimited. Default: 0
+    #[clap(long, default_value = "0")]
+    pub query_memory_limit: usize,
+
+    /// Default milliseconds a query runs before its job is cancelled, which a session overrides with SET max_execution_time, 0 means unlimited. Default: 0
+    #[clap(long, default_value = "0")]
+    pub query_max_execution_time: usize,
+
+    /// Number of the threads running the plans of the queries and tasks, shared by the MySQL handler and the executor, 0 means the number of CPUs. Default: 0
+    #[clap(long, default_value = "0")]
+    pub cpu_threads: usize,
+}
+
+// The secrets are left out, so that the config can be logged
+impl fmt::Debug for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let redact = |secret: &str| if secret.is_empty() { "" } else { "<redacted>" };
+        f.debug_struct("Config")
+            .field("config_file", &self.config_file)
+            .field("scheduler_host", &self.scheduler_host)
+            .field("scheduler_port", &self.scheduler_port)
+            .field("scheduler_urls", &self.scheduler_urls)
+            .field("bind_host", &self.bind_host)
+            .field("bind_port", &self.bind_port)
+            .field("external_host", &self.external_host)
+            .field("bind_grpc_port", &self.bind_grpc_port)
+            .field("work_dir", &self.work_dir)
+            .field("concurrent_tasks", &self.concurrent_tasks)
+            .field("task_scheduling_policy", &self.task_scheduling_policy)
+            .field("executor_cleanup_enable", &self.executor_cleanup_enable)
+            .field("executor_cleanup_interval", &self.executor_cleanup_interval)
+            .field("executor_cleanup_ttl", &self.executor_cleanup_ttl)
+            .field("mysql_handler_host", &self.mysql_handler_host)
+            .field("mysql_handler_port", &self.mysql_handler_port)
+            .field(
+                "mysql_handler_max_connections",
+                &self.mysql_handler_max_connections,
+            )
+            .field(
+                "mysql_handler_idle_timeout",
+                &self.mysql_handler_idle_timeout,
+            )
+            .field("metrics_port", &self.metrics_port)
+            .field("executor_auth_token", &redact(&self.executor_auth_token))
+            .field("scheduler_tls_ca_file", &self.scheduler_tls_ca_file)
+            .field("scheduler_tls_domain", &self.scheduler_tls_domain)
+            .field("tls_cert_file", &self.tls_cert_file)
+            .field("tls_key_file", &self.tls_key_file)
+            .field("tls_client_ca_file", &self.tls_client_ca_file)
+            .field("executor_tls_ca_file", &self.executor_tls_ca_file)
+            .field("s3_endpoint", &self.s3_endpoint)
+            .field("s3_region", &self.s3_region)
+            .field("s3_access_key_id", &self.s3_access_key_id)
+            .field("s3_secret_access_key", &redact(&self.s3_secret_access_key))
+            .field("s3_credentials_file", &self.s3_credentials_file)
+            .field("hdfs_namenode_url", &self.hdfs_namenode_url)
+            .field("hdfs_user", &self.hdfs_user)
+            .field("shuffle_service_enable", &self.shuffle_service_enable)
+            .field("shuffle_service_host", &self.shuffle_service_host)
+            .field("shuffle_service_port", &self.shuffle_service_port)
+            .field("executor_memory_limit", &self.executor_memory_limit)
+            .field("query_memory_limit", &self.query_memory_limit)
+            .field("query_max_execution_time", &self.query_max_execution_time)
+            .field("cpu_threads", &self.cpu_threads)
+            .finish()
+    }
 }
 
 fn true_or_false(s: &str) -> Result<bool> {
@@ -95,6 +261,147 @@ fn true_or_false(s: &str) -> Result<bool> {
 }
 
 impl Config {
+    /// The urls of the schedulers, the leader is looked up in this order
+    pub fn scheduler_urls(&self) -> Vec<String> {
+        let scheme = if self.scheduler_tls_ca_file.is_empty() {
+            "http"
+        } else {
+            "https"
+        };
+        if self.scheduler_urls.is_empty() {
+            vec![format!(
+                "{}://{}:{}",
+                scheme, self.scheduler_host, self.scheduler_port
+            )]
+        } else {
+            self.scheduler_urls
+                .split(',')
+                .map(|addr| addr.trim())
+                .filter(|addr| !addr.is_empty())
+                .map(|addr| format!("{}://{}", scheme, addr))
+                .collect()
+        }
+    }
+
+    /// The TLS config of the scheduler clients, None if TLS is not enabled
+    pub fn scheduler_tls_config(&self) -> Result<Option<ClientTlsConfig>> {
+        if self.scheduler_tls_ca_file.is_empty() {
+            return Ok(None);
+        }
+        let ca = fs::read(&self.scheduler_tls_ca_file)?;
+        let mut tls_config =
+            ClientTlsConfig::new().ca_certificate(Certificate::from_pem(ca));
+        if !self.scheduler_tls_domain.is_empty() {
+            tls_config = tls_config.domain_name(&self.scheduler_tls_domain);
+        }
+        if let Some(identity) = self.tls_identity()? {
+            tls_config = tls_config.identity(identity);
+        }
+        Ok(Some(tls_config))
+    }
+
+    /// The TLS config of the connections to the other executors and to the shuffle
+    /// service, None if they are reached over plain http
+    pub fn executor_tls_config(&self) -> Result<Option<ClientTlsConfig>> {
+        if self.executor_tls_ca_file.is_empty() {
+            return Ok(None);
+        }
+        let ca = fs::read(&self.executor_tls_ca_file)?;
+        let mut tls_config =
+            ClientTlsConfig::new().ca_certificate(Certificate::from_pem(ca));
+        if let Some(identity) = self.tls_identity()? {
+            tls_config = tls_config.identity(identity);
+        }
+        Ok(Some(tls_config))
+    }
+
+    /// The TLS config of the Flight and gRPC services, None if TLS is not enabled
+    pub fn server_tls_config(&self) -> Result<Option<ServerTlsConfig>> {
+        let identity = match self.tls_identity()? {
+            Some(identity) => identity,
+            None => return Ok(None),
+        };
+        let mut tls_config = ServerTlsConfig::new().identity(identity);
+        if !self.tls_client_ca_file.is_empty() {
+            let client_ca = fs::read(&self.tls_client_ca_file)?;
+            tls_config = tls_config.client_ca_root(Certificate::from_pem(client_ca));
+        }
+        Ok(Some(tls_config))
+    }
+
+    fn tls_identity(&self) -> Result<Option<Identity>> {
+        if self.tls_cert_file.is_empty() || self.tls_key_file.is_empty() {
+            return Ok(None);
+        }
+        let cert = fs::read(&self.tls_cert_file)?;
+        let key = fs::read(&self.tls_key_file)?;
+        Ok(Some(Identity::from_pem(cert, key)))
+    }
+
+    /// The object store settings of the cluster, the sessions may override them
+    pub fn object_store_settings(&self) -> Result<HashMap<String, String>> {
+        let s3_secret_access_key = if self.s3_secret_access_key.is_empty()
+            && !self.s3_access_key_id.is_empty()
+        {
+            self.s3_credentials()?
+                .remove(&self.s3_access_key_id)
+                .unwrap_or_default()
+        } else {
+            self.s3_secret_access_key.clone()
+        };
+        Ok(object_store_settings(&HashMap::from([
+            (
+                OBJECT_STORE_S3_ENDPOINT.to_owned(),
+                self.s3_endpoint.clone(),
+            ),
+            (OBJECT_STORE_S3_REGION.to_owned(), self.s3_region.clone()),
+            (
+                OBJECT_STORE_S3_ACCESS_KEY_ID.to_owned(),
+                self.s3_access_key_id.clone(),
+            ),
+            (
+                OBJECT_STORE_S3_SECRET_ACCESS_KEY.to_owned(),
+                s3_secret_access_key,
+            ),
+            (
+                OBJECT_STORE_HDFS_NAMENODE.to_owned(),
+                self.hdfs_namenode_url.clone(),
+            ),
+            (OBJECT_STORE_HDFS_USER.to_owned(), self.hdfs_user.clone()),
+        ])))
+    }
+
+    /// The secret access keys of the S3 access key ids the sessions may use, by id
+    pub fn s3_credentials(&self) -> Result<HashMap<String, String>> {
+        if self.s3_credentials_file.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let content = fs::read_to_string(&self.s3_credentials_file)?;
+        parse_s3_credentials(&content).map_err(|e| HetuError::Internal(e.to_string()))
+    }
+
+    /// The runtime of the executor tasks, limited to a memory pool of
+    /// executor_memory_limit bytes the sorts spill to disk beyond
+    pub fn runtime_config(&self) -> Result<RuntimeConfig> {
+        let config = RuntimeConfig::new();
+        if self.executor_memory_limit == 0 {
+            return Ok(config);
+        }
+        let memory_manager =
+            MemoryManagerConfig::try_new_limit(self.executor_memory_limit, 1.0)?;
+        Ok(config.with_memory_manager(memory_manager))
+    }
+
+    /// The size of the thread pool running the plans
+    pub fn cpu_threads(&self) -> usize {
+        if self.cpu_threads > 0 {
+            return self.cpu_threads;
+        }
+        std::thread::available_parallelism()
+            .map(|threads| threads.get())
+            .unwrap_or(1)
+    }
+
     pub fn load() -> Result<Self> {
         let args: Self = Config::parse();
 
@@ -131,6 +438,9 @@ impl Config {
 #[cfg(test)]
 mod test {
     use crate::config::config::Config;
+    use clap::Parser;
+    use datafusion::execution::memory_manager::MemoryManagerConfig;
+    use hetu_core::object_store::OBJECT_STORE_S3_SECRET_ACCESS_KEY;
     use hetu_error::Result;
 
     #[tokio::test]
@@ -146,4 +456,100 @@ mod test {
             }
         }
     }
+
+    #[test]
+    fn cpu_threads() {
+        assert!(Config::default().cpu_threads() > 0);
+        let conf = Config {
+            cpu_threads: 3,
+            ..Default::default()
+        };
+        assert_eq!(conf.cpu_threads(), 3);
+    }
+
+    #[test]
+    fn tls_configs() -> Result<()> {
+        let conf = Config::default();
+        assert!(conf.server_tls_config()?.is_none());
+        assert!(conf.executor_tls_config()?.is_none());
+        assert!(conf.scheduler_tls_config()?.is_none());
+
+        // TLS is enabled by the certificate and the key of the executor
+        let conf = Config {
+            tls_cert_file: "/nonexistent/executor.pem".to_owned(),
+            ..Default::default()
+        };
+        assert!(conf.server_tls_config()?.is_none());
+        let conf = Config {
+            tls_key_file: "/nonexistent/executor.key".to_owned(),
+            ..conf
+        };
+        assert!(conf.server_tls_config().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn debug_redacts_secrets() {
+        let conf = Config {
+            executor_auth_token: "token".to_owned(),
+            s3_access_key_id: "key-id".to_owned(),
+            s3_secret_access_key: "secret".to_owned(),
+            ..Default::default()
+        };
+        let debug = format!("{:?}", conf);
+        assert!(debug.contains("key-id"));
+        assert!(!debug.contains("\"token\""));
+        assert!(!debug.contains("\"secret\""));
+        assert!(debug.contains("<redacted>"));
+    }
+
+    #[test]
+    fn s3_secret_access_key_from_credentials_file() -> Result<()> {
+        let credentials_file = std::env::temp_dir()
+            .join(format!("hetu-s3-credentials-{}", uuid::Uuid::new_v4()));
+        std::fs::write(&credentials_file, "key-id secret\nother-id other-secret\n")?;
+        let conf = Config {
+            s3_access_key_id: "key-id".to_owned(),
+            s3_credentials_file: credentials_file.to_string_lossy().into_owned(),
+            ..Default::default()
+        };
+        let settings = conf.object_store_settings()?;
+        assert_eq!(
+            settings.get(OBJECT_STORE_S3_SECRET_ACCESS_KEY),
+            Some(&"secret".to_owned())
+        );
+
+        // The secret of the config wins over the credentials file
+        let conf = Config {
+            s3_secret_access_key: "config-secret".to_owned(),
+            ..conf
+        };
+        let settings = conf.object_store_settings()?;
+        assert_eq!(
+            settings.get(OBJECT_STORE_S3_SECRET_ACCESS_KEY),
+            Some(&"config-secret".to_owned())
+        );
+        std::fs::remove_file(credentials_file)?;
+        Ok(())
+    }
+
+    #[test]
+    fn s3_secret_access_key_is_not_an_argument() {
+        let args = ["hetu-query", "--s3-secret-access-key", "secret"];
+        assert!(Config::try_parse_from(args).is_err());
+    }
+
+    #[test]
+    fn runtime_config_memory_limit() -> Result<()> {
+        let conf = Config {
+            executor_memory_limit: 1024 * 1024,
+            ..Default::default()
+        };
+        let config = conf.runtime_config()?;
+        assert!(matches!(
+            config.memory_manager,
+            MemoryManagerConfig::New { max_memory, .. } if max_memory == 1024 * 1024
+        ));
+        Ok(())
+    }
 }
@@ -0,0 +1,251 @@
+// Copyright 2021 HetuDB.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Graceful decommissioning of an executor.
+//!
+//! A draining executor accepts no new task and finishes the running ones. It keeps serving
+//! its shuffle files until the scheduler reports that no unfinished job reads them anymore,
+//! the scheduler then deregisters it and the executor can exit without losing shuffle data.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use hetu_core::serde::protobuf::scheduler_grpc_client::SchedulerGrpcClient;
+use hetu_core::serde::protobuf::DrainExecutorParams;
+use log::{info, warn};
+use tonic::transport::Channel;
+
+use crate::executor::Executor;
+use crate::scheduler_failover::SchedulerFailover;
+
+/// How often a draining executor asks the scheduler whether it is drained
+const DRAIN_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Drain the executor until the scheduler deregisters it, then notify the waiters of
+/// `Executor::wait_drained`. Nothing is done if the executor is already draining.
+pub async fn drain(
+    executor: Arc<Executor>,
+    scheduler: SchedulerGrpcClient<Channel>,
+    scheduler_failover: SchedulerFailover,
+) {
+    drain_every(
+        executor,
+        scheduler,
+        scheduler_failover,
+        DRAIN_CHECK_INTERVAL,
+    )
+    .await
+}
+
+async fn drain_every(
+    executor: Arc<Executor>,
+    mut scheduler: SchedulerGrpcClient<Channel>,
+    scheduler_failover: SchedulerFailover,
+    check_interval: Duration,
+) {
+    if !executor.start_draining() {
+        return;
+    }
+    let executor_id = executor.metadata.id.clone();
+    info!("Draining executor {}", executor_id);
+    loop {
+        match scheduler
+            .drain_executor(DrainExecutorParams {
+                executor_id: executor_id.clone(),
+                auth_token: executor.metadata.auth_token.clone(),
+            })
+            .await
+        {
+            Ok(response) => {
+                if response.into_inner().drained {
+                    break;
+                }
+            }
+            Err(status) => {
+                warn!("Fail to drain executor {}: {}", executor_id, status);
+                if SchedulerFailover::should_fail_over(&status) {
+                    scheduler_failover.fail_over().await;
+                }
+            }
+        }
+        tokio::time::sleep(check_interval).await;
+    }
+    info!("Executor {} is drained and deregistered", executor_id);
+    executor.notify_drained();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::LoggingMetricsCollector;
+    use datafusion::execution::runtime_env::{RuntimeConfig, RuntimeEnv};
+    use futures::Stream;
+    use hetu_core::serde::protobuf::scheduler_grpc_server::{
+        SchedulerGrpc, SchedulerGrpcServer,
+    };
+    use hetu_core::serde::protobuf::{
+        CancelJobParams, CancelJobResult, DrainExecutorResult, ExecuteQueryParams,
+        ExecuteQueryResult, ExecutorRegistration, GetFileMetadataParams,
+        GetFileMetadataResult, GetJobStatusParams, GetJobStatusResult, HeartBeatParams,
+        HeartBeatResult, JobStatus, PollWorkParams, PollWorkResult,
+        RegisterExecutorParams, RegisterExecutorResult, UpdateTaskStatusParams,
+        UpdateTaskStatusResult, WatchJobStatusParams,
+    };
+    use parking_lot::Mutex;
+    use std::pin::Pin;
+    use tokio::net::TcpListener;
+    use tonic::transport::Server;
+    use tonic::{Request, Response, Status};
+
+    /// A scheduler reporting the executor drained from the second drain request on
+    #[derive(Clone, Default)]
+    struct DrainingScheduler {
+        requests: Arc<Mutex<Vec<DrainExecutorParams>>>,
+    }
+
+    #[tonic::async_trait]
+    impl SchedulerGrpc for DrainingScheduler {
+        async fn poll_work(
+            &self,
+            _request: Request<PollWorkParams>,
+        ) -> Result<Response<PollWorkResult>, Status> {
+            Err(Status::unimplemented("poll_work"))
+        }
+
+        async fn register_executor(
+            &self,
+            _request: Request<RegisterExecutorParams>,
+        ) -> Result<Response<RegisterExecutorResult>, Status> {
+            Err(Status::unimplemented("register_executor"))
+        }
+
+        async fn heart_beat_from_executor(
+            &self,
+            _request: Request<HeartBeatParams>,
+        ) -> Result<Response<HeartBeatResult>, Status> {
+            Err(Status::unimplemented("heart_beat_from_executor"))
+        }
+
+        async fn update_task_status(
+            &self,
+            _request: Request<UpdateTaskStatusParams>,
+        ) -> Result<Response<UpdateTaskStatusResult>, Status> {
+            Err(Status::unimplemented("update_task_status"))
+        }
+
+        async fn get_file_metadata(
+            &self,
+            _request: Request<GetFileMetadataParams>,
+        ) -> Result<Response<GetFileMetadataResult>, Status> {
+            Err(Status::unimplemented("get_file_metadata"))
+        }
+
+        async fn execute_query(
+            &self,
+            _request: Request<ExecuteQueryParams>,
+        ) -> Result<Response<ExecuteQueryResult>, Status> {
+            Err(Status::unimplemented("execute_query"))
+        }
+
+        async fn get_job_status(
+            &self,
+            _request: Request<GetJobStatusParams>,
+        ) -> Result<Response<GetJobStatusResult>, Status> {
+            Err(Status::unimplemented("get_job_status"))
+        }
+
+        type WatchJobStatusStream =
+            Pin<Box<dyn Stream<Item = Result<JobStatus, Status>> + Send + 'static>>;
+
+        async fn watch_job_status(
+            &self,
+            _request: Request<WatchJobStatusParams>,
+        ) -> Result<Response<Self::WatchJobStatusStream>, Status> {
+            Err(Status::unimplemented("watch_job_status"))
+        }
+
+        async fn cancel_job(
+            &self,
+            _request: Request<CancelJobParams>,
+        ) -> Result<Response<CancelJobResult>, Status> {
+            Err(Status::unimplemented("cancel_job"))
+        }
+
+        async fn drain_executor(
+            &self,
+            request: Request<DrainExecutorParams>,
+        ) -> Result<Response<DrainExecutorResult>, Status> {
+            let mut requests = self.requests.lock();
+            requests.push(request.into_inner());
+            Ok(Response::new(DrainExecutorResult {
+                drained: requests.len() >= 2,
+            }))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_drain() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let scheduler = DrainingScheduler::default();
+        tokio::spawn(
+            Server::builder()
+                .add_service(SchedulerGrpcServer::new(scheduler.clone()))
+                .serve_with_incoming(tokio_stream::wrappers::TcpListenerStream::new(
+                    listener,
+                )),
+        );
+        let client = SchedulerGrpcClient::connect(format!("http://{}", addr))
+            .await
+            .unwrap();
+        let executor = Arc::new(Executor::new(
+            ExecutorRegistration {
+                id: "abc".to_owned(),
+                auth_token: "secret".to_owned(),
+                ..Default::default()
+            },
+            "/tmp",
+            Arc::new(RuntimeEnv::new(RuntimeConfig::new()).unwrap()),
+            Arc::new(LoggingMetricsCollector::default()),
+        ));
+
+        drain_every(
+            executor.clone(),
+            client.clone(),
+            SchedulerFailover::single(),
+            Duration::from_millis(10),
+        )
+        .await;
+        assert!(executor.is_draining());
+        executor.wait_drained().await;
+        {
+            let requests = scheduler.requests.lock();
+            assert_eq!(requests.len(), 2);
+            for request in requests.iter() {
+                assert_eq!(request.executor_id, "abc");
+                assert_eq!(request.auth_token, "secret");
+            }
+        }
+
+        // An executor is drained once
+        drain_every(
+            executor,
+            client,
+            SchedulerFailover::single(),
+            Duration::from_millis(10),
+        )
+        .await;
+        assert_eq!(scheduler.requests.lock().len(), 2);
+    }
+}
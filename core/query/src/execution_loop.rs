@@ -16,10 +16,14 @@
 // under the License.
 
 use crate::as_task_status;
+use crate::decommission;
 use crate::executor::Executor;
+use crate::resources::collect_executor_state;
+use crate::scheduler_failover::SchedulerFailover;
 use datafusion::execution::context::TaskContext;
 use datafusion::physical_plan::ExecutionPlan;
 use datafusion_proto::logical_plan::AsLogicalPlan;
+use hetu_core::config::BallistaConfig;
 use hetu_core::error::BallistaError;
 use hetu_core::serde::physical_plan::from_proto::parse_protobuf_hash_partitioning;
 use hetu_core::serde::protobuf::{
@@ -38,6 +42,7 @@ use tonic::transport::Channel;
 
 pub async fn poll_loop<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan>(
     mut scheduler: SchedulerGrpcClient<Channel>,
+    scheduler_failover: SchedulerFailover,
     executor: Arc<Executor>,
     codec: BallistaCodec<T, U>,
 ) {
@@ -69,8 +74,10 @@ pub async fn poll_loop<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan>
         > = scheduler
             .poll_work(PollWorkParams {
                 metadata: Some(executor.metadata.clone()),
-                can_accept_task: available_tasks_slots.load(Ordering::SeqCst) > 0,
+                can_accept_task: available_tasks_slots.load(Ordering::SeqCst) > 0
+                    && !executor.is_draining(),
                 task_status,
+                state: Some(collect_executor_state(&executor).into()),
             })
             .await;
 
@@ -78,7 +85,18 @@ pub async fn poll_loop<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan>
 
         match poll_work_result {
             Ok(result) => {
-                if let Some(task) = result.into_inner().task {
+                let result = result.into_inner();
+                if result.drain && !executor.is_draining() {
+                    tokio::spawn(decommission::drain(
+                        executor.clone(),
+                        scheduler.clone(),
+                        scheduler_failover.clone(),
+                    ));
+                }
+                for job_id in result.cancelled_jobs.iter() {
+                    executor.cancel_tasks(job_id);
+                }
+                if let Some(task) = result.task {
                     match run_received_tasks(
                         executor.clone(),
                         available_tasks_slots.clone(),
@@ -102,6 +120,9 @@ pub async fn poll_loop<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan>
             }
             Err(error) => {
                 warn!("Executor registration failed. If this continues to happen the executor might be marked as dead by the scheduler. Error: {}", error);
+                if SchedulerFailover::should_fail_over(&error) {
+                    scheduler_failover.fail_over().await;
+                }
             }
         }
         if !active_job {
@@ -125,12 +146,13 @@ async fn run_received_tasks<T: 'static + AsLogicalPlan, U: 'static + AsExecution
     info!("Received task {}", task_id_log);
     available_tasks_slots.fetch_sub(1, Ordering::SeqCst);
 
-    let runtime = executor.runtime.clone();
     let session_id = task.session_id;
     let mut task_props = HashMap::new();
     for kv_pair in task.props {
         task_props.insert(kv_pair.key, kv_pair.value);
     }
+    let runtime = executor.task_runtime(&task_props)?;
+    let task_config = BallistaConfig::with_settings(task_props.clone())?;
 
     let mut task_scalar_functions = HashMap::new();
     let mut task_aggregate_functions = HashMap::new();
@@ -172,6 +194,7 @@ async fn run_received_tasks<T: 'static + AsLogicalPlan, U: 'static + AsExecution
                 task_id.partition_id as usize,
                 plan,
                 task_context,
+                task_config,
                 shuffle_output_partitioning,
             )
             .await;
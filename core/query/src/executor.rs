@@ -18,20 +18,40 @@
 //! Ballista executor logic
 
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Instant;
 
+use crate::base::ProcessList;
+use crate::cpu_bound_executor::DedicatedExecutor;
 use crate::metrics::ExecutorMetricsCollector;
+use datafusion::datasource::object_store::ObjectStoreRegistry;
 use datafusion::error::DataFusionError;
 use datafusion::execution::context::TaskContext;
 use datafusion::execution::runtime_env::RuntimeEnv;
+use hetu_core::client::BallistaClient;
+use hetu_core::config::{BallistaConfig, ShuffleFormat};
 use hetu_core::error::BallistaError;
-use hetu_core::execution_plans::ShuffleWriterExec;
+use hetu_core::execution_plans::{
+    set_shuffle_fetch_runtime, with_memory_reservations, with_shuffle_fetch_options,
+    ShuffleFetchOptions, ShuffleWriterExec,
+};
+use hetu_core::object_store::{
+    register_object_stores, resolve_task_object_store_settings,
+};
 use hetu_core::serde::protobuf;
 use hetu_core::serde::protobuf::ExecutorRegistration;
+use hetu_core::utils::collect_plan_metrics;
 
 use datafusion::physical_plan::udaf::AggregateUDF;
 use datafusion::physical_plan::udf::ScalarUDF;
 use datafusion::physical_plan::{ExecutionPlan, Partitioning};
+use futures::future::{AbortHandle, Abortable};
+use futures::Future;
+use log::{info, warn};
+use parking_lot::Mutex;
+use tokio::runtime::Handle;
+use tokio::sync::Notify;
 
 /// Ballista executor
 pub struct Executor {
@@ -52,6 +72,33 @@ pub struct Executor {
 
     /// Collector for runtime execution metrics
     pub metrics_collector: Arc<dyn ExecutorMetricsCollector>,
+
+    /// Object store settings of the cluster, registered in the runtime
+    object_store_settings: HashMap<String, String>,
+
+    /// Secret access keys of the S3 access key ids the sessions may use, the tasks are
+    /// sent without them
+    s3_credentials: HashMap<String, String>,
+
+    /// Host and port of the remote shuffle service the shuffle partitions are pushed to,
+    /// they are kept in the work dir if None
+    shuffle_service: Option<(String, u16)>,
+
+    /// Max bytes of memory the tasks of a query use together, 0 means unlimited
+    query_memory_limit: usize,
+
+    /// Thread pool the plans of the tasks run on, they run on the current runtime if
+    /// None
+    cpu_executor: Option<DedicatedExecutor>,
+
+    /// Whether the executor stopped accepting new tasks before exiting
+    draining: AtomicBool,
+
+    /// Notified once the executor is drained and deregistered
+    drained: Notify,
+
+    /// Abort handles of the running tasks by job, keyed by stage and partition
+    running_tasks: Mutex<HashMap<String, HashMap<(usize, usize), AbortHandle>>>,
 }
 
 impl Executor {
@@ -70,14 +117,93 @@ impl Executor {
             aggregate_functions: HashMap::new(),
             runtime,
             metrics_collector,
+            object_store_settings: HashMap::new(),
+            s3_credentials: HashMap::new(),
+            shuffle_service: None,
+            query_memory_limit: 0,
+            cpu_executor: None,
+            draining: AtomicBool::new(false),
+            drained: Notify::new(),
+            running_tasks: Mutex::new(HashMap::new()),
         }
     }
 }
 
+impl Executor {
+    /// Set the object store settings of the cluster, the runtime must have their object
+    /// stores registered
+    pub fn with_object_store_settings(
+        mut self,
+        settings: HashMap<String, String>,
+    ) -> Self {
+        self.object_store_settings = settings;
+        self
+    }
+
+    /// Set the secret access keys of the S3 access key ids the sessions may override
+    /// the one of the cluster with
+    pub fn with_s3_credentials(
+        mut self,
+        s3_credentials: HashMap<String, String>,
+    ) -> Self {
+        self.s3_credentials = s3_credentials;
+        self
+    }
+
+    /// Push the shuffle partitions to a remote shuffle service, so that they outlive the
+    /// executor. The tasks then write the hash shuffle format whatever their sessions set
+    pub fn with_shuffle_service(mut self, host: impl Into<String>, port: u16) -> Self {
+        self.shuffle_service = Some((host.into(), port));
+        self
+    }
+
+    /// Fail the tasks of a query once they use more than query_memory_limit bytes of
+    /// memory together, 0 means unlimited
+    pub fn with_query_memory_limit(mut self, query_memory_limit: usize) -> Self {
+        self.query_memory_limit = query_memory_limit;
+        self
+    }
+
+    /// Run the plans of the tasks on a thread pool, so that they don't hold up the
+    /// flight and grpc services. The connections the plans fetch their shuffle
+    /// partitions through stay on the current runtime, like the pushes.
+    pub fn with_cpu_executor(mut self, cpu_executor: DedicatedExecutor) -> Self {
+        if let Ok(runtime) = Handle::try_current() {
+            set_shuffle_fetch_runtime(runtime);
+        }
+        self.cpu_executor = Some(cpu_executor);
+        self
+    }
+
+    /// The runtime of a task, one with its own object stores if the session of the task
+    /// overrides the object store settings of the cluster
+    pub fn task_runtime(
+        &self,
+        task_props: &HashMap<String, String>,
+    ) -> Result<Arc<RuntimeEnv>, BallistaError> {
+        let settings = resolve_task_object_store_settings(
+            &self.object_store_settings,
+            &self.s3_credentials,
+            task_props,
+        );
+        if settings == self.object_store_settings {
+            return Ok(self.runtime.clone());
+        }
+        let runtime = RuntimeEnv {
+            memory_manager: self.runtime.memory_manager.clone(),
+            disk_manager: self.runtime.disk_manager.clone(),
+            object_store_registry: Arc::new(ObjectStoreRegistry::new()),
+        };
+        register_object_stores(&runtime, &settings)?;
+        Ok(Arc::new(runtime))
+    }
+}
+
 impl Executor {
     /// Execute one partition of a query stage and persist the result to disk in IPC format. On
     /// success, return a RecordBatch containing metadata about the results, including path
-    /// and statistics.
+    /// and statistics, together with the metrics of the executed operators.
+    #[allow(clippy::too_many_arguments)]
     pub async fn execute_shuffle_write(
         &self,
         job_id: String,
@@ -85,8 +211,28 @@ impl Executor {
         part: usize,
         plan: Arc<dyn ExecutionPlan>,
         task_ctx: Arc<TaskContext>,
+        task_config: BallistaConfig,
         _shuffle_output_partitioning: Option<Partitioning>,
-    ) -> Result<Vec<protobuf::ShuffleWritePartition>, BallistaError> {
+    ) -> Result<
+        (
+            Vec<protobuf::ShuffleWritePartition>,
+            Vec<protobuf::OperatorMetricsSet>,
+        ),
+        BallistaError,
+    > {
+        // The shuffle service keeps one file per pushed partition
+        let shuffle_format = if self.shuffle_service.is_some() {
+            ShuffleFormat::Hash
+        } else {
+            task_config.shuffle_format()
+        };
+        let shuffle_compression = task_config.shuffle_compression();
+        // the memory of the task is attributed to its job in the process list
+        let process = ProcessList::global().enter(
+            &job_id,
+            &format!("job {}", job_id),
+            self.query_memory_limit as i64,
+        );
         let exec = if let Some(shuffle_writer) =
             plan.as_any().downcast_ref::<ShuffleWriterExec>()
         {
@@ -94,10 +240,19 @@ impl Executor {
             ShuffleWriterExec::try_new(
                 job_id.clone(),
                 stage_id,
-                plan.children()[0].clone(),
+                process.track_plan(with_memory_reservations(
+                    with_shuffle_fetch_options(
+                        plan.children()[0].clone(),
+                        &ShuffleFetchOptions {
+                            local_executor_id: Some(self.metadata.id.clone()),
+                            ..ShuffleFetchOptions::from(&task_config)
+                        },
+                    )?,
+                )?)?,
                 self.work_dir.clone(),
                 shuffle_writer.shuffle_output_partitioning().cloned(),
             )
+            .map(|exec| exec.with_shuffle_format(shuffle_format, shuffle_compression))
         } else {
             Err(DataFusionError::Internal(
                 "Plan passed to execute_shuffle_write is not a ShuffleWriterExec"
@@ -105,15 +260,180 @@ impl Executor {
             ))
         }?;
 
-        let partitions = exec.execute_shuffle_write(part, task_ctx).await?;
+        let start = Instant::now();
+        let task = exec.execute_shuffle_write(part, task_ctx);
+        let task = async move {
+            match process.run(task).await {
+                Ok(result) => result.map_err(BallistaError::from),
+                Err(e) => Err(BallistaError::General(e.to_string())),
+            }
+        };
+        // the task is aborted wherever it runs if its job is cancelled
+        let task = {
+            let task = self.register_task(&job_id, stage_id, part, task);
+            let task_name = format!("{}/{}/{}", job_id, stage_id, part);
+            async move {
+                task.await.unwrap_or_else(|_| {
+                    Err(BallistaError::General(format!(
+                        "Task {} was cancelled",
+                        task_name
+                    )))
+                })
+            }
+        };
+        let result = match &self.cpu_executor {
+            Some(cpu_executor) => cpu_executor.spawn(task).await.unwrap_or_else(|_| {
+                Err(BallistaError::Internal(format!(
+                    "Task {}/{}/{} was dropped by the cpu executor",
+                    job_id, stage_id, part
+                )))
+            }),
+            None => task.await,
+        };
+        self.remove_running_task(&job_id, stage_id, part);
+        self.metrics_collector.record_task(
+            &job_id,
+            stage_id,
+            part,
+            start.elapsed(),
+            result.is_ok(),
+        );
+        let mut partitions = result?;
+        if let Some((host, port)) = self.shuffle_service.as_ref() {
+            self.push_partitions(&job_id, stage_id, part, &mut partitions, host, *port)
+                .await?;
+        }
 
+        let operator_metrics = collect_plan_metrics(&exec);
         self.metrics_collector
             .record_stage(&job_id, stage_id, part, exec);
 
-        Ok(partitions)
+        Ok((partitions, operator_metrics))
+    }
+
+    // Move the written shuffle partitions to the shuffle service
+    async fn push_partitions(
+        &self,
+        job_id: &str,
+        stage_id: usize,
+        input_partition_id: usize,
+        partitions: &mut [protobuf::ShuffleWritePartition],
+        host: &str,
+        port: u16,
+    ) -> Result<(), BallistaError> {
+        let mut client = BallistaClient::try_new(host, port).await?;
+        for partition in partitions.iter_mut().filter(|p| !p.path.is_empty()) {
+            let remote_path = client
+                .push_partition(
+                    job_id,
+                    stage_id,
+                    partition.partition_id as usize,
+                    input_partition_id,
+                    &partition.path,
+                    &self.metadata.auth_token,
+                )
+                .await?;
+            if let Err(e) = std::fs::remove_file(&partition.path) {
+                warn!("Fail to remove pushed partition {}: {}", partition.path, e);
+            }
+            partition.path = remote_path;
+            partition.remote_host = host.to_owned();
+            partition.remote_port = port as u32;
+        }
+        Ok(())
+    }
+
+    fn register_task<F: Future>(
+        &self,
+        job_id: &str,
+        stage_id: usize,
+        part: usize,
+        task: F,
+    ) -> Abortable<F> {
+        let (abort_handle, abort_registration) = AbortHandle::new_pair();
+        self.running_tasks
+            .lock()
+            .entry(job_id.to_owned())
+            .or_default()
+            .insert((stage_id, part), abort_handle);
+        Abortable::new(task, abort_registration)
+    }
+
+    fn remove_running_task(&self, job_id: &str, stage_id: usize, part: usize) {
+        let mut running_tasks = self.running_tasks.lock();
+        if let Some(tasks) = running_tasks.get_mut(job_id) {
+            tasks.remove(&(stage_id, part));
+            if tasks.is_empty() {
+                running_tasks.remove(job_id);
+            }
+        }
+    }
+
+    /// Abort the running tasks of a cancelled job, return the number of aborted tasks
+    pub fn cancel_tasks(&self, job_id: &str) -> usize {
+        let tasks = self.running_tasks.lock().remove(job_id).unwrap_or_default();
+        for abort_handle in tasks.values() {
+            abort_handle.abort();
+        }
+        if !tasks.is_empty() {
+            info!("Cancelled {} running tasks of job {}", tasks.len(), job_id);
+        }
+        tasks.len()
     }
 
     pub fn work_dir(&self) -> &str {
         &self.work_dir
     }
+
+    /// Stop accepting new tasks, return false if the executor is already draining
+    pub fn start_draining(&self) -> bool {
+        !self.draining.swap(true, Ordering::SeqCst)
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn notify_drained(&self) {
+        self.drained.notify_one();
+    }
+
+    /// Wait until the executor is drained and deregistered from the scheduler
+    pub async fn wait_drained(&self) {
+        self.drained.notified().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::LoggingMetricsCollector;
+    use datafusion::execution::runtime_env::RuntimeConfig;
+    use futures::future::{pending, Aborted};
+
+    #[tokio::test]
+    async fn test_cancel_tasks() {
+        let executor = Executor::new(
+            ExecutorRegistration::default(),
+            "/tmp",
+            Arc::new(RuntimeEnv::new(RuntimeConfig::new()).unwrap()),
+            Arc::new(LoggingMetricsCollector::default()),
+        );
+        let tasks = vec![
+            executor.register_task("job1", 1, 0, pending::<()>()),
+            executor.register_task("job1", 1, 1, pending::<()>()),
+        ];
+        let other = executor.register_task("job2", 1, 0, async {});
+
+        assert_eq!(executor.cancel_tasks("job3"), 0);
+        assert_eq!(executor.cancel_tasks("job1"), 2);
+        for task in tasks {
+            assert_eq!(task.await, Err(Aborted));
+        }
+        assert_eq!(executor.cancel_tasks("job1"), 0);
+
+        assert_eq!(other.await, Ok(()));
+        executor.remove_running_task("job2", 1, 0);
+        assert_eq!(executor.cancel_tasks("job2"), 0);
+    }
 }
@@ -21,13 +21,14 @@ use std::sync::Arc;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::sync::mpsc;
 
-use log::{debug, error, info};
-use tonic::transport::{Channel, Server};
+use log::{debug, error, info, warn};
+use tonic::transport::{Channel, Server, ServerTlsConfig};
 use tonic::{Request, Response, Status};
 
 use datafusion::execution::context::TaskContext;
 use datafusion::physical_plan::ExecutionPlan;
 use datafusion_proto::logical_plan::AsLogicalPlan;
+use hetu_core::config::BallistaConfig;
 use hetu_core::error::BallistaError;
 use hetu_core::serde::physical_plan::from_proto::parse_protobuf_hash_partitioning;
 use hetu_core::serde::protobuf::executor_grpc_server::{
@@ -36,26 +37,43 @@ use hetu_core::serde::protobuf::executor_grpc_server::{
 use hetu_core::serde::protobuf::executor_registration::OptionalHost;
 use hetu_core::serde::protobuf::scheduler_grpc_client::SchedulerGrpcClient;
 use hetu_core::serde::protobuf::{
-    HeartBeatParams, LaunchTaskParams, LaunchTaskResult, RegisterExecutorParams,
-    StopExecutorParams, StopExecutorResult, TaskDefinition, UpdateTaskStatusParams,
+    CancelTasksParams, CancelTasksResult, HeartBeatParams, LaunchTaskParams,
+    LaunchTaskResult, RegisterExecutorParams, StopExecutorParams, StopExecutorResult,
+    TaskDefinition, UpdateTaskStatusParams,
 };
 use hetu_core::serde::scheduler::ExecutorState;
 use hetu_core::serde::{AsExecutionPlan, BallistaCodec};
 
 use crate::as_task_status;
-use crate::cpu_bound_executor::DedicatedExecutor;
+use crate::decommission;
 use crate::executor::Executor;
+use crate::resources::collect_executor_state;
+use crate::scheduler_failover::SchedulerFailover;
+
+/// A builder of the servers of the executor services, serving over TLS if a TLS config is
+/// given
+pub fn server_builder(
+    tls_config: Option<ServerTlsConfig>,
+) -> Result<Server, BallistaError> {
+    match tls_config {
+        Some(tls_config) => Ok(Server::builder().tls_config(tls_config)?),
+        None => Ok(Server::builder()),
+    }
+}
 
 pub async fn startup<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan>(
     mut scheduler: SchedulerGrpcClient<Channel>,
+    scheduler_failover: SchedulerFailover,
     executor: Arc<Executor>,
     codec: BallistaCodec<T, U>,
+    tls_config: Option<ServerTlsConfig>,
 ) {
     // TODO make the buffer size configurable
     let (tx_task, rx_task) = mpsc::channel::<TaskDefinition>(1000);
 
     let executor_server = ExecutorServer::new(
         scheduler.clone(),
+        scheduler_failover.clone(),
         executor.clone(),
         ExecutorEnv { tx_task },
         codec,
@@ -78,21 +96,36 @@ pub async fn startup<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan>(
         info!("Setup executor grpc service for {:?}", addr);
 
         let server = ExecutorGrpcServer::new(executor_server.clone());
-        let grpc_server_future = Server::builder().add_service(server).serve(addr);
+        let grpc_server_future = server_builder(tls_config)
+            .expect("Invalid TLS config")
+            .add_service(server)
+            .serve(addr);
         tokio::spawn(async move { grpc_server_future.await });
     }
 
     let executor_server = Arc::new(executor_server);
 
-    // 2. Do executor registration
-    match register_executor(&mut scheduler, executor.clone()).await {
-        Ok(_) => {
-            info!("Executor registration succeed");
-        }
-        Err(error) => {
-            panic!("Executor registration failed due to: {}", error);
+    // 2. Do executor registration, only the leader of the schedulers accepts it
+    let mut attempts = 0;
+    loop {
+        match register_executor(&mut scheduler, executor.clone()).await {
+            Ok(_) => {
+                info!("Executor registration succeed");
+                break;
+            }
+            Err(BallistaError::GrpcError(status))
+                if SchedulerFailover::should_fail_over(&status)
+                    && attempts < scheduler_failover.num_schedulers() =>
+            {
+                warn!("Executor registration failed due to: {}", status);
+                attempts += 1;
+                scheduler_failover.fail_over().await;
+            }
+            Err(error) => {
+                panic!("Executor registration failed due to: {}", error);
+            }
         }
-    };
+    }
 
     // 3. Start Heartbeater
     {
@@ -131,6 +164,7 @@ pub struct ExecutorServer<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPl
     _start_time: u128,
     executor: Arc<Executor>,
     scheduler: SchedulerGrpcClient<Channel>,
+    scheduler_failover: SchedulerFailover,
     executor_env: ExecutorEnv,
     codec: BallistaCodec<T, U>,
 }
@@ -145,6 +179,7 @@ unsafe impl Sync for ExecutorEnv {}
 impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan> ExecutorServer<T, U> {
     fn new(
         scheduler: SchedulerGrpcClient<Channel>,
+        scheduler_failover: SchedulerFailover,
         executor: Arc<Executor>,
         executor_env: ExecutorEnv,
         codec: BallistaCodec<T, U>,
@@ -156,21 +191,41 @@ impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan> ExecutorServer<T,
                 .as_millis(),
             executor,
             scheduler,
+            scheduler_failover,
             executor_env,
             codec,
         }
     }
 
     async fn heartbeat(&self) {
-        // TODO Error handling
-        self.scheduler
+        let result = self
+            .scheduler
             .clone()
             .heart_beat_from_executor(HeartBeatParams {
                 executor_id: self.executor.metadata.id.clone(),
                 state: Some(self.get_executor_state().into()),
+                auth_token: self.executor.metadata.auth_token.clone(),
             })
-            .await
-            .unwrap();
+            .await;
+        match result {
+            Ok(result) => {
+                // The scheduler failed over and the new leader does not know this executor
+                if result.into_inner().reregister {
+                    let mut scheduler = self.scheduler.clone();
+                    if let Err(e) =
+                        register_executor(&mut scheduler, self.executor.clone()).await
+                    {
+                        error!("Executor reregistration failed due to: {}", e);
+                    }
+                }
+            }
+            Err(status) => {
+                warn!("Executor heartbeat failed due to: {}", status);
+                if SchedulerFailover::should_fail_over(&status) {
+                    self.scheduler_failover.fail_over().await;
+                }
+            }
+        }
     }
 
     async fn run_task(&self, task: TaskDefinition) -> Result<(), BallistaError> {
@@ -181,12 +236,13 @@ impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan> ExecutorServer<T,
         );
         info!("Start to run task {}", task_id_log);
 
-        let runtime = self.executor.runtime.clone();
         let session_id = task.session_id;
         let mut task_props = HashMap::new();
         for kv_pair in task.props {
             task_props.insert(kv_pair.key, kv_pair.value);
         }
+        let runtime = self.executor.task_runtime(&task_props)?;
+        let task_config = BallistaConfig::with_settings(task_props.clone())?;
 
         let mut task_scalar_functions = HashMap::new();
         let mut task_aggregate_functions = HashMap::new();
@@ -230,6 +286,7 @@ impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan> ExecutorServer<T,
                 task_id.partition_id as usize,
                 plan,
                 task_context,
+                task_config,
                 shuffle_output_partitioning,
             )
             .await;
@@ -238,7 +295,8 @@ impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan> ExecutorServer<T,
 
         let executor_id = &self.executor.metadata.id;
         // TODO use another channel to update the status of a task set
-        self.scheduler
+        let result = self
+            .scheduler
             .clone()
             .update_task_status(UpdateTaskStatusParams {
                 executor_id: executor_id.clone(),
@@ -247,17 +305,21 @@ impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan> ExecutorServer<T,
                     executor_id.clone(),
                     task_id,
                 )],
+                auth_token: self.executor.metadata.auth_token.clone(),
             })
-            .await?;
+            .await;
+        if let Err(status) = &result {
+            if SchedulerFailover::should_fail_over(status) {
+                self.scheduler_failover.fail_over().await;
+            }
+        }
+        result?;
 
         Ok(())
     }
 
-    // TODO with real state
     fn get_executor_state(&self) -> ExecutorState {
-        ExecutorState {
-            available_memory_size: u64::MAX,
-        }
+        collect_executor_state(&self.executor)
     }
 }
 
@@ -295,10 +357,6 @@ impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan> TaskRunnerPool<T,
         let executor_server = self.executor_server.clone();
         tokio::spawn(async move {
             info!("Starting the task runner pool");
-            // Use a dedicated executor for CPU bound tasks so that the main tokio
-            // executor can still answer requests even when under load
-            // TODO make it configurable
-            let dedicated_executor = DedicatedExecutor::new("task_runner", 4);
             loop {
                 if let Some(task) = rx_task.recv().await {
                     if let Some(task_id) = &task.task_id {
@@ -308,8 +366,10 @@ impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan> TaskRunnerPool<T,
                         );
                         info!("Received task {:?}", &task_id_log);
 
+                        // the plan of the task runs on the cpu executor of the
+                        // executor, its status updates stay on this runtime
                         let server = executor_server.clone();
-                        dedicated_executor.spawn(async move {
+                        tokio::spawn(async move {
                             server.run_task(task).await.unwrap_or_else(|e| {
                                 error!(
                                     "Fail to run the task {:?} due to {:?}",
@@ -345,10 +405,28 @@ impl<T: 'static + AsLogicalPlan, U: 'static + AsExecutionPlan> ExecutorGrpc
         Ok(Response::new(LaunchTaskResult { success: true }))
     }
 
+    /// Drain the executor before it exits, see `decommission::drain`
     async fn stop_executor(
         &self,
         _request: Request<StopExecutorParams>,
     ) -> Result<Response<StopExecutorResult>, Status> {
-        todo!()
+        info!("Received stop_executor request");
+        tokio::spawn(decommission::drain(
+            self.executor.clone(),
+            self.scheduler.clone(),
+            self.scheduler_failover.clone(),
+        ));
+        Ok(Response::new(StopExecutorResult {}))
+    }
+
+    /// Abort the running tasks of cancelled jobs
+    async fn cancel_tasks(
+        &self,
+        request: Request<CancelTasksParams>,
+    ) -> Result<Response<CancelTasksResult>, Status> {
+        for job_id in request.into_inner().job_id {
+            self.executor.cancel_tasks(&job_id);
+        }
+        Ok(Response::new(CancelTasksResult {}))
     }
 }
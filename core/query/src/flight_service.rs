@@ -17,13 +17,20 @@
 
 //! Implementation of the Apache Arrow Flight protocol that wraps an executor.
 
+use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::fs::File;
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::sync::Arc;
 
 use crate::executor::Executor;
 use arrow_flight::SchemaAsIpc;
+use hetu_core::client::{decode_flight_data, tokens_match, FETCH_SHUFFLE_BLOCK_ACTION};
 use hetu_core::error::BallistaError;
+use hetu_core::execution_plans::sort_shuffle::{
+    decode_shuffle_block, is_sort_shuffle_file, read_shuffle_block,
+};
 use hetu_core::serde::decode_protobuf;
 use hetu_core::serde::scheduler::Action as BallistaAction;
 
@@ -33,12 +40,16 @@ use arrow_flight::{
     PutResult, SchemaResult, Ticket,
 };
 use datafusion::arrow::{
-    error::ArrowError, ipc::reader::FileReader, ipc::writer::IpcWriteOptions,
+    datatypes::{Schema, SchemaRef},
+    error::{ArrowError, Result as ArrowResult},
+    ipc::reader::FileReader,
+    ipc::writer::FileWriter,
+    ipc::writer::IpcWriteOptions,
     record_batch::RecordBatch,
 };
-use futures::{Stream, StreamExt};
+use datafusion::error::DataFusionError;
+use futures::Stream;
 use log::{info, warn};
-use std::io::{Read, Seek};
 use tokio::sync::mpsc::channel;
 use tokio::{
     sync::mpsc::{Receiver, Sender},
@@ -50,16 +61,39 @@ use tonic::{Request, Response, Status, Streaming};
 type FlightDataSender = Sender<Result<FlightData, Status>>;
 type FlightDataReceiver = Receiver<Result<FlightData, Status>>;
 
+/// Bytes of a shuffle block sent in one message of a do_action stream
+const SHUFFLE_BLOCK_CHUNK_SIZE: usize = 1024 * 1024;
+
 /// Service implementing the Apache Arrow Flight Protocol
 #[derive(Clone)]
 pub struct BallistaFlightService {
-    /// Executor
-    _executor: Arc<Executor>,
+    /// Executor, None for a shuffle service
+    _executor: Option<Arc<Executor>>,
+    /// Directory the pushed shuffle partitions are written to, only the shuffle services
+    /// accept them
+    shuffle_dir: Option<String>,
+    /// Auth token the executors push the shuffle partitions with, not checked if None
+    auth_token: Option<String>,
 }
 
 impl BallistaFlightService {
-    pub fn new(_executor: Arc<Executor>) -> Self {
-        Self { _executor }
+    pub fn new(executor: Arc<Executor>) -> Self {
+        Self {
+            _executor: Some(executor),
+            shuffle_dir: None,
+            auth_token: None,
+        }
+    }
+
+    /// Create a remote shuffle service, it keeps the shuffle partitions pushed by the
+    /// executors in its work dir and serves them to the readers like an executor does.
+    /// Only the executors presenting the auth token may push, anyone if it is empty
+    pub fn shuffle_service(work_dir: &str, auth_token: &str) -> Self {
+        Self {
+            _executor: None,
+            shuffle_dir: Some(work_dir.to_owned()),
+            auth_token: Some(auth_token.to_owned()).filter(|token| !token.is_empty()),
+        }
     }
 }
 
@@ -86,6 +120,35 @@ impl FlightService for BallistaFlightService {
             decode_protobuf(&ticket.ticket).map_err(|e| from_ballista_err(&e))?;
 
         match &action {
+            BallistaAction::FetchPartition {
+                path, partition_id, ..
+            } if is_sort_shuffle_file(path) => {
+                info!(
+                    "FetchPartition reading partition {} of {}",
+                    partition_id, path
+                );
+                let batches =
+                    read_sort_shuffle_partition(path.clone(), *partition_id).await?;
+                let schema =
+                    batches.first().map(|batch| batch.schema()).ok_or_else(|| {
+                        Status::not_found(format!(
+                            "Partition {} of {} is empty",
+                            partition_id, path
+                        ))
+                    })?;
+
+                let (tx, rx): (FlightDataSender, FlightDataReceiver) = channel(2);
+                task::spawn(async move {
+                    let batches = batches.into_iter().map(Ok);
+                    if let Err(e) = stream_flight_data(schema, batches, tx).await {
+                        warn!("Error streaming results: {:?}", e);
+                    }
+                });
+
+                Ok(Response::new(
+                    Box::pin(ReceiverStream::new(rx)) as Self::DoGetStream
+                ))
+            }
             BallistaAction::FetchPartition { path, .. } => {
                 info!("FetchPartition reading {}", &path);
                 let file = File::open(&path)
@@ -104,7 +167,8 @@ impl FlightService for BallistaFlightService {
                 // Arrow IPC reader does not implement Sync + Send so we need to use a channel
                 // to communicate
                 task::spawn(async move {
-                    if let Err(e) = stream_flight_data(reader, tx).await {
+                    let schema = reader.schema();
+                    if let Err(e) = stream_flight_data(schema, reader, tx).await {
                         warn!("Error streaming results: {:?}", e);
                     }
                 });
@@ -113,6 +177,9 @@ impl FlightService for BallistaFlightService {
                     Box::pin(ReceiverStream::new(rx)) as Self::DoGetStream
                 ))
             }
+            BallistaAction::PushPartition { .. } => Err(Status::invalid_argument(
+                "PushPartition is only supported by do_put",
+            )),
         }
     }
 
@@ -148,13 +215,95 @@ impl FlightService for BallistaFlightService {
         &self,
         request: Request<Streaming<FlightData>>,
     ) -> Result<Response<Self::DoPutStream>, Status> {
-        let mut request = request.into_inner();
-
-        while let Some(data) = request.next().await {
-            let _data = data?;
+        let shuffle_dir = match self.shuffle_dir.as_ref() {
+            Some(shuffle_dir) => shuffle_dir,
+            None => return Err(Status::unimplemented("do_put")),
+        };
+        let mut stream = request.into_inner();
+
+        // The first message holds the schema and the partition in its descriptor
+        let first = stream
+            .message()
+            .await?
+            .ok_or_else(|| Status::invalid_argument("Empty do_put stream"))?;
+        let descriptor = first
+            .flight_descriptor
+            .as_ref()
+            .ok_or_else(|| Status::invalid_argument("Missing flight descriptor"))?;
+        let (job_id, stage_id, partition_id, input_partition_id) =
+            match decode_protobuf(&descriptor.cmd).map_err(|e| from_ballista_err(&e))? {
+                BallistaAction::PushPartition {
+                    job_id,
+                    stage_id,
+                    partition_id,
+                    input_partition_id,
+                    auth_token,
+                } => {
+                    match self.auth_token.as_ref() {
+                        Some(expected) if !tokens_match(expected, &auth_token) => {
+                            warn!(
+                                "Rejecting a push of job {} with a wrong auth token",
+                                job_id
+                            );
+                            return Err(Status::unauthenticated(
+                                "The executor is not authenticated",
+                            ));
+                        }
+                        _ => {}
+                    }
+                    (job_id, stage_id, partition_id, input_partition_id)
+                }
+                _ => {
+                    return Err(Status::invalid_argument(
+                        "do_put only supports PushPartition",
+                    ))
+                }
+            };
+        if job_id.is_empty()
+            || job_id.contains(['/', '\\'])
+            || job_id == "."
+            || job_id == ".."
+        {
+            return Err(Status::invalid_argument(format!(
+                "Invalid job id {:?}",
+                job_id
+            )));
+        }
+        let schema = Arc::new(Schema::try_from(&first).map_err(|e| from_arrow_err(&e))?);
+
+        let mut path = PathBuf::from(shuffle_dir);
+        path.push(&job_id);
+        path.push(stage_id.to_string());
+        path.push(partition_id.to_string());
+        std::fs::create_dir_all(&path).map_err(|e| from_io_err(&e))?;
+        path.push(format!("data-{}.arrow", input_partition_id));
+        info!("PushPartition writing {:?}", path);
+
+        // The partition is written aside and renamed once complete, so a reader never sees
+        // a partial file, which is removed if the push fails
+        let partial_path = path.with_extension("arrow.partial");
+        let written = write_pushed_partition(&mut stream, schema, &partial_path)
+            .await
+            .and_then(|row_count| {
+                std::fs::rename(&partial_path, &path).map_err(|e| from_io_err(&e))?;
+                Ok(row_count)
+            });
+        match written {
+            Ok(row_count) => info!("PushPartition received {} rows", row_count),
+            Err(e) => {
+                if let Err(e) = std::fs::remove_file(&partial_path) {
+                    warn!("Fail to remove partial partition {:?}: {}", partial_path, e);
+                }
+                return Err(e);
+            }
         }
 
-        Err(Status::unimplemented("do_put"))
+        let result = PutResult {
+            app_metadata: path.to_string_lossy().into_owned().into_bytes(),
+        };
+        Ok(Response::new(
+            Box::pin(futures::stream::once(async { Ok(result) })) as Self::DoPutStream,
+        ))
     }
 
     async fn do_action(
@@ -163,10 +312,47 @@ impl FlightService for BallistaFlightService {
     ) -> Result<Response<Self::DoActionStream>, Status> {
         let action = request.into_inner();
 
-        let _action =
+        let decoded_action =
             decode_protobuf(&action.body.to_vec()).map_err(|e| from_ballista_err(&e))?;
 
-        Err(Status::unimplemented("do_action"))
+        match decoded_action {
+            BallistaAction::FetchPartition {
+                path, partition_id, ..
+            } if action.r#type == FETCH_SHUFFLE_BLOCK_ACTION => {
+                if !is_sort_shuffle_file(&path) {
+                    return Err(Status::invalid_argument(format!(
+                        "{} is not a sort based shuffle file",
+                        path
+                    )));
+                }
+                info!(
+                    "FetchShuffleBlock reading partition {} of {}",
+                    partition_id, path
+                );
+                let block =
+                    task::spawn_blocking(move || read_shuffle_block(&path, partition_id))
+                        .await
+                        .map_err(|e| Status::internal(format!("{:?}", e)))?
+                        .map_err(|e| from_datafusion_err(&e))?;
+
+                // The blocks are sent as they are, the client decompresses them
+                let chunks = block
+                    .chunks(SHUFFLE_BLOCK_CHUNK_SIZE)
+                    .map(|chunk| {
+                        Ok(arrow_flight::Result {
+                            body: chunk.to_vec(),
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                Ok(Response::new(
+                    Box::pin(futures::stream::iter(chunks)) as Self::DoActionStream
+                ))
+            }
+            _ => Err(Status::unimplemented(format!(
+                "do_action {}",
+                action.r#type
+            ))),
+        }
     }
 
     async fn list_actions(
@@ -200,19 +386,17 @@ fn create_flight_iter(
     )
 }
 
-async fn stream_flight_data<T>(
-    reader: FileReader<T>,
+async fn stream_flight_data(
+    schema: SchemaRef,
+    batches: impl Iterator<Item = ArrowResult<RecordBatch>>,
     tx: FlightDataSender,
-) -> Result<(), Status>
-where
-    T: Read + Seek,
-{
+) -> Result<(), Status> {
     let options = arrow::ipc::writer::IpcWriteOptions::default();
-    let schema_flight_data = SchemaAsIpc::new(reader.schema().as_ref(), &options).into();
+    let schema_flight_data = SchemaAsIpc::new(schema.as_ref(), &options).into();
     send_response(&tx, Ok(schema_flight_data)).await?;
 
     let mut row_count = 0;
-    for batch in reader {
+    for batch in batches {
         if let Ok(x) = &batch {
             row_count += x.num_rows();
         }
@@ -236,10 +420,141 @@ async fn send_response(
         .map_err(|e| Status::internal(format!("{:?}", e)))
 }
 
+// Write the batches of a do_put stream to an Arrow IPC file, return the number of rows
+async fn write_pushed_partition(
+    stream: &mut Streaming<FlightData>,
+    schema: SchemaRef,
+    path: &Path,
+) -> Result<usize, Status> {
+    let file = File::create(path).map_err(|e| from_io_err(&e))?;
+    let mut writer =
+        FileWriter::try_new(file, &schema).map_err(|e| from_arrow_err(&e))?;
+    let mut dictionaries_by_id = HashMap::new();
+    let mut row_count = 0;
+    while let Some(data) = stream.message().await? {
+        let batch = decode_flight_data(&data, schema.clone(), &mut dictionaries_by_id)
+            .map_err(|e| from_arrow_err(&e))?;
+        if let Some(batch) = batch {
+            row_count += batch.num_rows();
+            writer.write(&batch).map_err(|e| from_arrow_err(&e))?;
+        }
+    }
+    writer.finish().map_err(|e| from_arrow_err(&e))?;
+    Ok(row_count)
+}
+
 fn from_arrow_err(e: &ArrowError) -> Status {
     Status::internal(format!("ArrowError: {:?}", e))
 }
 
+fn from_io_err(e: &std::io::Error) -> Status {
+    Status::internal(format!("IoError: {:?}", e))
+}
+
+fn from_datafusion_err(e: &DataFusionError) -> Status {
+    Status::internal(format!("DataFusionError: {:?}", e))
+}
+
+// Read and decode a partition of a sort based shuffle file on a blocking thread
+async fn read_sort_shuffle_partition(
+    path: String,
+    partition_id: usize,
+) -> Result<Vec<RecordBatch>, Status> {
+    task::spawn_blocking(move || {
+        decode_shuffle_block(&read_shuffle_block(&path, partition_id)?)
+    })
+    .await
+    .map_err(|e| Status::internal(format!("{:?}", e)))?
+    .map_err(|e| from_datafusion_err(&e))
+}
+
 fn from_ballista_err(e: &hetu_core::error::BallistaError) -> Status {
     Status::internal(format!("Ballista Error: {:?}", e))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_flight::flight_service_server::FlightServiceServer;
+    use datafusion::arrow::array::{DictionaryArray, Int32Array};
+    use datafusion::arrow::datatypes::{DataType, Field, Int32Type};
+    use datafusion::physical_plan::common::collect;
+    use hetu_core::client::BallistaClient;
+    use tempfile::TempDir;
+    use tokio::net::TcpListener;
+    use tokio_stream::wrappers::TcpListenerStream;
+    use tonic::transport::Server;
+
+    #[tokio::test]
+    async fn test_push_and_fetch_partition() {
+        let shuffle_dir = TempDir::new().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let service = BallistaFlightService::shuffle_service(
+            shuffle_dir.path().to_str().unwrap(),
+            "secret",
+        );
+        tokio::spawn(
+            Server::builder()
+                .add_service(FlightServiceServer::new(service))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+
+        // A shuffle partition with a dictionary encoded column
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("a", DataType::Int32, false),
+            Field::new(
+                "b",
+                DataType::Dictionary(Box::new(DataType::Int32), Box::new(DataType::Utf8)),
+                false,
+            ),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![
+                Arc::new(Int32Array::from(vec![1, 2, 3])),
+                Arc::new(
+                    vec!["x", "y", "x"]
+                        .into_iter()
+                        .collect::<DictionaryArray<Int32Type>>(),
+                ),
+            ],
+        )
+        .unwrap();
+        let input_dir = TempDir::new().unwrap();
+        let input_path = input_dir.path().join("data.arrow");
+        let mut writer =
+            FileWriter::try_new(File::create(&input_path).unwrap(), &schema).unwrap();
+        writer.write(&batch).unwrap();
+        writer.finish().unwrap();
+        let input_path = input_path.to_str().unwrap();
+
+        let mut client = BallistaClient::try_new("127.0.0.1", port).await.unwrap();
+        // Only the executors presenting the auth token may push
+        assert!(client
+            .push_partition("job", 1, 0, 2, input_path, "wrong")
+            .await
+            .is_err());
+        assert!(!shuffle_dir.path().join("job").exists());
+
+        let path = client
+            .push_partition("job", 1, 0, 2, input_path, "secret")
+            .await
+            .unwrap();
+        assert!(path.ends_with("job/1/0/data-2.arrow"));
+        assert!(!Path::new(&path).with_extension("arrow.partial").exists());
+
+        let stream = client.fetch_partition("job", 1, 0, &path).await.unwrap();
+        let batches = collect(stream).await.unwrap();
+        let expected = vec![
+            "+---+---+",
+            "| a | b |",
+            "+---+---+",
+            "| 1 | x |",
+            "| 2 | y |",
+            "| 3 | x |",
+            "+---+---+",
+        ];
+        datafusion::assert_batches_eq!(expected, &batches);
+    }
+}
@@ -18,13 +18,16 @@
 #![doc = include_str!("../README.md")]
 
 pub mod collect;
+pub mod decommission;
 pub mod execution_loop;
 pub mod executor;
 pub mod executor_server;
 pub mod flight_service;
 pub mod metrics;
+pub mod resources;
+pub mod scheduler_failover;
 
-mod cpu_bound_executor;
+pub mod cpu_bound_executor;
 mod standalone;
 
 pub use standalone::new_standalone_executor;
@@ -35,6 +38,12 @@ pub mod protocol;
 pub mod session;
 pub mod utils;
 
+// the unit tests attribute their allocations to the memory trackers like the binary
+#[cfg(test)]
+#[global_allocator]
+static ALLOC: base::TrackingAllocator<std::alloc::System> =
+    base::TrackingAllocator::new(std::alloc::System);
+
 pub const HETU_QUERY_SERVICE_VERSION: &str = env!("CARGO_PKG_VERSION");
 
 pub fn print_version() {
@@ -43,17 +52,20 @@ pub fn print_version() {
 use log::info;
 
 use hetu_core::serde::protobuf::{
-    task_status, CompletedTask, FailedTask, PartitionId, ShuffleWritePartition,
-    TaskStatus,
+    task_status, CompletedTask, FailedTask, FetchPartitionError, OperatorMetricsSet,
+    PartitionId, ShuffleWritePartition, TaskStatus,
 };
 
 pub fn as_task_status(
-    execution_result: hetu_core::error::Result<Vec<ShuffleWritePartition>>,
+    execution_result: hetu_core::error::Result<(
+        Vec<ShuffleWritePartition>,
+        Vec<OperatorMetricsSet>,
+    )>,
     executor_id: String,
     task_id: PartitionId,
 ) -> TaskStatus {
     match execution_result {
-        Ok(partitions) => {
+        Ok((partitions, metrics)) => {
             info!("Task {:?} finished", task_id);
 
             TaskStatus {
@@ -61,6 +73,7 @@ pub fn as_task_status(
                 status: Some(task_status::Status::Completed(CompletedTask {
                     executor_id,
                     partitions,
+                    metrics,
                 })),
             }
         }
@@ -68,10 +81,18 @@ pub fn as_task_status(
             let error_msg = e.to_string();
             info!("Task {:?} failed: {}", task_id, error_msg);
 
+            let fetch_partition_error =
+                e.fetch_failure().map(|failure| FetchPartitionError {
+                    executor_id: failure.executor_id.clone(),
+                    job_id: failure.job_id.clone(),
+                    stage_id: failure.stage_id as u32,
+                    partition_id: failure.partition_id as u32,
+                });
             TaskStatus {
                 task_id: Some(task_id),
                 status: Some(task_status::Status::Failed(FailedTask {
                     error: format!("Task failed due to Tokio error: {}", error_msg),
+                    fetch_partition_error,
                 })),
             }
         }
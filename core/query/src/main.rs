@@ -22,45 +22,77 @@ use std::time::Duration as Core_Duration;
 
 use anyhow::{Context, Result};
 use arrow_flight::flight_service_server::FlightServiceServer;
-use hetu_query::{execution_loop, executor_server, HETU_QUERY_SERVICE_VERSION};
-use log::{error, info};
+use futures::StreamExt;
+use hetu_query::base::signal_stream;
+use hetu_query::{
+    decommission, execution_loop, executor_server, HETU_QUERY_SERVICE_VERSION,
+};
+use log::{error, info, warn};
 use tempfile::TempDir;
 use tokio::fs::ReadDir;
 use tokio::{fs, time};
-use tonic::transport::Server;
 use uuid::Uuid;
 
-use datafusion::execution::runtime_env::{RuntimeConfig, RuntimeEnv};
+use datafusion::execution::runtime_env::RuntimeEnv;
 use datafusion_proto::protobuf::LogicalPlanNode;
-use hetu_core::config::{BallistaConfig, TaskSchedulingPolicy};
+use hetu_core::client::set_executor_tls_config;
+use hetu_core::config::{
+    BallistaConfig, TaskSchedulingPolicy, BALLISTA_QUERY_MAX_EXECUTION_TIME,
+};
 use hetu_core::error::BallistaError;
+use hetu_core::object_store::register_object_stores;
 use hetu_core::serde::protobuf::{
-    executor_registration, scheduler_grpc_client::SchedulerGrpcClient,
-    ExecutorRegistration, PhysicalPlanNode,
+    executor_registration, ExecutorRegistration, PhysicalPlanNode,
 };
 use hetu_core::serde::scheduler::ExecutorSpecification;
 use hetu_core::serde::BallistaCodec;
 use hetu_core::BALLISTA_VERSION;
+use hetu_query::base::TrackingAllocator;
 use hetu_query::config::Config;
+use hetu_query::cpu_bound_executor::DedicatedExecutor;
 use hetu_query::executor::Executor;
 use hetu_query::flight_service::BallistaFlightService;
-use hetu_query::metrics::LoggingMetricsCollector;
+use hetu_query::metrics::{exporter, PrometheusMetricsCollector};
+use hetu_query::scheduler_failover::SchedulerFailover;
 
+// the allocations are tracked to attribute the memory to the runtimes and queries
 #[cfg(feature = "snmalloc")]
 #[global_allocator]
-static ALLOC: snmalloc_rs::SnMalloc = snmalloc_rs::SnMalloc;
+static ALLOC: TrackingAllocator<snmalloc_rs::SnMalloc> =
+    TrackingAllocator::new(snmalloc_rs::SnMalloc);
+
+#[cfg(not(feature = "snmalloc"))]
+#[global_allocator]
+static ALLOC: TrackingAllocator<std::alloc::System> =
+    TrackingAllocator::new(std::alloc::System);
 
 #[tokio::main]
 async fn main() -> Result<()> {
     env_logger::init();
 
     let conf: Config = Config::load()?;
+    // the executors and the shuffle service are reached over TLS from the first query on
+    if let Some(tls_config) = conf
+        .executor_tls_config()
+        .context("Could not load the executor TLS certificates")?
+    {
+        set_executor_tls_config(tls_config)?;
+    }
+    let server_tls_config = conf
+        .server_tls_config()
+        .context("Could not load the TLS certificates")?;
+    // the plans of the queries and tasks run on a thread pool apart from the I/O
+    let cpu_executor = DedicatedExecutor::new("hetu-cpu", conf.cpu_threads());
     // MySQL handler.
     {
-        println!("Config: {:?}", conf);
+        info!("Config: {:?}", conf);
         let config = BallistaConfig::builder()
             .set("ballista.shuffle.partitions", "2")
             .set("ballista.with_information_schema", "true")
+            .set(
+                BALLISTA_QUERY_MAX_EXECUTION_TIME,
+                &conf.query_max_execution_time.to_string(),
+            )
             .build()?;
 
         let context = Arc::new(
@@ -72,7 +104,8 @@ async fn main() -> Result<()> {
             .await?,
         );
 
-        let mut mysql_handler = MySQLHandler::create(conf.clone(), context.clone());
+        let mut mysql_handler =
+            MySQLHandler::create(conf.clone(), context.clone(), cpu_executor.clone());
         let listening = mysql_handler.start().await?;
         info!("Hetu Query Service v{} Rust MySQL Handler listening on {}, Usage: mysql -h {} -P {} -u root",
                      HETU_QUERY_SERVICE_VERSION,
@@ -81,6 +114,26 @@ async fn main() -> Result<()> {
                      conf.mysql_handler_port);
     }
 
+    if conf.metrics_port > 0 {
+        let metrics_addr = format!("{}:{}", conf.bind_host, conf.metrics_port);
+        let metrics_addr = metrics_addr
+            .parse()
+            .with_context(|| format!("Could not parse address: {}", metrics_addr))?;
+        tokio::spawn(async move {
+            if let Err(e) = exporter::serve(metrics_addr).await {
+                error!("Metrics server failed: {}", e);
+            }
+        });
+    }
+
+    let scheduler_urls = conf.scheduler_urls();
+    let scheduler_tls_config = conf
+        .scheduler_tls_config()
+        .context("Could not load the scheduler TLS certificates")?;
+    let object_store_settings = conf.object_store_settings()?;
+    let runtime_config = conf.runtime_config()?;
+    let executor_memory_limit = conf.executor_memory_limit;
+    let shuffle_service_host = conf.shuffle_service_host.clone();
     let external_host = Some(conf.external_host);
     let bind_host = conf.bind_host;
     let port = conf.bind_port;
@@ -91,10 +144,6 @@ async fn main() -> Result<()> {
         .parse()
         .with_context(|| format!("Could not parse address: {}", addr))?;
 
-    let scheduler_host = conf.scheduler_host;
-    let scheduler_port = conf.scheduler_port;
-    let scheduler_url = format!("http://{}:{}", scheduler_host, scheduler_port);
-
     let work_dir = if conf.work_dir.is_empty() {
         TempDir::new()?
             .into_path()
@@ -108,6 +157,41 @@ async fn main() -> Result<()> {
     info!("work_dir: {}", work_dir);
     info!("concurrent_tasks: {}", conf.concurrent_tasks);
 
+    let cleanup_ttl = conf.executor_cleanup_ttl;
+
+    if conf.executor_cleanup_enable {
+        let mut interval_time =
+            time::interval(Core_Duration::from_secs(conf.executor_cleanup_interval));
+        let work_dir = work_dir.clone();
+        tokio::spawn(async move {
+            loop {
+                interval_time.tick().await;
+                if let Err(e) =
+                    clean_shuffle_data_loop(&work_dir, cleanup_ttl as i64).await
+                {
+                    error!("Ballista executor fail to clean_shuffle_data {:?}", e)
+                }
+            }
+        });
+    }
+
+    // A shuffle service only keeps and serves the shuffle partitions of the executors
+    if conf.shuffle_service_enable {
+        let service =
+            BallistaFlightService::shuffle_service(&work_dir, &conf.executor_auth_token);
+        let server = FlightServiceServer::new(service);
+        info!(
+            "Ballista v{} Rust Shuffle Service listening on {:?}",
+            BALLISTA_VERSION, addr
+        );
+        executor_server::server_builder(server_tls_config)?
+            .add_service(server)
+            .serve(addr)
+            .await
+            .context("Could not start shuffle service")?;
+        return Ok(());
+    }
+
     let executor_meta = ExecutorRegistration {
         id: Uuid::new_v4().to_string(), // assign this executor a unique ID
         optional_host: external_host
@@ -121,25 +205,45 @@ async fn main() -> Result<()> {
             }
             .into(),
         ),
+        auth_token: conf.executor_auth_token.clone(),
     };
 
-    let config = RuntimeConfig::new().with_temp_file_path(work_dir.clone());
+    if executor_memory_limit > 0 {
+        info!(
+            "Executor tasks share {} bytes of memory, spilling to {}",
+            executor_memory_limit, work_dir
+        );
+    }
+    // the sorts spill to the work dir, the hash joins and final aggregations
+    // reserve the memory they keep
+    let config = runtime_config.with_temp_file_path(work_dir.clone());
     let runtime = Arc::new(RuntimeEnv::new(config).map_err(|_| {
         BallistaError::Internal("Failed to init Executor RuntimeEnv".to_owned())
     })?);
+    register_object_stores(&runtime, &object_store_settings)?;
 
-    let metrics_collector = Arc::new(LoggingMetricsCollector::default());
+    let metrics_collector = Arc::new(PrometheusMetricsCollector::default());
 
-    let executor = Arc::new(Executor::new(
-        executor_meta,
-        &work_dir,
-        runtime,
-        metrics_collector,
-    ));
+    let mut executor =
+        Executor::new(executor_meta, &work_dir, runtime, metrics_collector)
+            .with_object_store_settings(object_store_settings)
+            .with_s3_credentials(conf.s3_credentials()?)
+            .with_query_memory_limit(conf.query_memory_limit)
+            .with_cpu_executor(cpu_executor);
+    if !shuffle_service_host.is_empty() {
+        info!(
+            "Pushing the shuffle partitions to {}:{}",
+            shuffle_service_host, conf.shuffle_service_port
+        );
+        executor = executor
+            .with_shuffle_service(shuffle_service_host, conf.shuffle_service_port);
+    }
+    let executor = Arc::new(executor);
 
-    let scheduler = SchedulerGrpcClient::connect(scheduler_url)
-        .await
-        .context("Could not connect to scheduler")?;
+    let (scheduler, scheduler_failover) =
+        SchedulerFailover::connect(scheduler_urls, scheduler_tls_config)
+            .await
+            .context("Could not connect to scheduler")?;
 
     let default_codec: BallistaCodec<LogicalPlanNode, PhysicalPlanNode> =
         BallistaCodec::default();
@@ -150,34 +254,20 @@ async fn main() -> Result<()> {
             _ => hetu_core::config::TaskSchedulingPolicy::PullStaged,
         };
 
-    let cleanup_ttl = conf.executor_cleanup_ttl;
-
-    if conf.executor_cleanup_enable {
-        let mut interval_time =
-            time::interval(Core_Duration::from_secs(conf.executor_cleanup_interval));
-        tokio::spawn(async move {
-            loop {
-                interval_time.tick().await;
-                if let Err(e) =
-                    clean_shuffle_data_loop(&work_dir, cleanup_ttl as i64).await
-                {
-                    error!("Ballista executor fail to clean_shuffle_data {:?}", e)
-                }
-            }
-        });
-    }
-
     match scheduler_policy {
         TaskSchedulingPolicy::PushStaged => {
             tokio::spawn(executor_server::startup(
-                scheduler,
+                scheduler.clone(),
+                scheduler_failover.clone(),
                 executor.clone(),
                 default_codec,
+                server_tls_config.clone(),
             ));
         }
         _ => {
             tokio::spawn(execution_loop::poll_loop(
-                scheduler,
+                scheduler.clone(),
+                scheduler_failover.clone(),
                 executor.clone(),
                 default_codec,
             ));
@@ -192,12 +282,42 @@ async fn main() -> Result<()> {
             "Ballista v{} Rust Executor listening on {:?}",
             BALLISTA_VERSION, addr
         );
-        let server_future =
-            tokio::spawn(Server::builder().add_service(server).serve(addr));
-        server_future
-            .await
-            .context("Tokio error")?
-            .context("Could not start executor server")?;
+        let server_future = tokio::spawn(
+            executor_server::server_builder(server_tls_config)?
+                .add_service(server)
+                .serve(addr),
+        );
+
+        // A terminated executor drains itself first so that its shuffle files are not
+        // lost, it exits at once on a second signal
+        let mut signals = signal_stream()?;
+        let draining_executor = executor.clone();
+        tokio::spawn(async move {
+            let mut draining = false;
+            while signals.next().await.is_some() {
+                if draining {
+                    warn!("Exiting without waiting for the executor to be drained");
+                    std::process::exit(1);
+                }
+                draining = true;
+                tokio::spawn(decommission::drain(
+                    draining_executor.clone(),
+                    scheduler.clone(),
+                    scheduler_failover.clone(),
+                ));
+            }
+        });
+
+        tokio::select! {
+            result = server_future => {
+                result
+                    .context("Tokio error")?
+                    .context("Could not start executor server")?;
+            }
+            _ = executor.wait_drained() => {
+                info!("Executor drained, shutting down");
+            }
+        }
     }
 
     Ok(())
@@ -0,0 +1,426 @@
+// Copyright 2021 HetuDB.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Prometheus metrics of hetu-query, served in the text format on `/metrics`.
+
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use datafusion::physical_plan::metrics::MetricsSet;
+use datafusion::physical_plan::ExecutionPlan;
+use hetu_core::execution_plans::{ShuffleReaderExec, ShuffleWriterExec};
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use log::{error, info};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+
+use crate::base::{ProcessList, RuntimeTracker};
+use crate::metrics::ExecutorMetricsCollector;
+
+static METRICS: Lazy<QueryMetrics> = Lazy::new(QueryMetrics::new);
+
+struct QueryMetrics {
+    registry: Registry,
+    tasks: IntCounterVec,
+    task_duration: Histogram,
+    output_rows: IntCounter,
+    shuffle_written_bytes: IntCounter,
+    shuffle_fetched_bytes: IntCounter,
+    shuffle_local_bytes: IntCounter,
+    shuffle_remote_bytes: IntCounter,
+    spills: IntCounter,
+    spilled_bytes: IntCounter,
+    runtime_memory: IntGaugeVec,
+    query_memory: IntGaugeVec,
+    // runtime name -> tracker, the dropped runtimes are removed when gathering
+    runtime_trackers: Mutex<Vec<(String, Weak<RuntimeTracker>)>>,
+}
+
+impl QueryMetrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("hetu_query".to_owned()), None)
+            .expect("Invalid metrics prefix");
+        let tasks = IntCounterVec::new(
+            Opts::new("tasks_total", "Number of the executed tasks"),
+            &["status"],
+        )
+        .unwrap();
+        let task_duration = Histogram::with_opts(
+            HistogramOpts::new("task_duration_seconds", "Execution time of the tasks")
+                .buckets(prometheus::exponential_buckets(0.01, 2.0, 16).unwrap()),
+        )
+        .unwrap();
+        let output_rows = IntCounter::new(
+            "task_output_rows_total",
+            "Number of the rows written by the tasks",
+        )
+        .unwrap();
+        let shuffle_written_bytes = IntCounter::new(
+            "shuffle_written_bytes_total",
+            "Number of the bytes written to the shuffle files",
+        )
+        .unwrap();
+        let shuffle_fetched_bytes = IntCounter::new(
+            "shuffle_fetched_bytes_total",
+            "Number of the shuffle bytes fetched from the executors",
+        )
+        .unwrap();
+        let shuffle_local_bytes = IntCounter::new(
+            "shuffle_local_bytes_total",
+            "Number of the shuffle bytes read from the local work dir",
+        )
+        .unwrap();
+        let shuffle_remote_bytes = IntCounter::new(
+            "shuffle_remote_bytes_total",
+            "Number of the shuffle bytes fetched from the other executors",
+        )
+        .unwrap();
+        let spills = IntCounter::new(
+            "task_spills_total",
+            "Number of the spills of the operators out of memory",
+        )
+        .unwrap();
+        let spilled_bytes = IntCounter::new(
+            "task_spilled_bytes_total",
+            "Number of the bytes spilled to disk by the operators",
+        )
+        .unwrap();
+        let runtime_memory = IntGaugeVec::new(
+            Opts::new(
+                "runtime_memory_bytes",
+                "Memory tracked by the runtime trackers",
+            ),
+            &["runtime"],
+        )
+        .unwrap();
+        let query_memory = IntGaugeVec::new(
+            Opts::new(
+                "query_memory_bytes",
+                "Memory used by the queries running in the process",
+            ),
+            &["query"],
+        )
+        .unwrap();
+
+        registry.register(Box::new(tasks.clone())).unwrap();
+        registry.register(Box::new(task_duration.clone())).unwrap();
+        registry.register(Box::new(output_rows.clone())).unwrap();
+        registry
+            .register(Box::new(shuffle_written_bytes.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(shuffle_fetched_bytes.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(shuffle_local_bytes.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(shuffle_remote_bytes.clone()))
+            .unwrap();
+        registry.register(Box::new(spills.clone())).unwrap();
+        registry.register(Box::new(spilled_bytes.clone())).unwrap();
+        registry.register(Box::new(runtime_memory.clone())).unwrap();
+        registry.register(Box::new(query_memory.clone())).unwrap();
+
+        Self {
+            registry,
+            tasks,
+            task_duration,
+            output_rows,
+            shuffle_written_bytes,
+            shuffle_fetched_bytes,
+            shuffle_local_bytes,
+            shuffle_remote_bytes,
+            spills,
+            spilled_bytes,
+            runtime_memory,
+            query_memory,
+            runtime_trackers: Mutex::new(vec![]),
+        }
+    }
+
+    fn update_runtime_memory(&self) {
+        self.runtime_memory.reset();
+        let mut runtime_trackers = self.runtime_trackers.lock();
+        runtime_trackers.retain(|(name, tracker)| match tracker.upgrade() {
+            Some(tracker) => {
+                self.runtime_memory
+                    .with_label_values(&[name])
+                    .add(tracker.get_memory_tracker().get_memory_usage());
+                true
+            }
+            None => false,
+        });
+    }
+
+    fn update_query_memory(&self) {
+        // the finished queries are not reported any more
+        self.query_memory.reset();
+        for process in ProcessList::global().processes() {
+            self.query_memory
+                .with_label_values(&[&process.query_id])
+                .set(process.memory_usage);
+        }
+    }
+}
+
+/// Report the memory tracked by a runtime as long as the runtime is alive
+pub fn register_runtime_tracker(name: &str, tracker: &Arc<RuntimeTracker>) {
+    METRICS
+        .runtime_trackers
+        .lock()
+        .push((name.to_owned(), Arc::downgrade(tracker)));
+}
+
+/// Memory tracked by all the alive runtimes in bytes
+pub fn tracked_memory() -> i64 {
+    METRICS
+        .runtime_trackers
+        .lock()
+        .iter()
+        .filter_map(|(_, tracker)| tracker.upgrade())
+        .map(|tracker| tracker.get_memory_tracker().get_memory_usage())
+        .sum()
+}
+
+/// Encode all the metrics in the Prometheus text format
+pub fn gather() -> Vec<u8> {
+    METRICS.update_runtime_memory();
+    METRICS.update_query_memory();
+    let mut buffer = vec![];
+    if let Err(e) = TextEncoder::new().encode(&METRICS.registry.gather(), &mut buffer) {
+        error!("Fail to encode the metrics: {}", e);
+    }
+    buffer
+}
+
+async fn handle(request: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let response = if request.uri().path() == "/metrics" {
+        Response::builder()
+            .header(CONTENT_TYPE, TextEncoder::new().format_type())
+            .body(Body::from(gather()))
+    } else {
+        Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())
+    };
+    Ok(response.unwrap())
+}
+
+/// Serve the metrics on `/metrics` until the server fails
+pub async fn serve(addr: SocketAddr) -> hyper::Result<()> {
+    info!("Hetu query metrics listening on {}", addr);
+    let make_service =
+        make_service_fn(|_| async { Ok::<_, Infallible>(service_fn(handle)) });
+    Server::bind(&addr).serve(make_service).await
+}
+
+fn sum_metric(metrics: Option<MetricsSet>, name: &str) -> u64 {
+    metrics
+        .and_then(|metrics| metrics.sum_by_name(name))
+        .map(|value| value.as_usize() as u64)
+        .unwrap_or_default()
+}
+
+/// Sum of a metric of all the operators in a stage plan
+fn plan_metric(plan: &dyn ExecutionPlan, name: &str) -> u64 {
+    sum_metric(plan.metrics(), name)
+        + plan
+            .children()
+            .iter()
+            .map(|child| plan_metric(child.as_ref(), name))
+            .sum::<u64>()
+}
+
+/// Sum of a metric of the shuffle readers in a stage plan
+fn shuffle_reader_metric(plan: &dyn ExecutionPlan, name: &str) -> u64 {
+    let value = if plan.as_any().is::<ShuffleReaderExec>() {
+        sum_metric(plan.metrics(), name)
+    } else {
+        0
+    };
+    value
+        + plan
+            .children()
+            .iter()
+            .map(|child| shuffle_reader_metric(child.as_ref(), name))
+            .sum::<u64>()
+}
+
+/// Implementation of `ExecutorMetricsCollector` which feeds the Prometheus metrics.
+#[derive(Default)]
+pub struct PrometheusMetricsCollector {}
+
+impl ExecutorMetricsCollector for PrometheusMetricsCollector {
+    fn record_stage(
+        &self,
+        _job_id: &str,
+        _stage_id: usize,
+        _partition: usize,
+        plan: ShuffleWriterExec,
+    ) {
+        METRICS
+            .output_rows
+            .inc_by(sum_metric(plan.metrics(), "output_rows"));
+        METRICS
+            .shuffle_written_bytes
+            .inc_by(sum_metric(plan.metrics(), "output_bytes"));
+        METRICS
+            .shuffle_fetched_bytes
+            .inc_by(shuffle_reader_metric(&plan, "fetched_bytes"));
+        METRICS
+            .shuffle_local_bytes
+            .inc_by(shuffle_reader_metric(&plan, "local_bytes"));
+        METRICS
+            .shuffle_remote_bytes
+            .inc_by(shuffle_reader_metric(&plan, "remote_bytes"));
+        METRICS.spills.inc_by(plan_metric(&plan, "spill_count"));
+        METRICS
+            .spilled_bytes
+            .inc_by(plan_metric(&plan, "spilled_bytes"));
+    }
+
+    fn record_task(
+        &self,
+        _job_id: &str,
+        _stage_id: usize,
+        _partition: usize,
+        elapsed: Duration,
+        succeeded: bool,
+    ) {
+        let status = if succeeded { "succeeded" } else { "failed" };
+        METRICS.tasks.with_label_values(&[status]).inc();
+        METRICS.task_duration.observe(elapsed.as_secs_f64());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use datafusion::arrow::array::UInt32Array;
+    use datafusion::arrow::compute::SortOptions;
+    use datafusion::arrow::datatypes::{DataType, Field, Schema};
+    use datafusion::arrow::record_batch::RecordBatch;
+    use datafusion::error::Result;
+    use datafusion::execution::memory_manager::MemoryManagerConfig;
+    use datafusion::execution::runtime_env::{RuntimeConfig, RuntimeEnv};
+    use datafusion::physical_plan::expressions::{col, PhysicalSortExpr};
+    use datafusion::physical_plan::memory::MemoryExec;
+    use datafusion::physical_plan::sorts::sort::SortExec;
+    use datafusion::prelude::{SessionConfig, SessionContext};
+    use hetu_core::execution_plans::ShuffleWriterExec;
+    use hetu_core::utils::collect_plan_metrics;
+    use tempfile::TempDir;
+
+    use crate::base::{ProcessList, RuntimeTracker};
+    use crate::metrics::exporter::{
+        gather, plan_metric, register_runtime_tracker, PrometheusMetricsCollector,
+        METRICS,
+    };
+    use crate::metrics::ExecutorMetricsCollector;
+
+    #[test]
+    fn test_gather() {
+        let collector = PrometheusMetricsCollector::default();
+        collector.record_task("job", 1, 0, Duration::from_millis(20), true);
+        let tracker = RuntimeTracker::create();
+        tracker.get_memory_tracker().alloc_memory(1024);
+        register_runtime_tracker("test-runtime", &tracker);
+
+        let text = String::from_utf8(gather()).unwrap();
+        assert!(text.contains("hetu_query_tasks_total{status=\"succeeded\"}"));
+        assert!(text
+            .contains("hetu_query_runtime_memory_bytes{runtime=\"test-runtime\"} 1024"));
+
+        let process = ProcessList::global().enter("test-query", "select 1", 0);
+        process.tracker().alloc_memory(2048);
+        let text = String::from_utf8(gather()).unwrap();
+        assert!(text.contains("hetu_query_query_memory_bytes{query=\"test-query\"} 2048"));
+        drop(process);
+
+        // A dropped runtime is not reported any more
+        drop(tracker);
+        let text = String::from_utf8(gather()).unwrap();
+        assert!(!text.contains("test-runtime"));
+        assert!(!text.contains("test-query"));
+    }
+
+    #[tokio::test]
+    async fn test_sort_spill() -> Result<()> {
+        let work_dir = TempDir::new()?;
+        let schema =
+            Arc::new(Schema::new(vec![Field::new("a", DataType::UInt32, false)]));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![Arc::new(UInt32Array::from(
+                (0..1024).rev().collect::<Vec<u32>>(),
+            ))],
+        )?;
+        let input = Arc::new(MemoryExec::try_new(
+            &[vec![batch; 64]],
+            schema.clone(),
+            None,
+        )?);
+        let sort = Arc::new(SortExec::try_new(
+            vec![PhysicalSortExpr {
+                expr: col("a", &schema)?,
+                options: SortOptions::default(),
+            }],
+            input,
+        )?);
+        let writer = ShuffleWriterExec::try_new(
+            "job".to_owned(),
+            1,
+            sort,
+            work_dir.path().to_str().unwrap().to_owned(),
+            None,
+        )?;
+
+        // the 256KB of input don't fit in the 64KB pool
+        let config = RuntimeConfig::new()
+            .with_memory_manager(MemoryManagerConfig::try_new_limit(64 * 1024, 1.0)?)
+            .with_temp_file_path(work_dir.path());
+        let runtime = Arc::new(RuntimeEnv::new(config)?);
+        let task_ctx =
+            SessionContext::with_config_rt(SessionConfig::new(), runtime).task_ctx();
+        writer.execute_shuffle_write(0, task_ctx).await?;
+
+        let spills = plan_metric(&writer, "spill_count");
+        assert!(spills > 0);
+        let operators = collect_plan_metrics(&writer);
+        let sort_metrics = operators
+            .iter()
+            .find(|o| o.operator.starts_with("SortExec"))
+            .expect("sort metrics");
+        assert!(sort_metrics
+            .metrics
+            .iter()
+            .any(|m| m.name == "spill_count" && m.value == spills));
+
+        let reported = METRICS.spills.get();
+        PrometheusMetricsCollector::default().record_stage("job", 1, 0, writer);
+        assert!(METRICS.spills.get() >= reported + spills);
+        Ok(())
+    }
+}
@@ -15,9 +15,15 @@
 // specific language governing permissions and limitations
 // under the License.
 
+use std::time::Duration;
+
 use datafusion::physical_plan::display::DisplayableExecutionPlan;
 use hetu_core::execution_plans::ShuffleWriterExec;
 
+pub mod exporter;
+
+pub use exporter::PrometheusMetricsCollector;
+
 /// `ExecutorMetricsCollector` records metrics for `ShuffleWriteExec`
 /// after they are executed.
 ///
@@ -32,6 +38,17 @@ pub trait ExecutorMetricsCollector: Send + Sync {
         partition: usize,
         plan: ShuffleWriterExec,
     );
+
+    /// Record the execution time of a task, whether it succeeded or not
+    fn record_task(
+        &self,
+        _job_id: &str,
+        _stage_id: usize,
+        _partition: usize,
+        _elapsed: Duration,
+        _succeeded: bool,
+    ) {
+    }
 }
 
 /// Implementation of `ExecutorMetricsCollector` which logs the completed
@@ -12,6 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+mod idle_timeout;
 mod mysql_handler;
 
 pub use self::mysql_handler::MySQLHandler;
@@ -21,28 +21,122 @@
 
 use futures_util::future::{AbortHandle, AbortRegistration, Abortable};
 use futures_util::StreamExt;
-use log::{error, info};
+use log::{error, warn};
+use std::collections::HashMap;
 use std::future::Future;
 use std::io;
 use std::marker::PhantomData;
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
-use crate::base::{Runtime, Thread, TrySpawn};
+use crate::base::ProcessList;
 use crate::config::Config;
+use crate::cpu_bound_executor::DedicatedExecutor;
+use crate::protocol::idle_timeout::IdleTimeoutStream;
 use crate::session::HetuContext;
 use crate::utils::DFQueryResultWriter;
+use datafusion::arrow::array::{Int64Array, StringArray, UInt64Array};
+use datafusion::arrow::datatypes::{DataType, Field, Schema};
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::error::DataFusionError;
+use datafusion::physical_plan::collect;
+use hetu_core::config::{
+    BallistaConfig, BALLISTA_QUERY_MAX_EXECUTION_TIME, BALLISTA_QUERY_MAX_RESULT_BYTES,
+    BALLISTA_QUERY_MAX_RESULT_ROWS,
+};
 use hetu_error::{HetuError, Result};
 use hetu_mywire::*;
+use tokio::io::AsyncWriteExt;
 use tokio::net::TcpStream;
+use tokio::sync::Semaphore;
 use tokio::task::JoinHandle;
 use tokio_stream::wrappers::TcpListenerStream;
 
+static NEXT_QUERY_ID: AtomicU64 = AtomicU64::new(1);
+static NEXT_CONNECTION_ID: AtomicU32 = AtomicU32::new(1);
+
 struct Backend<W: std::io::Write> {
     ctx: Arc<HetuContext>,
+    connection_id: u32,
+    query_memory_limit: usize,
+    cpu_executor: DedicatedExecutor,
+    /// The settings of the session overriding the ones of the context
+    settings: HashMap<String, String>,
     generic_hold: PhantomData<W>,
 }
 
+fn is_show_processlist(sql: &str) -> bool {
+    let words = sql
+        .trim()
+        .trim_end_matches(';')
+        .split_whitespace()
+        .map(|word| word.to_ascii_lowercase())
+        .collect::<Vec<_>>();
+    words == ["show", "processlist"] || words == ["show", "full", "processlist"]
+}
+
+/// The setting of a `SET [SESSION] name = value` statement of one of the session
+/// variables of the queries, named like their MySQL counterparts
+fn parse_set_variable(sql: &str) -> Option<(&'static str, String)> {
+    let (head, value) = sql.trim().trim_end_matches(';').split_once('=')?;
+    let words = head
+        .split_whitespace()
+        .map(|word| word.to_ascii_lowercase())
+        .collect::<Vec<_>>();
+    let name = match words.as_slice() {
+        [set, name] if set == "set" => name,
+        [set, session, name] if set == "set" && session == "session" => name,
+        _ => return None,
+    };
+    let key = match name.trim_start_matches("@@").trim_start_matches("session.") {
+        "max_execution_time" => BALLISTA_QUERY_MAX_EXECUTION_TIME,
+        "max_result_rows" => BALLISTA_QUERY_MAX_RESULT_ROWS,
+        "max_result_bytes" => BALLISTA_QUERY_MAX_RESULT_BYTES,
+        _ => return None,
+    };
+    Some((key, value.trim().trim_matches('\'').to_owned()))
+}
+
+/// The queries running in the process with their memory usage
+fn processlist_batch() -> Result<RecordBatch> {
+    let processes = ProcessList::global().processes();
+    let schema = Arc::new(Schema::new(vec![
+        Field::new("Id", DataType::Utf8, false),
+        Field::new("Time", DataType::UInt64, false),
+        Field::new("Tasks", DataType::UInt64, false),
+        Field::new("Memory_usage", DataType::Int64, false),
+        Field::new("Memory_limit", DataType::Int64, false),
+        Field::new("Info", DataType::Utf8, false),
+    ]));
+    let batch = RecordBatch::try_new(
+        schema,
+        vec![
+            Arc::new(StringArray::from_iter_values(
+                processes.iter().map(|p| &p.query_id),
+            )),
+            Arc::new(UInt64Array::from_iter_values(
+                processes.iter().map(|p| p.elapsed.as_secs()),
+            )),
+            Arc::new(UInt64Array::from_iter_values(
+                processes.iter().map(|p| p.tasks as u64),
+            )),
+            Arc::new(Int64Array::from_iter_values(
+                processes.iter().map(|p| p.memory_usage),
+            )),
+            Arc::new(Int64Array::from_iter_values(
+                processes.iter().map(|p| p.memory_limit),
+            )),
+            Arc::new(StringArray::from_iter_values(
+                processes.iter().map(|p| &p.info),
+            )),
+        ],
+    )
+    .map_err(DataFusionError::from)?;
+    Ok(batch)
+}
+
 #[async_trait::async_trait]
 impl<W: io::Write + Send + Sync> AsyncMysqlShim<W> for Backend<W> {
     type Error = HetuError;
@@ -90,39 +184,72 @@ impl<W: io::Write + Send + Sync> AsyncMysqlShim<W> for Backend<W> {
         // TODO: sql command dispatch
         let mut writer = DFQueryResultWriter::create(results);
 
-        // create a plan to run a SQL query
-        let df_result = self.ctx.sql(sql).await;
-        match df_result {
-            Ok(df) => {
-                let records_result = df.collect().await;
-                match records_result {
-                    Ok(records) => {
-                        let result = Ok((records, String::from("ExtraInfo")));
-                        let mut write_result = writer.write(result);
-                        if let Err(hetu_error) = write_result {
-                            write_result = Err(hetu_error);
-                        }
-                        write_result
-                    }
-                    Err(err) => {
-                        let result = Err(HetuError::from(err));
-                        println!("DataFusionError: {}", result.as_ref().err().unwrap());
-                        let mut write_result = writer.write(result);
-                        if let Err(hetu_error) = write_result {
-                            write_result = Err(hetu_error);
-                        }
-                        write_result
-                    }
-                }
-            }
+        if is_show_processlist(sql) {
+            let result = processlist_batch().map(|batch| (vec![batch], String::new()));
+            return writer.write(result);
+        }
+
+        if let Some((key, value)) = parse_set_variable(sql) {
+            let mut settings = self.settings.clone();
+            settings.insert(key.to_owned(), value);
+            // validate the value before the next queries use it
+            let result = BallistaConfig::with_settings(settings.clone())
+                .map(|_| {
+                    self.settings = settings;
+                    (vec![], String::new())
+                })
+                .map_err(|e| HetuError::External(Box::new(e)));
+            return writer.write(result);
+        }
+
+        // the memory of the query is attributed to it in the process list
+        let query_id = format!("mysql-{}", NEXT_QUERY_ID.fetch_add(1, Ordering::Relaxed));
+        let process = Arc::new(ProcessList::global().enter(
+            &query_id,
+            sql,
+            self.query_memory_limit as i64,
+        ));
+        // plan the SQL query on the cpu executor
+        let ctx = self.ctx.clone();
+        let sql = sql.to_owned();
+        let settings = self.settings.clone();
+        let planning = process.clone();
+        let plan = self
+            .cpu_executor
+            .spawn(async move {
+                planning
+                    .run(async move {
+                        ctx.sql_with_settings(&sql, &settings)
+                            .await?
+                            .create_physical_plan()
+                            .await
+                    })
+                    .await
+            })
+            .await
+            .map_err(|_| {
+                HetuError::Internal(format!(
+                    "Query {} was dropped by the cpu executor",
+                    query_id
+                ))
+            })
+            .and_then(|result| result)
+            .and_then(|result| result.map_err(HetuError::from))
+            .and_then(|plan| process.track_plan(plan).map_err(HetuError::from));
+        // the distributed plan mostly waits for the scheduler and the executors, it
+        // runs on the I/O runtime of the connection
+        let records_result = match plan {
+            Ok(plan) => process
+                .run(collect(plan, self.ctx.task_ctx()))
+                .await
+                .and_then(|result| result.map_err(HetuError::from)),
+            Err(e) => Err(e),
+        };
+        match records_result {
+            Ok(records) => writer.write(Ok((records, String::from("ExtraInfo")))),
             Err(err) => {
-                let result = Err(HetuError::from(err));
-                println!("DataFusionError: {}", result.as_ref().err().unwrap());
-                let mut write_result = writer.write(result);
-                if let Err(hetu_error) = write_result {
-                    write_result = Err(hetu_error);
-                }
-                write_result
+                error!("Query error: {}", err);
+                writer.write(Err(err))
             }
         }
     }
@@ -144,7 +271,7 @@ impl<W: io::Write + Send + Sync> AsyncMysqlShim<W> for Backend<W> {
     }
 
     fn connect_id(&self) -> u32 {
-        u32::from_le_bytes([0x08, 0x00, 0x00, 0x00])
+        self.connection_id
     }
 
     fn default_auth_plugin(&self) -> &str {
@@ -170,22 +297,54 @@ impl<W: io::Write + Send + Sync> AsyncMysqlShim<W> for Backend<W> {
 
 pub type ListeningStream = Abortable<TcpListenerStream>;
 
-pub struct MySQLHandler {
+/// What the connections of the handler share
+#[derive(Clone)]
+struct Connections {
     context: Arc<HetuContext>,
+    query_memory_limit: usize,
+    cpu_executor: DedicatedExecutor,
+    /// A permit per open connection
+    permits: Arc<Semaphore>,
+    max_connections: usize,
+    idle_timeout: Option<Duration>,
+}
+
+pub struct MySQLHandler {
     hostname: String,
     port: i32,
+    connections: Connections,
     abort_handle: AbortHandle,
     abort_registration: Option<AbortRegistration>,
     join_handle: Option<JoinHandle<()>>,
 }
 
 impl MySQLHandler {
-    pub fn create(conf: Config, context: Arc<HetuContext>) -> MySQLHandler {
+    pub fn create(
+        conf: Config,
+        context: Arc<HetuContext>,
+        cpu_executor: DedicatedExecutor,
+    ) -> MySQLHandler {
         let (abort_handle, registration) = AbortHandle::new_pair();
+        let max_connections = if conf.mysql_handler_max_connections == 0 {
+            Semaphore::MAX_PERMITS
+        } else {
+            conf.mysql_handler_max_connections
+        };
+        let idle_timeout = match conf.mysql_handler_idle_timeout {
+            0 => None,
+            seconds => Some(Duration::from_secs(seconds)),
+        };
         MySQLHandler {
-            context,
             hostname: conf.mysql_handler_host,
             port: conf.mysql_handler_port,
+            connections: Connections {
+                context,
+                query_memory_limit: conf.query_memory_limit,
+                cpu_executor,
+                permits: Arc::new(Semaphore::new(max_connections)),
+                max_connections,
+                idle_timeout,
+            },
             abort_handle,
             abort_registration: Some(registration),
             join_handle: None,
@@ -198,15 +357,10 @@ impl MySQLHandler {
                 "MySQLHandler already running.",
             ))),
             Some(registration) => {
-                let rejected_rt = Arc::new(Runtime::with_worker_threads(
-                    1,
-                    Some("mysql-handler".to_string()),
-                )?);
                 let listening = format!("{}:{}", self.hostname, self.port);
                 let (stream, listener) = Self::listener_tcp(listening).await?;
                 let stream = Abortable::new(stream, registration);
-                self.join_handle =
-                    Some(tokio::spawn(self.listen_loop(stream, rejected_rt)));
+                self.join_handle = Some(tokio::spawn(self.listen_loop(stream)));
                 Ok(listener)
             }
         }
@@ -234,75 +388,147 @@ impl MySQLHandler {
         Ok((TcpListenerStream::new(listener), listener_addr))
     }
 
-    fn listen_loop(
-        &self,
-        stream: ListeningStream,
-        rt: Arc<Runtime>,
-    ) -> impl Future<Output = ()> {
-        let context = self.context.clone();
+    fn listen_loop(&self, stream: ListeningStream) -> impl Future<Output = ()> {
+        let connections = self.connections.clone();
         stream.for_each(move |accept_socket| {
-            let executor = rt.clone();
-            let ctx = context.clone();
+            let connections = connections.clone();
             async move {
                 match accept_socket {
                     Err(error) => error!("Broken session connection: {}", error),
-                    Ok(socket) => MySQLHandler::accept_socket(ctx, executor, socket),
+                    Ok(socket) => MySQLHandler::accept_socket(connections, socket),
                 };
             }
         })
     }
 
-    pub fn accept_socket(
-        context: Arc<HetuContext>,
-        executor: Arc<Runtime>,
-        socket: TcpStream,
-    ) {
-        executor.spawn(async move {
-            if let Err(error) = Self::run_on_stream(context, socket) {
+    /// Serve a connection on the current runtime, the queries run on the cpu executor
+    fn accept_socket(connections: Connections, socket: TcpStream) {
+        tokio::spawn(async move {
+            let permit = match connections.permits.clone().try_acquire_owned() {
+                Ok(permit) => permit,
+                Err(_) => {
+                    warn!(
+                        "Rejecting a connection, the {} connections are open",
+                        connections.max_connections
+                    );
+                    if let Err(error) = Self::reject(socket).await {
+                        error!("Fail to reject a connection: {}", error);
+                    }
+                    return;
+                }
+            };
+            if let Err(error) = Self::run_on_stream(connections, socket).await {
                 error!("Unexpected error occurred during query: {:?}", error);
             };
+            drop(permit);
         });
     }
 
-    fn run_on_stream(context: Arc<HetuContext>, stream: TcpStream) -> Result<()> {
-        let blocking_stream = Self::convert_stream(stream)?;
-
-        let non_blocking_stream = TcpStream::from_std(blocking_stream)?;
-        let query_executor =
-            Runtime::with_worker_threads(1, Some("mysql-query-executor".to_string()))?;
-        Thread::spawn(move || {
-            let join_handle = query_executor.spawn(async move {
-                let interactive_worker = Backend {
-                    // create local execution context
-                    // ctx: SessionContext::with_config(
-                    //     SessionConfig::new().with_information_schema(true),
-                    // ),
-                    ctx: context,
-                    generic_hold: Default::default(),
-                };
+    // The server is full, the client gets an error instead of the handshake
+    async fn reject(mut socket: TcpStream) -> io::Result<()> {
+        let message = b"Too many connections";
+        let mut payload = vec![0xff];
+        payload.extend_from_slice(&(ErrorKind::ER_CON_COUNT_ERROR as u16).to_le_bytes());
+        payload.extend_from_slice(message);
+        // 3 bytes of payload length and the sequence id 0
+        let mut packet = (payload.len() as u32).to_le_bytes()[..3].to_vec();
+        packet.push(0);
+        packet.extend_from_slice(&payload);
+        socket.write_all(&packet).await?;
+        socket.shutdown().await
+    }
 
-                let opts = IntermediaryOptions {
-                    process_use_statement_on_query: true,
-                };
+    async fn run_on_stream(connections: Connections, stream: TcpStream) -> Result<()> {
+        let interactive_worker = Backend {
+            ctx: connections.context,
+            connection_id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+            query_memory_limit: connections.query_memory_limit,
+            cpu_executor: connections.cpu_executor,
+            settings: HashMap::new(),
+            generic_hold: Default::default(),
+        };
+        let opts = IntermediaryOptions {
+            process_use_statement_on_query: true,
+        };
+        match connections.idle_timeout {
+            Some(idle_timeout) => {
                 AsyncMysqlIntermediary::run_with_options(
                     interactive_worker,
-                    non_blocking_stream,
+                    IdleTimeoutStream::new(stream, idle_timeout),
                     &opts,
                 )
                 .await
-            });
-            let _ = futures::executor::block_on(join_handle);
-        });
-        info!("Start MySQL Handler successful.");
-        Ok(())
+            }
+            None => {
+                AsyncMysqlIntermediary::run_with_options(
+                    interactive_worker,
+                    stream,
+                    &opts,
+                )
+                .await
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_set_variable() {
+        assert_eq!(
+            Some((BALLISTA_QUERY_MAX_EXECUTION_TIME, "1000".to_owned())),
+            parse_set_variable("SET max_execution_time = 1000;")
+        );
+        assert_eq!(
+            Some((BALLISTA_QUERY_MAX_RESULT_ROWS, "10".to_owned())),
+            parse_set_variable("set session max_result_rows=10")
+        );
+        assert_eq!(
+            Some((BALLISTA_QUERY_MAX_EXECUTION_TIME, "0".to_owned())),
+            parse_set_variable("SET @@session.max_execution_time = 0")
+        );
+        assert_eq!(None, parse_set_variable("SET autocommit = 1"));
+        assert_eq!(None, parse_set_variable("SELECT max_execution_time = 1"));
     }
 
-    // TODO: move to ToBlockingStream trait
-    fn convert_stream(stream: TcpStream) -> Result<std::net::TcpStream> {
-        let stream = stream.into_std()?;
-        // .map_err(Err(HetuError::Internal(String::from("Cannot to convert Tokio TcpStream to Std TcpStream"))))?;
-        stream.set_nonblocking(false)?;
-        // .map_err(Err(HetuError::Internal(String::from("Cannot to convert Tokio TcpStream to Std TcpStream"))))?;
-        Ok(stream)
+    #[tokio::test]
+    async fn test_max_connections() -> Result<()> {
+        let conf = Config {
+            mysql_handler_host: "127.0.0.1".to_owned(),
+            mysql_handler_port: 0,
+            mysql_handler_max_connections: 2,
+            ..Default::default()
+        };
+        let context = Arc::new(HetuContext::local(&BallistaConfig::new().unwrap()));
+        let mut handler =
+            MySQLHandler::create(conf, context, DedicatedExecutor::new("test-cpu", 1));
+        let addr = handler.start().await?;
+        let opts = mysql_async::OptsBuilder::default()
+            .ip_or_hostname("127.0.0.1")
+            .tcp_port(addr.port())
+            .user(Some("root"))
+            .prefer_socket(false)
+            // the settings are not queried from the handler
+            .max_allowed_packet(Some(16 * 1024 * 1024))
+            .wait_timeout(Some(28800));
+
+        let mut conns = vec![];
+        for _ in 0..2 {
+            conns.push(mysql_async::Conn::new(opts.clone()).await.unwrap());
+        }
+        // the connection over the limit gets ER_CON_COUNT_ERROR
+        match mysql_async::Conn::new(opts.clone()).await {
+            Err(mysql_async::Error::Server(error)) => assert_eq!(error.code, 1040),
+            Err(error) => panic!("Unexpected error {:?}", error),
+            Ok(_) => panic!("The connection over the limit was accepted"),
+        }
+
+        for conn in conns {
+            conn.disconnect().await.unwrap();
+        }
+        handler.shutdown(true).await;
+        Ok(())
     }
 }
@@ -0,0 +1,148 @@
+// Copyright 2021 HetuDB.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Resources of the executor reported to the scheduler in the heartbeats.
+//!
+//! The system wide figures are read from `/proc`, they are left unknown on the platforms
+//! without it so that the scheduler never considers the executor short of resources.
+
+use std::fs;
+
+use hetu_core::serde::scheduler::ExecutorState;
+use log::warn;
+
+use crate::executor::Executor;
+use crate::metrics::exporter;
+
+/// Collect the current resource usage of an executor
+pub fn collect_executor_state(executor: &Executor) -> ExecutorState {
+    let mut state = ExecutorState::default();
+
+    // The memory tracked by the runtimes and reserved by the DataFusion operators only
+    // covers part of the process, so the resident size is reported when it is larger
+    let tracked_memory = exporter::tracked_memory().max(0) as u64
+        + executor.runtime.memory_manager.get_requester_total() as u64;
+    state.used_memory_size = tracked_memory.max(resident_memory().unwrap_or_default());
+
+    if let Some((total, available)) = system_memory() {
+        state.total_memory_size = total;
+        state.available_memory_size = available;
+    }
+    if let Some(cpu_load) = cpu_load() {
+        state.cpu_load = cpu_load;
+    }
+    match disk_space(executor.work_dir()) {
+        Ok(Some((total, available))) => {
+            state.total_disk_size = total;
+            state.available_disk_size = available;
+        }
+        Ok(None) => {}
+        Err(e) => warn!(
+            "Fail to get the disk space of {}: {}",
+            executor.work_dir(),
+            e
+        ),
+    }
+    state
+}
+
+/// Resident memory of this process in bytes
+fn resident_memory() -> Option<u64> {
+    let statm = fs::read_to_string("/proc/self/statm").ok()?;
+    let resident_pages = statm.split_whitespace().nth(1)?.parse::<u64>().ok()?;
+    Some(resident_pages * page_size())
+}
+
+#[cfg(unix)]
+fn page_size() -> u64 {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as u64 }
+}
+
+#[cfg(not(unix))]
+fn page_size() -> u64 {
+    4096
+}
+
+/// Total and available memory of the system in bytes
+fn system_memory() -> Option<(u64, u64)> {
+    parse_meminfo(&fs::read_to_string("/proc/meminfo").ok()?)
+}
+
+fn parse_meminfo(meminfo: &str) -> Option<(u64, u64)> {
+    let field = |name: &str| {
+        meminfo
+            .lines()
+            .find_map(|line| line.strip_prefix(name))
+            .and_then(|value| {
+                value
+                    .trim()
+                    .trim_end_matches("kB")
+                    .trim()
+                    .parse::<u64>()
+                    .ok()
+            })
+            .map(|kb| kb * 1024)
+    };
+    Some((field("MemTotal:")?, field("MemAvailable:")?))
+}
+
+/// Load average of the last minute divided by the number of cores
+fn cpu_load() -> Option<f64> {
+    let loadavg = fs::read_to_string("/proc/loadavg").ok()?;
+    let load = loadavg.split_whitespace().next()?.parse::<f64>().ok()?;
+    let cores = std::thread::available_parallelism().ok()?.get();
+    Some(load / cores as f64)
+}
+
+/// Total and available space in bytes of the file system holding a path
+#[cfg(unix)]
+fn disk_space(path: &str) -> std::io::Result<Option<(u64, u64)>> {
+    use std::ffi::CString;
+    use std::mem::MaybeUninit;
+
+    let path = CString::new(path)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+    if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let stat = unsafe { stat.assume_init() };
+    let block_size = stat.f_frsize as u64;
+    Ok(Some((
+        stat.f_blocks as u64 * block_size,
+        stat.f_bavail as u64 * block_size,
+    )))
+}
+
+#[cfg(not(unix))]
+fn disk_space(_path: &str) -> std::io::Result<Option<(u64, u64)>> {
+    Ok(None)
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse_meminfo;
+
+    #[test]
+    fn test_parse_meminfo() {
+        let meminfo = "MemTotal:       16318480 kB\n\
+                       MemFree:          891652 kB\n\
+                       MemAvailable:    8071372 kB\n";
+        assert_eq!(
+            parse_meminfo(meminfo),
+            Some((16318480 * 1024, 8071372 * 1024))
+        );
+        assert_eq!(parse_meminfo("MemTotal: 1024 kB\n"), None);
+    }
+}
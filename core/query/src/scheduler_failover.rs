@@ -0,0 +1,137 @@
+// Copyright 2021 HetuDB.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Failover between the schedulers of a cluster.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use hetu_core::error::BallistaError;
+use hetu_core::serde::protobuf::scheduler_grpc_client::SchedulerGrpcClient;
+use log::warn;
+use tokio::sync::mpsc::Sender;
+use tonic::transport::{Channel, ClientTlsConfig, Endpoint};
+use tonic::Code;
+use tower::discover::Change;
+
+/// Routes the scheduler channel to one scheduler address at a time.
+///
+/// Only the leader among the schedulers serves requests, the other ones answer with
+/// `Unavailable` like an unreachable scheduler. All the clients created from the same
+/// channel switch to the next address together on [`SchedulerFailover::fail_over`].
+#[derive(Clone)]
+pub struct SchedulerFailover {
+    endpoints: Arc<Vec<Endpoint>>,
+    current: Arc<AtomicUsize>,
+    changes: Sender<Change<usize, Endpoint>>,
+}
+
+impl SchedulerFailover {
+    /// Create a scheduler client routed to the first of the scheduler urls, over TLS if
+    /// a TLS config is given
+    pub async fn connect(
+        urls: Vec<String>,
+        tls_config: Option<ClientTlsConfig>,
+    ) -> Result<(SchedulerGrpcClient<Channel>, Self), BallistaError> {
+        if urls.is_empty() {
+            return Err(BallistaError::General(
+                "No scheduler address is configured".to_owned(),
+            ));
+        }
+        let endpoints = urls
+            .into_iter()
+            .map(|url| {
+                let endpoint = Endpoint::from_shared(url.clone());
+                match tls_config.clone() {
+                    Some(tls_config) => {
+                        endpoint.and_then(|endpoint| endpoint.tls_config(tls_config))
+                    }
+                    None => endpoint,
+                }
+                .map_err(|e| {
+                    BallistaError::General(format!(
+                        "Invalid scheduler address {}: {}",
+                        url, e
+                    ))
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let (channel, changes) = Channel::balance_channel(endpoints.len());
+        changes
+            .send(Change::Insert(0, endpoints[0].clone()))
+            .await
+            .map_err(|e| BallistaError::General(e.to_string()))?;
+
+        Ok((
+            SchedulerGrpcClient::new(channel),
+            Self {
+                endpoints: Arc::new(endpoints),
+                current: Arc::new(AtomicUsize::new(0)),
+                changes,
+            },
+        ))
+    }
+
+    /// Failover for a client which is connected to a single scheduler
+    pub fn single() -> Self {
+        let (changes, _) = tokio::sync::mpsc::channel(1);
+        Self {
+            endpoints: Arc::new(vec![]),
+            current: Arc::new(AtomicUsize::new(0)),
+            changes,
+        }
+    }
+
+    /// Whether a request failed since the scheduler is gone or is not the leader
+    pub fn should_fail_over(status: &tonic::Status) -> bool {
+        status.code() == Code::Unavailable
+    }
+
+    /// Number of the configured scheduler addresses
+    pub fn num_schedulers(&self) -> usize {
+        std::cmp::max(self.endpoints.len(), 1)
+    }
+
+    /// Route the channel to the next scheduler address
+    pub async fn fail_over(&self) {
+        if self.endpoints.len() <= 1 {
+            return;
+        }
+        let current = self.current.load(Ordering::SeqCst);
+        let next = (current + 1) % self.endpoints.len();
+        // Another request already failed over
+        if self
+            .current
+            .compare_exchange(current, next, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            return;
+        }
+        warn!(
+            "Failing over from scheduler {} to {}",
+            self.endpoints[current].uri(),
+            self.endpoints[next].uri()
+        );
+        let changes = vec![
+            Change::Insert(next, self.endpoints[next].clone()),
+            Change::Remove(current),
+        ];
+        for change in changes {
+            if let Err(e) = self.changes.send(change).await {
+                warn!("Fail to switch the scheduler address due to {}", e);
+            }
+        }
+    }
+}
@@ -31,6 +31,7 @@ use datafusion::catalog::TableReference;
 use datafusion::dataframe::DataFrame;
 use datafusion::datasource::TableProvider;
 use datafusion::error::{DataFusionError, Result};
+use datafusion::execution::context::TaskContext;
 use datafusion::logical_plan::{
     source_as_provider, CreateExternalTable, FileType, LogicalPlan, TableScan,
 };
@@ -203,6 +204,43 @@ impl HetuContext {
         })
     }
 
+    /// A context planning the queries of the remote session with the settings of the
+    /// context overridden by the given ones
+    fn context_with_settings(
+        &self,
+        settings: &HashMap<String, String>,
+    ) -> Result<SessionContext> {
+        let state = self.state.lock();
+        let mut merged = state.config.settings().clone();
+        merged.extend(settings.iter().map(|(k, v)| (k.clone(), v.clone())));
+        let config = BallistaConfig::with_settings(merged)
+            .map_err(|e| DataFusionError::Plan(e.to_string()))?;
+        let scheduler_url =
+            format!("http://{}:{}", state.scheduler_host, state.scheduler_port);
+        Ok(
+            create_df_ctx_with_ballista_query_planner::<LogicalPlanNode>(
+                scheduler_url,
+                self.context.session_id(),
+                &config,
+            ),
+        )
+    }
+
+    /// A context planning the queries locally, for the tests of the handlers
+    #[cfg(test)]
+    pub(crate) fn local(config: &BallistaConfig) -> Self {
+        let state = HetuContextState::new("localhost".to_owned(), 50050, config);
+        Self {
+            state: Arc::new(Mutex::new(state)),
+            context: Arc::new(SessionContext::new()),
+        }
+    }
+
+    /// The context to execute the physical plans of the queries with
+    pub fn task_ctx(&self) -> Arc<TaskContext> {
+        self.context.task_ctx()
+    }
+
     /// Create a DataFrame representing an Avro table scan
     /// TODO fetch schema from scheduler instead of resolving locally
     pub async fn read_avro(
@@ -210,12 +248,10 @@ impl HetuContext {
         path: &str,
         options: AvroReadOptions<'_>,
     ) -> Result<Arc<DataFrame>> {
-        // convert to absolute path because the executor likely has a different working directory
-        let path = PathBuf::from(path);
-        let path = fs::canonicalize(&path)?;
+        let path = absolute_path(path)?;
 
         let ctx = self.context.clone();
-        let df = ctx.read_avro(path.to_str().unwrap(), options).await?;
+        let df = ctx.read_avro(&path, options).await?;
         Ok(df)
     }
 
@@ -226,12 +262,10 @@ impl HetuContext {
         path: &str,
         options: ParquetReadOptions<'_>,
     ) -> Result<Arc<DataFrame>> {
-        // convert to absolute path because the executor likely has a different working directory
-        let path = PathBuf::from(path);
-        let path = fs::canonicalize(&path)?;
+        let path = absolute_path(path)?;
 
         let ctx = self.context.clone();
-        let df = ctx.read_parquet(path.to_str().unwrap(), options).await?;
+        let df = ctx.read_parquet(&path, options).await?;
         Ok(df)
     }
 
@@ -242,12 +276,10 @@ impl HetuContext {
         path: &str,
         options: CsvReadOptions<'_>,
     ) -> Result<Arc<DataFrame>> {
-        // convert to absolute path because the executor likely has a different working directory
-        let path = PathBuf::from(path);
-        let path = fs::canonicalize(&path)?;
+        let path = absolute_path(path)?;
 
         let ctx = self.context.clone();
-        let df = ctx.read_csv(path.to_str().unwrap(), options).await?;
+        let df = ctx.read_csv(&path, options).await?;
         Ok(df)
     }
 
@@ -338,7 +370,21 @@ impl HetuContext {
     /// This method is `async` because queries of type `CREATE EXTERNAL TABLE`
     /// might require the schema to be inferred.
     pub async fn sql(&self, sql: &str) -> Result<Arc<DataFrame>> {
-        let mut ctx = self.context.clone();
+        self.sql_with_settings(sql, &HashMap::new()).await
+    }
+
+    /// Create a DataFrame from a SQL statement, the `ballista.*` settings of a session
+    /// override the ones of the context for the query.
+    pub async fn sql_with_settings(
+        &self,
+        sql: &str,
+        settings: &HashMap<String, String>,
+    ) -> Result<Arc<DataFrame>> {
+        let mut ctx = if settings.is_empty() {
+            self.context.clone()
+        } else {
+            Arc::new(self.context_with_settings(settings)?)
+        };
 
         let is_show = self.is_show_statement(sql).await?;
         // the show tables、 show columns sql can not run at scheduler because the tables is store at client
@@ -435,6 +481,16 @@ impl HetuContext {
     }
 }
 
+/// Convert a local path to an absolute one because the executor likely has a different
+/// working directory, the urls of the object stores are kept as they are
+fn absolute_path(path: &str) -> Result<String> {
+    if path.contains("://") {
+        return Ok(path.to_owned());
+    }
+    let path = fs::canonicalize(&PathBuf::from(path))?;
+    Ok(path.to_str().unwrap().to_owned())
+}
+
 #[cfg(test)]
 mod tests {
 
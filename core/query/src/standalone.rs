@@ -16,6 +16,7 @@
 // under the License.
 
 use crate::metrics::LoggingMetricsCollector;
+use crate::scheduler_failover::SchedulerFailover;
 use crate::{execution_loop, executor::Executor, flight_service::BallistaFlightService};
 use arrow_flight::flight_service_server::FlightServiceServer;
 use datafusion::execution::runtime_env::{RuntimeConfig, RuntimeEnv};
@@ -63,6 +64,7 @@ pub async fn new_standalone_executor<
             }
             .into(),
         ),
+        auth_token: "".to_owned(),
     };
     let work_dir = TempDir::new()?
         .into_path()
@@ -88,6 +90,11 @@ pub async fn new_standalone_executor<
         ),
     );
 
-    tokio::spawn(execution_loop::poll_loop(scheduler, executor, codec));
+    tokio::spawn(execution_loop::poll_loop(
+        scheduler,
+        SchedulerFailover::single(),
+        executor,
+        codec,
+    ));
     Ok(())
 }